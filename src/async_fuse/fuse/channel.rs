@@ -1,6 +1,6 @@
 //! The implementation of FUSE channel
 
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use anyhow::{anyhow, Context};
 use clippy_utilities::Cast;
@@ -20,14 +20,19 @@ pub struct Channel {
 }
 
 impl Channel {
-    /// Create FUSE channel
-    #[allow(dead_code)]
+    /// Create FUSE channel, the requests of the FUSE session can be read from
+    /// the channel and replied through it. The channel is non-blocking, the
+    /// same as the session fd.
     pub async fn new<F: FileSystem + Send + Sync + 'static>(
         session: &Session<F>,
     ) -> anyhow::Result<Self> {
         let devname = "/dev/fuse";
         let clonefd = tokio::task::spawn_blocking(move || {
-            fcntl::open(devname, OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty())
+            fcntl::open(
+                devname,
+                OFlag::O_RDWR | OFlag::O_CLOEXEC | OFlag::O_NONBLOCK,
+                Mode::empty(),
+            )
         })
         .await?;
 
@@ -63,9 +68,20 @@ impl Channel {
     }
 
     /// Get channel fd
-    #[allow(dead_code)]
     #[must_use]
     pub const fn fd(&self) -> RawFd {
         self.chan_fd
     }
 }
+
+impl AsRawFd for Channel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.chan_fd
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        close(self.chan_fd).ok();
+    }
+}
@@ -5,13 +5,20 @@ use std::path::Path;
 
 use async_trait::async_trait;
 
+#[cfg(feature = "abi-7-21")]
+use super::fuse_reply::ReplyDirectoryPlus;
 use super::fuse_reply::{
     ReplyAttr, ReplyBMap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyLock, ReplyOpen, ReplyStatFs, ReplyWrite, ReplyXAttr,
+    ReplyLSeek, ReplyLock, ReplyOpen, ReplyStatFs, ReplyWrite, ReplyXAttr,
 };
 use super::fuse_request::Request;
+#[cfg(feature = "abi-7-16")]
+use super::protocol::FuseForgetOne;
 use super::protocol::INum;
-use crate::async_fuse::memfs::{CreateParam, FileLockParam, RenameParam, SetAttrParam};
+use crate::async_fuse::memfs::{
+    CopyRangeParam, CreateParam, FileLockParam, HandleSnapshot, RenameParam, SetAttrParam,
+    WriteData,
+};
 
 /// FUSE filesystem trait
 #[async_trait]
@@ -37,6 +44,10 @@ pub trait FileSystem {
     /// Forget about an inode
     async fn forget(&self, req: &Request<'_>, nlookup: u64);
 
+    /// Forget about multiple inodes
+    #[cfg(feature = "abi-7-16")]
+    async fn batch_forget(&self, req: &Request<'_>, nodes: &[FuseForgetOne]);
+
     /// Get file attributes.
     async fn getattr(&self, req: &Request<'_>, reply: ReplyAttr) -> nix::Result<usize>;
 
@@ -108,9 +119,9 @@ pub trait FileSystem {
     /// Create a hard link
     async fn link(
         &self,
-        _req: &Request<'_>,
-        _newparent: u64,
-        _newname: &str,
+        req: &Request<'_>,
+        oldnodeid: u64,
+        newname: &str,
         reply: ReplyEntry,
     ) -> nix::Result<usize>;
 
@@ -133,7 +144,7 @@ pub trait FileSystem {
         req: &Request<'_>,
         fh: u64,
         offset: i64,
-        data: Vec<u8>,
+        data: WriteData,
         flags: u32,
         reply: ReplyWrite,
     ) -> nix::Result<usize>;
@@ -152,8 +163,8 @@ pub trait FileSystem {
         &self,
         req: &Request<'_>,
         fh: u64,
-        flags: u32, // same as the open flags
-        lock_owner: u64,
+        flags: u32,              // same as the open flags
+        lock_owner: Option<u64>, // set if the flock locks of the owner should be released
         flush: bool,
         reply: ReplyEmpty,
     ) -> nix::Result<usize>;
@@ -179,6 +190,16 @@ pub trait FileSystem {
         mut reply: ReplyDirectory,
     ) -> nix::Result<usize>;
 
+    /// Read directory with attributes of its entries
+    #[cfg(feature = "abi-7-21")]
+    async fn readdirplus(
+        &self,
+        req: &Request<'_>,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) -> nix::Result<usize>;
+
     /// Release an open directory
     async fn releasedir(
         &self,
@@ -268,6 +289,45 @@ pub trait FileSystem {
         reply: ReplyEmpty,
     ) -> nix::Result<usize>;
 
+    /// Acquire, convert or release a BSD flock lock
+    async fn flock(
+        &self,
+        _req: &Request<'_>,
+        _lk_param: FileLockParam,
+        _sleep: bool,
+        reply: ReplyEmpty,
+    ) -> nix::Result<usize>;
+
+    /// Allocate, punch or zero a range of an open file
+    #[cfg(feature = "abi-7-19")]
+    async fn fallocate(
+        &self,
+        _req: &Request<'_>,
+        _fh: u64,
+        _offset: u64,
+        _length: u64,
+        _mode: u32,
+        reply: ReplyEmpty,
+    ) -> nix::Result<usize>;
+
+    /// Find the next data or hole after the specified offset
+    async fn lseek(
+        &self,
+        _req: &Request<'_>,
+        _fh: u64,
+        _offset: u64,
+        _whence: u32,
+        reply: ReplyLSeek,
+    ) -> nix::Result<usize>;
+
+    /// Copy a range of data from one file to another
+    async fn copy_file_range(
+        &self,
+        _req: &Request<'_>,
+        _param: CopyRangeParam,
+        reply: ReplyWrite,
+    ) -> nix::Result<usize>;
+
     /// Map block index within file to block index within device
     async fn bmap(
         &self,
@@ -279,4 +339,17 @@ pub trait FileSystem {
 
     /// Set fuse fd into `FileSystem`
     async fn set_fuse_fd(&self, fuse_fd: RawFd);
+
+    /// Flush the data of all the open files before shutdown
+    async fn flush_all(&self) -> anyhow::Result<()>;
+
+    /// Take a snapshot of the handles the kernel holds, for live upgrade
+    fn handle_snapshot(&self) -> HandleSnapshot;
+
+    /// Restore the handles handed over by the previous process
+    fn restore_handles(&self, snapshot: HandleSnapshot);
+
+    /// Drop the lookups held by the kernel, which never forgets them after
+    /// un-mount
+    async fn release_lookups(&self) -> anyhow::Result<()>;
 }
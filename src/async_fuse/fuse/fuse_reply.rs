@@ -1,12 +1,16 @@
 //! The implementation of FUSE response
 
+#[cfg(feature = "abi-7-15")]
+use std::collections::HashMap;
 use std::convert::AsRef;
-#[cfg(feature = "abi-7-18")]
+#[cfg(feature = "abi-7-12")]
 use std::ffi::CString;
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
+#[cfg(feature = "abi-7-15")]
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use std::{mem, slice};
 
@@ -14,15 +18,35 @@ use clippy_utilities::{Cast, OverflowArithmetic};
 use nix::errno::Errno;
 use nix::sys::stat::SFlag;
 use nix::sys::uio::{self, IoVec};
+#[cfg(feature = "abi-7-15")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "abi-7-15")]
+use parking_lot::Mutex;
+#[cfg(feature = "abi-7-15")]
+use tokio::sync::oneshot;
 use tracing::debug;
 
 use super::abi_marker;
+#[cfg(feature = "abi-7-21")]
+use super::protocol::FuseDirEntPlus;
 use super::protocol::{
     FuseAttr, FuseAttrOut, FuseBMapOut, FuseDirEnt, FuseEntryOut, FuseFileLock, FuseGetXAttrOut,
-    FuseInitOut, FuseKStatFs, FuseLockOut, FuseOpenOut, FuseOutHeader, FuseStatFsOut, FuseWriteOut,
+    FuseInitOut, FuseKStatFs, FuseLSeekOut, FuseLockOut, FuseOpenOut, FuseOutHeader, FuseStatFsOut,
+    FuseWriteOut,
 };
 #[cfg(feature = "abi-7-18")]
 use super::protocol::{FuseNotifyCode::FUSE_NOTIFY_DELETE, FuseNotifyDeleteOut};
+#[cfg(feature = "abi-7-12")]
+use super::protocol::{
+    FuseNotifyCode::{FUSE_NOTIFY_INVAL_ENTRY, FUSE_NOTIFY_INVAL_INODE},
+    FuseNotifyInvalEntryOut, FuseNotifyInvalINodeOut,
+};
+#[cfg(feature = "abi-7-15")]
+use super::protocol::{
+    FuseNotifyCode::{FUSE_NOTIFY_RETRIEVE, FUSE_NOTIFY_STORE},
+    FuseNotifyRetrieveOut, FuseNotifyStoreOut,
+};
+use super::splice::{self, REPLY_PIPE_SIZE};
 
 /// This trait describes a type that can be converted to Vec<`IoVec`<&[u8]>>
 pub trait AsIoVecList {
@@ -212,6 +236,40 @@ impl ReplyRaw {
         self.send_raw_message(0_i32, data).await
     }
 
+    /// Send response to FUSE kernel by splice, the pages of `data` are mapped
+    /// into the reply pipe of the blocking thread and moved to the FUSE device
+    /// without copying in user space. Fall back to `send` if the response
+    /// cannot fit in the pipe.
+    async fn splice(self, data: impl AsIoVecList + Send + Sync + 'static) -> nix::Result<usize> {
+        let header_len = mem::size_of::<FuseOutHeader>();
+        let len = header_len.overflow_add(data.len());
+        if len > REPLY_PIPE_SIZE {
+            return self.send(data).await;
+        }
+        let fd = self.fd;
+        let wsize = tokio::task::spawn_blocking(move || {
+            let header = FuseOutHeader {
+                len: len.cast(),
+                error: 0_i32,
+                unique: self.unique,
+            };
+            let mut iovecs = data.as_io_vec_list();
+            iovecs.insert(0, IoVec::from_slice(abi_marker::as_abi_bytes(&header)));
+            splice::with_reply_pipe(|pipe| {
+                pipe.vmsplice(&iovecs)?;
+                // The whole response is spliced to the FUSE device in one message
+                pipe.splice_to(fd, len)
+            })
+        })
+        .await
+        .unwrap_or_else(|e| {
+            panic!("failed to splice bytes to fuse device for error {e}");
+        })?;
+
+        debug!("spliced {} bytes to fuse device successfully", wsize);
+        Ok(wsize)
+    }
+
     /// Send error code response to FUSE kernel
     async fn send_error_code(self, error_code: Errno) -> nix::Result<usize> {
         // FUSE requires the error number to be negative
@@ -274,10 +332,10 @@ impl_fuse_reply_new_for! {
     ReplyAttr,
     ReplyBMap,
     ReplyCreate,
-    ReplyData,
     ReplyEmpty,
     ReplyEntry,
     ReplyInit,
+    ReplyLSeek,
     ReplyLock,
     ReplyOpen,
     ReplyStatFs,
@@ -315,6 +373,7 @@ impl_fuse_reply_error_for! {
     ReplyEmpty,
     ReplyEntry,
     ReplyInit,
+    ReplyLSeek,
     ReplyLock,
     ReplyOpen,
     ReplyStatFs,
@@ -322,6 +381,11 @@ impl_fuse_reply_error_for! {
     ReplyXAttr,
 }
 
+#[cfg(feature = "abi-7-21")]
+impl_fuse_reply_error_for! {
+    ReplyDirectoryPlus,
+}
+
 /// Impl `AsIoVec` trait
 macro_rules! impl_as_iovec_for {
     {$($t:ty,)+} => {
@@ -357,12 +421,23 @@ impl_as_iovec_for! {
     FuseBMapOut,
     FuseEntryOut,
     FuseInitOut,
+    FuseLSeekOut,
     FuseLockOut,
     FuseOpenOut,
     FuseStatFsOut,
     FuseWriteOut,
     FuseGetXAttrOut,
 }
+#[cfg(feature = "abi-7-12")]
+impl_as_iovec_for! {
+    FuseNotifyInvalEntryOut,
+    FuseNotifyInvalINodeOut,
+}
+#[cfg(feature = "abi-7-15")]
+impl_as_iovec_for! {
+    FuseNotifyRetrieveOut,
+    FuseNotifyStoreOut,
+}
 #[cfg(feature = "abi-7-18")]
 impl_as_iovec_for! {
     FuseNotifyDeleteOut,
@@ -376,9 +451,13 @@ pub struct ReplyInit {
 }
 
 impl ReplyInit {
-    /// Reply init response
-    pub async fn init(self, resp: FuseInitOut) -> nix::Result<usize> {
-        self.reply.send(resp).await
+    /// Reply init response, only the first `size` bytes are sent so that
+    /// older kernels get the response size they expect
+    pub async fn init(self, resp: FuseInitOut, size: usize) -> nix::Result<usize> {
+        let bytes = abi_marker::as_abi_bytes(&resp);
+        self.reply
+            .send(bytes.get(..size).unwrap_or(bytes).to_vec())
+            .await
     }
 }
 
@@ -401,12 +480,36 @@ impl ReplyEmpty {
 pub struct ReplyData {
     /// The inner raw reply
     reply: ReplyRaw,
+    /// Whether to splice the data to the FUSE device instead of writing it
+    splice: bool,
 }
 
 impl ReplyData {
+    /// New fuse reply
+    #[must_use]
+    pub const fn new(unique: u64, fd: RawFd) -> Self {
+        Self {
+            reply: ReplyRaw::new(unique, fd),
+            splice: false,
+        }
+    }
+
+    /// New fuse reply which splices the data to the FUSE device
+    #[must_use]
+    pub const fn new_spliced(unique: u64, fd: RawFd) -> Self {
+        Self {
+            reply: ReplyRaw::new(unique, fd),
+            splice: true,
+        }
+    }
+
     /// Reply with byte data response
     pub async fn data(self, bytes: impl AsIoVecList + Send + Sync + 'static) -> nix::Result<usize> {
-        self.reply.send(bytes).await
+        if self.splice {
+            self.reply.splice(bytes).await
+        } else {
+            self.reply.send(bytes).await
+        }
     }
 }
 
@@ -548,8 +651,7 @@ pub struct ReplyCreate {
 }
 
 impl ReplyCreate {
-    /// Reply to a request with the given entry
-    #[allow(dead_code)]
+    /// Reply to a request with the given entry and open result
     pub async fn created(
         self,
         ttl: &Duration,
@@ -587,8 +689,7 @@ pub struct ReplyLock {
 }
 
 impl ReplyLock {
-    /// Reply to a request with the given open result
-    #[allow(dead_code)]
+    /// Reply to a request with the given lock
     pub async fn locked(self, start: u64, end: u64, typ: u32, pid: u32) -> nix::Result<usize> {
         self.reply
             .send(FuseLockOut {
@@ -618,6 +719,20 @@ impl ReplyBMap {
     }
 }
 
+/// FUSE lseek response
+#[derive(Debug)]
+pub struct ReplyLSeek {
+    /// The inner raw reply
+    reply: ReplyRaw,
+}
+
+impl ReplyLSeek {
+    /// Reply to a request with the found offset
+    pub async fn offset(self, offset: u64) -> nix::Result<usize> {
+        self.reply.send(FuseLSeekOut { offset }).await
+    }
+}
+
 /// FUSE directory response
 #[derive(Debug)]
 pub struct ReplyDirectory {
@@ -703,6 +818,113 @@ unsafe fn fuse_dir_ent_in_raw(from: &FuseDirEnt) -> &[u8] {
     bytes
 }
 
+/// FUSE directory plus response, used by `readdirplus()`
+#[cfg(feature = "abi-7-21")]
+#[derive(Debug)]
+pub struct ReplyDirectoryPlus {
+    /// The inner raw reply
+    reply: ReplyRaw,
+    /// The directory data in bytes
+    data: Vec<u8>,
+}
+
+#[cfg(feature = "abi-7-21")]
+impl ReplyDirectoryPlus {
+    /// Creates a new `ReplyDirectoryPlus` with a specified buffer size.
+    #[must_use]
+    pub fn new(unique: u64, fd: RawFd, size: usize) -> Self {
+        Self {
+            reply: ReplyRaw::new(unique, fd),
+            data: Vec::with_capacity(size),
+        }
+    }
+
+    /// Add an entry together with its attributes to the directory reply
+    /// buffer. Returns true if the buffer is full, in which case the entry is
+    /// not added. The offset is a transparent value, which the kernel uses
+    /// to request the next entries in further readdirplus calls.
+    ///
+    /// The kernel increases the lookup count of every entry successfully
+    /// added, so the caller should do the same on its side.
+    pub fn add<T: AsRef<OsStr>>(
+        &mut self,
+        offset: i64,
+        ttl: Duration,
+        attr: FuseAttr,
+        generation: u64,
+        name: T,
+    ) -> bool {
+        let name_bytes = name.as_ref().as_bytes();
+        let dirent_plus = FuseDirEntPlus {
+            entry_out: FuseEntryOut {
+                nodeid: attr.ino,
+                generation,
+                entry_valid: ttl.as_secs(),
+                attr_valid: ttl.as_secs(),
+                entry_valid_nsec: ttl.subsec_nanos(),
+                attr_valid_nsec: ttl.subsec_nanos(),
+                attr,
+            },
+            dirent: FuseDirEnt {
+                ino: attr.ino,
+                off: offset.cast(),
+                namelen: name_bytes.len().cast(),
+                typ: attr.mode.overflow_shr(12),
+            },
+        };
+        let entlen = dirent_plus.size_with_name();
+
+        // This is similar to call `FUSE_DIRENTPLUS_SIZE(d)` in `fuse.h`.
+        //
+        // <https://github.com/torvalds/linux/blob/00c570f4ba43ae73b41fa0a2269c3b0ac20386ef/include/uapi/linux/fuse.h#L711-L712>
+        let entsize = super::super::util::round_up(entlen, mem::size_of::<u64>()); // 64bit align
+
+        let padlen = entsize.overflow_sub(entlen);
+        if self.data.len().overflow_add(entsize) > self.data.capacity() {
+            return true;
+        }
+
+        // # Safety
+        // The `fuse_dir_ent_plus_in_raw` call is safe here, because:
+        // 1. The `dirent_plus` is just built above, as a in-stack allocated object.
+        //    Therefore, `&dirent_plus` is a valid reference.
+        // 2. `dirent_plus.dirent.namelen` is evaluated from `name_bytes`, and
+        //    `name_bytes` is to be written into `self.data` right after
+        //    `dirent_plus_bytes` is written.
+        let dirent_plus_bytes = unsafe { fuse_dir_ent_plus_in_raw(&dirent_plus) };
+        // Write dirent plus
+        self.data.extend_from_slice(dirent_plus_bytes);
+
+        // write name
+        self.data.extend_from_slice(name_bytes);
+
+        // write zero padding
+        self.data.extend(std::iter::repeat(0).take(padlen));
+
+        false
+    }
+
+    /// Reply to a request with the filled directory buffer
+    pub async fn ok(self) -> nix::Result<usize> {
+        self.reply.send(self.data).await
+    }
+}
+
+/// Get the underlying raw part of a `FuseDirEntPlus`, represented in `&[u8]`.
+///
+/// # Safety
+/// Behavior is undefined if any of the following conditions are violated:
+/// - `from` must be a valid reference to `FuseDirEntPlus`.
+/// - The `dirent.namelen` field in `from` must represent a valid length of the
+///   nearly following data of the `FuseDirEntPlus` struct, the same as
+///   `fuse_dir_ent_in_raw`.
+#[cfg(feature = "abi-7-21")]
+unsafe fn fuse_dir_ent_plus_in_raw(from: &FuseDirEntPlus) -> &[u8] {
+    let base: *const u8 = <*const FuseDirEntPlus>::cast(from);
+    let bytes = slice::from_raw_parts(base, mem::size_of::<FuseDirEntPlus>());
+    bytes
+}
+
 /// FUSE extended attribute response
 #[derive(Debug)]
 pub struct ReplyXAttr {
@@ -712,14 +934,12 @@ pub struct ReplyXAttr {
 
 impl ReplyXAttr {
     /// Reply to a request with the size of the xattr.
-    #[allow(dead_code)]
     pub async fn size(self, size: u32) -> nix::Result<usize> {
         self.reply.send(FuseGetXAttrOut { size, padding: 0 }).await
     }
 
     /// Reply to a request with the data in the xattr.
-    #[allow(dead_code)]
-    pub async fn data(self, bytes: FuseGetXAttrOut) -> nix::Result<usize> {
+    pub async fn data(self, bytes: Vec<u8>) -> nix::Result<usize> {
         self.reply.send(bytes).await
     }
 }
@@ -778,6 +998,178 @@ impl FuseDeleteNotification {
     }
 }
 
+/// Fuse invalidate i-node notification
+#[cfg(feature = "abi-7-12")]
+#[derive(Debug)]
+pub struct FuseInvalINodeNotification {
+    /// The inner raw reply
+    reply: ReplyRaw,
+}
+
+#[cfg(feature = "abi-7-12")]
+impl FuseInvalINodeNotification {
+    /// Create `FuseInvalINodeNotification`
+    #[must_use]
+    pub const fn new(fd: RawFd) -> Self {
+        Self {
+            reply: ReplyRaw::new(0, fd),
+        }
+    }
+
+    /// Notify kernel to drop the attributes and the cached data of
+    /// `[offset, offset + len)`, a negative `offset` only drops the
+    /// attributes, and a zero `len` drops the data till EOF
+    pub async fn notify(self, ino: u64, offset: i64, len: i64) -> nix::Result<usize> {
+        let notify_inval_inode = FuseNotifyInvalINodeOut {
+            ino,
+            off: offset,
+            len,
+        };
+        #[allow(clippy::as_conversions)] // allow this for enum
+        self.reply
+            .send_raw_message(FUSE_NOTIFY_INVAL_INODE as i32, notify_inval_inode)
+            .await
+    }
+}
+
+/// Fuse invalidate entry notification
+#[cfg(feature = "abi-7-12")]
+#[derive(Debug)]
+pub struct FuseInvalEntryNotification {
+    /// The inner raw reply
+    reply: ReplyRaw,
+}
+
+#[cfg(feature = "abi-7-12")]
+impl FuseInvalEntryNotification {
+    /// Create `FuseInvalEntryNotification`
+    #[must_use]
+    pub const fn new(fd: RawFd) -> Self {
+        Self {
+            reply: ReplyRaw::new(0, fd),
+        }
+    }
+
+    /// Notify kernel to drop the dentry of `name` under `parent`
+    pub async fn notify(self, parent: u64, name: String) -> nix::Result<usize> {
+        let notify_inval_entry = FuseNotifyInvalEntryOut {
+            parent,
+            namelen: name.len().cast(),
+            padding: 0,
+        };
+        let file_name = CString::new(name.clone())
+            .unwrap_or_else(|e| panic!("failed to create CString for {name}, error is {e:?}"));
+        #[allow(clippy::as_conversions)] // allow this for enum
+        self.reply
+            .send_raw_message(
+                FUSE_NOTIFY_INVAL_ENTRY as i32,
+                (notify_inval_entry, file_name),
+            )
+            .await
+    }
+}
+
+/// Fuse store notification
+#[cfg(feature = "abi-7-15")]
+#[derive(Debug)]
+pub struct FuseStoreNotification {
+    /// The inner raw reply
+    reply: ReplyRaw,
+}
+
+#[cfg(feature = "abi-7-15")]
+impl FuseStoreNotification {
+    /// Create `FuseStoreNotification`
+    #[must_use]
+    pub const fn new(fd: RawFd) -> Self {
+        Self {
+            reply: ReplyRaw::new(0, fd),
+        }
+    }
+
+    /// Notify kernel to store `data` into the page cache of `ino` at `offset`
+    pub async fn notify(self, ino: u64, offset: u64, data: Vec<u8>) -> nix::Result<usize> {
+        let notify_store = FuseNotifyStoreOut {
+            nodeid: ino,
+            offset,
+            size: data.len().cast(),
+            padding: 0,
+        };
+        #[allow(clippy::as_conversions)] // allow this for enum
+        self.reply
+            .send_raw_message(FUSE_NOTIFY_STORE as i32, (notify_store, data))
+            .await
+    }
+}
+
+/// The retrieve notifications waiting for the data from kernel, the unique ID
+/// of the notification -> the sender of the retrieved data
+#[cfg(feature = "abi-7-15")]
+static PENDING_RETRIEVES: Lazy<Mutex<HashMap<u64, oneshot::Sender<Vec<u8>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The unique ID of the next retrieve notification
+#[cfg(feature = "abi-7-15")]
+static NEXT_NOTIFY_UNIQUE: AtomicU64 = AtomicU64::new(1);
+
+/// Fuse retrieve notification
+#[cfg(feature = "abi-7-15")]
+#[derive(Debug)]
+pub struct FuseRetrieveNotification {
+    /// The inner raw reply
+    reply: ReplyRaw,
+}
+
+#[cfg(feature = "abi-7-15")]
+impl FuseRetrieveNotification {
+    /// Create `FuseRetrieveNotification`
+    #[must_use]
+    pub const fn new(fd: RawFd) -> Self {
+        Self {
+            reply: ReplyRaw::new(0, fd),
+        }
+    }
+
+    /// Notify kernel to send back the cached data of `[offset, offset + size)`
+    /// of `ino`, and wait for the data, which comes back in a
+    /// `FUSE_NOTIFY_REPLY` request completed by `complete`. The data is
+    /// shorter than `size` if the pages are not all cached.
+    pub async fn notify(self, ino: u64, offset: u64, size: u32) -> nix::Result<Vec<u8>> {
+        let notify_unique = NEXT_NOTIFY_UNIQUE.fetch_add(1, Ordering::Relaxed);
+        // Register before sending, as the reply may come back before the
+        // sending returns
+        let (sender, receiver) = oneshot::channel();
+        PENDING_RETRIEVES.lock().insert(notify_unique, sender);
+        let notify_retrieve = FuseNotifyRetrieveOut {
+            notify_unique,
+            nodeid: ino,
+            offset,
+            size,
+            padding: 0,
+        };
+        #[allow(clippy::as_conversions)] // allow this for enum
+        let result = self
+            .reply
+            .send_raw_message(FUSE_NOTIFY_RETRIEVE as i32, notify_retrieve)
+            .await;
+        if let Err(e) = result {
+            PENDING_RETRIEVES.lock().remove(&notify_unique);
+            return Err(e);
+        }
+        // The sender is only dropped without sending if the session is gone
+        receiver.await.ok().ok_or(Errno::EIO)
+    }
+
+    /// Complete the retrieve notification of `notify_unique` with the `data`
+    /// sent back by kernel, return whether the notification is found
+    pub fn complete(notify_unique: u64, data: Vec<u8>) -> bool {
+        PENDING_RETRIEVES
+            .lock()
+            .remove(&notify_unique)
+            .map_or(false, |sender| sender.send(data).is_ok())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::os::unix::io::FromRawFd;
@@ -9,6 +9,8 @@ use super::context::ProtoVersion;
 use super::de::{DeserializeError, Deserializer};
 #[cfg(feature = "abi-7-19")]
 use super::protocol::FuseFAllocateIn;
+#[cfg(feature = "abi-7-15")]
+use super::protocol::FuseNotifyRetrieveIn;
 #[cfg(feature = "abi-7-23")]
 use super::protocol::FuseRename2In;
 use super::protocol::{
@@ -232,7 +234,9 @@ pub enum Operation<'a> {
     /// FUSE_NOTIFY_REPLY = 41
     #[cfg(feature = "abi-7-15")]
     NotifyReply {
-        /// FUSE notify reply data
+        /// The FUSE notify retrieve reply
+        arg: &'a FuseNotifyRetrieveIn,
+        /// The retrieved data
         data: &'a [u8],
     },
     /// FUSE_BATCH_FORGET = 42
@@ -483,13 +487,21 @@ impl<'a> Operation<'a> {
             },
             #[cfg(feature = "abi-7-15")]
             FuseOpCode::FUSE_NOTIFY_REPLY => Operation::NotifyReply {
+                arg: data.fetch_ref()?,
                 data: data.fetch_all_bytes(),
             },
             #[cfg(feature = "abi-7-16")]
-            FuseOpCode::FUSE_BATCH_FORGET => Operation::BatchForget {
-                arg: data.fetch_ref()?,
-                nodes: data.fetch_all_as_slice()?,
-            },
+            FuseOpCode::FUSE_BATCH_FORGET => {
+                let arg: &FuseBatchForgetIn = data.fetch_ref()?;
+                let nodes: &[FuseForgetOne] = data.fetch_all_as_slice()?;
+                Operation::BatchForget {
+                    arg,
+                    // Only the first `count` nodes are valid
+                    nodes: nodes
+                        .get(..arg.count.cast())
+                        .ok_or(DeserializeError::NotEnough)?,
+                }
+            }
             #[cfg(feature = "abi-7-19")]
             FuseOpCode::FUSE_FALLOCATE => Operation::FAllocate {
                 arg: data.fetch_ref()?,
@@ -641,7 +653,11 @@ impl fmt::Display for Operation<'_> {
                 )
             }
             #[cfg(feature = "abi-7-15")]
-            Operation::NotifyReply { data } => write!(f, "NOTIFY REPLY data={data:?}"),
+            Operation::NotifyReply { arg, data } => write!(
+                f,
+                "NOTIFY REPLY offset={}, size={}, data={:?}",
+                arg.offset, arg.size, data,
+            ),
             #[cfg(feature = "abi-7-16")]
             Operation::BatchForget { arg, nodes } => {
                 write!(f, "BATCH FORGOT count={}, nodes={:?}", arg.count, nodes)
@@ -716,13 +732,6 @@ impl<'a> Request<'a> {
         let mut de = Deserializer::new(bytes);
         // Parse header
         let header = de.fetch_ref::<FuseInHeader>()?;
-        // Check data size
-        debug_assert!(
-            data_len >= header.len.cast(), // TODO: why not daten_len == header.len?
-            "failed to assert {} >= {}",
-            data_len,
-            header.len,
-        );
         // Parse/check operation arguments
         let operation = Operation::parse(header.opcode, &mut de, proto_version).map_err(|e| {
             if let DeserializeError::UnknownOpCode { code, .. } = e {
@@ -734,6 +743,15 @@ impl<'a> Request<'a> {
                 e
             }
         })?;
+        // Check data size, the data of a spliced write request may be left in
+        // the pipe
+        // TODO: why not daten_len == header.len?
+        debug_assert!(
+            data_len >= header.len.cast() || matches!(operation, Operation::Write { .. }),
+            "failed to assert {} >= {}",
+            data_len,
+            header.len,
+        );
         if de.remaining_len() > 0 {
             warn!(
                 "request bytes is not completely consumed: \
@@ -2183,8 +2201,14 @@ mod test {
     #[cfg(feature = "abi-7-15")]
     define_payload! {
         NOTIFY_REPLY_REQUEST;
-        len: 48;
+        len: 88;
         opcode: 41;
+        u64: 0,            // dummy1
+        u64: 0x0a,         // offset
+        u32: 8,            // size
+        u32: 0,            // dummy2
+        u64: 0,            // dummy3
+        u64: 0,            // dummy4
         str: b"foobar2k",  // data
     }
 
@@ -2199,7 +2223,9 @@ mod test {
 
         #[allow(clippy::wildcard_enum_match_arm)]
         match *req.operation() {
-            Operation::NotifyReply { data } => {
+            Operation::NotifyReply { arg, data } => {
+                assert_eq!(arg.offset, 0x0a);
+                assert_eq!(arg.size, 8);
                 assert_eq!(data, b"foobar2k");
             }
             _ => panic!("unexpected request operation"),
@@ -2242,6 +2268,59 @@ mod test {
         }
     }
 
+    #[cfg(feature = "abi-7-16")]
+    define_payload! {
+        BATCH_FORGET_PARTIAL_REQUEST;
+        len: 80;
+        opcode: 42;
+        u32: 1,       // count
+        u32: 0,       // dummy
+        u64: 3,       // nodes[0].nodeid
+        u64: 1,       // nodes[0].nlookup
+        u64: 0,       // trailing garbage
+        u64: 0,       // trailing garbage
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget_partial() {
+        let req = Request::new(&BATCH_FORGET_PARTIAL_REQUEST[..], PROTO_VERSION)
+            .unwrap_or_else(|err| panic!("failed to build FUSE request, the error is: {err}"));
+        check_header(&req);
+
+        #[allow(clippy::wildcard_enum_match_arm, clippy::indexing_slicing)]
+        match *req.operation() {
+            Operation::BatchForget { arg, nodes } => {
+                assert_eq!(arg.count, 1);
+                // Only the first `count` nodes are parsed
+                assert_eq!(nodes.len(), 1);
+                assert_eq!(nodes[0].nodeid, 3);
+                assert_eq!(nodes[0].nlookup, 1);
+            }
+            _ => panic!("unexpected request operation"),
+        }
+    }
+
+    #[cfg(feature = "abi-7-16")]
+    define_payload! {
+        BATCH_FORGET_SHORT_REQUEST;
+        len: 64;
+        opcode: 42;
+        u32: 2,       // count
+        u32: 0,       // dummy
+        u64: 1,       // nodes[0].nodeid
+        u64: 5,       // nodes[0].nlookup
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-16")]
+    fn batch_forget_short() {
+        #[allow(clippy::expect_used)]
+        let err = Request::new(&BATCH_FORGET_SHORT_REQUEST[..], PROTO_VERSION)
+            .expect_err("Unexpected request parsing result");
+        assert_eq!(err, DeserializeError::NotEnough);
+    }
+
     #[cfg(feature = "abi-7-19")]
     define_payload! {
         FALLOCATE_REQUEST;
@@ -17,3 +17,5 @@ pub mod mount;
 #[allow(clippy::arithmetic_side_effects)]
 pub mod protocol;
 pub mod session;
+pub mod splice;
+pub mod upgrade;
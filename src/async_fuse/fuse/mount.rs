@@ -5,6 +5,7 @@ use std::os::unix::io::RawFd;
 use std::path::Path;
 
 use anyhow::Context;
+use datenlord::config::MountOptions;
 use nix::fcntl::{self, OFlag};
 use nix::sys::stat::{self, Mode};
 use tracing::{debug, info};
@@ -12,6 +13,29 @@ use tracing::{debug, info};
 // Linux mount flags, check the following link for details
 // <https://github.com/torvalds/linux/blob/master/include/uapi/linux/mount.h#L11>
 
+/// Get the FUSE specific mount options, which are understood by both
+/// fusermount and the kernel
+#[cfg(target_os = "linux")]
+fn fuse_mount_options(options: &MountOptions) -> Vec<&'static str> {
+    let mut opts = Vec::new();
+    if options.allow_other {
+        opts.push("allow_other");
+    }
+    if options.default_permissions {
+        opts.push("default_permissions");
+    }
+    opts
+}
+
+/// Get the file system type shown in the mount table
+#[cfg(target_os = "linux")]
+fn fs_type(options: &MountOptions) -> String {
+    options
+        .subtype
+        .as_ref()
+        .map_or_else(|| "fuse".to_owned(), |subtype| format!("fuse.{subtype}"))
+}
+
 /// Linux un-mount
 #[cfg(target_os = "linux")]
 pub async fn umount(short_path: &Path) -> anyhow::Result<()> {
@@ -56,21 +80,21 @@ pub async fn umount(short_path: &Path) -> anyhow::Result<()> {
 
 /// Linux mount
 #[cfg(target_os = "linux")]
-pub async fn mount(mount_point: &Path) -> anyhow::Result<RawFd> {
+pub async fn mount(mount_point: &Path, options: &MountOptions) -> anyhow::Result<RawFd> {
     use nix::unistd;
 
     if unistd::geteuid().is_root() {
         // Direct umount
-        direct_mount(mount_point).await
+        direct_mount(mount_point, options).await
     } else {
         // Use fusermount to mount
-        fuser_mount(mount_point).await
+        fuser_mount(mount_point, options).await
     }
 }
 
 /// Linux fusermount
 #[cfg(target_os = "linux")]
-async fn fuser_mount(mount_point: &Path) -> anyhow::Result<RawFd> {
+async fn fuser_mount(mount_point: &Path, options: &MountOptions) -> anyhow::Result<RawFd> {
     use std::process::Command;
 
     use nix::cmsg_space;
@@ -80,6 +104,22 @@ async fn fuser_mount(mount_point: &Path) -> anyhow::Result<RawFd> {
     use nix::sys::uio::IoVec;
 
     let mount_path = mount_point.to_path_buf();
+    // fusermount option allow_other only allowed if user_allow_other is set in
+    // /etc/fuse.conf
+    let mut opts = vec!["nosuid", "nodev"];
+    opts.extend(fuse_mount_options(options));
+    opts.push(if options.read_only { "ro" } else { "rw" });
+    let fsname = format!("fsname={}", options.fsname);
+    opts.push(&fsname);
+    let subtype = options
+        .subtype
+        .as_ref()
+        .map(|subtype| format!("subtype={subtype}"));
+    if let Some(ref subtype) = subtype {
+        opts.push(subtype);
+    }
+    let opts = opts.join(",");
+    debug!("fusermount opts={:?}", &opts);
 
     let (local, remote) = tokio::task::spawn_blocking(|| {
         socket::socketpair(
@@ -95,9 +135,7 @@ async fn fuser_mount(mount_point: &Path) -> anyhow::Result<RawFd> {
     let mount_handle = tokio::task::spawn_blocking(move || {
         Command::new("fusermount")
             .arg("-o")
-            // fusermount option allow_other only allowed if user_allow_other is set in
-            // /etc/fuse.conf
-            .arg("nosuid,nodev,allow_other,default_permissions") // rw,async,noatime,noexec,auto_unmount,allow_other
+            .arg(opts) // rw,async,noatime,noexec,auto_unmount,allow_other
             .arg(mount_path.as_os_str())
             .env("_FUSE_COMMFD", remote.to_string())
             .output()
@@ -143,7 +181,7 @@ async fn fuser_mount(mount_point: &Path) -> anyhow::Result<RawFd> {
 
 /// Linux directly mount
 #[cfg(target_os = "linux")]
-async fn direct_mount(mount_point: &Path) -> anyhow::Result<RawFd> {
+async fn direct_mount(mount_point: &Path, options: &MountOptions) -> anyhow::Result<RawFd> {
     use nix::mount::MsFlags;
     use nix::sys::stat::SFlag;
     use nix::unistd;
@@ -157,29 +195,37 @@ async fn direct_mount(mount_point: &Path) -> anyhow::Result<RawFd> {
     let mount_path = mount_point.to_path_buf();
     let full_path = tokio::task::spawn_blocking(move || fs::canonicalize(mount_path)).await??;
     let target_path = full_path.clone();
-    let fstype = "fuse";
-    let fsname = "/dev/fuse";
+    let fstype = fs_type(options);
+    let fsname = options.fsname.clone();
 
     let mnt_sb = tokio::task::spawn_blocking(move || stat::stat(&full_path))
         .await?
         .context(format!(
             "failed to get the file stat of mount point={mount_point:?}",
         ))?;
-    let opts = format!(
+    let mut opts = format!(
         "fd={},rootmode={:o},user_id={},group_id={}",
         dev_fd,
         mnt_sb.st_mode & SFlag::S_IFMT.bits(),
         unistd::getuid().as_raw(),
         unistd::getgid().as_raw(),
     );
+    for opt in fuse_mount_options(options) {
+        opts.push(',');
+        opts.push_str(opt);
+    }
+    let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+    if options.read_only {
+        flags |= MsFlags::MS_RDONLY;
+    }
 
     debug!("direct mount opts={:?}", &opts);
     tokio::task::spawn_blocking(move || {
         nix::mount::mount(
-            Some(fsname),
+            Some(fsname.as_str()),
             &target_path,
-            Some(fstype),
-            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some(fstype.as_str()),
+            flags,
             Some(opts.as_str()),
         )
     })
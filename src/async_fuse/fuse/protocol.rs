@@ -1311,11 +1311,7 @@ impl FuseDirEntPlus {
     /// `FUSE_DIRENTPLUS_SIZE(d)` in `fuse.h`.
     ///
     /// <https://github.com/torvalds/linux/blob/00c570f4ba43ae73b41fa0a2269c3b0ac20386ef/include/uapi/linux/fuse.h#L711-L712>
-    ///
-    /// This method is not used now, that's because the FUSE operation
-    /// `ReadDirPlus` has not yet been implemented.
     #[must_use]
-    #[allow(dead_code)]
     pub fn size_with_name(&self) -> usize {
         mem::size_of::<Self>().overflow_add(self.dirent.namelen.cast())
     }
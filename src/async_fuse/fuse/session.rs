@@ -1,25 +1,41 @@
 //! The implementation of FUSE session
 
-use std::os::unix::io::RawFd;
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, UNIX_EPOCH};
 
 use aligned_utils::bytes::AlignedBytes;
 use anyhow::{anyhow, Context};
 use clippy_utilities::Cast;
+#[cfg(feature = "abi-7-13")]
+use clippy_utilities::OverflowArithmetic;
 use crossbeam_channel::{Receiver, Sender};
 use crossbeam_utils::atomic::AtomicCell;
+use datenlord::config::{FuseConfig, MountOptions};
 use nix::errno::Errno;
+use nix::fcntl::{self, FcntlArg, OFlag};
+use nix::poll::{self, PollFd, PollFlags};
 use nix::sys::stat::SFlag;
 use nix::unistd;
-use tracing::{debug, error, info};
+use parking_lot::Mutex;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
 
+use super::channel::Channel;
 use super::context::ProtoVersion;
 use super::file_system::FileSystem;
+#[cfg(feature = "abi-7-15")]
+use super::fuse_reply::FuseRetrieveNotification;
+#[cfg(feature = "abi-7-21")]
+use super::fuse_reply::ReplyDirectoryPlus;
 use super::fuse_reply::{
     ReplyAttr, ReplyBMap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyInit, ReplyLock, ReplyOpen, ReplyStatFs, ReplyWrite, ReplyXAttr,
+    ReplyInit, ReplyLSeek, ReplyLock, ReplyOpen, ReplyStatFs, ReplyWrite, ReplyXAttr,
 };
 use super::fuse_request::{Operation, Request};
 use super::mount;
@@ -27,20 +43,97 @@ use super::mount;
 use super::protocol::FATTR_CTIME;
 #[cfg(feature = "abi-7-9")]
 use super::protocol::FATTR_LOCKOWNER; // {FATTR_ATIME_NOW, FATTR_MTIME_NOW};
+#[cfg(feature = "abi-7-22")]
+use super::protocol::FUSE_ASYNC_DIO;
+#[cfg(feature = "abi-7-20")]
+use super::protocol::FUSE_AUTO_INVAL_DATA;
+#[cfg(feature = "abi-7-9")]
+use super::protocol::FUSE_BIG_WRITES;
+#[cfg(feature = "abi-7-25")]
+use super::protocol::FUSE_PARALLEL_DIROPS;
+#[cfg(feature = "abi-7-26")]
+use super::protocol::FUSE_POSIX_ACL;
 use super::protocol::{
-    FuseInitIn, FuseInitOut, FuseSetXAttrIn, FATTR_ATIME, FATTR_FH, FATTR_GID, FATTR_MODE,
-    FATTR_MTIME, FATTR_SIZE, FATTR_UID, FUSE_ASYNC_READ, FUSE_KERNEL_MINOR_VERSION,
-    FUSE_KERNEL_VERSION, FUSE_RELEASE_FLUSH,
+    FuseInHeader, FuseInitIn, FuseInitOut, FuseOpCode, FuseSetXAttrIn, FuseWriteIn, FATTR_ATIME,
+    FATTR_FH, FATTR_GID, FATTR_MODE, FATTR_MTIME, FATTR_SIZE, FATTR_UID, FUSE_ASYNC_READ,
+    FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION, FUSE_POSIX_LOCKS, FUSE_RELEASE_FLUSH,
 };
-use crate::async_fuse::fuse::de::DeserializeError;
+#[cfg(feature = "abi-7-28")]
+use super::protocol::{FUSE_CACHE_SYMLINKS, FUSE_MAX_PAGES};
+#[cfg(feature = "abi-7-23")]
+use super::protocol::{FUSE_COMPAT_22_INIT_OUT_SIZE, FUSE_WRITEBACK_CACHE};
+#[cfg(feature = "abi-7-21")]
+use super::protocol::{FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO};
+#[cfg(feature = "abi-7-17")]
+use super::protocol::{FUSE_FLOCK_LOCKS, FUSE_LK_FLOCK, FUSE_RELEASE_FLOCK_UNLOCK};
+#[cfg(feature = "abi-7-14")]
+use super::protocol::{FUSE_SPLICE_MOVE, FUSE_SPLICE_READ, FUSE_SPLICE_WRITE};
+use super::splice::Pipe;
+use super::upgrade::{self, SessionSnapshot};
+use crate::async_fuse::fuse::de::{DeserializeError, Deserializer};
 use crate::async_fuse::memfs::{
-    CreateParam, FileLockParam, MemFs, MetaData, RenameParam, SetAttrParam,
+    CopyRangeParam, CreateParam, FileLockParam, MemFs, MetaData, RenameParam, SetAttrParam,
+    WriteData,
 };
 
-/// We generally support async reads
-#[cfg(target_os = "linux")]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ;
-// TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
+/// We generally support async reads and remote POSIX locks
+#[cfg(all(target_os = "linux", not(feature = "abi-7-17")))]
+const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_POSIX_LOCKS;
+/// We generally support async reads, remote POSIX locks and remote BSD flock
+/// locks
+#[cfg(all(target_os = "linux", feature = "abi-7-17", not(feature = "abi-7-21")))]
+const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS;
+/// We generally support async reads, remote POSIX and BSD flock locks, and
+/// readdirplus when the kernel supports it, letting the kernel decide
+/// adaptively when to use readdirplus
+#[cfg(all(target_os = "linux", feature = "abi-7-21", not(feature = "abi-7-26")))]
+const INIT_FLAGS: u32 = FUSE_ASYNC_READ
+    | FUSE_POSIX_LOCKS
+    | FUSE_FLOCK_LOCKS
+    | FUSE_DO_READDIRPLUS
+    | FUSE_READDIRPLUS_AUTO;
+/// We generally support async reads, remote POSIX and BSD flock locks,
+/// readdirplus and POSIX ACLs, which are stored as extended attributes and
+/// enforced by the kernel
+#[cfg(all(target_os = "linux", feature = "abi-7-26"))]
+const INIT_FLAGS: u32 = FUSE_ASYNC_READ
+    | FUSE_POSIX_LOCKS
+    | FUSE_FLOCK_LOCKS
+    | FUSE_DO_READDIRPLUS
+    | FUSE_READDIRPLUS_AUTO
+    | FUSE_POSIX_ACL;
+// TODO: Add FUSE_EXPORT_SUPPORT (requires ABI 7.10)
+
+/// Get the init flags we want to negotiate with the kernel, which are
+/// `INIT_FLAGS` plus the optional capabilities enabled in `config`
+#[cfg_attr(not(feature = "abi-7-14"), allow(unused_variables))]
+fn wanted_init_flags(config: &FuseConfig) -> u32 {
+    let capabilities: &[(bool, u32)] = &[
+        #[cfg(feature = "abi-7-9")]
+        (true, FUSE_BIG_WRITES),
+        #[cfg(feature = "abi-7-14")]
+        (
+            config.splice,
+            FUSE_SPLICE_WRITE | FUSE_SPLICE_MOVE | FUSE_SPLICE_READ,
+        ),
+        #[cfg(feature = "abi-7-20")]
+        (config.auto_inval_data, FUSE_AUTO_INVAL_DATA),
+        #[cfg(feature = "abi-7-22")]
+        (config.async_dio, FUSE_ASYNC_DIO),
+        #[cfg(feature = "abi-7-23")]
+        (config.writeback_cache, FUSE_WRITEBACK_CACHE),
+        #[cfg(feature = "abi-7-25")]
+        (config.parallel_dirops, FUSE_PARALLEL_DIROPS),
+        #[cfg(feature = "abi-7-28")]
+        (true, FUSE_MAX_PAGES),
+        #[cfg(feature = "abi-7-28")]
+        (config.cache_symlinks, FUSE_CACHE_SYMLINKS),
+    ];
+    capabilities
+        .iter()
+        .filter(|&&(enabled, _)| enabled)
+        .fold(INIT_FLAGS, |flags, &(_, flag)| flags | flag)
+}
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
 /// FUSE recommends at least 128k, max 16M. The FUSE default is  128k on Linux.
@@ -54,15 +147,105 @@ const BUFFER_SIZE: u32 = MAX_WRITE_SIZE + 512;
 
 /// We use `PAGE_SIZE` (4 KiB) as the alignment of the buffer.
 const PAGE_SIZE: usize = 4096;
-/// Max background pending requests under processing, at least to be 4,
-/// otherwise deadlock
-const MAX_BACKGROUND: u16 = 10; // TODO: set to larger value when release
+
+/// The max time to wait for the in-flight requests on shutdown
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The interval to check whether the in-flight requests are finished on
+/// shutdown
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The timeout in milliseconds to wait for a request, after which the reading
+/// thread checks whether the session is stopped
+const READ_POLL_TIMEOUT_MS: i32 = 100;
+
+/// The data of the spliced write requests larger than this is left in the
+/// pipe and moved into the cache directly, the smaller ones are read into the
+/// buffer as usual.
+const SPLICE_WRITE_THRESHOLD: usize = PAGE_SIZE;
+
+/// The op-code of `FUSE_WRITE`
+#[allow(clippy::as_conversions)] // allow this for enum
+const FUSE_WRITE_OPCODE: u32 = FuseOpCode::FUSE_WRITE as u32;
 
 /// Static variable to indicate whether FUSE is initialized or not
 // static FUSE_INITIALIZED: AtomicBool = AtomicBool::new(false);
 /// Static variable to indicate whether FUSE is destroyed or not
 // static FUSE_DESTROYED: AtomicBool = AtomicBool::new(false);
 
+/// The in-flight requests, `unique` -> the sender to cancel the request, which
+/// is `None` if the request is not interruptible or has been interrupted
+type InFlightRequests = Arc<Mutex<HashMap<u64, Option<oneshot::Sender<()>>>>>;
+
+/// The pool of the buffers to read FUSE requests, each buffer is identified by
+/// its index
+type BufferPool = (Sender<(u16, AlignedBytes)>, Receiver<(u16, AlignedBytes)>);
+
+/// Set `fd` non-blocking
+fn set_nonblocking(fd: RawFd) -> nix::Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl::fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl::fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    Ok(())
+}
+
+/// Read a FUSE request from the non-blocking `fuse_fd` into `buf`, through
+/// `pipe` if splice is enabled. Wait until a request arrives, or return
+/// `ECANCELED` once `stopped` is set, in which case no request is consumed.
+fn read_request(
+    fuse_fd: RawFd,
+    pipe: Option<&Pipe>,
+    buf: &mut [u8],
+    stopped: &AtomicBool,
+) -> nix::Result<usize> {
+    loop {
+        if stopped.load(Ordering::Acquire) {
+            return Err(Errno::ECANCELED);
+        }
+        let mut poll_fds = [PollFd::new(fuse_fd, PollFlags::POLLIN)];
+        match poll::poll(&mut poll_fds, READ_POLL_TIMEOUT_MS) {
+            Ok(0) | Err(Errno::EINTR) => continue,
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+        let res = match pipe {
+            Some(pipe) => read_request_spliced(fuse_fd, pipe, buf),
+            None => unistd::read(fuse_fd, buf),
+        };
+        // The request is taken by another channel or process
+        if res != Err(Errno::EAGAIN) {
+            return res;
+        }
+    }
+}
+
+/// Read a FUSE request through `pipe` into `buf`. The data of a large write
+/// request is left in the pipe, so that it can be moved into the cache without
+/// copying it to `buf` first. Return the size read into `buf`.
+fn read_request_spliced(fuse_fd: RawFd, pipe: &Pipe, buf: &mut [u8]) -> nix::Result<usize> {
+    let size = pipe.splice_from(fuse_fd, buf.len())?;
+    let res = (|| {
+        let header_len = mem::size_of::<FuseInHeader>();
+        let header_buf = buf.get_mut(..header_len).ok_or(Errno::EIO)?;
+        pipe.read_exact(header_buf)?;
+        let header = Deserializer::new(header_buf)
+            .fetch_ref::<FuseInHeader>()
+            .map_err(|_| Errno::EIO)?;
+        let body_len = if header.opcode == FUSE_WRITE_OPCODE && size > SPLICE_WRITE_THRESHOLD {
+            mem::size_of::<FuseWriteIn>()
+        } else {
+            size.overflow_sub(header_len)
+        };
+        let read_size = header_len.overflow_add(body_len);
+        let body_buf = buf.get_mut(header_len..read_size).ok_or(Errno::EIO)?;
+        pipe.read_exact(body_buf)?;
+        Ok(read_size)
+    })();
+    if res.is_err() {
+        pipe.drain();
+    }
+    res
+}
+
 /// FUSE session
 #[allow(missing_debug_implementations)]
 pub struct Session<F: FileSystem + Send + Sync + 'static> {
@@ -76,25 +259,54 @@ pub struct Session<F: FileSystem + Send + Sync + 'static> {
     filesystem: Arc<F>,
     /// All sub-tasks
     tasks: Vec<tokio::task::JoinHandle<()>>,
+    /// The tasks reading the requests from the cloned channels
+    channel_tasks: Vec<tokio::task::JoinHandle<()>>,
+    /// The requests under processing, which can be interrupted by the kernel
+    in_flight_requests: InFlightRequests,
+    /// The FUSE capabilities to negotiate with the kernel
+    fuse_config: FuseConfig,
+    /// Whether the file system is still mounted, it's un-mounted when the
+    /// session is dropped if so
+    mounted: bool,
+    /// The negotiated init flags, `None` before INIT
+    init_flags: Option<u32>,
+    /// Whether to stop reading requests
+    stopped: Arc<AtomicBool>,
+    /// Whether all the in-flight requests are finished on shutdown, the
+    /// session is handed over only if so
+    drained: bool,
 }
 
 /// FUSE device fd
 #[derive(Debug)]
 struct FuseFd(RawFd);
 
+impl AsRawFd for FuseFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
 impl Drop for FuseFd {
     fn drop(&mut self) {
         unistd::close(self.0).ok();
     }
 }
 
+/// The fd a channel reads the requests from and replies through, it's closed
+/// after the last request read from it is replied
+type ChannelFd = Arc<dyn AsRawFd + Send + Sync>;
+
 impl<F: FileSystem + Send + Sync + 'static> Drop for Session<F> {
     fn drop(&mut self) {
         futures::executor::block_on(async {
             // join fuse request handling tasks.
-            for join_handle in &self.tasks {
+            for join_handle in self.tasks.iter().chain(&self.channel_tasks) {
                 join_handle.abort();
             }
+            if !self.mounted {
+                return;
+            }
             let mount_path = &self.mount_path;
             let res = mount::umount(mount_path).await;
             match res {
@@ -113,6 +325,8 @@ impl<F: FileSystem + Send + Sync + 'static> Drop for Session<F> {
 pub async fn new_session_of_memfs<M>(
     mount_path: &Path,
     fs: MemFs<M>,
+    fuse_config: FuseConfig,
+    mount_options: &MountOptions,
 ) -> anyhow::Result<Session<MemFs<M>>>
 where
     M: MetaData + Send + Sync + 'static,
@@ -124,22 +338,57 @@ where
     );
 
     // Must create filesystem before mount
-    let fuse_fd = mount::mount(mount_path)
+    let fuse_fd = mount::mount(mount_path, mount_options)
         .await
         .context("failed to mount fuse device")?;
     fs.set_fuse_fd(fuse_fd).await;
 
-    let fsarc = Arc::new(fs);
-    Ok(Session {
-        fuse_fd: Arc::new(FuseFd(fuse_fd)),
-        proto_version: AtomicCell::new(ProtoVersion::UNSPECIFIED),
-        mount_path: mount_path.to_owned(),
-        tasks: Vec::new(),
-        filesystem: fsarc,
-    })
+    Ok(Session::with_fd(mount_path, fs, fuse_config, fuse_fd))
+}
+
+/// Resume the FUSE session taken over from the previous process on `fuse_fd`,
+/// the file system is mounted and initialized already
+pub async fn resume_session_of_memfs<M>(
+    mount_path: &Path,
+    fs: MemFs<M>,
+    fuse_config: FuseConfig,
+    fuse_fd: RawFd,
+    snapshot: SessionSnapshot,
+) -> Session<MemFs<M>>
+where
+    M: MetaData + Send + Sync + 'static,
+{
+    fs.set_fuse_fd(fuse_fd).await;
+    fs.restore_handles(snapshot.handles);
+
+    let mut session = Session::with_fd(mount_path, fs, fuse_config, fuse_fd);
+    session.proto_version.store(ProtoVersion {
+        major: snapshot.proto_major,
+        minor: snapshot.proto_minor,
+    });
+    session.init_flags = Some(snapshot.init_flags);
+    session
 }
 
 impl<F: FileSystem + Send + Sync + 'static> Session<F> {
+    /// Create a session of `fs` mounted on `fuse_fd`
+    fn with_fd(mount_path: &Path, fs: F, fuse_config: FuseConfig, fuse_fd: RawFd) -> Self {
+        Self {
+            fuse_fd: Arc::new(FuseFd(fuse_fd)),
+            proto_version: AtomicCell::new(ProtoVersion::UNSPECIFIED),
+            mount_path: mount_path.to_owned(),
+            tasks: Vec::new(),
+            channel_tasks: Vec::new(),
+            filesystem: Arc::new(fs),
+            in_flight_requests: Arc::new(Mutex::new(HashMap::new())),
+            fuse_config,
+            mounted: true,
+            init_flags: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+            drained: false,
+        }
+    }
+
     /// Get FUSE device fd
     #[inline]
     pub fn dev_fd(&self) -> RawFd {
@@ -147,24 +396,226 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
     }
 
     /// Run the FUSE session
-    #[allow(clippy::wildcard_enum_match_arm)] // nix::Errno is marked as non_exhaustive
-    #[allow(clippy::arithmetic_side_effects)] // The `select` macro will generate code that goes against this rule.
     pub async fn run(mut self) -> anyhow::Result<()> {
+        self.run_until(future::pending::<()>()).await.map(|_| ())
+    }
+
+    /// Run the FUSE session until `shutdown` completes. Then stop reading new
+    /// requests, wait for the in-flight requests to finish and flush the data
+    /// of all the open files. Return the output of `shutdown`, or `None` if
+    /// the file system is un-mounted before that. The file system is still
+    /// mounted after it returns, call `umount` to un-mount it or `hand_over`
+    /// to hand it over to a new process.
+    #[allow(clippy::arithmetic_side_effects)] // for the auto generate code from tokio select!
+    #[allow(clippy::pattern_type_mismatch)] // for tokio::select!
+    pub async fn run_until<T>(
+        &mut self,
+        shutdown: impl Future<Output = T> + Send,
+    ) -> anyhow::Result<Option<T>> {
         // For recycling the buffers used by process_fuse_request.
-        let (pool_sender, pool_receiver) = self
-            .setup_buffer_pool()
+        let pool = self.setup_buffer_pool();
+        let flags = if let Some(flags) = self.init_flags {
+            // The session is taken over from the previous process
+            flags
+        } else {
+            let flags = self
+                .wait_init(&pool)
+                .await
+                .context("failed to initialize FUSE session")?;
+            self.init_flags = Some(flags);
+            flags
+        };
+        // Read the requests without blocking, so that the reading can be
+        // stopped without consuming any request
+        set_nonblocking(self.dev_fd()).context("failed to set FUSE device non-blocking")?;
+        let pipes = self.setup_pipes(flags)?;
+
+        // Clone the FUSE device after INIT, so that the requests are read from
+        // all the channels in parallel, each with its own buffer pool
+        for _ in 1..self.fuse_config.channels {
+            let channel = Channel::new(self)
+                .await
+                .context("failed to clone FUSE channel")?;
+            let chan_pool = self.setup_buffer_pool();
+            let chan_pipes = self.setup_pipes(flags)?;
+            let fs = Arc::clone(&self.filesystem);
+            let proto_version = self.proto_version.load();
+            let in_flight_requests = Arc::clone(&self.in_flight_requests);
+            let stopped = Arc::clone(&self.stopped);
+            let chan_fd = channel.fd();
+            self.channel_tasks.push(tokio::task::spawn(async move {
+                let mut tasks = Vec::new();
+                let res = Self::run_channel(
+                    Arc::new(channel),
+                    fs,
+                    chan_pool,
+                    chan_pipes,
+                    proto_version,
+                    in_flight_requests,
+                    stopped,
+                    &mut tasks,
+                )
+                .await;
+                if let Err(e) = res {
+                    error!("FUSE channel fd={} quit, the error is: {:?}", chan_fd, e);
+                }
+            }));
+        }
+
+        let fuse_dev_fd: ChannelFd = Arc::<FuseFd>::clone(&self.fuse_fd);
+        let fs = Arc::clone(&self.filesystem);
+        let proto_version = self.proto_version.load();
+        let in_flight_requests = Arc::clone(&self.in_flight_requests);
+        let stopped = Arc::clone(&self.stopped);
+        let shutdown_res = {
+            let main_channel = Self::run_channel(
+                fuse_dev_fd,
+                fs,
+                pool,
+                pipes,
+                proto_version,
+                in_flight_requests,
+                Arc::clone(&stopped),
+                &mut self.tasks,
+            );
+            tokio::pin!(main_channel);
+            let shutdown_res = tokio::select! {
+                res = &mut main_channel => {
+                    res?;
+                    None
+                }
+                output = shutdown => Some(output),
+            };
+            if shutdown_res.is_some() {
+                info!("FUSE session of {:?} is shutting down", self.mount_path);
+                stopped.store(true, Ordering::Release);
+                // Process the requests read before the reading is stopped
+                main_channel.await?;
+            }
+            shutdown_res
+        };
+        if shutdown_res.is_some() {
+            self.shutdown().await?;
+        }
+        Ok(shutdown_res)
+    }
+
+    /// Wait for the channels to stop reading requests and the in-flight
+    /// requests to finish, then flush the data of all the open files
+    async fn shutdown(&mut self) -> anyhow::Result<()> {
+        // The main channel is stopped already, the others stop soon after
+        for join_handle in self.channel_tasks.drain(..) {
+            if let Err(e) = join_handle.await {
+                error!("FUSE channel task failed, the error is: {}", e);
+            }
+        }
+
+        let in_flight_requests = &self.in_flight_requests;
+        let drain_res = tokio::time::timeout(DRAIN_TIMEOUT, async {
+            while !in_flight_requests.lock().is_empty() {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        })
+        .await;
+        self.drained = drain_res.is_ok();
+        if drain_res.is_err() {
+            warn!(
+                "{} in-flight FUSE requests are not finished in {:?}, shut down anyway",
+                in_flight_requests.lock().len(),
+                DRAIN_TIMEOUT,
+            );
+        }
+
+        self.filesystem
+            .flush_all()
+            .await
+            .context("failed to flush file system before shutdown")
+    }
+
+    /// Un-mount the file system
+    pub async fn umount(mut self) -> anyhow::Result<()> {
+        for join_handle in self.tasks.iter().chain(&self.channel_tasks) {
+            join_handle.abort();
+        }
+        mount::umount(&self.mount_path).await?;
+        self.mounted = false;
+        info!("successfully umount {:?}", self.mount_path);
+        self.filesystem.release_lookups().await
+    }
+
+    /// Hand over the FUSE session stopped by `run_until` to the new process
+    /// connected by `stream`, the file system stays mounted. Return the
+    /// connection, which should be kept open until this process exits. The
+    /// hand over is aborted if any in-flight request is not finished, since
+    /// the new process cannot reply it, the session should be un-mounted then.
+    pub async fn hand_over(
+        &mut self,
+        stream: tokio::net::UnixStream,
+    ) -> anyhow::Result<std::os::unix::net::UnixStream> {
+        let init_flags = self
+            .init_flags
+            .ok_or_else(|| anyhow!("cannot hand over the FUSE session before INIT"))?;
+        if !self.drained {
+            return Err(anyhow!(
+                "cannot hand over the FUSE session with in-flight requests not finished in {:?}",
+                DRAIN_TIMEOUT,
+            ));
+        }
+        let proto_version = self.proto_version.load();
+        let snapshot = SessionSnapshot {
+            proto_major: proto_version.major,
+            proto_minor: proto_version.minor,
+            init_flags,
+            handles: self.filesystem.handle_snapshot(),
+        };
+        let stream = upgrade::hand_over(stream, self.dev_fd(), &snapshot)
             .await
-            .context("failed to setup buffer pool")?;
-        let fuse_dev_fd = self.dev_fd();
+            .context("failed to hand over FUSE session")?;
+        self.mounted = false;
+        info!(
+            "handed over the FUSE session of {:?} with {} open files",
+            self.mount_path,
+            snapshot.handles.open_files.len(),
+        );
+        Ok(stream)
+    }
+
+    /// Read the FUSE requests from `chan_fd` and process them until the
+    /// file system is unmounted or the session is stopped, each request holds
+    /// `chan_fd` until it's replied
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::wildcard_enum_match_arm)] // nix::Errno is marked as non_exhaustive
+    #[allow(clippy::arithmetic_side_effects)] // The `select` macro will generate code that goes against this rule.
+    async fn run_channel(
+        chan_fd: ChannelFd,
+        fs: Arc<F>,
+        (pool_sender, pool_receiver): BufferPool,
+        pipes: Vec<Arc<Pipe>>,
+        proto_version: ProtoVersion,
+        in_flight_requests: InFlightRequests,
+        stopped: Arc<AtomicBool>,
+        tasks: &mut Vec<tokio::task::JoinHandle<()>>,
+    ) -> anyhow::Result<()> {
+        let fuse_dev_fd = chan_fd.as_raw_fd();
         let mut buffer_idx = 0;
+        let mut buffer_pipe = None;
         let mut read_fuse_task = None;
         loop {
             if read_fuse_task.is_none() {
                 let (buffer_idx_, mut byte_buffer) = pool_receiver.recv()?;
                 buffer_idx = buffer_idx_;
+                // The pipe is used along with the buffer if splice is enabled
+                buffer_pipe = pipes.get(usize::from(buffer_idx)).map(Arc::clone);
+                let read_pipe = buffer_pipe.as_ref().map(Arc::clone);
+                let stopped = Arc::clone(&stopped);
                 // Read msg from FUSE
                 read_fuse_task = Some(tokio::task::spawn_blocking(move || {
-                    let res = unistd::read(fuse_dev_fd, &mut byte_buffer);
+                    let res = read_request(
+                        fuse_dev_fd,
+                        read_pipe.as_deref(),
+                        &mut byte_buffer,
+                        &stopped,
+                    );
                     (res, byte_buffer)
                 }));
             }
@@ -175,21 +626,25 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
                         Ok(read_size) => {
                             debug!("read successfully {} byte data from FUSE device", read_size);
 
-                            // let chan = Channel::new(self).await?;
-                            let fuse_fd = fuse_dev_fd;
-                            let fs = Arc::clone(&self.filesystem);
+                            let fuse_fd = Arc::clone(&chan_fd);
+                            let fs = Arc::clone(&fs);
                             let sender = pool_sender.clone();
-                            let proto_version = self.proto_version.load();
-                            self.tasks
-                                .push(tokio::task::spawn(Self::process_fuse_request(
-                                    buffer_idx,
-                                    byte_buffer,
-                                    read_size,
-                                    fuse_fd,
-                                    fs,
-                                    sender,
-                                    proto_version,
-                                )));
+                            let in_flight_requests = Arc::clone(&in_flight_requests);
+                            tasks.push(tokio::task::spawn(Self::process_fuse_request(
+                                (buffer_idx, byte_buffer),
+                                buffer_pipe.take(),
+                                read_size,
+                                fuse_fd,
+                                fs,
+                                sender,
+                                proto_version,
+                                in_flight_requests,
+                            )));
+                        }
+                        // The session is stopped, no request is read
+                        Err(Errno::ECANCELED) => {
+                            info!("FUSE session is stopped, quit the run loop");
+                            return false;
                         }
                         Err(err) => {
                             let err_msg = crate::async_fuse::util::format_nix_error(err); // TODO: refactor format_nix_error()
@@ -245,16 +700,20 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
         Ok(())
     }
 
-    /// Process one FUSE request
+    /// Process one FUSE request, `pipe` holds the data of the request left
+    /// unread if it's spliced, `chan_fd` is kept open until it's replied
+    #[allow(clippy::too_many_arguments)]
     async fn process_fuse_request(
-        buffer_idx: u16,
-        byte_buffer: AlignedBytes,
+        (buffer_idx, byte_buffer): (u16, AlignedBytes),
+        pipe: Option<Arc<Pipe>>,
         read_size: usize,
-        fuse_fd: RawFd,
+        chan_fd: ChannelFd,
         fs: Arc<dyn FileSystem + Send + Sync + 'static>,
         sender: Sender<(u16, AlignedBytes)>,
         proto_version: ProtoVersion,
+        in_flight_requests: InFlightRequests,
     ) {
+        let fuse_fd = chan_fd.as_raw_fd();
         let bytes = byte_buffer.get(..read_size).unwrap_or_else(|| {
             panic!("failed to read {read_size} bytes from the {buffer_idx}-th buffer",)
         });
@@ -282,7 +741,11 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
             }
         };
         debug!("received FUSE req={}", fuse_req);
-        let res = dispatch(&fuse_req, fuse_fd, fs).await;
+        let res = if let Operation::Interrupt { arg } = *fuse_req.operation() {
+            interrupt(&fuse_req, arg.unique, fuse_fd, fs, &in_flight_requests).await
+        } else {
+            dispatch_interruptible(&fuse_req, fuse_fd, fs, pipe.as_ref(), &in_flight_requests).await
+        };
         if let Err(e) = res {
             panic!(
                 "failed to process req={:?}, the error is: {}",
@@ -290,6 +753,16 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
                 crate::async_fuse::util::format_nix_error(e), // TODO: refactor format_nix_error()
             );
         }
+        // Drop the data not consumed by the file system before reusing the pipe
+        if let Some(pipe) = pipe {
+            let drained = pipe.drain();
+            if drained > 0 {
+                debug!(
+                    "drained {} bytes left in the pipe of req={}",
+                    drained, fuse_req
+                );
+            }
+        }
         let res = sender.send((buffer_idx, byte_buffer));
         if let Err(e) = res {
             panic!(
@@ -299,13 +772,13 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
     }
 
     /// Setup buffer pool
-    async fn setup_buffer_pool(
-        &self,
-    ) -> anyhow::Result<(Sender<(u16, AlignedBytes)>, Receiver<(u16, AlignedBytes)>)> {
+    fn setup_buffer_pool(&self) -> BufferPool {
+        // Each pending background request takes a buffer
+        let max_background = self.fuse_config.max_background;
         let (pool_sender, pool_receiver) =
-            crossbeam_channel::bounded::<(u16, AlignedBytes)>(MAX_BACKGROUND.into());
+            crossbeam_channel::bounded::<(u16, AlignedBytes)>(max_background.into());
 
-        (0..MAX_BACKGROUND).for_each(|i| {
+        (0..max_background).for_each(|i| {
             let buf = AlignedBytes::new_zeroed(BUFFER_SIZE.cast(), PAGE_SIZE);
             let res = pool_sender.send((i, buf));
             if let Err(e) = res {
@@ -315,6 +788,29 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
             }
         });
 
+        (pool_sender, pool_receiver)
+    }
+
+    /// Setup a pipe for each buffer of a buffer pool if splice is negotiated
+    /// with the kernel, otherwise the requests are read without pipes
+    #[cfg_attr(not(feature = "abi-7-14"), allow(unused_variables))]
+    fn setup_pipes(&self, flags: u32) -> anyhow::Result<Vec<Arc<Pipe>>> {
+        #[cfg(feature = "abi-7-14")]
+        if flags & FUSE_SPLICE_READ != 0 {
+            return (0..self.fuse_config.max_background)
+                .map(|_| {
+                    Pipe::new(BUFFER_SIZE.cast())
+                        .map(Arc::new)
+                        .context("failed to create pipe to splice FUSE requests")
+                })
+                .collect();
+        }
+        Ok(Vec::new())
+    }
+
+    /// Wait for the FUSE INIT request from the FUSE device and reply it,
+    /// return the negotiated init flags
+    async fn wait_init(&self, (pool_sender, pool_receiver): &BufferPool) -> anyhow::Result<u32> {
         let fuse_fd = self.dev_fd();
         let (idx, mut byte_buf) = pool_receiver.recv()?;
         let read_result = tokio::task::spawn_blocking(move || {
@@ -323,6 +819,7 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
         })
         .await?;
         byte_buf = read_result.1;
+        let mut flags = 0;
         if let Ok(read_size) = read_result.0 {
             debug!("read successfully {} byte data from FUSE device", read_size);
             let bytes = byte_buf.get(..read_size).unwrap_or_else(|| {
@@ -335,7 +832,7 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
             if let Ok(req) = Request::new(bytes, self.proto_version.load()) {
                 if let Operation::Init { arg } = *req.operation() {
                     let filesystem = Arc::clone(&self.filesystem);
-                    self.init(arg, &req, &*filesystem, fuse_fd).await?;
+                    flags = self.init(arg, &req, &*filesystem, fuse_fd).await?;
                 }
             }
         }
@@ -343,10 +840,10 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
             "failed to put buffer idx={idx} back to buffer pool after FUSE init",
         ))?;
 
-        Ok((pool_sender, pool_receiver))
+        Ok(flags)
     }
 
-    /// Initialize FUSE session
+    /// Initialize FUSE session, return the negotiated init flags
     #[allow(single_use_lifetimes)] // false positive
     async fn init<'a>(
         &self,
@@ -354,9 +851,9 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
         req: &'_ Request<'a>,
         fs: &'_ (dyn FileSystem + Send + Sync + 'static),
         fd: RawFd,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<u32> {
         debug!("Init args={:?}", arg);
-        // TODO: rewrite init based on do_init() in fuse_lowlevel.c
+        // Negotiate like do_init() in fuse_lowlevel.c
         // https://github.com/libfuse/libfuse/blob/master/lib/fuse_lowlevel.c#L1892
         let reply = ReplyInit::new(req.unique(), fd);
         // We don't support ABI versions before 7.8
@@ -372,84 +869,184 @@ impl<F: FileSystem + Send + Sync + 'static> Session<F> {
             reply.error_code(Errno::ENOSYS).await?;
             return Err(anyhow!("user defined init failed, the error is: {}", err,));
         }
-        debug_assert!(
-            arg.max_readahead <= MAX_WRITE_SIZE,
-            "the max readahead={} larger than max write size 16M={}",
-            arg.max_readahead,
-            MAX_WRITE_SIZE,
-        );
-        let flags = arg.flags & INIT_FLAGS; // TODO: handle init flags properly
+        // Only enable the capabilities both we want and the kernel supports
+        let wanted = wanted_init_flags(&self.fuse_config);
+        let flags = arg.flags & wanted;
+        if flags != wanted {
+            warn!(
+                "FUSE kernel module does not support init flags={:#x}",
+                wanted & !flags,
+            );
+        }
         #[cfg(not(feature = "abi-7-13"))]
         let unused = 0_u32;
         #[cfg(feature = "abi-7-13")]
-        let congestion_threshold = 10_u16; // TODO: set congestion threshold
+        let max_background = self.fuse_config.max_background;
+        // Mark the filesystem as congested when 3/4 of the background
+        // requests are pending, the same as the kernel default
+        #[cfg(feature = "abi-7-13")]
+        let congestion_threshold: u16 = u32::from(max_background)
+            .overflow_mul(3)
+            .overflow_div(4)
+            .cast();
+        // We support nanosecond timestamps
         #[cfg(feature = "abi-7-23")]
-        let time_gran = 1_u32; // TODO: set time_gran
+        let time_gran = 1_u32;
         #[cfg(all(feature = "abi-7-23", not(feature = "abi-7-28")))]
         let unused = [0_u32; 9];
         #[cfg(feature = "abi-7-28")]
-        let max_pages = 0_u16; // TODO: max_pages = (max_write - 1) / getpagesize() + 1;
+        let max_pages: u16 = MAX_WRITE_SIZE
+            .overflow_sub(1)
+            .overflow_div(PAGE_SIZE.cast())
+            .overflow_add(1)
+            .cast();
         #[cfg(feature = "abi-7-28")]
         let padding = 0_u16;
         #[cfg(feature = "abi-7-28")]
         let unused = [0_u32; 8];
+        // Older kernels expect a shorter response
+        #[cfg(feature = "abi-7-23")]
+        let reply_size = if arg.minor < 23 {
+            FUSE_COMPAT_22_INIT_OUT_SIZE
+        } else {
+            mem::size_of::<FuseInitOut>()
+        };
+        #[cfg(not(feature = "abi-7-23"))]
+        let reply_size = mem::size_of::<FuseInitOut>();
         // Reply with our desired version and settings. If the kernel supports a
         // larger major version, it'll re-send a matching init message. If it
         // supports only lower major versions, we replied with an error above.
+        // The kernel uses the smaller one of both minor versions.
         reply
-            .init(FuseInitOut {
-                major: FUSE_KERNEL_VERSION,
-                minor: FUSE_KERNEL_MINOR_VERSION, /* Do not change minor version, otherwise
-                                                   * unknown panic */
-                max_readahead: arg.max_readahead, // accept FUSE kernel module max_readahead
-                flags,                            /* TODO: use features given in INIT_FLAGS and
-                                                   * reported as capable */
-                #[cfg(not(feature = "abi-7-13"))]
-                unused,
-                #[cfg(feature = "abi-7-13")]
-                max_background: MAX_BACKGROUND,
-                #[cfg(feature = "abi-7-13")]
-                congestion_threshold,
-                max_write: MAX_WRITE_SIZE,
-                #[cfg(feature = "abi-7-23")]
-                time_gran,
-                #[cfg(all(feature = "abi-7-23", not(feature = "abi-7-28")))]
-                unused,
-                #[cfg(feature = "abi-7-28")]
-                max_pages,
-                #[cfg(feature = "abi-7-28")]
-                padding,
-                #[cfg(feature = "abi-7-28")]
-                unused,
-            })
+            .init(
+                FuseInitOut {
+                    major: FUSE_KERNEL_VERSION,
+                    minor: FUSE_KERNEL_MINOR_VERSION,
+                    max_readahead: arg.max_readahead, // accept FUSE kernel module max_readahead
+                    flags,
+                    #[cfg(not(feature = "abi-7-13"))]
+                    unused,
+                    #[cfg(feature = "abi-7-13")]
+                    max_background,
+                    #[cfg(feature = "abi-7-13")]
+                    congestion_threshold,
+                    max_write: MAX_WRITE_SIZE,
+                    #[cfg(feature = "abi-7-23")]
+                    time_gran,
+                    #[cfg(all(feature = "abi-7-23", not(feature = "abi-7-28")))]
+                    unused,
+                    #[cfg(feature = "abi-7-28")]
+                    max_pages,
+                    #[cfg(feature = "abi-7-28")]
+                    padding,
+                    #[cfg(feature = "abi-7-28")]
+                    unused,
+                },
+                reply_size,
+            )
             .await?;
         debug!(
-            "INIT response: ABI version={}.{}, flags={:#x}, max readahead={}, max write={}",
+            "INIT response: ABI version={}.{}, flags={:#x}, max readahead={}, max write={}, \
+                reply size={}",
             FUSE_KERNEL_VERSION,
             FUSE_KERNEL_MINOR_VERSION,
             flags,
             arg.max_readahead,
             MAX_WRITE_SIZE,
+            reply_size,
         );
 
-        // Store the kernel FUSE major and minor version
+        // Store the negotiated FUSE major and minor version
         self.proto_version.store(ProtoVersion {
             major: arg.major,
-            minor: arg.minor,
+            minor: arg.minor.min(FUSE_KERNEL_MINOR_VERSION),
         });
 
-        Ok(())
+        Ok(flags)
     }
 }
 
+/// Whether the request can be cancelled safely when it's interrupted. Only the
+/// requests reading data or waiting for locks are interruptible, the others
+/// always run to completion to keep the metadata consistent.
+fn is_interruptible(operation: &Operation<'_>) -> bool {
+    matches!(
+        *operation,
+        Operation::Read { .. } | Operation::SetLkW { .. }
+    )
+}
+
+/// Handle `FUSE_INTERRUPT`, cancel the interrupted request if it's
+/// interruptible. If the request is not found, it may not be processed yet, so
+/// reply `EAGAIN` to let the kernel resend the interrupt.
+async fn interrupt(
+    req: &Request<'_>,
+    unique: u64,
+    fd: RawFd,
+    fs: Arc<dyn FileSystem + Send + Sync + 'static>,
+    in_flight_requests: &InFlightRequests,
+) -> nix::Result<usize> {
+    let cancel_sender = in_flight_requests.lock().get_mut(&unique).map(Option::take);
+    match cancel_sender {
+        Some(Some(sender)) => {
+            // The request may finish before cancelled, just ignore it
+            sender.send(()).ok();
+            fs.interrupt(req, unique).await;
+        }
+        Some(None) => debug!("the request unique={} is not interruptible", unique),
+        None => {
+            let reply = ReplyEmpty::new(req.unique(), fd);
+            // The reply fails if the interrupted request has finished
+            if let Err(e) = reply.error_code(Errno::EAGAIN).await {
+                debug!("failed to reply EAGAIN to req={}, the error is: {}", req, e);
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// Dispatch request to the filesystem, and track it as in-flight. The
+/// interruptible request is cancelled and replied `EINTR` when interrupted.
+#[allow(clippy::arithmetic_side_effects)] // for the auto generate code from tokio select!
+#[allow(clippy::pattern_type_mismatch)] // for tokio::select!
+async fn dispatch_interruptible(
+    req: &Request<'_>,
+    fd: RawFd,
+    fs: Arc<dyn FileSystem + Send + Sync + 'static>,
+    pipe: Option<&Arc<Pipe>>,
+    in_flight_requests: &InFlightRequests,
+) -> nix::Result<usize> {
+    let unique = req.unique();
+    let (cancel_sender, cancel_receiver) = oneshot::channel();
+    let cancel_sender = is_interruptible(req.operation()).then_some(cancel_sender);
+    in_flight_requests.lock().insert(unique, cancel_sender);
+    let res = tokio::select! {
+        biased;
+        res = dispatch(req, fd, fs, pipe) => res,
+        Ok(()) = cancel_receiver => {
+            debug!("req={} is interrupted", req);
+            let reply = ReplyEmpty::new(unique, fd);
+            // The reply fails if the request was replied right before cancelled
+            if let Err(e) = reply.error_code(Errno::EINTR).await {
+                debug!("failed to reply EINTR to req={}, the error is: {}", req, e);
+            }
+            Ok(0)
+        }
+    };
+    in_flight_requests.lock().remove(&unique);
+    res
+}
+
 /// Dispatch request to the filesystem
 /// This calls the appropriate filesystem operation method for the
-/// request and sends back the returned reply to the kernel
+/// request and sends back the returned reply to the kernel. The data of
+/// spliced requests is moved through `pipe`.
 #[allow(clippy::too_many_lines)]
 async fn dispatch<'a>(
     req: &'a Request<'a>,
     fd: RawFd,
     fs: Arc<dyn FileSystem + Send + Sync + 'static>,
+    pipe: Option<&Arc<Pipe>>,
 ) -> nix::Result<usize> {
     match *req.operation() {
         // Filesystem initialization
@@ -462,10 +1059,8 @@ async fn dispatch<'a>(
             reply.ok().await
         }
 
-        Operation::Interrupt { arg } => {
-            fs.interrupt(req, arg.unique).await; // No reply
-            Ok(0)
-        }
+        // Interrupt is handled before dispatching
+        Operation::Interrupt { .. } => unreachable!("FUSE_INTERRUPT should have been handled"),
 
         Operation::Lookup { name } => {
             let reply = ReplyEntry::new(req.unique(), fd);
@@ -559,6 +1154,13 @@ async fn dispatch<'a>(
             fs.readlink(req, reply).await
         }
         Operation::MkNod { arg, name } => {
+            // The file type is in the mode, zero means a regular file
+            let file_type = arg.mode & SFlag::S_IFMT.bits();
+            let node_type = if file_type == 0 {
+                SFlag::S_IFREG
+            } else {
+                SFlag::from_bits_truncate(file_type)
+            };
             let param = CreateParam {
                 parent: req.nodeid(),
                 name: name.to_owned(),
@@ -566,7 +1168,7 @@ async fn dispatch<'a>(
                 rdev: arg.rdev,
                 uid: req.uid(),
                 gid: req.gid(),
-                node_type: SFlag::S_IFREG,
+                node_type,
                 link: None,
             };
             let reply = ReplyEntry::new(req.unique(), fd);
@@ -613,22 +1215,30 @@ async fn dispatch<'a>(
             fs.open(req, arg.flags, reply).await
         }
         Operation::Read { arg } => {
-            let reply = ReplyData::new(req.unique(), fd);
+            // Splice the reply if the request is spliced
+            let reply = if pipe.is_some() {
+                ReplyData::new_spliced(req.unique(), fd)
+            } else {
+                ReplyData::new(req.unique(), fd)
+            };
             fs.read(req, arg.fh, arg.offset.cast(), arg.size, reply)
                 .await
         }
         Operation::Write { arg, data } => {
-            assert_eq!(data.len(), arg.size.cast::<usize>());
+            let size = arg.size.cast::<usize>();
+            let data = match pipe {
+                // The data is left in the pipe
+                Some(pipe) if data.is_empty() && size > 0 => {
+                    WriteData::Pipe(Arc::clone(pipe), size)
+                }
+                _ => {
+                    assert_eq!(data.len(), size);
+                    WriteData::Bytes(data.to_vec())
+                }
+            };
             let reply = ReplyWrite::new(req.unique(), fd);
-            fs.write(
-                req,
-                arg.fh,
-                arg.offset.cast(),
-                data.to_vec(), // TODO: consider zero copy
-                arg.write_flags,
-                reply,
-            )
-            .await
+            fs.write(req, arg.fh, arg.offset.cast(), data, arg.write_flags, reply)
+                .await
         }
         Operation::Flush { arg } => {
             let reply = ReplyEmpty::new(req.unique(), fd);
@@ -636,8 +1246,14 @@ async fn dispatch<'a>(
         }
         Operation::Release { arg } => {
             let flush = !matches!(arg.release_flags & FUSE_RELEASE_FLUSH, 0);
+            // The flock locks of the owner should be released
+            #[cfg(feature = "abi-7-17")]
+            let lock_owner = (!matches!(arg.release_flags & FUSE_RELEASE_FLOCK_UNLOCK, 0))
+                .then_some(arg.lock_owner);
+            #[cfg(not(feature = "abi-7-17"))]
+            let lock_owner = None;
             let reply = ReplyEmpty::new(req.unique(), fd);
-            fs.release(req, arg.fh, arg.flags, arg.lock_owner, flush, reply)
+            fs.release(req, arg.fh, arg.flags, lock_owner, flush, reply)
                 .await
         }
         Operation::FSync { arg } => {
@@ -722,6 +1338,10 @@ async fn dispatch<'a>(
                 typ: arg.lk.typ,
                 pid: arg.lk.pid,
             };
+            #[cfg(feature = "abi-7-17")]
+            if !matches!(arg.lk_flags & FUSE_LK_FLOCK, 0) {
+                return fs.flock(req, lock_param, false, reply).await;
+            }
             fs.setlk(req, lock_param, false, reply).await
         }
         Operation::SetLkW { arg } => {
@@ -734,6 +1354,10 @@ async fn dispatch<'a>(
                 typ: arg.lk.typ,
                 pid: arg.lk.pid,
             };
+            #[cfg(feature = "abi-7-17")]
+            if !matches!(arg.lk_flags & FUSE_LK_FLOCK, 0) {
+                return fs.flock(req, lock_param, true, reply).await;
+            }
             fs.setlk(
                 req, lock_param, true, // sleep
                 reply,
@@ -756,27 +1380,39 @@ async fn dispatch<'a>(
             not_implement_helper(req, fd).await
         }
         #[cfg(feature = "abi-7-15")]
-        Operation::NotifyReply { data } => {
-            error!("NotifyReply not implemented, data={:?}", data);
-            not_implement_helper(req, fd).await
+        Operation::NotifyReply { arg, data } => {
+            // The data retrieved by `FuseRetrieveNotification`, the kernel
+            // expects no reply
+            debug!(
+                "NotifyReply of notify unique={} ino={}, {} bytes retrieved at offset={}",
+                req.unique(),
+                req.nodeid(),
+                data.len(),
+                arg.offset,
+            );
+            if !FuseRetrieveNotification::complete(req.unique(), data.to_vec()) {
+                warn!(
+                    "NotifyReply of notify unique={} matches no retrieve notification",
+                    req.unique(),
+                );
+            }
+            Ok(0)
         }
         #[cfg(feature = "abi-7-16")]
-        Operation::BatchForget { arg, nodes } => {
-            error!(
-                "BatchForget not implemented, arg={:?}, nodes={:?}",
-                arg, nodes
-            );
-            not_implement_helper(req, fd).await
+        Operation::BatchForget { nodes, .. } => {
+            fs.batch_forget(req, nodes).await; // No reply
+            Ok(0)
         }
         #[cfg(feature = "abi-7-19")]
         Operation::FAllocate { arg } => {
-            error!("FAllocate not implemented, arg={:?}", arg);
-            not_implement_helper(req, fd).await
+            let reply = ReplyEmpty::new(req.unique(), fd);
+            fs.fallocate(req, arg.fh, arg.offset, arg.length, arg.mode, reply)
+                .await
         }
         #[cfg(feature = "abi-7-21")]
         Operation::ReadDirPlus { arg } => {
-            error!("ReadDirPlus not implemented, arg={:?}", arg);
-            not_implement_helper(req, fd).await
+            let reply = ReplyDirectoryPlus::new(req.unique(), fd, arg.size.cast());
+            fs.readdirplus(req, arg.fh, arg.offset.cast(), reply).await
         }
         #[cfg(feature = "abi-7-23")]
         Operation::Rename2 {
@@ -796,13 +1432,23 @@ async fn dispatch<'a>(
         }
         // #[cfg(feature = "abi-7-24")]
         Operation::LSeek { arg } => {
-            error!("LSeek not implemented, arg={:?}", arg);
-            not_implement_helper(req, fd).await
+            let reply = ReplyLSeek::new(req.unique(), fd);
+            fs.lseek(req, arg.fh, arg.offset, arg.whence, reply).await
         }
         // #[cfg(feature = "abi-7-28")]
         Operation::CopyFileRange { arg } => {
-            error!("ReadDirPlusCopyFileRange not implemented, arg={:?}", arg);
-            not_implement_helper(req, fd).await
+            let reply = ReplyWrite::new(req.unique(), fd);
+            let param = CopyRangeParam {
+                ino_in: req.nodeid(),
+                fh_in: arg.fh_in,
+                off_in: arg.off_in,
+                ino_out: arg.nodeid_out,
+                fh_out: arg.fh_out,
+                off_out: arg.off_out,
+                len: arg.len,
+                flags: arg.flags,
+            };
+            fs.copy_file_range(req, param, reply).await
         }
         #[cfg(feature = "abi-7-11")]
         Operation::CuseInit { arg } => {
@@ -0,0 +1,187 @@
+//! The pipes to move data through the FUSE device by splice
+
+use std::cell::RefCell;
+use std::os::unix::io::RawFd;
+
+use clippy_utilities::{Cast, OverflowArithmetic};
+use nix::errno::Errno;
+use nix::fcntl::{self, FcntlArg, OFlag, SpliceFFlags};
+use nix::sys::uio::IoVec;
+use nix::unistd;
+
+/// The size of the pipes to splice replies, which is large enough to hold the
+/// reply of the max read size
+pub const REPLY_PIPE_SIZE: usize = 256 * 1024;
+
+thread_local! {
+    /// The pipe of the current thread to splice replies, which is created on
+    /// first use
+    static REPLY_PIPE: RefCell<Option<Pipe>> = RefCell::new(None);
+}
+
+/// Run `f` with the reply pipe of the current thread. Each blocking thread has
+/// its own pipe, so a reply is never mixed with the others even if the task
+/// sending it is cancelled. The pipe is drained if `f` fails.
+pub fn with_reply_pipe<T>(f: impl FnOnce(&Pipe) -> nix::Result<T>) -> nix::Result<T> {
+    REPLY_PIPE.with(|cell| {
+        let mut reply_pipe = cell.borrow_mut();
+        if reply_pipe.is_none() {
+            *reply_pipe = Some(Pipe::new(REPLY_PIPE_SIZE)?);
+        }
+        let pipe = reply_pipe
+            .as_ref()
+            .unwrap_or_else(|| unreachable!("the reply pipe is created above"));
+        let res = f(pipe);
+        if res.is_err() {
+            pipe.drain();
+        }
+        res
+    })
+}
+
+/// A pipe to splice FUSE requests and replies, both ends are non-blocking, as
+/// the data is always moved into the pipe before it's read out
+#[derive(Debug)]
+pub struct Pipe {
+    /// The read end of the pipe
+    read_fd: RawFd,
+    /// The write end of the pipe
+    write_fd: RawFd,
+    /// The capacity of the pipe
+    capacity: usize,
+}
+
+impl Pipe {
+    /// Create a pipe which can hold at least `size` bytes
+    pub fn new(size: usize) -> nix::Result<Self> {
+        let (read_fd, write_fd) = unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+        // The fds are closed on drop if it fails to resize the pipe
+        let mut pipe = Self {
+            read_fd,
+            write_fd,
+            capacity: 0,
+        };
+        pipe.capacity = fcntl::fcntl(write_fd, FcntlArg::F_SETPIPE_SZ(size.cast()))?.cast();
+        Ok(pipe)
+    }
+
+    /// The capacity of the pipe
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Splice a message of at most `len` bytes from `fd` into the pipe
+    pub fn splice_from(&self, fd: RawFd, len: usize) -> nix::Result<usize> {
+        fcntl::splice(
+            fd,
+            None,
+            self.write_fd,
+            None,
+            len,
+            SpliceFFlags::SPLICE_F_MOVE,
+        )
+    }
+
+    /// Splice `len` bytes from the pipe to `fd` as a single message
+    pub fn splice_to(&self, fd: RawFd, len: usize) -> nix::Result<usize> {
+        fcntl::splice(
+            self.read_fd,
+            None,
+            fd,
+            None,
+            len,
+            SpliceFFlags::SPLICE_F_MOVE,
+        )
+    }
+
+    /// Map the user pages of `iovecs` into the pipe, the pages should not be
+    /// modified until they are spliced out
+    pub fn vmsplice(&self, iovecs: &[IoVec<&[u8]>]) -> nix::Result<usize> {
+        let total = iovecs.iter().map(|v| v.as_slice().len()).sum::<usize>();
+        let mut written = 0_usize;
+        let mut remaining: Vec<&[u8]> = iovecs.iter().map(IoVec::as_slice).collect();
+        while written < total {
+            let vecs: Vec<IoVec<&[u8]>> = remaining
+                .iter()
+                .filter(|s| !s.is_empty())
+                .map(|s| IoVec::from_slice(s))
+                .collect();
+            let size = fcntl::vmsplice(self.write_fd, &vecs, SpliceFFlags::empty())?;
+            written = written.overflow_add(size);
+            // Skip the bytes already in the pipe
+            let mut skip = size;
+            for slice in &mut remaining {
+                let n = skip.min(slice.len());
+                *slice = slice.get(n..).unwrap_or_default();
+                skip = skip.overflow_sub(n);
+            }
+        }
+        Ok(written)
+    }
+
+    /// Read exactly `buf.len()` bytes from the pipe, which should be already
+    /// in the pipe
+    pub fn read_exact(&self, buf: &mut [u8]) -> nix::Result<()> {
+        let mut have_read = 0_usize;
+        while have_read < buf.len() {
+            let dst = buf.get_mut(have_read..).unwrap_or_default();
+            match unistd::read(self.read_fd, dst) {
+                Ok(0) | Err(Errno::EAGAIN) => return Err(Errno::EIO),
+                Ok(size) => have_read = have_read.overflow_add(size),
+                Err(Errno::EINTR) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the data left in the pipe, return the number of dropped bytes
+    pub fn drain(&self) -> usize {
+        let mut buf = [0_u8; 4096];
+        let mut drained = 0_usize;
+        while let Ok(size) = unistd::read(self.read_fd, &mut buf) {
+            if size == 0 {
+                break;
+            }
+            drained = drained.overflow_add(size);
+        }
+        drained
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unistd::close(self.read_fd).ok();
+        unistd::close(self.write_fd).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nix::sys::uio::IoVec;
+
+    use super::Pipe;
+
+    #[test]
+    fn test_pipe_vmsplice_read() {
+        let pipe = Pipe::new(64 * 1024)
+            .unwrap_or_else(|e| panic!("failed to create pipe, the error is: {e}"));
+        assert!(pipe.capacity() >= 64 * 1024);
+
+        let (header, data) = ([1_u8; 16], [2_u8; 100]);
+        let written = pipe
+            .vmsplice(&[IoVec::from_slice(&header), IoVec::from_slice(&data)])
+            .unwrap_or_else(|e| panic!("failed to vmsplice to pipe, the error is: {e}"));
+        assert_eq!(written, 116);
+
+        let mut buf = [0_u8; 16];
+        pipe.read_exact(&mut buf)
+            .unwrap_or_else(|e| panic!("failed to read from pipe, the error is: {e}"));
+        assert_eq!(buf, header);
+        // The data not read is dropped
+        assert_eq!(pipe.drain(), 100);
+        // Reading more than the data in the pipe fails
+        assert!(pipe.read_exact(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+//! Live upgrade of the FUSE daemon without re-mounting.
+//!
+//! The running process listens on a Unix domain socket. A new process connects
+//! to it to take over the FUSE session: the running process stops reading
+//! requests, waits for the in-flight ones and flushes the open files, then
+//! sends the FUSE device fd by `SCM_RIGHTS` along with a snapshot of the
+//! session. The new process continues the session on the received fd, and the
+//! old process exits.
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use clippy_utilities::Cast;
+use nix::cmsg_space;
+use nix::sys::socket::{self, ControlMessage, ControlMessageOwned, MsgFlags};
+use nix::sys::uio::IoVec;
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixListener;
+use tracing::info;
+
+use crate::async_fuse::memfs::HandleSnapshot;
+
+/// The state of a FUSE session handed over to the new process
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionSnapshot {
+    /// The negotiated FUSE major version
+    pub proto_major: u32,
+    /// The negotiated FUSE minor version
+    pub proto_minor: u32,
+    /// The negotiated init flags
+    pub init_flags: u32,
+    /// The handles the kernel holds on the file system
+    pub handles: HandleSnapshot,
+}
+
+/// Listen on `path` for a new process to take over the FUSE session, the socket
+/// file left by the previous process is replaced
+pub fn listen(path: &Path) -> anyhow::Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).context(format!("failed to remove upgrade socket {path:?}"));
+        }
+    }
+    UnixListener::bind(path).context(format!("failed to listen on upgrade socket {path:?}"))
+}
+
+/// Hand over the FUSE session on `fuse_fd` to the new process connected by
+/// `stream`, the session should be stopped and flushed already. Return the
+/// connection, the new process waits for it to be closed, which means this
+/// process has released its resources.
+pub async fn hand_over(
+    stream: tokio::net::UnixStream,
+    fuse_fd: RawFd,
+    snapshot: &SessionSnapshot,
+) -> anyhow::Result<UnixStream> {
+    let payload = bincode::serialize(snapshot).context("failed to serialize session snapshot")?;
+    let mut stream = stream
+        .into_std()
+        .context("failed to convert upgrade connection")?;
+    tokio::task::spawn_blocking(move || {
+        stream
+            .set_nonblocking(false)
+            .context("failed to set upgrade connection blocking")?;
+        // Send the fd along with the length of the snapshot
+        let len_buf = payload.len().cast::<u64>().to_le_bytes();
+        let iov = [IoVec::from_slice(&len_buf)];
+        let fds = [fuse_fd];
+        let cmsg = [ControlMessage::ScmRights(&fds)];
+        let sent = socket::sendmsg(stream.as_raw_fd(), &iov, &cmsg, MsgFlags::empty(), None)
+            .context("failed to send FUSE device fd")?;
+        let rest = len_buf
+            .get(sent..)
+            .ok_or_else(|| anyhow!("sent more bytes than the length of snapshot"))?;
+        stream.write_all(rest)?;
+        stream
+            .write_all(&payload)
+            .context("failed to send session snapshot")?;
+        Ok(stream)
+    })
+    .await?
+}
+
+/// Take over the FUSE session from the process listening on `path`, return the
+/// FUSE device fd and the session snapshot, or `None` if no process is
+/// listening. It returns after the old process closes the connection.
+pub async fn take_over(path: &Path) -> anyhow::Result<Option<(RawFd, SessionSnapshot)>> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let mut stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || e.kind() == io::ErrorKind::ConnectionRefused =>
+            {
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(e).context(format!("failed to connect to upgrade socket {path:?}"));
+            }
+        };
+        info!("taking over the FUSE session through {:?}", path);
+
+        let mut len_buf = [0_u8; 8];
+        #[allow(clippy::arithmetic_side_effects)]
+        let mut cmsgspace = cmsg_space!([RawFd; 1]);
+        let (received, fuse_fd) = {
+            let iov = [IoVec::from_mut_slice(&mut len_buf)];
+            let msg = socket::recvmsg(
+                stream.as_raw_fd(),
+                &iov,
+                Some(&mut cmsgspace),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )
+            .context("failed to receive FUSE device fd")?;
+            let fuse_fd = msg.cmsgs().find_map(|cmsg| {
+                if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                    fds.first().copied()
+                } else {
+                    None
+                }
+            });
+            (msg.bytes, fuse_fd)
+        };
+        let fuse_fd =
+            fuse_fd.ok_or_else(|| anyhow!("the old process did not send FUSE device fd"))?;
+        let rest = len_buf
+            .get_mut(received..)
+            .ok_or_else(|| anyhow!("received more bytes than the length of snapshot"))?;
+        stream.read_exact(rest)?;
+        let mut payload = vec![0_u8; u64::from_le_bytes(len_buf).cast()];
+        stream
+            .read_exact(&mut payload)
+            .context("failed to receive session snapshot")?;
+        let snapshot: SessionSnapshot =
+            bincode::deserialize(&payload).context("failed to deserialize session snapshot")?;
+
+        // Wait for the old process to release its resources
+        let mut remaining = Vec::new();
+        stream.read_to_end(&mut remaining)?;
+        info!(
+            "took over the FUSE session of fd={} with {} open files",
+            fuse_fd,
+            snapshot.handles.open_files.len(),
+        );
+        Ok(Some((fuse_fd, snapshot)))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::os::unix::io::AsRawFd;
+
+    use super::SessionSnapshot;
+    use crate::async_fuse::memfs::{FileLockKind, FileLockSnapshot, HandleSnapshot};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_hand_over_take_over() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("upgrade-{}.sock", std::process::id()));
+        // No process to take over from
+        assert!(super::take_over(&path).await?.is_none());
+
+        let listener = super::listen(&path)?;
+        let snapshot = SessionSnapshot {
+            proto_major: 7,
+            proto_minor: 31,
+            init_flags: 0x1,
+            handles: HandleSnapshot {
+                open_files: BTreeMap::from([(2, 1), (3, 2)]),
+                lookup_counts: BTreeMap::from([(2, 1)]),
+                next_fh: 42,
+                file_locks: FileLockSnapshot {
+                    lease_id: 7,
+                    lock_holders: vec![(2, 0x1234, FileLockKind::Posix)],
+                    lost_holders: vec![(3, 0x5678, FileLockKind::Flock)],
+                },
+            },
+        };
+        let file = std::fs::File::open("/dev/null")?;
+        let old = tokio::task::spawn({
+            let snapshot = snapshot.clone();
+            async move {
+                let (stream, _) = listener.accept().await?;
+                super::hand_over(stream, file.as_raw_fd(), &snapshot).await
+            }
+        });
+        let new = tokio::task::spawn({
+            let path = path.clone();
+            async move { super::take_over(&path).await }
+        });
+        // The new process returns after the connection is closed
+        drop(old.await??);
+        let (fd, received) = new
+            .await??
+            .unwrap_or_else(|| panic!("failed to take over from the old process"));
+        assert!(fd >= 0);
+        assert_eq!(received, snapshot);
+        nix::unistd::close(fd)?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
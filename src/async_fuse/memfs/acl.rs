@@ -0,0 +1,380 @@
+//! The implementation of POSIX ACLs, which are stored as the
+//! `system.posix_acl_access` and `system.posix_acl_default` extended attributes
+//! in the same binary format as Linux.
+
+use clippy_utilities::OverflowArithmetic;
+use nix::errno::Errno;
+
+use super::fs_util::FileAttr;
+use crate::async_fuse::util::build_error_result_from_errno;
+use crate::common::error::DatenLordResult;
+
+/// The xattr name of the access ACL
+pub const XATTR_NAME_POSIX_ACL_ACCESS: &str = "system.posix_acl_access";
+/// The xattr name of the default ACL, which is only valid for directories
+pub const XATTR_NAME_POSIX_ACL_DEFAULT: &str = "system.posix_acl_default";
+
+/// The version of the ACL xattr format
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+/// The size of the ACL xattr header
+const POSIX_ACL_XATTR_HEADER_SIZE: usize = 4;
+/// The size of an ACL xattr entry
+const POSIX_ACL_XATTR_ENTRY_SIZE: usize = 8;
+
+/// The entry of the file owner
+const ACL_USER_OBJ: u16 = 0x01;
+/// The entry of a named user
+const ACL_USER: u16 = 0x02;
+/// The entry of the owning group
+const ACL_GROUP_OBJ: u16 = 0x04;
+/// The entry of a named group
+const ACL_GROUP: u16 = 0x08;
+/// The entry of the maximum permission of the group class
+const ACL_MASK: u16 = 0x10;
+/// The entry of other users
+const ACL_OTHER: u16 = 0x20;
+/// The id of the entries which are not for a named user or group
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// An entry of POSIX ACL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PosixAclEntry {
+    /// The tag of the entry
+    tag: u16,
+    /// The rwx permission of the entry
+    perm: u16,
+    /// The uid or gid of a named user or group entry
+    id: u32,
+}
+
+/// POSIX ACL
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PosixAcl {
+    /// The entries sorted by tag and id
+    entries: Vec<PosixAclEntry>,
+}
+
+/// Convert a slice to an array, the length of the slice must be checked before
+fn to_array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    bytes.try_into().unwrap_or_else(|e| {
+        panic!(
+            "to_array() failed to convert a slice of length={} to an array, the error is: {e}",
+            bytes.len(),
+        )
+    })
+}
+
+impl PosixAcl {
+    /// Parse and validate POSIX ACL from its xattr value
+    pub fn from_xattr(value: &[u8]) -> DatenLordResult<Self> {
+        if value.len() <= POSIX_ACL_XATTR_HEADER_SIZE
+            || value
+                .len()
+                .overflow_sub(POSIX_ACL_XATTR_HEADER_SIZE)
+                .overflow_rem(POSIX_ACL_XATTR_ENTRY_SIZE)
+                != 0
+        {
+            return build_error_result_from_errno(
+                Errno::EINVAL,
+                format!("from_xattr() found invalid ACL size={}", value.len()),
+            );
+        }
+        let (header, body) = value.split_at(POSIX_ACL_XATTR_HEADER_SIZE);
+        let version = u32::from_le_bytes(to_array(header));
+        if version != POSIX_ACL_XATTR_VERSION {
+            return build_error_result_from_errno(
+                Errno::EOPNOTSUPP,
+                format!("from_xattr() found unsupported ACL version={version}"),
+            );
+        }
+        let entries = body
+            .chunks_exact(POSIX_ACL_XATTR_ENTRY_SIZE)
+            .map(|chunk| {
+                let (tag, rest) = chunk.split_at(2);
+                let (perm, id) = rest.split_at(2);
+                let tag = u16::from_le_bytes(to_array(tag));
+                let id = match tag {
+                    ACL_USER | ACL_GROUP => u32::from_le_bytes(to_array(id)),
+                    _ => ACL_UNDEFINED_ID,
+                };
+                PosixAclEntry {
+                    tag,
+                    perm: u16::from_le_bytes(to_array(perm)),
+                    id,
+                }
+            })
+            .collect();
+        let acl = Self { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    /// Build the xattr value of the ACL
+    pub fn to_xattr(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(
+            POSIX_ACL_XATTR_HEADER_SIZE
+                .overflow_add(self.entries.len().overflow_mul(POSIX_ACL_XATTR_ENTRY_SIZE)),
+        );
+        value.extend_from_slice(&POSIX_ACL_XATTR_VERSION.to_le_bytes());
+        for entry in &self.entries {
+            value.extend_from_slice(&entry.tag.to_le_bytes());
+            value.extend_from_slice(&entry.perm.to_le_bytes());
+            value.extend_from_slice(&entry.id.to_le_bytes());
+        }
+        value
+    }
+
+    /// Check the ACL is well formed, the same as `posix_acl_valid()` in Linux
+    fn validate(&self) -> DatenLordResult<()> {
+        let is_sorted = self.entries.windows(2).all(|pair| match *pair {
+            [ref prev, ref next] => (prev.tag, prev.id) < (next.tag, next.id),
+            _ => true,
+        });
+        let count = |tag: u16| self.entries.iter().filter(|e| e.tag == tag).count();
+        let has_named = count(ACL_USER) > 0 || count(ACL_GROUP) > 0;
+        let is_valid = is_sorted
+            && self.entries.iter().all(|e| {
+                e.perm & !0o7 == 0
+                    && matches!(
+                        e.tag,
+                        ACL_USER_OBJ | ACL_USER | ACL_GROUP_OBJ | ACL_GROUP | ACL_MASK | ACL_OTHER
+                    )
+            })
+            && count(ACL_USER_OBJ) == 1
+            && count(ACL_GROUP_OBJ) == 1
+            && count(ACL_OTHER) == 1
+            // A mask is required by the named entries, and optional otherwise
+            && (if has_named {
+                count(ACL_MASK) == 1
+            } else {
+                count(ACL_MASK) <= 1
+            });
+        if is_valid {
+            Ok(())
+        } else {
+            build_error_result_from_errno(
+                Errno::EINVAL,
+                format!("validate() found invalid ACL entries={:?}", self.entries),
+            )
+        }
+    }
+
+    /// Get the permission of the first entry with `tag`
+    fn get_entry_perm(&self, tag: u16) -> Option<u16> {
+        self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+
+    /// Set the permission of the first entry with `tag`
+    fn set_entry_perm(&mut self, tag: u16, perm: u16) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.tag == tag) {
+            entry.perm = perm & 0o7;
+        }
+    }
+
+    /// Whether the ACL can be fully represented by the permission bits, which
+    /// means there is no need to store it. The mask of an ACL without named
+    /// entries is represented by the group bits, the same as
+    /// `posix_acl_equiv_mode()` in Linux.
+    pub fn is_equiv_mode(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|e| matches!(e.tag, ACL_USER_OBJ | ACL_GROUP_OBJ | ACL_MASK | ACL_OTHER))
+    }
+
+    /// Get the rwx permission bits represented by the ACL, the group bits come
+    /// from the mask entry if it exists
+    pub fn get_perm(&self) -> u16 {
+        let owner = self.get_entry_perm(ACL_USER_OBJ).unwrap_or(0);
+        let group = self
+            .get_entry_perm(ACL_MASK)
+            .or_else(|| self.get_entry_perm(ACL_GROUP_OBJ))
+            .unwrap_or(0);
+        let other = self.get_entry_perm(ACL_OTHER).unwrap_or(0);
+        owner.overflow_shl(6) | group.overflow_shl(3) | other
+    }
+
+    /// Update the ACL with the rwx permission bits after `chmod()`, the same as
+    /// `posix_acl_chmod()` in Linux
+    pub fn set_perm(&mut self, perm: u16) {
+        self.set_entry_perm(ACL_USER_OBJ, perm.overflow_shr(6));
+        if self.get_entry_perm(ACL_MASK).is_some() {
+            self.set_entry_perm(ACL_MASK, perm.overflow_shr(3));
+        } else {
+            self.set_entry_perm(ACL_GROUP_OBJ, perm.overflow_shr(3));
+        }
+        self.set_entry_perm(ACL_OTHER, perm);
+    }
+
+    /// Build the access ACL of a new child from the default ACL of its parent
+    /// and the permission bits requested by the creator, the same as
+    /// `posix_acl_create()` in Linux.
+    /// Return the access ACL and the permission bits of the new child.
+    pub fn inherit(&self, perm: u16) -> (Self, u16) {
+        let mut access_acl = self.clone();
+        let new_perm = (perm & !0o777) | (self.get_perm() & perm & 0o777);
+        access_acl.set_perm(new_perm);
+        (access_acl, new_perm)
+    }
+
+    /// Check whether the ACL grants `access_mode` to the user, the same as
+    /// `posix_acl_permission()` in Linux.
+    /// The superuser should be checked before calling this.
+    pub fn check_perm(
+        &self,
+        attr: &FileAttr,
+        user_id: u32,
+        group_id: u32,
+        access_mode: u8,
+    ) -> bool {
+        let access_mode = u16::from(access_mode);
+        let is_granted = |perm: u16| perm & access_mode == access_mode;
+        if user_id == attr.uid {
+            return is_granted(self.get_entry_perm(ACL_USER_OBJ).unwrap_or(0));
+        }
+        let mask = self.get_entry_perm(ACL_MASK).unwrap_or(0o7);
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|e| e.tag == ACL_USER && e.id == user_id)
+        {
+            return is_granted(entry.perm & mask);
+        }
+        let mut group_matched = false;
+        for entry in &self.entries {
+            let is_matched = (entry.tag == ACL_GROUP_OBJ && group_id == attr.gid)
+                || (entry.tag == ACL_GROUP && entry.id == group_id);
+            if is_matched {
+                if is_granted(entry.perm & mask) {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+        is_granted(self.get_entry_perm(ACL_OTHER).unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nix::sys::stat::SFlag;
+
+    use super::*;
+
+    /// Build the xattr value of an ACL from `(tag, perm, id)` tuples
+    fn build_xattr(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for &(tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_result_states)] // assert!(result.is_err()) is more readable for test
+    fn test_from_xattr() {
+        let value = build_xattr(&[
+            (ACL_USER_OBJ, 7, ACL_UNDEFINED_ID),
+            (ACL_USER, 6, 1001),
+            (ACL_GROUP_OBJ, 5, ACL_UNDEFINED_ID),
+            (ACL_MASK, 6, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0, ACL_UNDEFINED_ID),
+        ]);
+        let acl = PosixAcl::from_xattr(&value)
+            .unwrap_or_else(|e| panic!("failed to parse ACL, the error is: {e}"));
+        assert_eq!(acl.to_xattr(), value);
+        assert!(!acl.is_equiv_mode());
+        assert_eq!(acl.get_perm(), 0o760);
+
+        // Named entries require a mask entry
+        let value = build_xattr(&[
+            (ACL_USER_OBJ, 7, ACL_UNDEFINED_ID),
+            (ACL_USER, 6, 1001),
+            (ACL_GROUP_OBJ, 5, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0, ACL_UNDEFINED_ID),
+        ]);
+        assert!(PosixAcl::from_xattr(&value).is_err());
+
+        // Entries must be sorted
+        let value = build_xattr(&[
+            (ACL_GROUP_OBJ, 5, ACL_UNDEFINED_ID),
+            (ACL_USER_OBJ, 7, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0, ACL_UNDEFINED_ID),
+        ]);
+        assert!(PosixAcl::from_xattr(&value).is_err());
+
+        // Truncated value
+        let (truncated, _) = value.split_at(value.len().overflow_sub(1));
+        assert!(PosixAcl::from_xattr(truncated).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_result_states)] // assert!(result.is_err()) is more readable for test
+    fn test_mask_without_named_entries() {
+        // `setfacl -m m::r` sets a mask without any named entry
+        let value = build_xattr(&[
+            (ACL_USER_OBJ, 6, ACL_UNDEFINED_ID),
+            (ACL_GROUP_OBJ, 6, ACL_UNDEFINED_ID),
+            (ACL_MASK, 4, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 4, ACL_UNDEFINED_ID),
+        ]);
+        let acl = PosixAcl::from_xattr(&value)
+            .unwrap_or_else(|e| panic!("failed to parse ACL, the error is: {e}"));
+        assert!(acl.is_equiv_mode());
+        assert_eq!(acl.get_perm(), 0o644);
+
+        // At most one mask entry
+        let value = build_xattr(&[
+            (ACL_USER_OBJ, 6, ACL_UNDEFINED_ID),
+            (ACL_GROUP_OBJ, 6, ACL_UNDEFINED_ID),
+            (ACL_MASK, 4, ACL_UNDEFINED_ID),
+            (ACL_MASK, 4, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 4, ACL_UNDEFINED_ID),
+        ]);
+        assert!(PosixAcl::from_xattr(&value).is_err());
+    }
+
+    #[test]
+    fn test_check_perm_and_inherit() {
+        let value = build_xattr(&[
+            (ACL_USER_OBJ, 7, ACL_UNDEFINED_ID),
+            (ACL_USER, 6, 1001),
+            (ACL_GROUP_OBJ, 5, ACL_UNDEFINED_ID),
+            (ACL_GROUP, 7, 2001),
+            (ACL_MASK, 6, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0, ACL_UNDEFINED_ID),
+        ]);
+        let acl = PosixAcl::from_xattr(&value)
+            .unwrap_or_else(|e| panic!("failed to parse ACL, the error is: {e}"));
+        let attr = FileAttr {
+            kind: SFlag::S_IFDIR,
+            perm: 0o760,
+            uid: 1000,
+            gid: 1000,
+            ..FileAttr::default()
+        };
+
+        // Owner
+        assert!(acl.check_perm(&attr, 1000, 3000, 7));
+        // Named user is limited by the mask
+        assert!(acl.check_perm(&attr, 1001, 3000, 6));
+        assert!(!acl.check_perm(&attr, 1001, 3000, 1));
+        // Owning group is limited by the mask
+        assert!(acl.check_perm(&attr, 3000, 1000, 4));
+        assert!(!acl.check_perm(&attr, 3000, 1000, 1));
+        // Named group
+        assert!(acl.check_perm(&attr, 3000, 2001, 6));
+        // Other
+        assert!(!acl.check_perm(&attr, 3000, 3000, 4));
+
+        let (access_acl, perm) = acl.inherit(0o644);
+        assert_eq!(perm, 0o640);
+        assert_eq!(access_acl.get_perm(), 0o640);
+        assert!(!access_acl.check_perm(&attr, 3000, 2001, 2));
+        assert!(access_acl.check_perm(&attr, 3000, 2001, 4));
+    }
+}
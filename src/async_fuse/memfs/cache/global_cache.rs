@@ -2,7 +2,7 @@
 
 use std::fmt::{Debug, Error, Formatter};
 use std::hash::{Hash, Hasher};
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
@@ -20,6 +20,7 @@ use super::super::kv_engine::kv_utils::{add_node_to_file_list, remove_node_from_
 use super::super::kv_engine::KVEngineType;
 use crate::async_fuse::fuse::fuse_reply::{AsIoVec, CouldBeAsIoVecList};
 use crate::async_fuse::fuse::protocol::INum;
+use crate::async_fuse::fuse::splice::Pipe;
 
 /// Page Size
 const PAGE_SIZE: usize = 4096;
@@ -267,7 +268,8 @@ impl GlobalCache {
 
         let bucket_size = self.bucket_size_in_block;
 
-        let dealloc_fn = |global_index: usize| {
+        let mut dealloc_cnt: usize = 0;
+        let mut dealloc_fn = |global_index: usize| {
             let hash_index = global_index.overflow_div(bucket_size);
             let bucket = file_cache.get(&hash_index, &guard);
             let mut bucket = if let Some(b) = bucket {
@@ -285,8 +287,8 @@ impl GlobalCache {
                     )
                 });
 
-            if block.is_some() {
-                block.take();
+            if block.take().is_some() {
+                dealloc_cnt = dealloc_cnt.overflow_add(1);
             }
         };
 
@@ -300,6 +302,11 @@ impl GlobalCache {
                 }
             }
         }
+
+        if dealloc_cnt > 0 {
+            self.size
+                .fetch_sub(dealloc_cnt.overflow_mul(self.block_size), Ordering::Relaxed);
+        }
     }
 
     /// Check if file is available in cache
@@ -417,6 +424,32 @@ impl GlobalCache {
         result
     }
 
+    /// Copy the cached data of `[src_offset, src_offset + len)` of `src_ino`
+    /// to `dst_offset` of `dst_ino`, the partial blocks of the destination
+    /// should be cached before copying.
+    ///
+    /// Return `false` if some source data is not cached, nothing is copied.
+    pub(crate) async fn copy(
+        &self,
+        src_ino: INum,
+        src_offset: usize,
+        dst_ino: INum,
+        dst_offset: usize,
+        len: usize,
+    ) -> bool {
+        let src_blocks = self.get_file_cache(src_ino, src_offset, len);
+        if src_blocks.is_empty() || src_blocks.iter().any(|b| !b.can_convert()) {
+            return false;
+        }
+        let mut buf = Vec::with_capacity(len);
+        for block in &src_blocks {
+            buf.extend_from_slice(unsafe { block.as_slice() });
+        }
+        self.write_or_update(dst_ino, dst_offset, buf.len(), &buf, true)
+            .await;
+        true
+    }
+
     /// Update the Cache.
     ///
     /// 1. `offset` be `MemoryBlock` aligned.
@@ -430,7 +463,49 @@ impl GlobalCache {
         buf: &[u8],
         overwrite: bool,
     ) {
-        let exist = self.write_or_update_helper(file_ino, offset, len, buf, overwrite);
+        let exist =
+            self.write_or_update_helper(file_ino, offset, len, overwrite, |block, off, range| {
+                block.overwrite_offset(
+                    off,
+                    (*buf).get(range).unwrap_or_else(|| {
+                        panic!("should not reach here, buf out of range in cache write")
+                    }),
+                );
+            });
+        self.add_to_file_list(file_ino, exist).await;
+    }
+
+    /// Write the `len` bytes left in the `pipe` to the Cache, the data is read
+    /// from the pipe into the `MemoryBlock`s directly without copying. The
+    /// `offset` and `len` should be the same as `write_or_update`.
+    pub(crate) async fn write_from_pipe(
+        &self,
+        file_ino: INum,
+        offset: usize,
+        len: usize,
+        pipe: &Pipe,
+    ) -> nix::Result<()> {
+        let mut result = Ok(());
+        let exist =
+            self.write_or_update_helper(file_ino, offset, len, true, |block, off, range| {
+                // The following blocks are left unchanged once the reading fails
+                if result.is_ok() {
+                    result = pipe.read_exact(
+                        (**block.write())
+                            .get_mut(off..off.overflow_add(range.len()))
+                            .unwrap_or_else(|| {
+                                panic!("should not reach here, out of range in cache write")
+                            }),
+                    );
+                }
+            });
+        self.add_to_file_list(file_ino, exist).await;
+        result
+    }
+
+    /// Add current node to the node list of the file if the file is not cached
+    /// before
+    async fn add_to_file_list(&self, file_ino: INum, exist: bool) {
         if !exist {
             if let Some(ref kv_engine) = self.kv_engine {
                 if let Some(ref id) = self.node_id {
@@ -442,19 +517,20 @@ impl GlobalCache {
         }
     }
 
-    /// Update the Cache Helper
+    /// Update the Cache Helper, `fill` fills a `MemoryBlock` from the offset
+    /// in it with the range of the data.
     ///
     /// 1. `offset` be `MemoryBlock` aligned.
     /// 2. `len` should be multiple times of `MemoryBlock` Size unless it
     ///    contains the file's last `MemoryBlock`.
     #[allow(clippy::too_many_lines)]
-    fn write_or_update_helper(
+    fn write_or_update_helper<F: FnMut(&MemBlock, usize, Range<usize>)>(
         &self,
         file_ino: INum,
         offset: usize,
         len: usize,
-        buf: &[u8],
         overwrite: bool,
+        mut fill: F,
     ) -> bool {
         let guard = pin();
         let (exist, file_cache) = if let Some(cache) = self.inner.get(&file_ino, &guard) {
@@ -518,20 +594,13 @@ impl GlobalCache {
 
             assert!(b.is_some());
             if let Some(ref block) = *b {
-                if is_first_block {
+                let off = if is_first_block {
                     is_first_block = false;
-                    let off = offset.overflow_rem(self.block_size);
-                    block.overwrite_offset(
-                        off,
-                        (*buf).get(have_read..end).unwrap_or_else(|| {
-                            panic!("should not reach here, buf out of range in cache write")
-                        }),
-                    );
+                    offset.overflow_rem(self.block_size)
                 } else {
-                    block.overwrite((*buf).get(have_read..end).unwrap_or_else(|| {
-                        panic!("should not reach here, buf out of range in cache write")
-                    }));
-                }
+                    0
+                };
+                fill(block, off, have_read..end);
                 have_read = end;
             }
         };
@@ -756,6 +825,11 @@ impl IoMemBlock {
         Self { inner, offset, end }
     }
 
+    /// A `IoMemBlock` of `len` zeros, which is not in the cache
+    pub(crate) fn new_zeroed(len: usize) -> Self {
+        Self::new(Some(MemBlock::new(len)), 0, len)
+    }
+
     /// Turn `IoMemBlock` into slice
     /// ? Why unsafe?
     #[allow(dead_code)]
@@ -887,9 +961,12 @@ impl Clone for MemBlock {
 #[cfg(test)]
 mod test {
     use aligned_utils::bytes::AlignedBytes;
+    use nix::errno::Errno;
+    use nix::sys::uio::IoVec;
 
     use super::{
-        GlobalCache, MEMORY_BLOCK_SIZE_IN_BYTE, MEMORY_BUCKET_SIZE_IN_BYTE, MEMORY_BUCKET_VEC_SIZE,
+        GlobalCache, Pipe, MEMORY_BLOCK_SIZE_IN_BYTE, MEMORY_BUCKET_SIZE_IN_BYTE,
+        MEMORY_BUCKET_VEC_SIZE, PAGE_SIZE,
     };
     use crate::async_fuse::fuse::fuse_reply::AsIoVec;
 
@@ -926,6 +1003,38 @@ mod test {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_write_from_pipe() {
+        let global = GlobalCache::new();
+        let file_ino = 2345;
+        let pipe = Pipe::new(PAGE_SIZE).unwrap_or_else(|e| panic!("failed to create pipe: {e}"));
+        let content = b"abcdefgh";
+        pipe.vmsplice(&[IoVec::from_slice(content)])
+            .unwrap_or_else(|e| panic!("failed to write pipe: {e}"));
+        // The data crosses the boundary of two blocks
+        let offset = MEMORY_BLOCK_SIZE_IN_BYTE - 4;
+        global
+            .write_from_pipe(file_ino, offset, content.len(), &pipe)
+            .await
+            .unwrap_or_else(|e| panic!("failed to write from pipe: {e}"));
+
+        let cache = global.get_file_cache(file_ino, offset, content.len());
+        assert_eq!(cache.len(), 2);
+        let data: Vec<u8> = cache
+            .iter()
+            .flat_map(|block| unsafe { block.as_slice() }.iter().copied())
+            .collect();
+        assert_eq!(data, content);
+
+        // The data left in the pipe is shorter than expected
+        pipe.vmsplice(&[IoVec::from_slice(content)])
+            .unwrap_or_else(|e| panic!("failed to write pipe: {e}"));
+        let result = global
+            .write_from_pipe(file_ino, 0, content.len() * 2, &pipe)
+            .await;
+        assert_eq!(result, Err(Errno::EIO));
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_get_partial_result() {
         let global = GlobalCache::new();
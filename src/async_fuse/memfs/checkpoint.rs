@@ -0,0 +1,330 @@
+//! The checkpoints of the metadata in the storage backend.
+//!
+//! The namespace lives in the KV engine, so it is lost with the KV engine even
+//! though the file data is still in the storage backend. To recover from such a
+//! disaster, the i-nodes, their extended attributes and the quota limits are
+//! periodically serialized to a checkpoint object in the storage backend. A
+//! node started with `--storage-metadata-recover` and an empty KV engine
+//! rebuilds the metadata from the latest checkpoint, the changes after it are
+//! lost.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::SystemTime;
+
+use clippy_utilities::OverflowArithmetic;
+use nix::errno::Errno;
+use serde::{Deserialize, Serialize};
+
+use super::quota::{self, QuotaKind, QuotaLimit, QuotaUsage};
+use super::serial::{self, SerialNode};
+use super::volume_stat::{self, VolumeStat};
+use crate::async_fuse::fuse::protocol::INum;
+use crate::async_fuse::util::build_error_result_from_errno;
+use crate::common::error::DatenLordResult;
+
+/// The name of the checkpoint object in the storage backend
+pub const CHECKPOINT_OBJECT_NAME: &str = "checkpoint";
+
+/// The format version of the checkpoint
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// A checkpoint of the metadata
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetaCheckpoint {
+    /// The format version
+    version: u32,
+    /// The time the checkpoint is taken
+    pub time: SystemTime,
+    /// The i-nodes, i-number -> i-node
+    pub nodes: BTreeMap<INum, SerialNode>,
+    /// The extended attributes of the i-nodes, i-number -> xattrs
+    pub xattrs: BTreeMap<INum, BTreeMap<String, Vec<u8>>>,
+    /// The limits of the quotas, the usage is rebuilt from the i-nodes
+    pub quota_limits: Vec<(QuotaKind, u32, QuotaLimit)>,
+}
+
+impl MetaCheckpoint {
+    /// Build a checkpoint. The i-nodes pending deferred deletion are dropped,
+    /// as they are only referenced by the open files, which do not survive
+    /// the recovery.
+    #[must_use]
+    pub fn new(
+        nodes: Vec<SerialNode>,
+        xattrs: Vec<(INum, BTreeMap<String, Vec<u8>>)>,
+        quota_limits: Vec<(QuotaKind, u32, QuotaLimit)>,
+    ) -> Self {
+        let nodes: BTreeMap<INum, SerialNode> = nodes
+            .into_iter()
+            .filter(|node| !node.deferred_deletion)
+            .map(|mut node| {
+                node.open_count = 0;
+                node.lookup_count = 0;
+                (node.attr.get_ino(), node)
+            })
+            .collect();
+        let xattrs = xattrs
+            .into_iter()
+            .filter(|&(ino, _)| nodes.contains_key(&ino))
+            .collect();
+        let quota_limits = quota_limits
+            .into_iter()
+            .filter(|&(_, _, limit)| limit != QuotaLimit::default())
+            .collect();
+        Self {
+            version: CHECKPOINT_VERSION,
+            time: SystemTime::now(),
+            nodes,
+            xattrs,
+            quota_limits,
+        }
+    }
+
+    /// Serialize the checkpoint to the content of the checkpoint object
+    pub fn encode(&self) -> DatenLordResult<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Deserialize the checkpoint from the content of the checkpoint object
+    pub fn decode(data: &[u8]) -> DatenLordResult<Self> {
+        let checkpoint: Self = serde_json::from_slice(data)?;
+        if checkpoint.version != CHECKPOINT_VERSION {
+            return build_error_result_from_errno(
+                Errno::EINVAL,
+                format!(
+                    "the checkpoint version={} is not supported, expect {CHECKPOINT_VERSION}",
+                    checkpoint.version,
+                ),
+            );
+        }
+        Ok(checkpoint)
+    }
+
+    /// The max i-number in the checkpoint, including the ones allocated to the
+    /// S3 objects of the reflinked files
+    #[must_use]
+    pub fn max_ino(&self) -> INum {
+        self.nodes
+            .iter()
+            .flat_map(|(&ino, node)| {
+                let shared_objects = node.extents.shared_objects();
+                [ino, node.extents.object(ino)]
+                    .into_iter()
+                    .chain(shared_objects.into_iter().next_back())
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Rebuild the reference counts of the shared S3 objects from the i-nodes
+    #[must_use]
+    pub fn object_refs(&self) -> BTreeMap<INum, u64> {
+        let mut refs: BTreeMap<INum, u64> = BTreeMap::new();
+        for node in self.nodes.values() {
+            for object in node.extents.shared_objects() {
+                let count = refs.entry(object).or_default();
+                *count = count.overflow_add(1);
+            }
+        }
+        refs
+    }
+
+    /// Rebuild the usage of the volume and the quotas with a limit from the
+    /// i-nodes
+    #[must_use]
+    pub fn usage(&self) -> (VolumeStat, BTreeMap<(QuotaKind, u32), QuotaUsage>) {
+        let limited: BTreeSet<(QuotaKind, u32)> = self
+            .quota_limits
+            .iter()
+            .map(|&(kind, id, _)| (kind, id))
+            .collect();
+        let mut stat = VolumeStat::default();
+        let mut quota_usages: BTreeMap<(QuotaKind, u32), QuotaUsage> = BTreeMap::new();
+        for node in self.nodes.values() {
+            let attr = serial::serial_to_file_attr(&node.attr);
+            let used_bytes = volume_stat::used_bytes(&attr);
+            stat.used_bytes = stat.used_bytes.overflow_add(used_bytes);
+            stat.inodes = stat.inodes.overflow_add(1);
+            for key in quota::quota_ids(&attr) {
+                if !limited.contains(&key) {
+                    continue;
+                }
+                let usage = quota_usages.entry(key).or_default();
+                usage.bytes = usage.bytes.overflow_add(used_bytes);
+                usage.inodes = usage.inodes.overflow_add(1);
+            }
+        }
+        (stat, quota_usages)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::MetaCheckpoint;
+    use crate::async_fuse::memfs::fs_util::FileAttr;
+    use crate::async_fuse::memfs::hole::HoleMap;
+    use crate::async_fuse::memfs::quota::{QuotaKind, QuotaLimit, QuotaUsage};
+    use crate::async_fuse::memfs::reflink::ExtentMap;
+    use crate::async_fuse::memfs::serial::{self, SerialNode, SerialNodeData};
+    use crate::async_fuse::memfs::volume_stat::VolumeStat;
+
+    fn create_serial_node(attr: &FileAttr, deferred_deletion: bool) -> SerialNode {
+        SerialNode {
+            parent: 1,
+            name: format!("node_{}", attr.ino),
+            attr: serial::file_attr_to_serial(attr),
+            data: SerialNodeData::File,
+            open_count: 1,
+            lookup_count: 2,
+            deferred_deletion,
+            holes: HoleMap::default(),
+            extents: ExtentMap::default(),
+        }
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_result_states)]
+    fn test_checkpoint_encode_decode() {
+        let file = FileAttr {
+            ino: 5,
+            size: 100,
+            uid: 1000,
+            ..FileAttr::default()
+        };
+        let deleted = FileAttr {
+            ino: 9,
+            ..FileAttr::default()
+        };
+        let checkpoint = MetaCheckpoint::new(
+            vec![
+                create_serial_node(&file, false),
+                create_serial_node(&deleted, true),
+            ],
+            vec![
+                (5, BTreeMap::from([("user.a".to_owned(), b"1".to_vec())])),
+                (9, BTreeMap::from([("user.b".to_owned(), b"2".to_vec())])),
+            ],
+            vec![
+                (
+                    QuotaKind::User,
+                    1000,
+                    QuotaLimit {
+                        bytes: 4096,
+                        inodes: 0,
+                    },
+                ),
+                (QuotaKind::User, 1001, QuotaLimit::default()),
+            ],
+        );
+        let data = checkpoint.encode().unwrap_or_else(|e| panic!("{e}"));
+        let decoded = MetaCheckpoint::decode(&data).unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(decoded.time, checkpoint.time);
+        // The deferred deleted i-node is dropped with its xattrs
+        assert_eq!(decoded.nodes.keys().copied().collect::<Vec<_>>(), vec![5]);
+        assert_eq!(decoded.xattrs.keys().copied().collect::<Vec<_>>(), vec![5]);
+        assert_eq!(decoded.max_ino(), 5);
+        let node = decoded
+            .nodes
+            .get(&5)
+            .unwrap_or_else(|| panic!("failed to find ino=5"));
+        assert_eq!(node.open_count, 0);
+        assert_eq!(node.lookup_count, 0);
+        assert_eq!(decoded.quota_limits.len(), 1);
+        assert!(MetaCheckpoint::decode(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_object_refs() {
+        let src_attr = FileAttr {
+            ino: 2,
+            ..FileAttr::default()
+        };
+        let dst_attr = FileAttr {
+            ino: 3,
+            ..FileAttr::default()
+        };
+        let mut src = create_serial_node(&src_attr, false);
+        let mut dst = create_serial_node(&dst_attr, false);
+        // The data of the source file is frozen in its S3 object and shared
+        // with the destination file
+        src.extents.share(0, 4096, 2, 0);
+        src.extents.set_object(10);
+        dst.extents.share(0, 4096, 2, 0);
+        dst.extents.share(4096, 8192, 7, 0);
+        let checkpoint = MetaCheckpoint::new(vec![src, dst], vec![], vec![]);
+        assert_eq!(checkpoint.object_refs(), BTreeMap::from([(2, 2), (7, 1)]));
+        // The i-numbers allocated to the S3 objects are not reused
+        assert_eq!(checkpoint.max_ino(), 10);
+    }
+
+    #[test]
+    fn test_checkpoint_usage() {
+        let file = FileAttr {
+            ino: 2,
+            size: 100,
+            uid: 1000,
+            gid: 1000,
+            ..FileAttr::default()
+        };
+        let dir = FileAttr {
+            ino: 3,
+            size: 4096,
+            kind: nix::sys::stat::SFlag::S_IFDIR,
+            uid: 1000,
+            project_id: 7,
+            ..FileAttr::default()
+        };
+        let checkpoint = MetaCheckpoint::new(
+            vec![
+                create_serial_node(&file, false),
+                create_serial_node(&dir, false),
+            ],
+            vec![],
+            vec![
+                (
+                    QuotaKind::User,
+                    1000,
+                    QuotaLimit {
+                        bytes: 0,
+                        inodes: 10,
+                    },
+                ),
+                (
+                    QuotaKind::Project,
+                    7,
+                    QuotaLimit {
+                        bytes: 0,
+                        inodes: 10,
+                    },
+                ),
+            ],
+        );
+        let (stat, quota_usages) = checkpoint.usage();
+        // The size of directories is not counted, the data is counted in
+        // whole blocks
+        assert_eq!(
+            stat,
+            VolumeStat {
+                used_bytes: 4096,
+                inodes: 2,
+            }
+        );
+        assert_eq!(
+            quota_usages.get(&(QuotaKind::User, 1000)),
+            Some(&QuotaUsage {
+                bytes: 4096,
+                inodes: 2,
+            })
+        );
+        // The quotas without a limit are not counted
+        assert_eq!(quota_usages.get(&(QuotaKind::Group, 1000)), None);
+        assert_eq!(
+            quota_usages.get(&(QuotaKind::Project, 7)),
+            Some(&QuotaUsage {
+                bytes: 0,
+                inodes: 1,
+            })
+        );
+    }
+}
@@ -70,6 +70,30 @@ pub async fn invalidate(
     .await
 }
 
+/// Invalidate directory entry to remote
+pub async fn invalidate_entry(
+    kv_engine: &Arc<KVEngineType>,
+    node_id: &str,
+    volume_info: &str,
+    parent: INum,
+    name: &str,
+) -> anyhow::Result<()> {
+    debug!("invalidate entry {} under parent {}", name, parent);
+    let do_nothing = |_: &[u8]| -> (bool, anyhow::Result<()>) { (false, Ok(())) };
+
+    let invalid_req = request::invalidate_entry(parent, name.to_owned());
+
+    send_to_others(
+        kv_engine,
+        node_id,
+        volume_info,
+        &invalid_req,
+        do_nothing,
+        Ok(()),
+    )
+    .await
+}
+
 /// Read data from remote
 pub async fn read_data(
     kv_engine: &Arc<KVEngineType>,
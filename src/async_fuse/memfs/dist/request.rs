@@ -15,6 +15,8 @@ pub enum DistRequest {
     CheckAvailable(OpArgs),
     /// Read data request
     Read(OpArgs),
+    /// Invalidate directory entry request
+    InvalidateEntry(EntryArgs),
 }
 
 /// `RemoveDirEntry` request args
@@ -37,6 +39,15 @@ pub struct RemoveArgs {
     pub child_type: SerialSFlag,
 }
 
+/// Directory entry request args
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EntryArgs {
+    /// Parent inode number
+    pub parent: INum,
+    /// Entry name
+    pub name: String,
+}
+
 /// `Index` in a file
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Index {
@@ -79,6 +90,14 @@ pub fn read(file_ino: INum, index: Vec<Index>) -> Vec<u8> {
         .unwrap_or_else(|e| panic!("fail to serialize `Read` distributed cache operation, {e}"))
 }
 
+/// Serialize Invalidate directory entry request
+#[must_use]
+pub fn invalidate_entry(parent: INum, name: String) -> Vec<u8> {
+    bincode::serialize(&DistRequest::InvalidateEntry(EntryArgs { parent, name })).unwrap_or_else(
+        |e| panic!("fail to serialize `InvalidateEntry` distributed cache operation, {e}"),
+    )
+}
+
 /// Deserialize request
 #[must_use]
 pub fn deserialize_cache(bin: &[u8]) -> DistRequest {
@@ -1,14 +1,28 @@
 //! This is the server for the cache, which is used to accept the request
 
 use std::fmt::{self, Debug};
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
 
+#[cfg(feature = "abi-7-12")]
+use clippy_utilities::{Cast, OverflowArithmetic};
+#[cfg(feature = "abi-7-12")]
+use nix::errno::Errno;
 use tokio::net::TcpStream;
+use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
+#[cfg(feature = "abi-7-12")]
+use tracing::warn;
 
 use super::super::cache::GlobalCache;
-use super::request::{self, DistRequest, OpArgs};
+#[cfg(feature = "abi-7-12")]
+use super::request::Index;
+use super::request::{self, DistRequest, EntryArgs, OpArgs};
 use super::{response, tcp};
+#[cfg(feature = "abi-7-12")]
+use crate::async_fuse::fuse::fuse_reply::{FuseInvalEntryNotification, FuseInvalINodeNotification};
+#[cfg(feature = "abi-7-12")]
+use crate::async_fuse::fuse::protocol::INum;
 
 /// Distributed cache server
 pub struct CacheServer {
@@ -37,13 +51,19 @@ impl Drop for CacheServer {
 }
 
 impl CacheServer {
-    /// New a `CacheServer `
-    pub(crate) fn new(ip: String, port: u16, cache: Arc<GlobalCache>) -> Self {
+    /// New a `CacheServer `, the kernel cache is invalidated through `fuse_fd`
+    /// along with the data cache
+    pub(crate) fn new(
+        ip: String,
+        port: u16,
+        cache: Arc<GlobalCache>,
+        fuse_fd: Arc<Mutex<RawFd>>,
+    ) -> Self {
         let ip_copy = ip.clone();
         let port = port.to_string();
         let port_copy = port.clone();
 
-        let listener_join_handler = tokio::spawn(listen(ip_copy, port_copy, cache));
+        let listener_join_handler = tokio::spawn(listen(ip_copy, port_copy, cache, fuse_fd));
         Self {
             ip,
             port,
@@ -53,7 +73,7 @@ impl CacheServer {
 }
 
 /// async listen routine
-async fn listen(ip: String, port: String, cache: Arc<GlobalCache>) {
+async fn listen(ip: String, port: String, cache: Arc<GlobalCache>, fuse_fd: Arc<Mutex<RawFd>>) {
     let listener = tokio::net::TcpListener::bind(format!("{ip}:{port}"))
         .await
         .unwrap_or_else(|e| {
@@ -63,10 +83,11 @@ async fn listen(ip: String, port: String, cache: Arc<GlobalCache>) {
         match listener.accept().await {
             Ok((stream, _)) => {
                 let cache_clone = Arc::<GlobalCache>::clone(&cache);
+                let fuse_fd_clone = Arc::clone(&fuse_fd);
 
                 tokio::spawn(async move {
                     let mut local_stream = stream;
-                    match dispatch(&mut local_stream, cache_clone).await {
+                    match dispatch(&mut local_stream, cache_clone, fuse_fd_clone).await {
                         Ok(_) => {}
                         Err(e) => panic!("process cache request error: {e}"),
                     }
@@ -78,7 +99,11 @@ async fn listen(ip: String, port: String, cache: Arc<GlobalCache>) {
 }
 
 /// Dispatch request
-async fn dispatch(stream: &mut TcpStream, cache: Arc<GlobalCache>) -> anyhow::Result<bool> {
+async fn dispatch(
+    stream: &mut TcpStream,
+    cache: Arc<GlobalCache>,
+    fuse_fd: Arc<Mutex<RawFd>>,
+) -> anyhow::Result<bool> {
     let mut buf = Vec::new();
     if let Err(e) = tcp::read_message(stream, &mut buf).await {
         panic!("fail to read distributed cache request from tcp stream, {e}");
@@ -88,7 +113,7 @@ async fn dispatch(stream: &mut TcpStream, cache: Arc<GlobalCache>) -> anyhow::Re
 
     match request {
         DistRequest::Invalidate(args) => {
-            invalidate(stream, &cache, args).await?;
+            invalidate(stream, &cache, &fuse_fd, args).await?;
             Ok(true)
         }
 
@@ -101,20 +126,89 @@ async fn dispatch(stream: &mut TcpStream, cache: Arc<GlobalCache>) -> anyhow::Re
             read(stream, &cache, args).await?;
             Ok(true)
         }
+
+        DistRequest::InvalidateEntry(args) => {
+            invalidate_entry(stream, &fuse_fd, args).await?;
+            Ok(true)
+        }
     }
 }
 
 /// Handle `Invalidate` request
+#[cfg_attr(not(feature = "abi-7-12"), allow(unused_variables))]
 async fn invalidate(
     stream: &mut TcpStream,
     cache: &Arc<GlobalCache>,
+    fuse_fd: &Mutex<RawFd>,
     args: OpArgs,
 ) -> anyhow::Result<()> {
+    // Drop the stale pages in the kernel page cache as well, so that the
+    // readers on this node see the data written by other nodes
+    #[cfg(feature = "abi-7-12")]
+    notify_inval_inode(fuse_fd, args.file_ino, &args.index, cache.get_align()).await;
     cache.invalidate(args.file_ino, args.index);
     tcp::write_message(stream, response::invalidate().as_slice()).await?;
     Ok(())
 }
 
+/// Handle `InvalidateEntry` request
+#[cfg_attr(not(feature = "abi-7-12"), allow(unused_variables))]
+async fn invalidate_entry(
+    stream: &mut TcpStream,
+    fuse_fd: &Mutex<RawFd>,
+    args: EntryArgs,
+) -> anyhow::Result<()> {
+    // The entry is renamed or removed by other nodes
+    #[cfg(feature = "abi-7-12")]
+    {
+        let fd = *fuse_fd.lock().await;
+        if fd >= 0_i32 {
+            let res = FuseInvalEntryNotification::new(fd)
+                .notify(args.parent, args.name.clone())
+                .await;
+            warn_notify_error(res, &format!("entry {:?} under {}", args.name, args.parent));
+        }
+    }
+    tcp::write_message(stream, response::invalidate().as_slice()).await?;
+    Ok(())
+}
+
+/// Notify the kernel to drop the page cache of the invalidated blocks
+#[cfg(feature = "abi-7-12")]
+async fn notify_inval_inode(fuse_fd: &Mutex<RawFd>, ino: INum, index: &[Index], block_size: usize) {
+    let fd = *fuse_fd.lock().await;
+    // The file system is not mounted yet
+    if fd < 0_i32 {
+        return;
+    }
+    for i in index {
+        let (start, end) = match *i {
+            Index::Point(p) => (p, p),
+            Index::Range(s, e) => (s, e),
+        };
+        let offset = start.overflow_mul(block_size);
+        let len = end
+            .overflow_sub(start)
+            .overflow_add(1)
+            .overflow_mul(block_size);
+        let res = FuseInvalINodeNotification::new(fd)
+            .notify(ino, offset.cast(), len.cast())
+            .await;
+        warn_notify_error(res, &format!("i-node {ino}"));
+    }
+}
+
+/// Log the failure of a notification, `ENOENT` means the kernel has nothing
+/// cached to invalidate
+#[cfg(feature = "abi-7-12")]
+fn warn_notify_error(res: nix::Result<usize>, target: &str) {
+    if let Err(e) = res {
+        if e != Errno::ENOENT {
+            warn!("failed to notify kernel to invalidate {target}, the error is: {e}");
+        }
+    }
+}
+
 /// Handle `CheckAvailable` request
 async fn check_available(
     stream: &mut TcpStream,
@@ -0,0 +1,487 @@
+//! The implementation of cluster-wide POSIX byte-range locks and BSD `flock`
+//! locks. The two kinds of locks never conflict with each other, the same as
+//! Linux.
+//!
+//! The locks of an i-node held by each node are stored in the KV engine under
+//! `KeyType::FileLock(ino, node_id)`, attached to a lease of the node, so the
+//! locks of a dead node are released automatically when its lease expires.
+//! Updates of the locks of an i-node are serialized by a distributed lock, and
+//! the blocking lock requests watch the locks of the i-node to retry once they
+//! are changed.
+//!
+//! If the lease expires, e.g. the node is partitioned from the KV engine for a
+//! while, the locks of the node are released by the KV engine and may be
+//! acquired by others. The requests of the owners which held the lost locks
+//! fail with `EIO` until the file is closed, instead of silently losing them.
+
+use std::collections::HashSet;
+use std::mem;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use clippy_utilities::{Cast, OverflowArithmetic};
+use futures::StreamExt;
+use nix::errno::Errno;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
+
+use super::kv_engine::{kv_utils, KVEngine, KVEngineType};
+use super::FileLockParam;
+use crate::async_fuse::fuse::protocol::INum;
+use crate::async_fuse::util::build_error_result_from_errno;
+use crate::common::error::DatenLordResult;
+
+/// Shared lock, the same as `libc::F_RDLCK`
+pub const F_RDLCK: u32 = 0;
+/// Exclusive lock, the same as `libc::F_WRLCK`
+pub const F_WRLCK: u32 = 1;
+/// Unlock, the same as `libc::F_UNLCK`
+pub const F_UNLCK: u32 = 2;
+
+/// The ttl of the lease the locks of a node attached to
+const FILE_LOCK_LEASE_TTL_SECS: u64 = 10;
+/// The lease ID before the lease of a node is granted
+const NO_LEASE: i64 = 0;
+
+/// The kind of a file lock
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FileLockKind {
+    /// POSIX byte-range lock, owned by a process
+    Posix,
+    /// BSD `flock` lock on the whole file, owned by an open file description
+    Flock,
+}
+
+/// A POSIX byte-range lock or a BSD `flock` lock
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FileLock {
+    /// The kind of the lock
+    pub kind: FileLockKind,
+    /// The node holding the lock
+    pub node_id: String,
+    /// The lock owner on the node
+    pub lock_owner: u64,
+    /// Start offset of the lock range
+    pub start: u64,
+    /// End offset of the lock range, inclusive
+    pub end: u64,
+    /// Lock type, `F_RDLCK` or `F_WRLCK`
+    pub typ: u32,
+    /// The process ID of the lock holder
+    pub pid: u32,
+}
+
+impl FileLock {
+    /// Whether the two locks are of the same kind and held by the same owner
+    fn is_same_owner(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.node_id == other.node_id
+            && self.lock_owner == other.lock_owner
+    }
+
+    /// Whether the ranges of the two locks overlap
+    fn is_overlapped(&self, other: &Self) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    /// Whether the ranges of the two locks overlap or are adjacent
+    fn is_mergeable(&self, other: &Self) -> bool {
+        self.start <= other.end.saturating_add(1) && other.start <= self.end.saturating_add(1)
+    }
+
+    /// Whether the lock conflicts with the other lock
+    fn is_conflicted(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && !self.is_same_owner(other)
+            && self.is_overlapped(other)
+            && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+/// Find the first lock conflicting with `new_lock`
+fn find_conflict<'a>(locks: &'a [FileLock], new_lock: &FileLock) -> Option<&'a FileLock> {
+    locks.iter().find(|lock| lock.is_conflicted(new_lock))
+}
+
+/// Apply `new_lock` to the locks of the same owner, the overlapped ranges are
+/// split and replaced by the new lock, the adjacent ranges of the same type are
+/// merged. Unlocking is done by applying a lock of type `F_UNLCK`.
+fn apply_lock(locks: Vec<FileLock>, new_lock: &FileLock) -> Vec<FileLock> {
+    let mut result = Vec::with_capacity(locks.len().saturating_add(2));
+    let mut merged = new_lock.clone();
+    for lock in locks {
+        if !lock.is_same_owner(new_lock) || !lock.is_mergeable(new_lock) {
+            result.push(lock);
+        } else if lock.typ == new_lock.typ {
+            // Merge the overlapped or adjacent range of the same type
+            merged.start = merged.start.min(lock.start);
+            merged.end = merged.end.max(lock.end);
+        } else if lock.is_overlapped(new_lock) {
+            // Split the overlapped range of a different type
+            if lock.start < new_lock.start {
+                result.push(FileLock {
+                    end: new_lock.start.saturating_sub(1),
+                    ..lock.clone()
+                });
+            }
+            if lock.end > new_lock.end {
+                result.push(FileLock {
+                    start: new_lock.end.saturating_add(1),
+                    ..lock
+                });
+            }
+        } else {
+            result.push(lock);
+        }
+    }
+    if merged.typ != F_UNLCK {
+        result.push(merged);
+    }
+    result.sort_by_key(|lock| (lock.lock_owner, lock.start));
+    result
+}
+
+/// The state of `FileLockManager` handed over to the new process on live
+/// upgrade, so the locks held by the kernel are kept
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileLockSnapshot {
+    /// The lease the locks of current node attached to, `0` if not granted
+    pub lease_id: i64,
+    /// The `(ino, lock_owner, kind)` tuples which may hold locks
+    pub lock_holders: Vec<(INum, u64, FileLockKind)>,
+    /// The `(ino, lock_owner, kind)` tuples whose locks may be lost
+    pub lost_holders: Vec<(INum, u64, FileLockKind)>,
+}
+
+/// The cluster-wide POSIX lock manager of a node
+#[derive(Debug)]
+pub struct FileLockManager {
+    /// KV engine
+    kv_engine: Arc<KVEngineType>,
+    /// Current node id
+    node_id: Arc<str>,
+    /// The lease the locks of current node attached to, which is granted on
+    /// the first lock request, or adopted from the previous process on live
+    /// upgrade
+    lease_id: AtomicI64,
+    /// Serialize the granting of the lease
+    granting: tokio::sync::Mutex<()>,
+    /// The `(ino, lock_owner, kind)` tuples which may hold locks on current
+    /// node, to avoid accessing the KV engine when releasing locks on every
+    /// flush
+    lock_holders: Mutex<HashSet<(INum, u64, FileLockKind)>>,
+    /// The `(ino, lock_owner, kind)` tuples whose locks may be lost as the
+    /// lease expired, their lock requests fail with `EIO` until the file is
+    /// closed
+    lost_holders: Mutex<HashSet<(INum, u64, FileLockKind)>>,
+}
+
+impl FileLockManager {
+    /// Create `FileLockManager`, and keep the lease of current node alive in
+    /// background
+    pub fn new(kv_engine: Arc<KVEngineType>, node_id: Arc<str>) -> Arc<Self> {
+        let manager = Arc::new(Self {
+            kv_engine,
+            node_id,
+            lease_id: AtomicI64::new(NO_LEASE),
+            granting: tokio::sync::Mutex::new(()),
+            lock_holders: Mutex::new(HashSet::new()),
+            lost_holders: Mutex::new(HashSet::new()),
+        });
+        tokio::spawn(Self::keep_lease_alive(Arc::downgrade(&manager)));
+        manager
+    }
+
+    /// Take a snapshot of the lease and the lock holders, for live upgrade
+    pub fn snapshot(&self) -> FileLockSnapshot {
+        FileLockSnapshot {
+            lease_id: self.lease_id.load(Ordering::Acquire),
+            lock_holders: self.lock_holders.lock().iter().copied().collect(),
+            lost_holders: self.lost_holders.lock().iter().copied().collect(),
+        }
+    }
+
+    /// Adopt the lease and the lock holders handed over by the previous
+    /// process, which should be done before any lock request
+    pub fn restore(&self, snapshot: FileLockSnapshot) {
+        self.lease_id.store(snapshot.lease_id, Ordering::Release);
+        *self.lock_holders.lock() = snapshot.lock_holders.into_iter().collect();
+        *self.lost_holders.lock() = snapshot.lost_holders.into_iter().collect();
+    }
+
+    /// Get the lease the locks of current node attached to, the lease is
+    /// granted if there's none
+    async fn lease(&self) -> DatenLordResult<i64> {
+        let lease_id = self.lease_id.load(Ordering::Acquire);
+        if lease_id != NO_LEASE {
+            return Ok(lease_id);
+        }
+        let _granting = self.granting.lock().await;
+        let lease_id = self.lease_id.load(Ordering::Acquire);
+        if lease_id != NO_LEASE {
+            return Ok(lease_id);
+        }
+        let lease_id = self
+            .kv_engine
+            .lease_grant(FILE_LOCK_LEASE_TTL_SECS.cast())
+            .await?;
+        self.lease_id.store(lease_id, Ordering::Release);
+        Ok(lease_id)
+    }
+
+    /// Refresh the lease of current node periodically until the manager is
+    /// dropped. If the lease has expired, the locks of current node have been
+    /// released by the KV engine, so drop the lease for a new one to be
+    /// granted on the next lock request, and mark the holders of the locks as
+    /// lost.
+    async fn keep_lease_alive(manager: Weak<Self>) {
+        let interval = Duration::from_secs(FILE_LOCK_LEASE_TTL_SECS.overflow_div(3));
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(manager) = manager.upgrade() else {
+                break;
+            };
+            let lease_id = manager.lease_id.load(Ordering::Acquire);
+            if lease_id == NO_LEASE {
+                continue;
+            }
+            if let Err(e) = manager.kv_engine.lease_keep_alive(lease_id).await {
+                warn!(
+                    "failed to keep alive the file lock lease={}, the error is: {}",
+                    lease_id, e,
+                );
+                // Only drop the lease failed to keep alive
+                if manager
+                    .lease_id
+                    .compare_exchange(lease_id, NO_LEASE, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    continue;
+                }
+                let lost = mem::take(&mut *manager.lock_holders.lock());
+                for &(ino, lock_owner, kind) in &lost {
+                    error!(
+                        "the {:?} locks of lock_owner={} on ino={} may be lost \
+                            as the file lock lease={} expired",
+                        kind, lock_owner, ino, lease_id,
+                    );
+                }
+                manager.lost_holders.lock().extend(lost);
+            }
+        }
+    }
+
+    /// Build the lock of current node from the request
+    fn build_lock(&self, lk_param: &FileLockParam, kind: FileLockKind) -> FileLock {
+        FileLock {
+            kind,
+            node_id: self.node_id.to_string(),
+            lock_owner: lk_param.lock_owner,
+            start: lk_param.start,
+            end: lk_param.end,
+            typ: lk_param.typ,
+            pid: lk_param.pid,
+        }
+    }
+
+    /// Test for a POSIX lock, return the first conflicting lock if any
+    pub async fn getlk(
+        &self,
+        ino: INum,
+        lk_param: &FileLockParam,
+    ) -> DatenLordResult<Option<FileLock>> {
+        let new_lock = self.build_lock(lk_param, FileLockKind::Posix);
+        let locks = kv_utils::get_file_locks(&self.kv_engine, ino).await?;
+        Ok(find_conflict(&locks, &new_lock).map(|lock| {
+            let mut lock = lock.clone();
+            if lock.node_id != new_lock.node_id {
+                // The pid on other nodes is meaningless to the kernel
+                lock.pid = 0;
+            }
+            lock
+        }))
+    }
+
+    /// Try to acquire, modify or release a lock once, return whether the
+    /// request succeeded
+    async fn try_setlk(&self, ino: INum, new_lock: &FileLock) -> DatenLordResult<bool> {
+        let kv_engine = Arc::clone(&self.kv_engine);
+        let node_id = Arc::clone(&self.node_id);
+        let lease_id = self.lease().await?;
+        let new_lock = new_lock.clone();
+        // Update the locks in a separate task, so the KV lock of the lock table
+        // is always released even if the request is interrupted
+        tokio::spawn(async move {
+            kv_utils::modify_file_locks(&kv_engine, ino, &node_id, lease_id, |locks| {
+                if new_lock.typ != F_UNLCK {
+                    if let Some(lock) = find_conflict(&locks, &new_lock) {
+                        debug!("setlk() found conflicting lock={:?} of ino={}", lock, ino);
+                        return Ok((None, false));
+                    }
+                }
+                let node_locks = locks
+                    .into_iter()
+                    .filter(|lock| *lock.node_id == *node_id)
+                    .collect();
+                Ok((Some(apply_lock(node_locks, &new_lock)), true))
+            })
+            .await
+        })
+        .await
+        .unwrap_or_else(|e| panic!("failed to join the task to update file locks, error: {e}"))
+    }
+
+    /// Acquire, modify or release a lock of `kind`. If `sleep` is true, wait
+    /// until the conflicting locks are released. The waiting is cancelled by
+    /// dropping the future when the request is interrupted.
+    pub async fn setlk(
+        &self,
+        ino: INum,
+        lk_param: &FileLockParam,
+        kind: FileLockKind,
+        sleep: bool,
+    ) -> DatenLordResult<()> {
+        let new_lock = self.build_lock(lk_param, kind);
+        let holder = (ino, new_lock.lock_owner, new_lock.kind);
+        if self.lost_holders.lock().contains(&holder) {
+            return build_error_result_from_errno(
+                Errno::EIO,
+                format!(
+                    "setlk() failed as the locks of lock_owner={} on ino={ino} may be lost",
+                    new_lock.lock_owner,
+                ),
+            );
+        }
+        if new_lock.typ != F_UNLCK {
+            // Record the owner before locking, in case the request is
+            // interrupted after the lock is acquired
+            self.lock_holders.lock().insert(holder);
+        }
+        // Watch the locks before trying, so the conflicting locks released
+        // after the try are never missed
+        let mut changes = if sleep {
+            Some(kv_utils::watch_file_locks(&self.kv_engine, ino).await?)
+        } else {
+            None
+        };
+        while !self.try_setlk(ino, &new_lock).await? {
+            let Some(changes) = changes.as_mut() else {
+                return build_error_result_from_errno(
+                    Errno::EAGAIN,
+                    format!("setlk() failed to lock ino={ino} due to conflicting locks"),
+                );
+            };
+            if changes.next().await.transpose()?.is_none() {
+                return build_error_result_from_errno(
+                    Errno::EIO,
+                    format!("setlk() failed to wait for the locks of ino={ino} to change"),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Release all the locks of `kind` held by `lock_owner` on the i-node,
+    /// which is called when the file is closed
+    pub async fn release_owner_locks(
+        &self,
+        ino: INum,
+        lock_owner: u64,
+        kind: FileLockKind,
+    ) -> DatenLordResult<()> {
+        // The lost locks have been released by the KV engine
+        if self.lost_holders.lock().remove(&(ino, lock_owner, kind))
+            || !self.lock_holders.lock().remove(&(ino, lock_owner, kind))
+        {
+            return Ok(());
+        }
+        let unlock = FileLock {
+            kind,
+            node_id: self.node_id.to_string(),
+            lock_owner,
+            start: 0,
+            end: u64::MAX,
+            typ: F_UNLCK,
+            pid: 0,
+        };
+        self.try_setlk(ino, &unlock).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build a lock of `lock_owner` on node "node"
+    fn build_lock(lock_owner: u64, start: u64, end: u64, typ: u32) -> FileLock {
+        FileLock {
+            kind: FileLockKind::Posix,
+            node_id: "node".to_owned(),
+            lock_owner,
+            start,
+            end,
+            typ,
+            pid: 0,
+        }
+    }
+
+    #[test]
+    fn test_apply_lock() {
+        // Merge the adjacent locks of the same type
+        let locks = apply_lock(
+            vec![build_lock(1, 0, 9, F_WRLCK)],
+            &build_lock(1, 10, 19, F_WRLCK),
+        );
+        assert_eq!(locks, vec![build_lock(1, 0, 19, F_WRLCK)]);
+
+        // Split the lock of a different type
+        let locks = apply_lock(locks, &build_lock(1, 5, 14, F_RDLCK));
+        assert_eq!(
+            locks,
+            vec![
+                build_lock(1, 0, 4, F_WRLCK),
+                build_lock(1, 5, 14, F_RDLCK),
+                build_lock(1, 15, 19, F_WRLCK),
+            ]
+        );
+
+        // Unlock a range in the middle
+        let locks = apply_lock(locks, &build_lock(1, 3, 16, F_UNLCK));
+        assert_eq!(
+            locks,
+            vec![build_lock(1, 0, 2, F_WRLCK), build_lock(1, 17, 19, F_WRLCK)]
+        );
+
+        // The locks of other owners are kept
+        let locks = apply_lock(locks, &build_lock(2, 0, u64::MAX, F_UNLCK));
+        assert_eq!(
+            locks,
+            vec![build_lock(1, 0, 2, F_WRLCK), build_lock(1, 17, 19, F_WRLCK)]
+        );
+    }
+
+    #[test]
+    fn test_find_conflict() {
+        let locks = vec![build_lock(1, 0, 9, F_RDLCK), build_lock(2, 20, 29, F_WRLCK)];
+        // Shared locks are compatible
+        assert!(find_conflict(&locks, &build_lock(3, 5, 15, F_RDLCK)).is_none());
+        // Exclusive lock conflicts with shared lock
+        assert_eq!(
+            find_conflict(&locks, &build_lock(3, 5, 15, F_WRLCK)),
+            Some(&build_lock(1, 0, 9, F_RDLCK))
+        );
+        // The locks of the same owner never conflict
+        assert!(find_conflict(&locks, &build_lock(2, 25, 35, F_WRLCK)).is_none());
+        // The same owner on another node conflicts
+        let mut other_node_lock = build_lock(2, 25, 35, F_RDLCK);
+        other_node_lock.node_id = "other".to_owned();
+        assert!(find_conflict(&locks, &other_node_lock).is_some());
+        // POSIX locks and flock locks never conflict
+        let mut flock = build_lock(3, 0, u64::MAX, F_WRLCK);
+        flock.kind = FileLockKind::Flock;
+        assert!(find_conflict(&locks, &flock).is_none());
+    }
+}
@@ -8,6 +8,7 @@ use nix::fcntl::OFlag;
 use nix::sys::stat::{Mode, SFlag};
 use tracing::debug;
 
+use super::acl::PosixAcl;
 use crate::async_fuse::fuse::protocol::{FuseAttr, INum};
 use crate::async_fuse::util::build_error_result_from_errno;
 use crate::common::error::DatenLordResult;
@@ -40,6 +41,8 @@ pub struct FileAttr {
     pub gid: u32,
     /// Rdev
     pub rdev: u32,
+    /// Project id for quota, inherited from the parent directory
+    pub project_id: u32,
 }
 
 /// Whether to check permission.
@@ -65,6 +68,7 @@ impl FileAttr {
             uid: 0,
             gid: 0,
             rdev: 0,
+            project_id: 0,
         }
     }
 
@@ -94,6 +98,32 @@ impl FileAttr {
         }
     }
 
+    /// Check permission like `check_perm()`, but evaluate the POSIX access ACL
+    /// of the file instead of the permission bits if it has one.
+    pub fn check_perm_with_acl(
+        &self,
+        user_id: u32,
+        group_id: u32,
+        access_mode: u8,
+        acl: Option<&PosixAcl>,
+    ) -> DatenLordResult<()> {
+        match acl {
+            Some(acl) if NEED_CHECK_PERM => {
+                if user_id == 0 || acl.check_perm(self, user_id, group_id, access_mode) {
+                    Ok(())
+                } else {
+                    build_error_result_from_errno(
+                        Errno::EACCES,
+                        format!(
+                            "check_perm_with_acl() failed {user_id} {group_id} {access_mode} {acl:?}"
+                        ),
+                    )
+                }
+            }
+            _ => self.check_perm(user_id, group_id, access_mode),
+        }
+    }
+
     /// If `NEED_CHECK_PERM` is true, then check permission by ourselves not
     /// rely on kernel.
     #[inline]
@@ -107,7 +137,7 @@ impl FileAttr {
             access_mode <= 0o7 && access_mode != 0,
             "check_perm() found access_mode={access_mode} invalid",
         );
-        if uid== 0 {
+        if user_id == 0 {
             return Ok(());
         }
 
@@ -130,9 +160,9 @@ impl FileAttr {
     #[allow(clippy::arithmetic_side_effects)]
     fn get_access_mode(&self, user_id: u32, group_id: u32) -> u8 {
         let perm = self.perm;
-        let mode = if uid== self.uid {
+        let mode = if user_id == self.uid {
             (perm >> 6) & 0o7
-        } else if gid== self.gid {
+        } else if group_id == self.gid {
             (perm >> 3) & 0o7
         } else {
             perm & 0o7
@@ -156,6 +186,7 @@ impl Default for FileAttr {
             uid: 0,
             gid: 0,
             rdev: 0,
+            project_id: 0,
         }
     }
 }
@@ -258,6 +289,7 @@ mod tests {
             uid: 1000,
             gid: 1000,
             rdev: 0,
+            project_id: 0,
         };
 
         // Owner permission checks
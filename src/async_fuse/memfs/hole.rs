@@ -0,0 +1,311 @@
+//! The hole map of sparse regular files.
+//!
+//! The ranges punched by `fallocate(FALLOC_FL_PUNCH_HOLE)`, as well as the
+//! ranges never written when a file is extended, are neither cached nor
+//! uploaded to S3, the S3 object of a file only stores the data out of its
+//! holes. The hole map records the holes of a file as well as the holes when
+//! the file was last uploaded, so that a file offset can be mapped to the
+//! offset in the S3 object.
+
+use clippy_utilities::OverflowArithmetic;
+use serde::{Deserialize, Serialize};
+
+/// The hole map of a regular file, all the ranges are `[start, end)`, sorted
+/// and not overlapped
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct HoleMap {
+    /// The holes of the file
+    holes: Vec<(u64, u64)>,
+    /// The holes of the file when it was last uploaded to S3
+    object_holes: Vec<(u64, u64)>,
+    /// The size of the file when it was last uploaded to S3, `None` means the
+    /// S3 object is not sparse
+    object_size: Option<u64>,
+}
+
+impl HoleMap {
+    /// Punch a hole of `[start, end)`
+    pub fn punch(&mut self, start: u64, end: u64) {
+        insert_range(&mut self.holes, start, end);
+    }
+
+    /// Fill the holes in `[start, end)`, they become data
+    pub fn fill(&mut self, start: u64, end: u64) {
+        remove_range(&mut self.holes, start, end);
+    }
+
+    /// Drop the holes beyond `size`
+    pub fn truncate(&mut self, size: u64) {
+        remove_range(&mut self.holes, size, u64::MAX);
+    }
+
+    /// Split `[start, end)` into segments of `(start, end, is_hole)`
+    pub fn segments(&self, start: u64, end: u64) -> Vec<(u64, u64, bool)> {
+        split_range(&self.holes, start, end)
+    }
+
+    /// Get the data segments of `[start, end)`, skipping the holes
+    pub fn data_segments(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        self.segments(start, end)
+            .into_iter()
+            .filter(|&(_, _, is_hole)| !is_hole)
+            .map(|(s, e, _)| (s, e))
+            .collect()
+    }
+
+    /// Find the first data offset not less than `offset` in a file of `size`
+    pub fn seek_data(&self, offset: u64, size: u64) -> Option<u64> {
+        self.data_segments(offset, size).first().map(|&(s, _)| s)
+    }
+
+    /// Find the first hole offset not less than `offset` in a file of `size`,
+    /// there's an implicit hole at EOF
+    pub fn seek_hole(&self, offset: u64, size: u64) -> u64 {
+        self.segments(offset, size)
+            .into_iter()
+            .find(|&(_, _, is_hole)| is_hole)
+            .map_or(size, |(s, _, _)| s)
+    }
+
+    /// Record the size of the S3 object if it is not sparse yet, which has to
+    /// be done before the holes are changed
+    pub fn init_object_size(&mut self, size: u64) {
+        if self.object_size.is_none() {
+            self.object_size = Some(size);
+        }
+    }
+
+    /// Record the layout of the S3 object after the data of `[0, size)`, out
+    /// of the holes and the `excluded` ranges, is uploaded
+    pub fn uploaded(&mut self, size: u64, excluded: &[(u64, u64)]) {
+        self.object_holes = self.holes.clone();
+        for &(start, end) in excluded {
+            insert_range(&mut self.object_holes, start, end);
+        }
+        remove_range(&mut self.object_holes, size, u64::MAX);
+        self.object_size = Some(size);
+    }
+
+    /// Map `[start, end)` to the S3 object, return segments of
+    /// `(start, end, object_offset)`, the segments not stored in the S3 object
+    /// have no object offset and should be read as zeros
+    pub fn object_segments(&self, start: u64, end: u64) -> Vec<(u64, u64, Option<u64>)> {
+        let Some(object_size) = self.object_size else {
+            return vec![(start, end, Some(start))];
+        };
+        let mut segments = Vec::new();
+        let data_end = end.min(object_size);
+        if start < data_end {
+            // The total length of the object holes before the current segment
+            let mut hole_len: u64 = self
+                .object_holes
+                .iter()
+                .filter(|&&(s, _)| s < start)
+                .map(|&(s, e)| e.min(start).overflow_sub(s))
+                .sum();
+            for (s, e, is_hole) in split_range(&self.object_holes, start, data_end) {
+                if is_hole {
+                    segments.push((s, e, None));
+                    hole_len = hole_len.overflow_add(e.overflow_sub(s));
+                } else {
+                    segments.push((s, e, Some(s.overflow_sub(hole_len))));
+                }
+            }
+        }
+        let zero_start = start.max(object_size);
+        if zero_start < end {
+            segments.push((zero_start, end, None));
+        }
+        segments
+    }
+}
+
+/// Add `[start, end)` to `ranges`, merging the overlapped and adjacent ones
+fn insert_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    if start >= end {
+        return;
+    }
+    let mut new_start = start;
+    let mut new_end = end;
+    ranges.retain(|&(s, e)| {
+        if e < start || s > end {
+            true
+        } else {
+            new_start = new_start.min(s);
+            new_end = new_end.max(e);
+            false
+        }
+    });
+    let pos = ranges.partition_point(|&(s, _)| s < new_start);
+    ranges.insert(pos, (new_start, new_end));
+}
+
+/// Remove `[start, end)` from `ranges`, splitting the partially overlapped ones
+fn remove_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    if start >= end {
+        return;
+    }
+    let mut result = Vec::with_capacity(ranges.len().overflow_add(1));
+    for &(s, e) in ranges.iter() {
+        if e <= start || s >= end {
+            result.push((s, e));
+            continue;
+        }
+        if s < start {
+            result.push((s, start));
+        }
+        if e > end {
+            result.push((end, e));
+        }
+    }
+    *ranges = result;
+}
+
+/// Split `[start, end)` into segments of `(start, end, in_ranges)`
+fn split_range(ranges: &[(u64, u64)], start: u64, end: u64) -> Vec<(u64, u64, bool)> {
+    let mut segments = Vec::new();
+    let mut cur = start;
+    for &(s, e) in ranges {
+        if e <= cur {
+            continue;
+        }
+        if s >= end {
+            break;
+        }
+        if s > cur {
+            segments.push((cur, s, false));
+        }
+        let seg_end = e.min(end);
+        segments.push((s.max(cur), seg_end, true));
+        cur = seg_end;
+    }
+    if cur < end {
+        segments.push((cur, end, false));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove_range() {
+        let mut ranges = Vec::new();
+        insert_range(&mut ranges, 10, 20);
+        insert_range(&mut ranges, 30, 40);
+        insert_range(&mut ranges, 0, 5);
+        assert_eq!(ranges, vec![(0, 5), (10, 20), (30, 40)]);
+        // Merge the adjacent and overlapped ones
+        insert_range(&mut ranges, 20, 35);
+        assert_eq!(ranges, vec![(0, 5), (10, 40)]);
+        insert_range(&mut ranges, 3, 12);
+        assert_eq!(ranges, vec![(0, 40)]);
+
+        remove_range(&mut ranges, 10, 20);
+        assert_eq!(ranges, vec![(0, 10), (20, 40)]);
+        remove_range(&mut ranges, 5, 25);
+        assert_eq!(ranges, vec![(0, 5), (25, 40)]);
+        remove_range(&mut ranges, 0, 100);
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn test_split_range() {
+        let ranges = vec![(10, 20), (30, 40)];
+        assert_eq!(
+            split_range(&ranges, 0, 50),
+            vec![
+                (0, 10, false),
+                (10, 20, true),
+                (20, 30, false),
+                (30, 40, true),
+                (40, 50, false),
+            ]
+        );
+        assert_eq!(
+            split_range(&ranges, 15, 35),
+            vec![(15, 20, true), (20, 30, false), (30, 35, true)]
+        );
+        assert_eq!(split_range(&ranges, 22, 28), vec![(22, 28, false)]);
+        assert!(split_range(&ranges, 5, 5).is_empty());
+    }
+
+    #[test]
+    fn test_seek_data_hole() {
+        let mut holes = HoleMap::default();
+        assert_eq!(holes.seek_data(10, 100), Some(10));
+        assert_eq!(holes.seek_hole(10, 100), 100);
+
+        holes.punch(0, 20);
+        holes.punch(40, 60);
+        // The holes beyond EOF are ignored
+        holes.punch(90, 120);
+        assert_eq!(holes.seek_data(0, 100), Some(20));
+        assert_eq!(holes.seek_data(30, 100), Some(30));
+        assert_eq!(holes.seek_data(45, 100), Some(60));
+        assert_eq!(holes.seek_data(95, 100), None);
+        assert_eq!(holes.seek_hole(0, 100), 0);
+        assert_eq!(holes.seek_hole(25, 100), 40);
+        assert_eq!(holes.seek_hole(65, 100), 90);
+        assert_eq!(holes.seek_hole(65, 80), 80);
+    }
+
+    #[test]
+    fn test_object_segments() {
+        let mut holes = HoleMap::default();
+        // The S3 object is not sparse
+        assert_eq!(holes.object_segments(10, 20), vec![(10, 20, Some(10))]);
+
+        holes.init_object_size(100);
+        holes.punch(20, 40);
+        holes.punch(60, 70);
+        // The S3 object is not uploaded since the holes are punched
+        assert_eq!(holes.object_segments(0, 100), vec![(0, 100, Some(0))]);
+        assert_eq!(
+            holes.object_segments(90, 120),
+            vec![(90, 100, Some(90)), (100, 120, None)]
+        );
+
+        holes.uploaded(100, &[]);
+        assert_eq!(
+            holes.object_segments(0, 100),
+            vec![
+                (0, 20, Some(0)),
+                (20, 40, None),
+                (40, 60, Some(20)),
+                (60, 70, None),
+                (70, 100, Some(40)),
+            ]
+        );
+        assert_eq!(
+            holes.object_segments(50, 65),
+            vec![(50, 60, Some(30)), (60, 65, None)]
+        );
+
+        // The filled holes are not in the S3 object until the next upload
+        holes.fill(20, 40);
+        assert_eq!(holes.data_segments(0, 100), vec![(0, 60), (70, 100)]);
+        assert_eq!(
+            holes.object_segments(30, 50),
+            vec![(30, 40, None), (40, 50, Some(20))]
+        );
+        holes.truncate(65);
+        assert_eq!(
+            holes.segments(50, 80),
+            vec![(50, 60, false), (60, 65, true), (65, 80, false)]
+        );
+
+        // The excluded ranges are not in the S3 object either
+        holes.uploaded(65, &[(0, 10)]);
+        assert_eq!(
+            holes.object_segments(0, 70),
+            vec![
+                (0, 10, None),
+                (10, 60, Some(0)),
+                (60, 65, None),
+                (65, 70, None),
+            ]
+        );
+    }
+}
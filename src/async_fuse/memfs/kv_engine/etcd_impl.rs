@@ -1,23 +1,77 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
 use etcd_client::{
     Compare, CompareOp, DeleteOptions, GetOptions, LockOptions, PutOptions, Txn, TxnOp,
+    WatchOptions,
 };
+use futures::stream::{self, BoxStream, StreamExt};
+use parking_lot::Mutex;
 
 use super::{
     check_ttl, conv_u64_sec_2_i64, fmt, DeleteOption, KVEngine, KeyRange, KeyType, KvVersion,
     LockKeyType, MetaTxn, SetOption, ValueType,
 };
-use crate::common::error::{Context, DatenLordResult};
+use crate::common::async_fuse_error::KVEngineError;
+use crate::common::error::{Context, DatenLordError, DatenLordResult};
 
 #[derive(Clone)]
 /// Wrap the etcd client to support the `KVEngine` trait.
 pub struct EtcdKVEngine {
     /// The etcd client.
     client: etcd_client::Client,
+    /// The view of a snapshot the engine is pinned at, `None` for the latest
+    /// revision.
+    snapshot: Option<Arc<SnapshotView>>,
+}
+
+/// The view of a snapshot of the etcd data: the reads are pinned at the
+/// revision of the snapshot, and the writes are buffered in memory on top of
+/// it, which are never written to etcd.
+#[derive(Debug)]
+struct SnapshotView {
+    /// The revision the reads are pinned at.
+    revision: i64,
+    /// The buffered writes, the key in bytes -> the value in bytes, `None`
+    /// means deleted.
+    overlay: Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl SnapshotView {
+    /// Get the buffered write of the key, `None` if the key is not written.
+    fn buffered(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.overlay.lock().get(key).cloned()
+    }
+
+    /// Buffer the write of the key, `None` means delete.
+    fn write(&self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        self.overlay.lock().insert(key, value);
+    }
+
+    /// Get the value of the key in bytes at the revision of the snapshot with
+    /// the buffered writes applied, and its version at the revision.
+    async fn get(
+        &self,
+        client: &etcd_client::Client,
+        key: Vec<u8>,
+    ) -> DatenLordResult<(Option<Vec<u8>>, KvVersion)> {
+        let resp = client
+            .kv_client()
+            .get(
+                key.clone(),
+                Some(GetOptions::new().with_revision(self.revision)),
+            )
+            .await
+            .with_context(|| format!("failed to get at revision={}", self.revision))?;
+        let (value, version) = resp
+            .kvs()
+            .first()
+            .map_or((None, 0), |kv| (Some(kv.value().to_vec()), kv.version()));
+        Ok((self.buffered(&key).unwrap_or(value), version))
+    }
 }
 
 impl Debug for EtcdKVEngine {
@@ -36,7 +90,31 @@ impl EtcdKVEngine {
             .with_context(|| {
                 format!("failed to connect to etcd, the etcd address={etcd_address_vec:?}")
             })?;
-        Ok(EtcdKVEngine { client })
+        Ok(EtcdKVEngine {
+            client,
+            snapshot: None,
+        })
+    }
+
+    /// Create a view of the etcd data at `revision` for a read-only snapshot,
+    /// the writes through the view are only kept in its memory.
+    #[must_use]
+    pub fn pin_at_revision(&self, revision: i64) -> Self {
+        Self {
+            client: self.client.clone(),
+            snapshot: Some(Arc::new(SnapshotView {
+                revision,
+                overlay: Mutex::new(BTreeMap::new()),
+            })),
+        }
+    }
+
+    /// Decode the value in bytes to `ValueType`.
+    fn decode_value(value: &[u8]) -> DatenLordResult<ValueType> {
+        serde_json::from_slice::<ValueType>(value).with_context(|| {
+            "failed to deserialize value from bytes, KVEngine's value supposed to be `ValueType`"
+                .to_owned()
+        })
     }
 }
 
@@ -44,11 +122,14 @@ impl EtcdKVEngine {
 impl KVEngine for EtcdKVEngine {
     async fn new(end_points: Vec<String>) -> DatenLordResult<Self> {
         let client = etcd_client::Client::connect(end_points, None).await?;
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            snapshot: None,
+        })
     }
 
     async fn new_meta_txn(&self) -> Box<dyn MetaTxn + Send> {
-        Box::new(EtcdTxn::new(self.client.clone()))
+        Box::new(EtcdTxn::new(self.client.clone(), self.snapshot.clone()))
     }
 
     async fn lease_grant(&self, ttl: i64) -> DatenLordResult<i64> {
@@ -62,31 +143,151 @@ impl KVEngine for EtcdKVEngine {
             .id())
     }
 
+    async fn lease_keep_alive(&self, lease_id: i64) -> DatenLordResult<()> {
+        let mut client = self.client.clone();
+        let (mut keeper, mut stream) = client
+            .lease_keep_alive(lease_id)
+            .await
+            .with_context(|| format!("failed to keep alive lease={lease_id}"))?;
+        keeper
+            .keep_alive()
+            .await
+            .with_context(|| format!("failed to send keep alive request of lease={lease_id}"))?;
+        let resp = stream
+            .message()
+            .await
+            .with_context(|| format!("failed to get keep alive response of lease={lease_id}"))?;
+        match resp {
+            // The ttl is 0 if the lease has expired
+            Some(resp) if resp.ttl() > 0 => Ok(()),
+            _ => Err(DatenLordError::KVEngineErr {
+                source: KVEngineError::LeaseExpired(lease_id),
+                context: vec![format!("failed to keep alive lease={lease_id}")],
+            }),
+        }
+    }
+
     async fn range(&self, key_range: KeyRange) -> DatenLordResult<Vec<(Vec<u8>, Vec<u8>)>> {
         // check that with_all_keys and with_prefix are not set at the same time
         debug_assert!(
             !(key_range.with_all_keys && key_range.with_prefix),
             "with_all_keys and with_prefix are not set at the same time"
         );
+        // Read at the revision of the snapshot with the buffered writes,
+        // unless a past revision is specified
+        let snapshot = self.snapshot.as_ref().filter(|_| key_range.revision == 0);
+        // The buffered writes cannot be paged
+        debug_assert!(
+            snapshot.is_none() || key_range.limit == 0,
+            "a limited range should be read at a past revision"
+        );
+        let revision = snapshot.map_or(key_range.revision, |view| view.revision);
         let option = if key_range.with_all_keys {
-            Some(GetOptions::new().with_all_keys())
+            GetOptions::new().with_all_keys()
         } else if key_range.with_prefix {
-            Some(GetOptions::new().with_prefix())
+            GetOptions::new().with_prefix()
         } else {
-            Some(GetOptions::new().with_range(key_range.range_end))
+            GetOptions::new().with_range(key_range.range_end.clone())
         };
         let resp = self
             .client
             .kv_client()
-            .get(key_range.key, option)
+            .get(
+                key_range.key.clone(),
+                Some(option.with_revision(revision).with_limit(key_range.limit)),
+            )
             .await
             .with_context(|| "failed to get range at `KVEngine::range`".to_owned())?;
         let kvs = resp.kvs();
-        let result: Vec<_> = kvs
+        let Some(view) = snapshot else {
+            return Ok(kvs
+                .iter()
+                .map(|kv| (kv.key().to_vec(), kv.value().to_vec()))
+                .collect());
+        };
+        let mut result: BTreeMap<Vec<u8>, Vec<u8>> = kvs
             .iter()
             .map(|kv| (kv.key().to_vec(), kv.value().to_vec()))
             .collect();
-        Ok(result)
+        for (key, value) in view.overlay.lock().iter() {
+            if !key_range.contains(key) {
+                continue;
+            }
+            match *value {
+                Some(ref value) => result.insert(key.clone(), value.clone()),
+                None => result.remove(key),
+            };
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    async fn revision(&self) -> DatenLordResult<i64> {
+        if let Some(ref view) = self.snapshot {
+            return Ok(view.revision);
+        }
+        let resp = self
+            .client
+            .kv_client()
+            .get(vec![0], Some(GetOptions::new().with_count_only()))
+            .await
+            .with_context(|| "failed to get the revision at `KVEngine::revision`".to_owned())?;
+        resp.header()
+            .map(etcd_client::ResponseHeader::revision)
+            .ok_or_else(|| DatenLordError::KVEngineErr {
+                source: KVEngineError::NoResponseHeader,
+                context: vec!["failed to get the revision at `KVEngine::revision`".to_owned()],
+            })
+    }
+
+    async fn compact(&self, revision: i64) -> DatenLordResult<()> {
+        // A snapshot never writes to etcd
+        if self.snapshot.is_some() {
+            return Ok(());
+        }
+        let mut client = self.client.clone();
+        client
+            .compact(revision, None)
+            .await
+            .with_context(|| format!("failed to compact etcd engine to revision={revision}"))?;
+        Ok(())
+    }
+
+    async fn watch_prefix(
+        &self,
+        prefix: Vec<u8>,
+    ) -> DatenLordResult<BoxStream<'static, DatenLordResult<()>>> {
+        // A snapshot is never changed by others
+        if self.snapshot.is_some() {
+            return Ok(stream::pending().boxed());
+        }
+        let mut client = self.client.clone();
+        let (watcher, watch_stream) = client
+            .watch(prefix, Some(WatchOptions::new().with_prefix()))
+            .await
+            .with_context(|| "failed to watch at `KVEngine::watch_prefix`".to_owned())?;
+        // The watch is canceled once the watcher is dropped, so keep it along
+        // with the stream
+        let changes = stream::unfold(
+            (watcher, watch_stream),
+            |(watcher, mut watch_stream)| async move {
+                loop {
+                    let resp = match watch_stream.message().await.with_context(|| {
+                        "failed to receive the changes at `KVEngine::watch_prefix`".to_owned()
+                    }) {
+                        Ok(Some(resp)) => resp,
+                        Ok(None) => return None,
+                        Err(e) => return Some((Err(e), (watcher, watch_stream))),
+                    };
+                    if resp.canceled() {
+                        return None;
+                    }
+                    if !resp.events().is_empty() {
+                        return Some((Ok(()), (watcher, watch_stream)));
+                    }
+                }
+            },
+        );
+        Ok(changes.boxed())
     }
 
     /// Distribute lock - lock
@@ -124,6 +325,13 @@ impl KVEngine for EtcdKVEngine {
 
     /// Get the value by the key.
     async fn get(&self, key: &KeyType) -> DatenLordResult<Option<ValueType>> {
+        if let Some(ref view) = self.snapshot {
+            let (value, _) = view
+                .get(&self.client, key.get_key())
+                .await
+                .with_context(|| format!("failed to get from etcd engine, key={key:?}"))?;
+            return value.as_deref().map(Self::decode_value).transpose();
+        }
         let mut client = self.client.clone();
         let resp = client
             .get(key.get_key(), None)
@@ -146,6 +354,16 @@ impl KVEngine for EtcdKVEngine {
         value: &ValueType,
         option: Option<SetOption>,
     ) -> DatenLordResult<Option<ValueType>> {
+        if let Some(ref view) = self.snapshot {
+            let prev = match option {
+                Some(ref option) if option.prev_kv => self.get(key).await?,
+                _ => None,
+            };
+            let serial_value = serde_json::to_vec(value)
+                .with_context(|| format!("failed to serialize value={value:?} to bytes"))?;
+            view.write(key.get_key(), Some(serial_value));
+            return Ok(prev);
+        }
         let option = match option {
             Some(option) => {
                 let mut set_option = PutOptions::new();
@@ -180,6 +398,22 @@ impl KVEngine for EtcdKVEngine {
         key: &KeyType,
         option: Option<DeleteOption>,
     ) -> DatenLordResult<Option<ValueType>> {
+        if let Some(ref view) = self.snapshot {
+            let prev = match option {
+                Some(ref option) if option.prev_kv => self.get(key).await?,
+                _ => None,
+            };
+            if let Some(range_end) = option.and_then(|option| option.range_end) {
+                let mut key_range = KeyRange::new();
+                key_range.with_key(key.get_key());
+                key_range.with_range(range_end);
+                for (key, _) in self.range(key_range).await? {
+                    view.write(key, None);
+                }
+            }
+            view.write(key.get_key(), None);
+            return Ok(prev);
+        }
         let option = match option {
             Some(option) => {
                 let mut delete_option = DeleteOptions::new();
@@ -214,19 +448,26 @@ impl KVEngine for EtcdKVEngine {
 struct EtcdTxn {
     /// The etcd client.
     client: etcd_client::Client,
+    /// The view of a snapshot the transaction is pinned at, whose writes are
+    /// committed to the memory of the view.
+    snapshot: Option<Arc<SnapshotView>>,
     /// The key is the key in bytes, the value is the version of the key.
     version_map: HashMap<Vec<u8>, KvVersion>,
     /// Store the write operations in the buffer.
     buffer: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    /// The revision the transaction is committed at, 0 until it is committed.
+    revision: i64,
 }
 
 impl EtcdTxn {
     /// Create a new etcd transaction.
-    fn new(client: etcd_client::Client) -> Self {
+    fn new(client: etcd_client::Client, snapshot: Option<Arc<SnapshotView>>) -> Self {
         EtcdTxn {
             client,
+            snapshot,
             version_map: HashMap::new(),
             buffer: HashMap::new(),
+            revision: 0,
         }
     }
 }
@@ -244,6 +485,14 @@ impl MetaTxn for EtcdTxn {
             self.version_map.get(&key).is_none(),
             "get the key twice in the same transaction"
         );
+        if let Some(ref view) = self.snapshot {
+            let (value, version) = view
+                .get(&self.client, key.clone())
+                .await
+                .with_context(|| "failed to get at `MetaTxn::get`".to_owned())?;
+            self.version_map.insert(key, version);
+            return value.as_deref().map(EtcdKVEngine::decode_value).transpose();
+        }
         // Fetch the value from `etcd`
         let resp = self
             .client
@@ -283,6 +532,14 @@ impl MetaTxn for EtcdTxn {
         if self.version_map.is_empty() && self.buffer.is_empty() {
             return Ok(true);
         }
+        if let Some(ref view) = self.snapshot {
+            // Nobody else writes the view, so the transaction never conflicts
+            for (key, value) in self.buffer.drain() {
+                view.write(key, value);
+            }
+            self.revision = view.revision;
+            return Ok(true);
+        }
 
         let resp = self
             .client
@@ -311,8 +568,21 @@ impl MetaTxn for EtcdTxn {
             )
             .await
             .with_context(|| "failed to do txn operation at `MetaTxn::commit`".to_owned())?;
+        if resp.succeeded() {
+            self.revision = resp
+                .header()
+                .map(etcd_client::ResponseHeader::revision)
+                .ok_or_else(|| DatenLordError::KVEngineErr {
+                    source: KVEngineError::NoResponseHeader,
+                    context: vec!["failed to get the revision at `MetaTxn::commit`".to_owned()],
+                })?;
+        }
         Ok(resp.succeeded())
     }
+
+    fn revision(&self) -> i64 {
+        self.revision
+    }
 }
 
 #[cfg(test)]
@@ -377,6 +647,56 @@ mod test {
         assert!(get_value.is_none());
     }
 
+    #[tokio::test]
+    async fn test_pin_at_revision() {
+        let client = EtcdKVEngine::new_for_local_test(vec![ETCD_ADDRESS.to_owned()])
+            .await
+            .unwrap();
+        let key = KeyType::String("test_pin key".to_owned());
+        client.set(&key, &ValueType::INum(1), None).await.unwrap();
+        let revision = client.revision().await.unwrap();
+        client.set(&key, &ValueType::INum(2), None).await.unwrap();
+
+        // The view reads the value at the revision
+        let view = client.pin_at_revision(revision);
+        assert_eq!(view.revision().await.unwrap(), revision);
+        assert_eq!(view.get(&key).await.unwrap(), Some(ValueType::INum(1)));
+        // The writes through the view are only visible to the view
+        let mut txn = view.new_meta_txn().await;
+        let value = txn.get(&key).await.unwrap();
+        assert_eq!(value, Some(ValueType::INum(1)));
+        txn.set(&key, &ValueType::INum(3));
+        assert!(txn.commit().await.unwrap());
+        assert_eq!(view.get(&key).await.unwrap(), Some(ValueType::INum(3)));
+        let mut key_range = KeyRange::new();
+        key_range.with_key(key.get_key());
+        key_range.with_prefix();
+        assert_eq!(view.range(key_range).await.unwrap().len(), 1);
+        view.delete(&key, None).await.unwrap();
+        assert!(view.get(&key).await.unwrap().is_none());
+        assert_eq!(client.get(&key).await.unwrap(), Some(ValueType::INum(2)));
+        client.delete(&key, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_txn_revision() {
+        let client = EtcdKVEngine::new_for_local_test(vec![ETCD_ADDRESS.to_owned()])
+            .await
+            .unwrap();
+        let key = KeyType::String("test_txn_revision key".to_owned());
+        let mut txn = client.new_meta_txn().await;
+        assert_eq!(txn.revision(), 0);
+        txn.set(&key, &ValueType::INum(1));
+        assert!(txn.commit().await.unwrap());
+        // The revision of the commit, at which the value is set
+        let revision = txn.revision();
+        assert!(revision > 0);
+        client.set(&key, &ValueType::INum(2), None).await.unwrap();
+        let view = client.pin_at_revision(revision);
+        assert_eq!(view.get(&key).await.unwrap(), Some(ValueType::INum(1)));
+        client.delete(&key, None).await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_easy_commit_fail() {
         // Generate three transactions
@@ -1,18 +1,31 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
 use std::time::Duration;
 
+use clippy_utilities::{Cast, OverflowArithmetic};
+use futures::stream::BoxStream;
+use nix::errno::Errno;
 use tracing::debug;
 
 use crate::async_fuse::fuse::protocol::INum;
+use crate::async_fuse::memfs::file_lock::FileLock;
 use crate::async_fuse::memfs::kv_engine::{
-    self, KVEngine, KVEngineType, KeyType, LockKeyType, ValueType,
+    self, serialize_key, KVEngine, KVEngineType, KeyRange, KeyType, LockKeyType, SetOption,
+    ValueType,
 };
+use crate::async_fuse::memfs::quota::{QuotaKind, QuotaLimit, QuotaUsage};
+use crate::async_fuse::memfs::serial::SerialNode;
+use crate::async_fuse::memfs::snapshot::SnapshotInfo;
+use crate::async_fuse::memfs::volume_stat::VolumeStat;
+use crate::async_fuse::util::build_error_result_from_errno;
 use crate::common::error::{Context, DatenLordResult};
 
 /// The kv lock 's timeout
 const LOCK_TIME_OUT_SECS: u64 = 10;
 
+/// The max number of keys read in a page at a past revision
+const RANGE_PAGE_SIZE: i64 = 1000;
+
 /// Register current node to etcd.
 /// The registered information contains IP.
 pub async fn register_node_id(
@@ -118,6 +131,80 @@ pub async fn register_volume(
     Ok(())
 }
 
+/// Deregister current node from etcd, so that other nodes won't send requests
+/// to it any more
+pub async fn deregister_node_id(
+    kv_engine: &Arc<KVEngineType>,
+    node_id: &str,
+) -> DatenLordResult<()> {
+    kv_engine
+        .delete(&KeyType::NodeIpPort(node_id.to_owned()), None)
+        .await
+        .with_context(|| format!("Fail to deregister node {node_id} from etcd"))?;
+
+    Ok(())
+}
+
+/// Deregister volume information, remove the `node_id` from the volume node
+/// list, and remove the volume if no node is left
+pub async fn deregister_volume(
+    kv_engine: &Arc<KVEngineType>,
+    node_id: &str,
+    volume_info: &str,
+) -> DatenLordResult<()> {
+    let lock_key = kv_engine
+        .lock(
+            &LockKeyType::VolumeInfoLock,
+            Duration::from_secs(LOCK_TIME_OUT_SECS),
+        )
+        .await
+        .with_context(|| "lock fail while deregister volume")?;
+
+    let volume_node_list = kv_engine
+        .get(&KeyType::VolumeInfo(volume_info.to_owned()))
+        .await
+        .with_context(|| format!("Fail to get volume node list for volume {volume_info:?}",))?
+        .map(kv_engine::ValueType::into_raw);
+
+    if let Some(node_list) = volume_node_list {
+        let mut node_set: HashSet<String> = bincode::deserialize(node_list.as_slice())
+            .unwrap_or_else(|e| {
+                panic!("fail to deserialize node list for volume {volume_info:?}, error: {e}");
+            });
+        node_set.remove(node_id);
+
+        if node_set.is_empty() {
+            kv_engine
+                .delete(&KeyType::VolumeInfo(volume_info.to_owned()), None)
+                .await
+                .with_context(|| format!("Fail to remove volume {volume_info:?} from etcd"))?;
+        } else {
+            let volume_node_list_bin = bincode::serialize(&node_set).unwrap_or_else(|e| {
+                panic!("fail to serialize node list for volume {volume_info:?}, error: {e}")
+            });
+            kv_engine
+                .set(
+                    &KeyType::VolumeInfo(volume_info.to_owned()),
+                    &ValueType::Raw(volume_node_list_bin),
+                    None,
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Fail to deregister volume {volume_info:?} from etcd, node_id:{node_id}",
+                    )
+                })?;
+        }
+    }
+
+    kv_engine
+        .unlock(lock_key)
+        .await
+        .with_context(|| "unlock fail while deregister volume")?;
+
+    Ok(())
+}
+
 /// Get node list related to a volume, execluding the input `node_ide` as its
 /// the local node id. This function is used to sync metadata, the inode
 /// information.
@@ -257,3 +344,335 @@ pub async fn remove_node_from_file_list(
 
     modify_file_node_list(kv_engine, file_ino, remove_node_fun).await
 }
+
+/// Get the POSIX locks of a file held by all nodes
+pub async fn get_file_locks(
+    kv_engine: &Arc<KVEngineType>,
+    file_ino: INum,
+) -> DatenLordResult<Vec<FileLock>> {
+    let mut key_range = KeyRange::new();
+    // The keys of `KeyType::FileLock` held by all nodes share the same prefix
+    key_range.with_key(serialize_key(KeyType::FILE_LOCK_PREFIX, &file_ino));
+    key_range.with_prefix();
+    let kvs = kv_engine
+        .range(key_range)
+        .await
+        .with_context(|| format!("fail to get file locks for file {file_ino:?}"))?;
+
+    let mut file_locks = Vec::new();
+    for (_, value) in kvs {
+        let value: ValueType = serde_json::from_slice(&value)
+            .with_context(|| format!("fail to deserialize file locks for file {file_ino:?}"))?;
+        file_locks.extend(value.into_file_locks());
+    }
+    Ok(file_locks)
+}
+
+/// Watch the POSIX locks of a file held by all nodes, the stream yields an item
+/// once the locks are changed by some node
+pub async fn watch_file_locks(
+    kv_engine: &Arc<KVEngineType>,
+    file_ino: INum,
+) -> DatenLordResult<BoxStream<'static, DatenLordResult<()>>> {
+    kv_engine
+        .watch_prefix(serialize_key(KeyType::FILE_LOCK_PREFIX, &file_ino))
+        .await
+        .with_context(|| format!("fail to watch file locks for file {file_ino:?}"))
+}
+
+/// Modify the POSIX locks of a file held by the node `node_id`.
+/// The locks held by all nodes are passed to `fun`, which returns the new
+/// locks held by the node, or `None` if nothing changes. The locks are attached
+/// to the lease `lease_id`, so they are released automatically if the node is
+/// gone.
+pub async fn modify_file_locks<T, F>(
+    kv_engine: &Arc<KVEngineType>,
+    file_ino: INum,
+    node_id: &str,
+    lease_id: i64,
+    fun: F,
+) -> DatenLordResult<T>
+where
+    F: FnOnce(Vec<FileLock>) -> DatenLordResult<(Option<Vec<FileLock>>, T)> + Send,
+{
+    let lock_key = kv_engine
+        .lock(
+            &LockKeyType::FileLockLock(file_ino),
+            Duration::from_secs(LOCK_TIME_OUT_SECS),
+        )
+        .await
+        .with_context(|| "lock fail while update file locks")?;
+
+    let result = modify_file_locks_helper(kv_engine, file_ino, node_id, lease_id, fun).await;
+
+    kv_engine
+        .unlock(lock_key)
+        .await
+        .with_context(|| "unlock fail while update file locks")?;
+
+    result
+}
+
+/// Helper function to modify the POSIX locks of a file when holding the lock of
+/// the file lock table
+async fn modify_file_locks_helper<T, F>(
+    kv_engine: &Arc<KVEngineType>,
+    file_ino: INum,
+    node_id: &str,
+    lease_id: i64,
+    fun: F,
+) -> DatenLordResult<T>
+where
+    F: FnOnce(Vec<FileLock>) -> DatenLordResult<(Option<Vec<FileLock>>, T)> + Send,
+{
+    let file_locks = get_file_locks(kv_engine, file_ino).await?;
+    let (new_node_locks, result) = fun(file_locks)?;
+
+    if let Some(node_locks) = new_node_locks {
+        let key = KeyType::FileLock(file_ino, node_id.to_owned());
+        if node_locks.is_empty() {
+            kv_engine
+                .delete(&key, None)
+                .await
+                .with_context(|| format!("fail to delete file locks for file {file_ino:?}"))?;
+        } else {
+            kv_engine
+                .set(
+                    &key,
+                    &ValueType::FileLock(node_locks),
+                    Some(SetOption::new().with_lease(lease_id)),
+                )
+                .await
+                .with_context(|| format!("fail to set file locks for file {file_ino:?}"))?;
+        }
+    }
+    debug!(
+        "file locks of file {:?} on node {} updated",
+        file_ino, node_id
+    );
+
+    Ok(result)
+}
+
+/// Get the keys without the prefix and the values of a key type at
+/// `revision`, whose keys share the prefix of `key_prefix`, 0 means the latest
+/// revision
+async fn list_by_prefix(
+    kv_engine: &Arc<KVEngineType>,
+    key_prefix: u16,
+    revision: i64,
+) -> DatenLordResult<Vec<(Vec<u8>, ValueType)>> {
+    list_by_serialized_prefix(kv_engine, serialize_key(key_prefix, &()), revision).await
+}
+
+/// The end of the range of the keys starting with `prefix`
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last.overflow_add(1));
+            return end;
+        }
+    }
+    // All the keys are after the prefix
+    vec![0]
+}
+
+/// Get the keys without the prefix and the values of the keys starting with
+/// the serialized `prefix` at `revision`, 0 means the latest revision. The
+/// keys at a past revision are read in pages, which are consistent as the
+/// revision is fixed, while the latest keys are read at once.
+async fn list_by_serialized_prefix(
+    kv_engine: &Arc<KVEngineType>,
+    prefix: Vec<u8>,
+    revision: i64,
+) -> DatenLordResult<Vec<(Vec<u8>, ValueType)>> {
+    let prefix_len = prefix.len();
+    let mut key_range = KeyRange::new();
+    key_range.with_key(prefix.clone());
+    if revision == 0 {
+        key_range.with_prefix();
+    } else {
+        key_range.with_range(prefix_end(&prefix));
+        key_range.with_revision(revision);
+        key_range.with_limit(RANGE_PAGE_SIZE);
+    }
+
+    let mut result = Vec::new();
+    loop {
+        let kvs = kv_engine
+            .range(key_range.clone())
+            .await
+            .with_context(|| format!("fail to get the keys of prefix {prefix:?}"))?;
+        let page_len = kvs.len();
+        // The next page starts right after the last key
+        if let Some(&(ref last_key, _)) = kvs.last() {
+            let mut next_key = last_key.clone();
+            next_key.push(0);
+            key_range.with_key(next_key);
+        }
+        for (mut key, value) in kvs {
+            let value: ValueType = serde_json::from_slice(&value)
+                .with_context(|| format!("fail to deserialize the value of key {key:?}"))?;
+            // Skip the prefix of the key
+            result.push((key.split_off(prefix_len), value));
+        }
+        if revision == 0 || page_len < RANGE_PAGE_SIZE.cast::<usize>() {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+/// Get the usage of the volume, which is the sum of its shards
+pub async fn get_volume_stat(kv_engine: &Arc<KVEngineType>) -> DatenLordResult<VolumeStat> {
+    let kvs = list_by_prefix(kv_engine, KeyType::VOLUME_STAT_PREFIX, 0).await?;
+    let mut stat = VolumeStat::default();
+    for (_, value) in kvs {
+        stat.merge(&value.into_volume_stat());
+    }
+    Ok(stat)
+}
+
+/// Get the quota limits of all the users, groups and projects
+pub async fn list_quota_limits(
+    kv_engine: &Arc<KVEngineType>,
+) -> DatenLordResult<Vec<(QuotaKind, u32, QuotaLimit)>> {
+    list_quota_limits_at(kv_engine, 0).await
+}
+
+/// Get the quota limits of all the users, groups and projects at a past
+/// `revision` of the kv engine
+pub async fn list_quota_limits_at(
+    kv_engine: &Arc<KVEngineType>,
+    revision: i64,
+) -> DatenLordResult<Vec<(QuotaKind, u32, QuotaLimit)>> {
+    let kvs = list_by_prefix(kv_engine, KeyType::QUOTA_PREFIX, revision).await?;
+    Ok(kvs
+        .into_iter()
+        .map(|(key, value)| {
+            let (kind, id): (QuotaKind, u32) = bincode::deserialize(&key)
+                .unwrap_or_else(|e| panic!("fail to deserialize quota key {key:?}, error: {e}"));
+            (kind, id, value.into_quota_limit())
+        })
+        .collect())
+}
+
+/// Watch the quota limits of all the users, groups and projects, the stream
+/// yields an item once the limits are changed by some node
+pub async fn watch_quota_limits(
+    kv_engine: &Arc<KVEngineType>,
+) -> DatenLordResult<BoxStream<'static, DatenLordResult<()>>> {
+    kv_engine
+        .watch_prefix(serialize_key(KeyType::QUOTA_PREFIX, &()))
+        .await
+        .with_context(|| "fail to watch quota limits")
+}
+
+/// Get the shards of the usage of the quota of `kind` and `id`
+pub async fn list_quota_usages(
+    kv_engine: &Arc<KVEngineType>,
+    kind: QuotaKind,
+    id: u32,
+) -> DatenLordResult<Vec<(u32, QuotaUsage)>> {
+    let kvs = list_by_serialized_prefix(
+        kv_engine,
+        serialize_key(KeyType::QUOTA_USAGE_PREFIX, &(kind, id)),
+        0,
+    )
+    .await?;
+    Ok(kvs
+        .into_iter()
+        .map(|(key, value)| {
+            let shard: u32 = bincode::deserialize(&key).unwrap_or_else(|e| {
+                panic!("fail to deserialize quota usage key {key:?}, error: {e}")
+            });
+            (shard, value.into_quota_usage())
+        })
+        .collect())
+}
+
+/// Get all the i-nodes
+pub async fn list_nodes(kv_engine: &Arc<KVEngineType>) -> DatenLordResult<Vec<SerialNode>> {
+    list_nodes_at(kv_engine, 0).await
+}
+
+/// Get all the i-nodes at a past `revision` of the kv engine
+pub async fn list_nodes_at(
+    kv_engine: &Arc<KVEngineType>,
+    revision: i64,
+) -> DatenLordResult<Vec<SerialNode>> {
+    let kvs = list_by_prefix(kv_engine, KeyType::INUM2NODE_PREFIX, revision).await?;
+    Ok(kvs
+        .into_iter()
+        .map(|(_, value)| value.into_serial_node())
+        .collect())
+}
+
+/// Get the extended attributes of all the i-nodes at a past `revision` of the
+/// kv engine, 0 means the latest revision
+pub async fn list_xattrs_at(
+    kv_engine: &Arc<KVEngineType>,
+    revision: i64,
+) -> DatenLordResult<Vec<(INum, BTreeMap<String, Vec<u8>>)>> {
+    let kvs = list_by_prefix(kv_engine, KeyType::INUM2XATTR_PREFIX, revision).await?;
+    Ok(kvs
+        .into_iter()
+        .map(|(key, value)| {
+            let ino: INum = bincode::deserialize(&key)
+                .unwrap_or_else(|e| panic!("fail to deserialize xattr key {key:?}, error: {e}"));
+            (ino, value.into_xattr())
+        })
+        .collect())
+}
+
+/// Get all the snapshots of the volume
+pub async fn list_snapshots(
+    kv_engine: &Arc<KVEngineType>,
+) -> DatenLordResult<Vec<(String, SnapshotInfo)>> {
+    let kvs = list_by_prefix(kv_engine, KeyType::SNAPSHOT_PREFIX, 0).await?;
+    Ok(kvs
+        .into_iter()
+        .map(|(key, value)| {
+            let name: String = bincode::deserialize(&key)
+                .unwrap_or_else(|e| panic!("fail to deserialize snapshot key {key:?}, error: {e}"));
+            (name, value.into_snapshot())
+        })
+        .collect())
+}
+
+/// Get the view of the kv engine at the revision of the snapshot `name`
+pub async fn pin_at_snapshot(
+    kv_engine: &Arc<KVEngineType>,
+    name: &str,
+) -> DatenLordResult<KVEngineType> {
+    let revision = kv_engine
+        .get(&KeyType::Snapshot(name.to_owned()))
+        .await?
+        .map(ValueType::into_snapshot)
+        .and_then(|info| info.revision);
+    let Some(revision) = revision else {
+        return build_error_result_from_errno(
+            Errno::ENOENT,
+            format!("pin_at_snapshot() found no complete snapshot of name={name:?}"),
+        );
+    };
+    Ok(kv_engine.pin_at_revision(revision))
+}
+
+/// Get the S3 objects retained for the snapshots and the generations they are
+/// retained at
+pub async fn list_retained_objects(
+    kv_engine: &Arc<KVEngineType>,
+) -> DatenLordResult<Vec<(INum, u64)>> {
+    let kvs = list_by_prefix(kv_engine, KeyType::RETAINED_OBJECT_PREFIX, 0).await?;
+    Ok(kvs
+        .into_iter()
+        .map(|(key, value)| {
+            let object: INum = bincode::deserialize(&key).unwrap_or_else(|e| {
+                panic!("fail to deserialize retained object key {key:?}, error: {e}")
+            });
+            (object, value.into_generation())
+        })
+        .collect())
+}
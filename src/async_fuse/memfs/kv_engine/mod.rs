@@ -1,13 +1,19 @@
 use core::fmt::Debug;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Display;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use futures::stream::BoxStream;
 use serde::{Deserialize, Serialize};
 
+use super::file_lock::FileLock;
+use super::quota::{QuotaKind, QuotaLimit, QuotaUsage};
 use super::s3_node::S3Node;
 use super::s3_wrapper::S3BackEnd;
+use super::snapshot::{SnapshotInfo, SnapshotState};
+use super::volume_stat::VolumeStat;
 use super::{INum, S3MetaData};
 use crate::async_fuse::memfs::dist::id_alloc::IdType;
 use crate::common::async_fuse_error::KVEngineError;
@@ -24,6 +30,44 @@ pub mod etcd_impl;
 /// The `kv_utils` is used to provide some common functions for `KVEngine`
 pub mod kv_utils;
 
+/// Encode the extended attribute values in base64, which is much smaller than
+/// the JSON arrays of numbers
+mod xattr_values {
+    use std::collections::BTreeMap;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    /// Serialize the extended attributes with the values in base64
+    pub fn serialize<S: Serializer>(
+        xattrs: &BTreeMap<String, Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        xattrs
+            .iter()
+            .map(|(name, value)| (name, STANDARD.encode(value)))
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    /// Deserialize the extended attributes with the values in base64
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, Vec<u8>>, D::Error> {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(name, value)| {
+                STANDARD
+                    .decode(value)
+                    .map(|value| (name, value))
+                    .map_err(D::Error::custom)
+            })
+            .collect()
+    }
+}
+
 /// The `ValueType` is used to provide support for metadata.
 ///
 /// The variants `DirEntry`, `INum` and `Attr` are not used currently,
@@ -44,6 +88,24 @@ pub enum ValueType {
     Raw(Vec<u8>),
     /// String value
     String(String),
+    /// Extended attributes of an i-node, xattr name -> xattr value
+    XAttr(#[serde(with = "xattr_values")] BTreeMap<String, Vec<u8>>),
+    /// POSIX locks of an i-node held by a node
+    FileLock(Vec<FileLock>),
+    /// The usage of the volume
+    VolumeStat(VolumeStat),
+    /// The limit of a quota
+    QuotaLimit(QuotaLimit),
+    /// A shard of the usage of a quota
+    QuotaUsage(QuotaUsage),
+    /// The number of i-nodes referencing a shared S3 object
+    RefCount(u64),
+    /// The state of the snapshots of the volume
+    SnapshotState(SnapshotState),
+    /// A snapshot of the metadata
+    Snapshot(SnapshotInfo),
+    /// The snapshot generation an S3 object is retained at
+    Generation(u64),
 }
 
 impl ValueType {
@@ -63,6 +125,18 @@ impl ValueType {
         }
     }
 
+    /// Turn the `ValueType` into `SerialNode`.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::Node`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_serial_node(self) -> SerialNode {
+        match self {
+            ValueType::Node(node) => node,
+            _ => panic!("expect ValueType::Node but get {self:?}"),
+        }
+    }
+
     /// Turn the `ValueType` into `NextIdAllocateRangeBegin`.
     /// # Panics
     /// Panics if `ValueType` is not `ValueType::NextIdAllocateRangeBegin`.
@@ -99,6 +173,114 @@ impl ValueType {
         }
     }
 
+    /// Turn the `ValueType` into extended attributes.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::XAttr`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_xattr(self) -> BTreeMap<String, Vec<u8>> {
+        match self {
+            ValueType::XAttr(xattr) => xattr,
+            _ => panic!("expect ValueType::XAttr but get {self:?}"),
+        }
+    }
+
+    /// Turn the `ValueType` into POSIX locks.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::FileLock`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_file_locks(self) -> Vec<FileLock> {
+        match self {
+            ValueType::FileLock(locks) => locks,
+            _ => panic!("expect ValueType::FileLock but get {self:?}"),
+        }
+    }
+
+    /// Turn the `ValueType` into the usage of the volume.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::VolumeStat`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_volume_stat(self) -> VolumeStat {
+        match self {
+            ValueType::VolumeStat(stat) => stat,
+            _ => panic!("expect ValueType::VolumeStat but get {self:?}"),
+        }
+    }
+
+    /// Turn the `ValueType` into the limit of a quota.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::QuotaLimit`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_quota_limit(self) -> QuotaLimit {
+        match self {
+            ValueType::QuotaLimit(limit) => limit,
+            _ => panic!("expect ValueType::QuotaLimit but get {self:?}"),
+        }
+    }
+
+    /// Turn the `ValueType` into a shard of the usage of a quota.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::QuotaUsage`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_quota_usage(self) -> QuotaUsage {
+        match self {
+            ValueType::QuotaUsage(usage) => usage,
+            _ => panic!("expect ValueType::QuotaUsage but get {self:?}"),
+        }
+    }
+
+    /// Turn the `ValueType` into a reference count.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::RefCount`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_ref_count(self) -> u64 {
+        match self {
+            ValueType::RefCount(count) => count,
+            _ => panic!("expect ValueType::RefCount but get {self:?}"),
+        }
+    }
+
+    /// Turn the `ValueType` into the state of the snapshots.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::SnapshotState`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_snapshot_state(self) -> SnapshotState {
+        match self {
+            ValueType::SnapshotState(state) => state,
+            _ => panic!("expect ValueType::SnapshotState but get {self:?}"),
+        }
+    }
+
+    /// Turn the `ValueType` into a snapshot.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::Snapshot`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_snapshot(self) -> SnapshotInfo {
+        match self {
+            ValueType::Snapshot(info) => info,
+            _ => panic!("expect ValueType::Snapshot but get {self:?}"),
+        }
+    }
+
+    /// Turn the `ValueType` into a snapshot generation.
+    /// # Panics
+    /// Panics if `ValueType` is not `ValueType::Generation`.
+    #[allow(clippy::wildcard_enum_match_arm)] // Allow wildcard because there should be only one enum branch matches one specific type.
+    #[must_use]
+    pub fn into_generation(self) -> u64 {
+        match self {
+            ValueType::Generation(generation) => generation,
+            _ => panic!("expect ValueType::Generation but get {self:?}"),
+        }
+    }
+
     /// Turn the `ValueType` into `Raw`
     /// # Panics
     /// Panics if `ValueType` is not `ValueType::Raw`.
@@ -136,6 +318,34 @@ pub enum KeyType {
     /// Node list
     /// The corresponding value type is ValueType::RawData
     FileNodeList(INum),
+    /// INum -> extended attributes of the i-node
+    /// The corresponding value type is ValueType::XAttr
+    INum2XAttr(INum),
+    /// (INum, node id) -> POSIX locks of the i-node held by the node
+    /// The corresponding value type is ValueType::FileLock
+    FileLock(INum, String),
+    /// A shard of the usage of the volume
+    /// The corresponding value type is ValueType::VolumeStat
+    VolumeStat(u32),
+    /// (quota kind, id) -> the limit of the quota
+    /// The corresponding value type is ValueType::QuotaLimit
+    Quota(QuotaKind, u32),
+    /// (quota kind, id, shard) -> a shard of the usage of the quota
+    /// The corresponding value type is ValueType::QuotaUsage
+    QuotaUsage(QuotaKind, u32, u32),
+    /// The id of a shared S3 object -> the number of i-nodes referencing it
+    /// The corresponding value type is ValueType::RefCount
+    SharedObject(INum),
+    /// The state of the snapshots of the volume
+    /// The corresponding value type is ValueType::SnapshotState
+    SnapshotState,
+    /// The name of a snapshot -> the snapshot
+    /// The corresponding value type is ValueType::Snapshot
+    Snapshot(String),
+    /// The id of an S3 object retained for the snapshots -> the generation it
+    /// is retained at
+    /// The corresponding value type is ValueType::Generation
+    RetainedObject(INum),
     /// Just a string key for testing the KVEngine.
     #[cfg(test)]
     String(String),
@@ -152,6 +362,10 @@ pub enum LockKeyType {
     VolumeInfoLock,
     /// ETCD file node list lock
     FileNodeListLock(INum),
+    /// ETCD file lock table lock
+    FileLockLock(INum),
+    /// The lock electing the node to checkpoint the metadata
+    CheckpointLock,
 }
 
 impl Display for KeyType {
@@ -168,6 +382,17 @@ impl Display for KeyType {
             KeyType::NodeIpPort(ref s) => write!(f, "NodeIpPort{{s: {s}}}"),
             KeyType::VolumeInfo(ref s) => write!(f, "VolumeInfo{{s: {s}}}"),
             KeyType::FileNodeList(ref s) => write!(f, "FileNodeList{{s: {s:?}}}"),
+            KeyType::INum2XAttr(ref i) => write!(f, "INum2XAttr{{i: {i}}}"),
+            KeyType::FileLock(ref i, ref s) => write!(f, "FileLock{{i: {i}, s: {s}}}"),
+            KeyType::VolumeStat(ref shard) => write!(f, "VolumeStat{{shard: {shard}}}"),
+            KeyType::Quota(ref kind, ref id) => write!(f, "Quota{{kind: {kind:?}, id: {id}}}"),
+            KeyType::QuotaUsage(ref kind, ref id, ref shard) => {
+                write!(f, "QuotaUsage{{kind: {kind:?}, id: {id}, shard: {shard}}}")
+            }
+            KeyType::SharedObject(ref i) => write!(f, "SharedObject{{i: {i}}}"),
+            KeyType::SnapshotState => write!(f, "SnapshotState"),
+            KeyType::Snapshot(ref s) => write!(f, "Snapshot{{s: {s}}}"),
+            KeyType::RetainedObject(ref i) => write!(f, "RetainedObject{{i: {i}}}"),
         }
     }
 }
@@ -184,6 +409,12 @@ impl Display for LockKeyType {
             LockKeyType::FileNodeListLock(ref file_name) => {
                 write!(f, "LockKeyType::FileNodeList {{file_name: {file_name:?}}}")
             }
+            LockKeyType::FileLockLock(ref file_name) => {
+                write!(f, "LockKeyType::FileLock {{file_name: {file_name:?}}}")
+            }
+            LockKeyType::CheckpointLock => {
+                write!(f, "LockKeyType::CheckpointLock")
+            }
         }
     }
 }
@@ -204,19 +435,68 @@ fn serialize_key<K: ?Sized + Serialize>(key_prefix: u16, key: &K) -> Vec<u8> {
 }
 
 impl KeyType {
+    /// The key prefix of `KeyType::FileLock`
+    const FILE_LOCK_PREFIX: u16 = 14;
+    /// The key prefix of `KeyType::FileNodeList`
+    const FILE_NODE_LIST_PREFIX: u16 = 10;
+    /// The key prefix of `KeyType::IdAllocatorValue`
+    const ID_ALLOCATOR_VALUE_PREFIX: u16 = 4;
+    /// The key prefix of `KeyType::INum2Attr`
+    const INUM2ATTR_PREFIX: u16 = 3;
+    /// The key prefix of `KeyType::INum2DirEntry`
+    const INUM2DIRENTRY_PREFIX: u16 = 1;
+    /// The key prefix of `KeyType::INum2Node`
+    const INUM2NODE_PREFIX: u16 = 0;
+    /// The key prefix of `KeyType::INum2XAttr`
+    const INUM2XATTR_PREFIX: u16 = 12;
+    /// The key prefix of `KeyType::NodeIpPort`
+    const NODE_IP_PORT_PREFIX: u16 = 6;
+    /// The key prefix of `KeyType::Quota`
+    const QUOTA_PREFIX: u16 = 18;
+    /// The key prefix of `KeyType::QuotaUsage`
+    const QUOTA_USAGE_PREFIX: u16 = 28;
+    /// The key prefix of `KeyType::RetainedObject`
+    const RETAINED_OBJECT_PREFIX: u16 = 26;
+    /// The key prefix of `KeyType::SharedObject`
+    const SHARED_OBJECT_PREFIX: u16 = 20;
+    /// The key prefix of `KeyType::Snapshot`
+    const SNAPSHOT_PREFIX: u16 = 24;
+    /// The key prefix of `KeyType::SnapshotState`
+    const SNAPSHOT_STATE_PREFIX: u16 = 22;
+    /// The key prefix of `KeyType::String`
+    #[cfg(test)]
+    const STRING_PREFIX: u16 = 2;
+    /// The key prefix of `KeyType::VolumeInfo`
+    const VOLUME_INFO_PREFIX: u16 = 8;
+    /// The key prefix of `KeyType::VolumeStat`
+    const VOLUME_STAT_PREFIX: u16 = 16;
+
     /// Get the key in bytes.
     #[must_use]
     pub fn get_key(&self) -> Vec<u8> {
         match *self {
-            KeyType::INum2Node(ref i) => serialize_key(0, i),
-            KeyType::INum2DirEntry(ref i) => serialize_key(1, i),
+            KeyType::INum2Node(ref i) => serialize_key(Self::INUM2NODE_PREFIX, i),
+            KeyType::INum2DirEntry(ref i) => serialize_key(Self::INUM2DIRENTRY_PREFIX, i),
             #[cfg(test)]
-            KeyType::String(ref s) => serialize_key(2, s),
-            KeyType::INum2Attr(ref i) => serialize_key(3, i),
-            KeyType::IdAllocatorValue(ref id_type) => serialize_key(4, &id_type.to_unique_id()),
-            KeyType::NodeIpPort(ref s) => serialize_key(6, s),
-            KeyType::VolumeInfo(ref s) => serialize_key(8, s),
-            KeyType::FileNodeList(ref s) => serialize_key(10, s),
+            KeyType::String(ref s) => serialize_key(Self::STRING_PREFIX, s),
+            KeyType::INum2Attr(ref i) => serialize_key(Self::INUM2ATTR_PREFIX, i),
+            KeyType::IdAllocatorValue(ref id_type) => {
+                serialize_key(Self::ID_ALLOCATOR_VALUE_PREFIX, &id_type.to_unique_id())
+            }
+            KeyType::NodeIpPort(ref s) => serialize_key(Self::NODE_IP_PORT_PREFIX, s),
+            KeyType::VolumeInfo(ref s) => serialize_key(Self::VOLUME_INFO_PREFIX, s),
+            KeyType::FileNodeList(ref s) => serialize_key(Self::FILE_NODE_LIST_PREFIX, s),
+            KeyType::INum2XAttr(ref i) => serialize_key(Self::INUM2XATTR_PREFIX, i),
+            KeyType::FileLock(ref i, ref s) => serialize_key(Self::FILE_LOCK_PREFIX, &(i, s)),
+            KeyType::VolumeStat(ref shard) => serialize_key(Self::VOLUME_STAT_PREFIX, shard),
+            KeyType::Quota(ref kind, ref id) => serialize_key(Self::QUOTA_PREFIX, &(kind, id)),
+            KeyType::SharedObject(ref i) => serialize_key(Self::SHARED_OBJECT_PREFIX, i),
+            KeyType::SnapshotState => serialize_key(Self::SNAPSHOT_STATE_PREFIX, &0_i32),
+            KeyType::Snapshot(ref s) => serialize_key(Self::SNAPSHOT_PREFIX, s),
+            KeyType::RetainedObject(ref i) => serialize_key(Self::RETAINED_OBJECT_PREFIX, i),
+            KeyType::QuotaUsage(ref kind, ref id, ref shard) => {
+                serialize_key(Self::QUOTA_USAGE_PREFIX, &(kind, id, shard))
+            }
         }
     }
 }
@@ -230,6 +510,8 @@ impl LockKeyType {
             }
             LockKeyType::VolumeInfoLock => serialize_key(101, &0_i32),
             LockKeyType::FileNodeListLock(ref file_name) => serialize_key(102, file_name),
+            LockKeyType::FileLockLock(ref file_name) => serialize_key(103, file_name),
+            LockKeyType::CheckpointLock => serialize_key(104, &0_i32),
         }
     }
 }
@@ -248,6 +530,9 @@ pub trait MetaTxn {
     /// Only when commit is called, the write operations will be executed.
     /// If the commit is successful, return true, else return false.
     async fn commit(&mut self) -> DatenLordResult<bool>;
+    /// The revision of kv engine the transaction is committed at, which is 0
+    /// until the transaction with any operation is committed successfully.
+    fn revision(&self) -> i64;
 }
 
 /// The option of 'set' operation
@@ -360,6 +645,12 @@ pub struct KeyRange {
     /// A flag that, when set to true, causes the `build` method to include all
     /// keys in the range.
     pub(crate) with_all_keys: bool,
+
+    /// The revision of the KV engine to read the keys at, 0 means the latest.
+    pub(crate) revision: i64,
+
+    /// The max number of keys to read, 0 means no limit.
+    pub(crate) limit: i64,
 }
 
 impl KeyRange {
@@ -372,6 +663,8 @@ impl KeyRange {
             range_end: Vec::new(),
             with_prefix: false,
             with_all_keys: false,
+            revision: 0,
+            limit: 0,
         }
     }
 
@@ -403,6 +696,31 @@ impl KeyRange {
         self.with_all_keys = true;
         self.with_prefix = false;
     }
+
+    /// Reads the keys at a past revision.
+    #[inline]
+    pub fn with_revision(&mut self, revision: i64) {
+        self.revision = revision;
+    }
+
+    /// Reads at most `limit` keys, which should be read at a past revision.
+    #[inline]
+    pub fn with_limit(&mut self, limit: i64) {
+        self.limit = limit;
+    }
+
+    /// Check whether the key is in the range.
+    pub(crate) fn contains(&self, key: &[u8]) -> bool {
+        if self.with_all_keys {
+            true
+        } else if self.with_prefix {
+            key.starts_with(&self.key)
+        } else if self.range_end.is_empty() {
+            key == self.key.as_slice()
+        } else {
+            self.key.as_slice() <= key && key < self.range_end.as_slice()
+        }
+    }
 }
 
 /// To support different K/V storage engines, we need to a trait to abstract the
@@ -436,8 +754,25 @@ pub trait KVEngine: Send + Sync + Debug + Sized {
     /// Lease grant
     async fn lease_grant(&self, ttl: i64) -> DatenLordResult<i64>;
 
+    /// Lease keep alive, refresh the ttl of the lease once
+    async fn lease_keep_alive(&self, lease_id: i64) -> DatenLordResult<()>;
+
     /// Range query
     async fn range(&self, key_range: KeyRange) -> DatenLordResult<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Get the current revision of the KV engine
+    async fn revision(&self) -> DatenLordResult<i64>;
+
+    /// Compact the history of the KV engine before `revision`, the revisions
+    /// before it can no longer be read
+    async fn compact(&self, revision: i64) -> DatenLordResult<()>;
+
+    /// Watch the keys with the `prefix`, the stream yields an item once some
+    /// of the keys are changed after the watch is created
+    async fn watch_prefix(
+        &self,
+        prefix: Vec<u8>,
+    ) -> DatenLordResult<BoxStream<'static, DatenLordResult<()>>>;
 }
 
 /// The version of the key.
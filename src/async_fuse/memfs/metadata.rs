@@ -8,9 +8,15 @@ use nix::sys::stat::SFlag;
 
 use super::cache::IoMemBlock;
 use super::dist::server::CacheServer;
+use super::file_lock::FileLock;
 use super::kv_engine::KVEngineType;
 use super::node::Node;
-use super::{CreateParam, RenameParam, SetAttrParam};
+use super::{
+    CopyRangeParam, CreateParam, FileLockParam, HandleSnapshot, RenameParam, SetAttrParam,
+    WriteData,
+};
+#[cfg(feature = "abi-7-21")]
+use crate::async_fuse::fuse::fuse_reply::ReplyDirectoryPlus;
 use crate::async_fuse::fuse::fuse_reply::{ReplyDirectory, StatFsParam};
 use crate::async_fuse::fuse::protocol::{FuseAttr, INum};
 use crate::common::error::DatenLordResult;
@@ -74,6 +80,13 @@ pub trait MetaData {
     /// Helper function to create node
     async fn mknod(&self, param: CreateParam) -> DatenLordResult<(Duration, FuseAttr, u64)>;
 
+    /// Helper function to create and open a file in one transaction
+    async fn create(
+        &self,
+        param: CreateParam,
+        flags: u32,
+    ) -> DatenLordResult<(Duration, FuseAttr, u64, RawFd)>;
+
     /// Helper function to remove node
     async fn remove_node_helper(
         &self,
@@ -118,13 +131,37 @@ pub trait MetaData {
         ino: u64,
         fh: u64,
         offset: i64,
-        data: Vec<u8>,
+        data: WriteData,
         flags: u32,
     ) -> DatenLordResult<usize>;
 
+    /// Allocate, punch or zero a range of a file
+    async fn fallocate(&self, ino: u64, offset: u64, len: u64, mode: u32) -> DatenLordResult<()>;
+
+    /// Find the next data or hole of a file from `offset`
+    async fn lseek(&self, ino: u64, offset: u64, whence: u32) -> DatenLordResult<u64>;
+
+    /// Copy a range of data from one file to another
+    async fn copy_file_range(&self, param: &CopyRangeParam) -> DatenLordResult<usize>;
+
     /// Set fuse fd into `MetaData`
     async fn set_fuse_fd(&self, fuse_fd: RawFd);
 
+    /// Flush the data of all the files opened on this node to the storage
+    /// backend
+    async fn flush_all(&self) -> DatenLordResult<()>;
+
+    /// Take a snapshot of the files opened and the i-nodes looked up by the
+    /// kernel of this node
+    fn handle_snapshot(&self) -> HandleSnapshot;
+
+    /// Restore the open files and lookup counts of this node from a snapshot
+    fn restore_handles(&self, snapshot: HandleSnapshot);
+
+    /// Drop the lookups held by the kernel of this node after the file system
+    /// is un-mounted, since the kernel never forgets them
+    async fn release_lookups(&self) -> DatenLordResult<()>;
+
     /// Set Node's attribute
     async fn setattr_helper(
         &self,
@@ -133,6 +170,16 @@ pub trait MetaData {
         param: &SetAttrParam,
     ) -> DatenLordResult<(Duration, FuseAttr)>;
 
+    /// Helper function to create a hard link `new_name` under `new_parent`
+    /// to the i-node of `ino`
+    async fn link(
+        &self,
+        context: ReqContext,
+        ino: INum,
+        new_parent: INum,
+        new_name: &str,
+    ) -> DatenLordResult<(Duration, FuseAttr, u64)>;
+
     /// Helper function to unlink
     async fn unlink(&self, context: ReqContext, parent: INum, name: &str) -> DatenLordResult<()>;
 
@@ -142,8 +189,10 @@ pub trait MetaData {
     /// Open a file or directory by ino and flags
     async fn open(&self, context: ReqContext, ino: u64, flags: u32) -> DatenLordResult<RawFd>;
 
-    /// Forget a i-node by ino
-    async fn forget(&self, ino: u64, nlookup: u64) -> DatenLordResult<()>;
+    /// Forget i-nodes by `(ino, nlookup)` pairs, the KV updates are batched.
+    /// A failed i-node never stops forgetting the others, the last error is
+    /// returned after all of them are tried.
+    async fn forget(&self, nodes: &[(INum, u64)]) -> DatenLordResult<()>;
 
     /// Helper function to read data
     async fn read_helper(
@@ -154,8 +203,9 @@ pub trait MetaData {
         size: u32,
     ) -> DatenLordResult<Vec<IoMemBlock>>;
 
-    /// Helper function to flush node by ino
-    async fn flush(&self, ino: u64, fh: u64) -> DatenLordResult<()>;
+    /// Helper function to flush node by ino, and release the POSIX locks held
+    /// by `lock_owner`
+    async fn flush(&self, ino: u64, fh: u64, lock_owner: u64) -> DatenLordResult<()>;
 
     /// Helper function to release dir
     async fn releasedir(&self, ino: u64, fh: u64) -> DatenLordResult<()>;
@@ -179,13 +229,60 @@ pub trait MetaData {
         reply: &mut ReplyDirectory,
     ) -> DatenLordResult<()>;
 
-    /// Helper function to release
+    /// Helper function to readdirplus
+    #[cfg(feature = "abi-7-21")]
+    async fn readdirplus(
+        &self,
+        context: ReqContext,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        reply: &mut ReplyDirectoryPlus,
+    ) -> DatenLordResult<()>;
+
+    /// Helper function to set an extended attribute
+    async fn setxattr(
+        &self,
+        context: ReqContext,
+        ino: INum,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> DatenLordResult<()>;
+
+    /// Helper function to get the value of an extended attribute
+    async fn getxattr(
+        &self,
+        context: ReqContext,
+        ino: INum,
+        name: &str,
+    ) -> DatenLordResult<Vec<u8>>;
+
+    /// Helper function to list the extended attribute names, each name is
+    /// terminated by a null byte
+    async fn listxattr(&self, context: ReqContext, ino: INum) -> DatenLordResult<Vec<u8>>;
+
+    /// Helper function to remove an extended attribute
+    async fn removexattr(&self, context: ReqContext, ino: INum, name: &str) -> DatenLordResult<()>;
+
+    /// Test for a POSIX lock, return the conflicting lock if any
+    async fn getlk(&self, ino: INum, lk_param: &FileLockParam)
+        -> DatenLordResult<Option<FileLock>>;
+
+    /// Acquire, modify or release a POSIX lock
+    async fn setlk(&self, ino: INum, lk_param: &FileLockParam, sleep: bool) -> DatenLordResult<()>;
+
+    /// Acquire, convert or release a BSD `flock` lock on the whole file
+    async fn flock(&self, ino: INum, lk_param: &FileLockParam, sleep: bool) -> DatenLordResult<()>;
+
+    /// Helper function to release, the `flock` locks of `lock_owner` are
+    /// released if it's set
     async fn release(
         &self,
         ino: u64,
         fh: u64,
         flags: u32,
-        lock_owner: u64,
+        lock_owner: Option<u64>,
         flush: bool,
     ) -> DatenLordResult<()>;
 }
@@ -1,9 +1,14 @@
 //! The implementation of user space file system
+mod acl;
 mod cache;
+/// The checkpoints of the metadata in the storage backend
+mod checkpoint;
 mod dir;
 /// distributed communication module
 pub mod dist;
+mod file_lock;
 mod fs_util;
+mod hole;
 mod id_alloc_used;
 /// The KV engine module
 #[macro_use]
@@ -11,6 +16,10 @@ pub mod kv_engine;
 /// fs metadata module
 mod metadata;
 mod node;
+/// User, group and project quotas
+mod quota;
+/// Copy-on-write reflinks of regular files
+mod reflink;
 /// fs metadata with S3 backend module
 mod s3_metadata;
 mod s3_node;
@@ -18,7 +27,12 @@ mod s3_node;
 pub mod s3_wrapper;
 /// Serializable types module
 pub mod serial;
+/// Point-in-time snapshots of the metadata
+mod snapshot;
+/// The usage of the volume reported by statfs
+mod volume_stat;
 
+use std::collections::BTreeMap;
 use std::os::unix::prelude::RawFd;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -29,6 +43,7 @@ use cache::IoMemBlock;
 use clippy_utilities::Cast;
 use datenlord::config::StorageConfig;
 use dist::server::CacheServer;
+pub use file_lock::{FileLockKind, FileLockSnapshot};
 pub use metadata::MetaData;
 use nix::errno::Errno;
 use nix::sys::stat::SFlag;
@@ -36,14 +51,20 @@ pub use s3_metadata::S3MetaData;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, warn};
 
+use self::file_lock::F_UNLCK;
 use self::kv_engine::KVEngineType;
 use crate::async_fuse::fuse::file_system::FileSystem;
+#[cfg(feature = "abi-7-21")]
+use crate::async_fuse::fuse::fuse_reply::ReplyDirectoryPlus;
 use crate::async_fuse::fuse::fuse_reply::{
     AsIoVec, ReplyAttr, ReplyBMap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyLock, ReplyOpen, ReplyStatFs, ReplyWrite, ReplyXAttr,
+    ReplyLSeek, ReplyLock, ReplyOpen, ReplyStatFs, ReplyWrite, ReplyXAttr,
 };
 use crate::async_fuse::fuse::fuse_request::Request;
+#[cfg(feature = "abi-7-16")]
+use crate::async_fuse::fuse::protocol::FuseForgetOne;
 use crate::async_fuse::fuse::protocol::{INum, FUSE_ROOT_ID};
+use crate::async_fuse::fuse::splice::Pipe;
 use crate::async_fuse::memfs::metadata::ReqContext;
 use crate::async_fuse::util::build_error_result_from_errno;
 use crate::common::error::{Context, DatenLordResult};
@@ -86,7 +107,7 @@ pub struct SetAttrParam {
 }
 
 /// Create parameters
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CreateParam {
     /// Parent directory i-number
     pub parent: INum,
@@ -138,6 +159,68 @@ pub struct FileLockParam {
     pub pid: u32,
 }
 
+/// Copy file range parameters
+#[derive(Debug)]
+pub struct CopyRangeParam {
+    /// Source file i-number
+    pub ino_in: INum,
+    /// Source file handler
+    pub fh_in: u64,
+    /// Source file offset
+    pub off_in: u64,
+    /// Destination file i-number
+    pub ino_out: INum,
+    /// Destination file handler
+    pub fh_out: u64,
+    /// Destination file offset
+    pub off_out: u64,
+    /// The length to copy
+    pub len: u64,
+    /// Copy flags
+    pub flags: u64,
+}
+
+/// The data of a write request
+#[derive(Debug)]
+pub enum WriteData {
+    /// The data copied from the request
+    Bytes(Vec<u8>),
+    /// The data of the given length left in the pipe the request is spliced
+    /// into, which is read into the cache directly
+    Pipe(Arc<Pipe>, usize),
+}
+
+impl WriteData {
+    /// The length of the data
+    #[must_use]
+    pub fn len(&self) -> usize {
+        match *self {
+            Self::Bytes(ref bytes) => bytes.len(),
+            Self::Pipe(_, len) => len,
+        }
+    }
+
+    /// Whether the data is empty
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The handles the kernel holds on the file system of this node, which are
+/// handed over to the new process on live upgrade
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandleSnapshot {
+    /// The open files, i-number -> open count
+    pub open_files: BTreeMap<INum, usize>,
+    /// The i-nodes looked up by the kernel, i-number -> lookup count
+    pub lookup_counts: BTreeMap<INum, u64>,
+    /// The next file handle to allocate
+    pub next_fh: u32,
+    /// The lease and the holders of the file locks
+    pub file_locks: FileLockSnapshot,
+}
+
 /// MAX NAME LEN
 const MAX_NAME_LEN: usize = 255;
 
@@ -152,13 +235,20 @@ pub fn check_name_length(name: &str) -> DatenLordResult<()> {
     }
 }
 
-/// Check if `file_type` is supported. We only support
+/// Check if `file_type` is supported. We support
 /// 1. Regular file
 /// 2. Directory
 /// 3. Symbolic link
+/// 4. Special files: FIFO, Unix domain socket, character and block device
 pub fn check_type_supported(file_type: &SFlag) -> DatenLordResult<()> {
     match *file_type {
-        SFlag::S_IFREG | SFlag::S_IFDIR | SFlag::S_IFLNK => Ok(()),
+        SFlag::S_IFREG
+        | SFlag::S_IFDIR
+        | SFlag::S_IFLNK
+        | SFlag::S_IFIFO
+        | SFlag::S_IFSOCK
+        | SFlag::S_IFCHR
+        | SFlag::S_IFBLK => Ok(()),
         _ => {
             error!("type = {:?} is not supported", file_type);
             build_error_result_from_errno(Errno::ENOTSUP, "type not supported".to_owned())
@@ -166,6 +256,19 @@ pub fn check_type_supported(file_type: &SFlag) -> DatenLordResult<()> {
     }
 }
 
+/// Reply the xattr value or the xattr name list following the size-probe
+/// protocol: reply the size of `data` if `size` is 0, reply ERANGE if `data`
+/// does not fit in `size`, otherwise reply `data`.
+async fn reply_xattr_helper(data: Vec<u8>, size: u32, reply: ReplyXAttr) -> nix::Result<usize> {
+    if size == 0 {
+        reply.size(data.len().cast()).await
+    } else if data.len() > size.cast() {
+        reply.error_code(Errno::ERANGE).await
+    } else {
+        reply.data(data).await
+    }
+}
+
 impl<M: MetaData + Send + Sync + 'static> MemFs<M> {
     /// Create `FileSystem`
     #[allow(clippy::too_many_arguments)]
@@ -320,10 +423,30 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
     /// inodes will receive a forget message.
     async fn forget(&self, req: &Request<'_>, nlookup: u64) {
         let ino = req.nodeid();
-        self.metadata
-            .forget(ino, nlookup)
-            .await
-            .unwrap_or_else(|e| panic!("{e}"));
+        if let Err(e) = self.metadata.forget(&[(ino, nlookup)]).await {
+            error!("forget() failed to forget ino={}, the error is: {}", ino, e);
+        }
+    }
+
+    /// Forget about multiple inodes.
+    /// The same as `forget` for every `(nodeid, nlookup)` pair, sent by the
+    /// kernel under memory pressure.
+    #[cfg(feature = "abi-7-16")]
+    async fn batch_forget(&self, req: &Request<'_>, nodes: &[FuseForgetOne]) {
+        debug!("batch_forget(count={}, req={:?})", nodes.len(), req);
+        let nodes: Vec<(INum, u64)> = nodes
+            .iter()
+            .map(|node| (node.nodeid, node.nlookup))
+            .collect();
+        // The errors of each i-node are logged, and the other i-nodes are
+        // still forgotten
+        if let Err(e) = self.metadata.forget(&nodes).await {
+            error!(
+                "batch_forget() failed to forget some of {} i-nodes, the error is: {}",
+                nodes.len(),
+                e,
+            );
+        }
     }
 
     /// Set file attributes.
@@ -626,7 +749,7 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
         req: &Request<'_>,
         fh: u64,
         offset: i64,
-        data: Vec<u8>,
+        data: WriteData,
         flags: u32,
         reply: ReplyWrite,
     ) -> nix::Result<usize> {
@@ -696,7 +819,7 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
         // close the file. This is important if used on a network
         // filesystem like NFS which flush the data/metadata on close()
         self.metadata
-            .flush(ino, fh)
+            .flush(ino, fh, lock_owner)
             .await
             .unwrap_or_else(|e| panic!("{e}"));
         reply.ok().await
@@ -710,19 +833,20 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
     /// returned to close() or munmap() which triggered the release. fh will
     /// contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value. flags will contain the same
-    /// flags as for open.
+    /// flags as for open. If `lock_owner` is set, the flock locks held by it
+    /// are released.
     async fn release(
         &self,
         req: &Request<'_>,
         fh: u64,
         flags: u32, // same as the open flags
-        lock_owner: u64,
+        lock_owner: Option<u64>,
         flush: bool,
         reply: ReplyEmpty,
     ) -> nix::Result<usize> {
         let ino = req.nodeid();
         debug!(
-            "release(ino={}, fh={}, flags={}, lock_owner={}, flush={}, req={:?})",
+            "release(ino={}, fh={}, flags={}, lock_owner={:?}, flush={}, req={:?})",
             ino, fh, flags, lock_owner, flush, req,
         );
         self.metadata
@@ -828,6 +952,41 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
         }
     }
 
+    /// Read directory with attributes.
+    /// Same as readdir, but every entry is replied together with its
+    /// attributes, so that the kernel does not need to look up each entry
+    /// afterwards. The lookup count of every replied entry is increased.
+    #[cfg(feature = "abi-7-21")]
+    async fn readdirplus(
+        &self,
+        req: &Request<'_>,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) -> nix::Result<usize> {
+        let ino = req.nodeid();
+        debug!(
+            "readdirplus(ino={}, fh={}, offset={}, req={:?})",
+            ino, fh, offset, req,
+        );
+
+        let context = ReqContext {
+            user_id: req.uid(),
+            group_id: req.gid(),
+        };
+        match self
+            .metadata
+            .readdirplus(context, ino, fh, offset, &mut reply)
+            .await
+        {
+            Ok(()) => reply.ok().await,
+            Err(e) => {
+                debug!("readdirplus() failed, the error is: {:?}", e);
+                reply.error(e).await
+            }
+        }
+    }
+
     /// Release an open directory.
     /// For every opendir call there will be exactly one releasedir call. fh
     /// will contain the value set by the opendir method, or will be
@@ -970,40 +1129,91 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
         }
     }
 
-    // Un-implemented FUSE operations
-
-    /// Interrupt another FUSE request
+    /// Interrupt another FUSE request.
+    /// The interrupted request is cancelled by the session, which replies
+    /// `EINTR` to the kernel, so nothing else to do here.
     async fn interrupt(&self, req: &Request<'_>, unique: u64) {
-        debug!("interrupt(req={:?}), cache size={}", req, 0_i32);
-        // TODO: handle FUSE_INTERRUPT
-        warn!(
-            "FUSE INTERRUPT received, request w/ unique={} interrupted",
-            unique
-        );
+        debug!("interrupt(req={:?}, unique={})", req, unique);
     }
 
+    // Un-implemented FUSE operations
+
     /// Create a hard link.
+    /// The new entry `newname` is created under the directory of
+    /// `req.nodeid()`, pointing to the existing i-node of `oldnodeid`.
     async fn link(
         &self,
-        _req: &Request<'_>,
-        _newparent: u64,
-        _newname: &str,
+        req: &Request<'_>,
+        oldnodeid: u64,
+        newname: &str,
         reply: ReplyEntry,
     ) -> nix::Result<usize> {
-        reply.error_code(Errno::ENOSYS).await
+        let newparent = req.nodeid();
+        debug!(
+            "link(oldnodeid={}, newparent={}, newname={:?}, req={:?})",
+            oldnodeid, newparent, newname, req,
+        );
+        let context = ReqContext {
+            user_id: req.uid(),
+            group_id: req.gid(),
+        };
+        let link_res = self
+            .metadata
+            .link(context, oldnodeid, newparent, newname)
+            .await
+            .add_context(format!(
+                "link() failed to link ino={oldnodeid} to name={newname:?} under parent ino={newparent}",
+            ));
+        match link_res {
+            Ok((ttl, fuse_attr, generation)) => reply.entry(ttl, fuse_attr, generation).await,
+            Err(e) => {
+                debug!(
+                    "link() failed to link ino={} to name={:?} under parent ino={}, \
+                        the error is: {:?}",
+                    oldnodeid, newname, newparent, e,
+                );
+                reply.error(e).await
+            }
+        }
     }
 
     /// Set an extended attribute.
     async fn setxattr(
         &self,
-        _req: &Request<'_>,
-        _name: &str,
-        _value: &[u8],
-        _flags: u32,
+        req: &Request<'_>,
+        name: &str,
+        value: &[u8],
+        flags: u32,
         _position: u32,
         reply: ReplyEmpty,
     ) -> nix::Result<usize> {
-        reply.error_code(Errno::ENOSYS).await
+        let ino = req.nodeid();
+        debug!(
+            "setxattr(ino={}, name={:?}, value size={}, flags={}, req={:?})",
+            ino,
+            name,
+            value.len(),
+            flags,
+            req,
+        );
+        let context = ReqContext {
+            user_id: req.uid(),
+            group_id: req.gid(),
+        };
+        match self
+            .metadata
+            .setxattr(context, ino, name, value, flags)
+            .await
+        {
+            Ok(()) => reply.ok().await,
+            Err(e) => {
+                debug!(
+                    "setxattr() failed to set xattr name={:?} of ino={}, the error is: {:?}",
+                    name, ino, e,
+                );
+                reply.error(e).await
+            }
+        }
     }
 
     /// Get an extended attribute.
@@ -1012,12 +1222,30 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
     /// `reply.data()`, or `reply.error(ERANGE)` if it doesn't.
     async fn getxattr(
         &self,
-        _req: &Request<'_>,
-        _name: &str,
-        _size: u32,
+        req: &Request<'_>,
+        name: &str,
+        size: u32,
         reply: ReplyXAttr,
     ) -> nix::Result<usize> {
-        reply.error_code(Errno::ENOSYS).await
+        let ino = req.nodeid();
+        debug!(
+            "getxattr(ino={}, name={:?}, size={}, req={:?})",
+            ino, name, size, req,
+        );
+        let context = ReqContext {
+            user_id: req.uid(),
+            group_id: req.gid(),
+        };
+        match self.metadata.getxattr(context, ino, name).await {
+            Ok(value) => reply_xattr_helper(value, size, reply).await,
+            Err(e) => {
+                debug!(
+                    "getxattr() failed to get xattr name={:?} of ino={}, the error is: {:?}",
+                    name, ino, e,
+                );
+                reply.error(e).await
+            }
+        }
     }
 
     /// List extended attribute names.
@@ -1026,21 +1254,51 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
     /// `reply.data()`, or `reply.error(ERANGE)` if it doesn't.
     async fn listxattr(
         &self,
-        _req: &Request<'_>,
-        _size: u32,
+        req: &Request<'_>,
+        size: u32,
         reply: ReplyXAttr,
     ) -> nix::Result<usize> {
-        reply.error_code(Errno::ENOSYS).await
+        let ino = req.nodeid();
+        debug!("listxattr(ino={}, size={}, req={:?})", ino, size, req);
+        let context = ReqContext {
+            user_id: req.uid(),
+            group_id: req.gid(),
+        };
+        match self.metadata.listxattr(context, ino).await {
+            Ok(names) => reply_xattr_helper(names, size, reply).await,
+            Err(e) => {
+                debug!(
+                    "listxattr() failed to list xattr of ino={}, the error is: {:?}",
+                    ino, e,
+                );
+                reply.error(e).await
+            }
+        }
     }
 
     /// Remove an extended attribute.
     async fn removexattr(
         &self,
-        _req: &Request<'_>,
-        _name: &str,
+        req: &Request<'_>,
+        name: &str,
         reply: ReplyEmpty,
     ) -> nix::Result<usize> {
-        reply.error_code(Errno::ENOSYS).await
+        let ino = req.nodeid();
+        debug!("removexattr(ino={}, name={:?}, req={:?})", ino, name, req);
+        let context = ReqContext {
+            user_id: req.uid(),
+            group_id: req.gid(),
+        };
+        match self.metadata.removexattr(context, ino, name).await {
+            Ok(()) => reply.ok().await,
+            Err(e) => {
+                debug!(
+                    "removexattr() failed to remove xattr name={:?} of ino={}, the error is: {:?}",
+                    name, ino, e,
+                );
+                reply.error(e).await
+            }
+        }
     }
 
     /// Check file access permissions.
@@ -1069,42 +1327,217 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
     /// and open() methods will be called instead.
     async fn create(
         &self,
-        _req: &Request<'_>,
-        _parent: u64,
-        _name: &str,
-        _mode: u32,
-        _flags: u32,
+        req: &Request<'_>,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        flags: u32,
         reply: ReplyCreate,
     ) -> nix::Result<usize> {
-        reply.error_code(Errno::ENOSYS).await
+        debug!(
+            "create(parent={}, name={:?}, mode={}, flags={}, req={:?})",
+            parent, name, mode, flags, req,
+        );
+        let param = CreateParam {
+            parent,
+            name: name.to_owned(),
+            mode,
+            rdev: 0,
+            uid: req.uid(),
+            gid: req.gid(),
+            node_type: SFlag::S_IFREG,
+            link: None,
+        };
+        match self.metadata.create(param, flags).await {
+            Ok((ttl, fuse_attr, generation, fd)) => {
+                // The reply takes the `FOPEN_*` flags rather than the `O_*`
+                // open flags, and none of them is needed
+                reply
+                    .created(&ttl, fuse_attr, generation, fd.cast(), 0)
+                    .await
+            }
+            Err(e) => {
+                debug!(
+                    "create() failed to create name={:?} under parent ino={}, the error is: {:?}",
+                    name, parent, e,
+                );
+                reply.error(e).await
+            }
+        }
     }
 
     /// Test for a POSIX file lock.
+    /// If there is a conflicting lock, reply with it, otherwise reply with the
+    /// requested range and `F_UNLCK`.
     async fn getlk(
         &self,
-        _req: &Request<'_>,
-        _lk_param: FileLockParam,
+        req: &Request<'_>,
+        lk_param: FileLockParam,
         reply: ReplyLock,
     ) -> nix::Result<usize> {
-        reply.error_code(Errno::ENOSYS).await
+        let ino = req.nodeid();
+        debug!("getlk(ino={}, lk_param={:?}, req={:?})", ino, lk_param, req);
+        match self.metadata.getlk(ino, &lk_param).await {
+            Ok(Some(lock)) => reply.locked(lock.start, lock.end, lock.typ, lock.pid).await,
+            Ok(None) => {
+                reply
+                    .locked(lk_param.start, lk_param.end, F_UNLCK, lk_param.pid)
+                    .await
+            }
+            Err(e) => {
+                debug!(
+                    "getlk() failed to test lock of ino={}, the error is: {:?}",
+                    ino, e,
+                );
+                reply.error(e).await
+            }
+        }
     }
 
     /// Acquire, modify or release a POSIX file lock.
     /// For POSIX threads (NPTL) there's a 1-1 relation between pid and owner,
     /// but otherwise self is not always the case.  For checking lock
     /// ownership, `fi->owner` must be used. The `l_pid` field in `struct
-    /// flock` should only be used to fill in self field in `getlk()`. Note:
-    /// if the locking methods are not implemented, the kernel will still
-    /// allow file locking to work locally. Hence these are only interesting
-    /// for network filesystems and similar.
+    /// flock` should only be used to fill in self field in `getlk()`. The
+    /// locks are coordinated across nodes via the KV engine.
     async fn setlk(
         &self,
-        _req: &Request<'_>,
-        _lk_param: FileLockParam,
-        _sleep: bool,
+        req: &Request<'_>,
+        lk_param: FileLockParam,
+        sleep: bool,
         reply: ReplyEmpty,
     ) -> nix::Result<usize> {
-        reply.error_code(Errno::ENOSYS).await
+        let ino = req.nodeid();
+        debug!(
+            "setlk(ino={}, lk_param={:?}, sleep={}, req={:?})",
+            ino, lk_param, sleep, req,
+        );
+        match self.metadata.setlk(ino, &lk_param, sleep).await {
+            Ok(()) => reply.ok().await,
+            Err(e) => {
+                debug!(
+                    "setlk() failed to set lock of ino={}, the error is: {:?}",
+                    ino, e,
+                );
+                reply.error(e).await
+            }
+        }
+    }
+
+    /// Acquire, convert or release a BSD flock lock.
+    /// The lock is on the whole file and owned by the open file description,
+    /// it never conflicts with the POSIX locks. The locks are coordinated
+    /// across nodes via the KV engine.
+    async fn flock(
+        &self,
+        req: &Request<'_>,
+        lk_param: FileLockParam,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) -> nix::Result<usize> {
+        let ino = req.nodeid();
+        debug!(
+            "flock(ino={}, lk_param={:?}, sleep={}, req={:?})",
+            ino, lk_param, sleep, req,
+        );
+        match self.metadata.flock(ino, &lk_param, sleep).await {
+            Ok(()) => reply.ok().await,
+            Err(e) => {
+                debug!(
+                    "flock() failed to set lock of ino={}, the error is: {:?}",
+                    ino, e,
+                );
+                reply.error(e).await
+            }
+        }
+    }
+
+    /// Allocate, punch or zero a range of an open file.
+    /// The `mode` is the same as the one of `fallocate(2)`, mode 0,
+    /// `FALLOC_FL_KEEP_SIZE`, `FALLOC_FL_PUNCH_HOLE` and
+    /// `FALLOC_FL_ZERO_RANGE` are supported.
+    #[cfg(feature = "abi-7-19")]
+    async fn fallocate(
+        &self,
+        req: &Request<'_>,
+        fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+        reply: ReplyEmpty,
+    ) -> nix::Result<usize> {
+        let ino = req.nodeid();
+        debug!(
+            "fallocate(ino={}, fh={}, offset={}, length={}, mode={:#x}, req={:?})",
+            ino, fh, offset, length, mode, req,
+        );
+        match self.metadata.fallocate(ino, offset, length, mode).await {
+            Ok(()) => reply.ok().await,
+            Err(e) => {
+                debug!(
+                    "fallocate() failed to allocate the file of ino={}, the error is: {:?}",
+                    ino, e,
+                );
+                reply.error(e).await
+            }
+        }
+    }
+
+    /// Find the next data or hole after the specified offset
+    async fn lseek(
+        &self,
+        req: &Request<'_>,
+        fh: u64,
+        offset: u64,
+        whence: u32,
+        reply: ReplyLSeek,
+    ) -> nix::Result<usize> {
+        let ino = req.nodeid();
+        debug!(
+            "lseek(ino={}, fh={}, offset={}, whence={}, req={:?})",
+            ino, fh, offset, whence, req,
+        );
+        match self.metadata.lseek(ino, offset, whence).await {
+            Ok(off) => reply.offset(off).await,
+            Err(e) => {
+                debug!(
+                    "lseek() failed to seek the file of ino={}, the error is: {:?}",
+                    ino, e,
+                );
+                reply.error(e).await
+            }
+        }
+    }
+
+    /// Copy a range of data from one file to another
+    async fn copy_file_range(
+        &self,
+        req: &Request<'_>,
+        param: CopyRangeParam,
+        reply: ReplyWrite,
+    ) -> nix::Result<usize> {
+        debug!("copy_file_range(param={:?}, req={:?})", param, req);
+        if param.flags != 0 {
+            return reply.error_code(Errno::EINVAL).await;
+        }
+        match self.metadata.copy_file_range(&param).await {
+            Ok(copied_size) => {
+                debug!(
+                    "copy_file_range() successfully copied {} byte data from \
+                        the file of ino={} to the file of ino={}",
+                    copied_size, param.ino_in, param.ino_out,
+                );
+                reply.written(copied_size.cast()).await
+            }
+            Err(e) => {
+                debug!(
+                    "copy_file_range() failed to copy from the file of ino={} \
+                        to the file of ino={}, the error is: {:?}",
+                    param.ino_in, param.ino_out, e,
+                );
+                reply.error(e).await
+            }
+        }
     }
 
     /// Map block index within file to block index within device.
@@ -1124,6 +1557,34 @@ impl<M: MetaData + Send + Sync + 'static> FileSystem for MemFs<M> {
     async fn set_fuse_fd(&self, fuse_fd: RawFd) {
         self.metadata.set_fuse_fd(fuse_fd).await;
     }
+
+    /// Flush the data of all the open files
+    async fn flush_all(&self) -> anyhow::Result<()> {
+        self.metadata
+            .flush_all()
+            .await
+            .add_context("failed to flush the data of the open files")?;
+        Ok(())
+    }
+
+    /// Take a snapshot of the handles the kernel holds
+    fn handle_snapshot(&self) -> HandleSnapshot {
+        self.metadata.handle_snapshot()
+    }
+
+    /// Restore the handles handed over by the previous process
+    fn restore_handles(&self, snapshot: HandleSnapshot) {
+        self.metadata.restore_handles(snapshot);
+    }
+
+    /// Drop the lookups held by the kernel after un-mount
+    async fn release_lookups(&self) -> anyhow::Result<()> {
+        self.metadata
+            .release_lookups()
+            .await
+            .add_context("failed to release the lookups of the kernel")?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1157,14 +1618,15 @@ mod test {
     #[test]
     #[allow(clippy::assertions_on_result_states)]
     fn test_support_file_type() {
-        // Currently, we only support regular file, directory,and symlink
         assert!(check_type_supported(&SFlag::S_IFREG).is_ok());
         assert!(check_type_supported(&SFlag::S_IFDIR).is_ok());
         assert!(check_type_supported(&SFlag::S_IFLNK).is_ok());
+        assert!(check_type_supported(&SFlag::S_IFBLK).is_ok());
+        assert!(check_type_supported(&SFlag::S_IFCHR).is_ok());
+        assert!(check_type_supported(&SFlag::S_IFIFO).is_ok());
+        assert!(check_type_supported(&SFlag::S_IFSOCK).is_ok());
 
-        assert!(check_type_supported(&SFlag::S_IFBLK).is_err());
-        assert!(check_type_supported(&SFlag::S_IFCHR).is_err());
-        assert!(check_type_supported(&SFlag::S_IFIFO).is_err());
-        assert!(check_type_supported(&SFlag::S_IFSOCK).is_err());
+        // Not a valid file type
+        assert!(check_type_supported(&SFlag::S_IFMT).is_err());
     }
 }
@@ -10,11 +10,11 @@ use nix::fcntl::OFlag;
 use nix::sys::stat::{Mode, SFlag};
 use parking_lot::RwLock;
 
+use super::acl::PosixAcl;
 use super::cache::{GlobalCache, IoMemBlock};
 use super::dir::DirEntry;
 use super::fs_util::FileAttr;
-use super::{CreateParam, SetAttrParam};
-use crate::async_fuse::fuse::fuse_reply::StatFsParam;
+use super::{CreateParam, SetAttrParam, WriteData};
 use crate::async_fuse::fuse::protocol::INum;
 use crate::common::error::DatenLordResult;
 
@@ -43,6 +43,10 @@ pub trait Node: Sized {
     fn set_attr(&mut self, new_attr: FileAttr) -> FileAttr;
     /// Get node attr and increase lookup count
     fn lookup_attr(&self) -> FileAttr;
+    /// Increase node hard link count, return the new count
+    fn inc_nlink(&mut self) -> u32;
+    /// Decrease node hard link count, return the new count
+    fn dec_nlink(&mut self) -> u32;
     /// Get node open count
     fn get_open_count(&self) -> i64;
     /// Decrease node open count
@@ -105,20 +109,34 @@ pub trait Node: Sized {
         gid: u32,
         global_cache: Arc<GlobalCache>,
     ) -> DatenLordResult<Self>;
+    #[allow(clippy::too_many_arguments)]
+    /// Create a FIFO, socket or device node in a directory
+    async fn create_child_special(
+        &mut self,
+        inum: INum,
+        child_name: &str,
+        node_type: SFlag,
+        mode: Mode,
+        rdev: u32,
+        uid: u32,
+        gid: u32,
+    ) -> DatenLordResult<Self>;
     /// Load data from directory, file or symlink target.
     async fn load_data(&self, offset: usize, len: usize) -> DatenLordResult<usize>;
     /// Insert directory entry for rename()
     fn insert_entry_for_rename(&mut self, child_entry: DirEntry) -> Option<DirEntry>;
     /// Remove directory entry from cache only for rename()
     fn remove_entry_for_rename(&mut self, child_name: &str) -> Option<DirEntry>;
-    /// Unlink directory entry from both cache and disk
+    /// Insert directory entry pointing to an existing i-node for link()
+    fn insert_entry_for_link(&mut self, child_name: &str, child_attr: FileAttr)
+        -> Option<DirEntry>;
+    /// Unlink directory entry from cache, the data of the removed i-node is
+    /// kept until its last link is gone
     async fn unlink_entry(&mut self, child_name: &str) -> DatenLordResult<DirEntry>;
     /// Read directory
     fn read_dir(&self, func: &mut dyn FnMut(&BTreeMap<String, DirEntry>) -> usize) -> usize;
     /// Get symlink target path
     fn get_symlink_target(&self) -> &Path;
-    /// Get fs stat
-    async fn statefs(&self) -> DatenLordResult<StatFsParam>;
     /// Get file data
     async fn get_file_data(&self, offset: usize, len: usize) -> Vec<IoMemBlock>;
     /// Write to file
@@ -126,10 +144,30 @@ pub trait Node: Sized {
         &mut self,
         fh: u64,
         offset: i64,
-        data: Vec<u8>,
+        data: &[u8],
         oflags: OFlag,
         write_to_disk: bool,
     ) -> DatenLordResult<usize>;
+    /// Update the size and the blocks of file for writing `len` bytes at
+    /// `offset`, without writing the data, which is written by `write_cache`
+    /// once the change is committed
+    async fn prepare_write(&mut self, offset: u64, len: usize) -> DatenLordResult<()>;
+    /// Write the data prepared by `prepare_write` to the cache of file
+    async fn write_cache(&self, offset: u64, data: WriteData) -> DatenLordResult<()>;
+    /// Allocate, punch or zero the range `[offset, offset + len)` of file
+    async fn fallocate(&mut self, offset: u64, len: u64, mode: u32) -> DatenLordResult<()>;
+    /// Find the next data or hole offset of file, `whence` is `SEEK_DATA` or
+    /// `SEEK_HOLE`
+    fn lseek(&self, offset: u64, whence: u32) -> DatenLordResult<u64>;
+    /// Copy the range `[src_offset, src_offset + len)` of file `src` to
+    /// `dst_offset` of this file, return the copied size
+    async fn copy_file_range(
+        &mut self,
+        src: &mut Self,
+        src_offset: u64,
+        dst_offset: u64,
+        len: u64,
+    ) -> DatenLordResult<usize>;
     /// Close file
     async fn close(&mut self, ino: INum, fh: u64, flush: bool);
     /// Close dir
@@ -140,6 +178,7 @@ pub trait Node: Sized {
         param: &SetAttrParam,
         uid: u32,
         gid: u32,
+        acl: Option<&PosixAcl>,
     ) -> DatenLordResult<(bool, FileAttr)>;
     /// Mark as deferred deletion
     fn mark_deferred_deletion(&self);
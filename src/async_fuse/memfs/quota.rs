@@ -0,0 +1,401 @@
+//! User, group and project quotas.
+//!
+//! Each i-node is charged to the quotas of its owner user, its owner group and
+//! its project, whose id is inherited from the parent directory. The limit of
+//! each quota is stored in the KV engine under `KeyType::Quota(kind, id)`. The
+//! usage is split into shards by i-number like the usage of the volume, under
+//! `KeyType::QuotaUsage(kind, id, shard)`, so the quotas shared by most files,
+//! like the ones of root and of no project, are not hot keys. The usage is
+//! updated in the same transaction as the i-nodes it counts, which also checks
+//! it against the limit: the operations growing the usage beyond a limit fail
+//! with `EDQUOT`. The shards not charged by the transaction are read out of
+//! it, so the concurrent operations in other shards may exceed a limit
+//! slightly.
+//!
+//! Only the quotas with a limit are counted, each node caches the set of them
+//! and watches it for changes. The usage of a quota is counted from all the
+//! i-nodes when its limit is set, and removed with the limit, so the changes
+//! made by the nodes not yet aware of a new limit may be missed.
+//!
+//! The quotas are administrated by the superuser through the trusted extended
+//! attributes:
+//! - `trusted.datenlord.quota.<user|group|project>.<id>` of the root directory
+//!   sets, gets or removes the limit of a quota. The value is like
+//!   `bytes=1048576,inodes=1000`, a zero or missing limit means unlimited, and
+//!   the value read also contains the usage. Listing the extended attributes of
+//!   the root directory lists all the quotas with a limit.
+//! - `trusted.datenlord.project` of a directory or a regular file sets or gets
+//!   its project id.
+
+use std::collections::BTreeMap;
+
+use clippy_utilities::{Cast, OverflowArithmetic};
+use nix::errno::Errno;
+use serde::{Deserialize, Serialize};
+
+use super::fs_util::FileAttr;
+use super::volume_stat;
+use crate::async_fuse::util::build_error_result_from_errno;
+use crate::common::error::DatenLordResult;
+
+/// The xattr name prefix of the quota limits
+pub const XATTR_PREFIX_QUOTA: &str = "trusted.datenlord.quota.";
+/// The xattr name of the project id
+pub const XATTR_NAME_PROJECT: &str = "trusted.datenlord.project";
+
+/// The kind of a quota
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QuotaKind {
+    /// Quota of the files owned by a user
+    User,
+    /// Quota of the files owned by a group
+    Group,
+    /// Quota of the files in a project
+    Project,
+}
+
+impl QuotaKind {
+    /// The name of the kind used in the xattr names
+    const fn name(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Group => "group",
+            Self::Project => "project",
+        }
+    }
+}
+
+/// The limit of a quota, zero means unlimited
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QuotaLimit {
+    /// The max bytes of the regular files
+    pub bytes: u64,
+    /// The max number of i-nodes
+    pub inodes: u64,
+}
+
+/// The usage of a quota, or a shard of it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    /// The bytes used by the regular files
+    pub bytes: u64,
+    /// The number of i-nodes
+    pub inodes: u64,
+}
+
+impl QuotaUsage {
+    /// Apply the changes of the used bytes and the number of i-nodes
+    pub fn charge(&mut self, bytes_delta: i64, inodes_delta: i64) {
+        self.bytes = self.bytes.saturating_add_signed(bytes_delta);
+        self.inodes = self.inodes.saturating_add_signed(inodes_delta);
+    }
+
+    /// Add up the usage counted in another shard
+    pub fn merge(&mut self, other: &Self) {
+        self.bytes = self.bytes.overflow_add(other.bytes);
+        self.inodes = self.inodes.overflow_add(other.inodes);
+    }
+}
+
+/// The limit and the usage of a quota
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// The limit
+    pub limit: QuotaLimit,
+    /// The usage
+    pub usage: QuotaUsage,
+}
+
+/// Whether `used` grown by `delta` exceeds `limit`
+fn exceeds(limit: u64, used: u64, delta: i64) -> bool {
+    limit != 0 && delta > 0 && used.saturating_add_signed(delta) > limit
+}
+
+impl Quota {
+    /// Check the usage grown by the deltas is within the limit, the quota is
+    /// of `kind` and `id`
+    pub fn check(
+        &self,
+        kind: QuotaKind,
+        id: u32,
+        bytes_delta: i64,
+        inodes_delta: i64,
+    ) -> DatenLordResult<()> {
+        if exceeds(self.limit.bytes, self.usage.bytes, bytes_delta)
+            || exceeds(self.limit.inodes, self.usage.inodes, inodes_delta)
+        {
+            return build_error_result_from_errno(
+                Errno::EDQUOT,
+                format!(
+                    "the {} quota of id={id} is exceeded, limit={:?}, usage={:?}",
+                    kind.name(),
+                    self.limit,
+                    self.usage,
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    /// The xattr value of the quota
+    #[must_use]
+    pub fn to_xattr(&self) -> Vec<u8> {
+        format!(
+            "bytes={},inodes={},used_bytes={},used_inodes={}",
+            self.limit.bytes, self.limit.inodes, self.usage.bytes, self.usage.inodes,
+        )
+        .into_bytes()
+    }
+}
+
+/// The quotas an i-node of `attr` is charged to
+#[must_use]
+pub const fn quota_ids(attr: &FileAttr) -> [(QuotaKind, u32); 3] {
+    [
+        (QuotaKind::User, attr.uid),
+        (QuotaKind::Group, attr.gid),
+        (QuotaKind::Project, attr.project_id),
+    ]
+}
+
+/// Count the usage of the quota of `kind` and `id` from the attributes of the
+/// i-nodes, split into the shards the i-nodes are counted in
+#[must_use]
+pub fn count_usage<'a>(
+    attrs: impl IntoIterator<Item = &'a FileAttr>,
+    kind: QuotaKind,
+    id: u32,
+) -> BTreeMap<u32, QuotaUsage> {
+    let mut usages: BTreeMap<u32, QuotaUsage> = BTreeMap::new();
+    for attr in attrs {
+        if quota_ids(attr).contains(&(kind, id)) {
+            usages
+                .entry(volume_stat::shard_of(attr.ino))
+                .or_default()
+                .charge(volume_stat::used_bytes(attr).cast(), 1);
+        }
+    }
+    usages
+}
+
+/// The xattr name of the quota of `kind` and `id`
+#[must_use]
+pub fn quota_xattr_name(kind: QuotaKind, id: u32) -> String {
+    format!("{XATTR_PREFIX_QUOTA}{}.{id}", kind.name())
+}
+
+/// Build the `EINVAL` error of an invalid quota xattr
+fn invalid_xattr<T>(name: &str) -> DatenLordResult<T> {
+    build_error_result_from_errno(
+        Errno::EINVAL,
+        format!("invalid name or value of quota xattr name={name:?}"),
+    )
+}
+
+/// Parse the xattr name of a quota, which starts with `XATTR_PREFIX_QUOTA`
+pub fn parse_quota_xattr_name(name: &str) -> DatenLordResult<(QuotaKind, u32)> {
+    let Some((kind, id)) = name
+        .strip_prefix(XATTR_PREFIX_QUOTA)
+        .and_then(|suffix| suffix.split_once('.'))
+    else {
+        return invalid_xattr(name);
+    };
+    let kind = match kind {
+        "user" => QuotaKind::User,
+        "group" => QuotaKind::Group,
+        "project" => QuotaKind::Project,
+        _ => return invalid_xattr(name),
+    };
+    id.parse()
+        .map_or_else(|_| invalid_xattr(name), |id| Ok((kind, id)))
+}
+
+/// Parse the xattr value of a quota limit, like `bytes=1048576,inodes=1000`
+pub fn parse_quota_limit(name: &str, value: &[u8]) -> DatenLordResult<QuotaLimit> {
+    let Ok(value) = std::str::from_utf8(value) else {
+        return invalid_xattr(name);
+    };
+    let mut limit = QuotaLimit::default();
+    for item in value.trim().split(',').filter(|item| !item.is_empty()) {
+        let Some((key, number)) = item.split_once('=') else {
+            return invalid_xattr(name);
+        };
+        let Ok(number) = number.trim().parse() else {
+            return invalid_xattr(name);
+        };
+        match key.trim() {
+            "bytes" => limit.bytes = number,
+            "inodes" => limit.inodes = number,
+            _ => return invalid_xattr(name),
+        }
+    }
+    Ok(limit)
+}
+
+/// Parse the xattr value of a project id
+pub fn parse_project_id(value: &[u8]) -> DatenLordResult<u32> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .map_or_else(|| invalid_xattr(XATTR_NAME_PROJECT), Ok)
+}
+
+/// The sum of two deltas
+#[must_use]
+pub fn add_delta(left: (i64, i64), right: (i64, i64)) -> (i64, i64) {
+    (left.0.overflow_add(right.0), left.1.overflow_add(right.1))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{Quota, QuotaKind, QuotaLimit, QuotaUsage};
+    use crate::async_fuse::memfs::fs_util::FileAttr;
+
+    #[test]
+    #[allow(clippy::assertions_on_result_states)]
+    fn test_quota_check_and_charge() {
+        let mut quota = Quota {
+            limit: QuotaLimit {
+                bytes: 100,
+                inodes: 2,
+            },
+            ..Quota::default()
+        };
+        assert!(quota.check(QuotaKind::User, 1000, 100, 1).is_ok());
+        quota.usage.charge(100, 1);
+        assert!(quota.check(QuotaKind::User, 1000, 1, 0).is_err());
+        assert!(quota.check(QuotaKind::User, 1000, 0, 1).is_ok());
+        quota.usage.charge(0, 1);
+        assert!(quota.check(QuotaKind::User, 1000, 0, 1).is_err());
+        // Releasing the usage always succeeds
+        assert!(quota.check(QuotaKind::User, 1000, -50, -1).is_ok());
+        quota.usage.charge(-50, -1);
+        assert_eq!(quota.usage.bytes, 50);
+        assert_eq!(quota.usage.inodes, 1);
+
+        // The usage of the shards adds up
+        let mut usage = QuotaUsage::default();
+        usage.merge(&quota.usage);
+        usage.merge(&QuotaUsage {
+            bytes: 10,
+            inodes: 1,
+        });
+        assert_eq!(
+            usage,
+            QuotaUsage {
+                bytes: 60,
+                inodes: 2,
+            }
+        );
+
+        // Zero is unlimited
+        let quota = Quota::default();
+        assert!(quota.check(QuotaKind::Group, 1000, i64::MAX, 1).is_ok());
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_result_states)]
+    fn test_quota_xattr() {
+        let name = super::quota_xattr_name(QuotaKind::Project, 42);
+        assert_eq!(name, "trusted.datenlord.quota.project.42");
+        assert_eq!(
+            super::parse_quota_xattr_name(&name).ok(),
+            Some((QuotaKind::Project, 42))
+        );
+        assert!(super::parse_quota_xattr_name("trusted.datenlord.quota.user").is_err());
+        assert!(super::parse_quota_xattr_name("trusted.datenlord.quota.dir.1").is_err());
+        assert!(super::parse_quota_xattr_name("trusted.datenlord.quota.user.x").is_err());
+
+        let limit = super::parse_quota_limit(&name, b"bytes=1048576, inodes=1000\n").ok();
+        assert_eq!(
+            limit,
+            Some(QuotaLimit {
+                bytes: 1_048_576,
+                inodes: 1000,
+            })
+        );
+        let limit = super::parse_quota_limit(&name, b"inodes=10").ok();
+        assert_eq!(
+            limit,
+            Some(QuotaLimit {
+                bytes: 0,
+                inodes: 10,
+            })
+        );
+        assert!(super::parse_quota_limit(&name, b"files=10").is_err());
+        assert!(super::parse_quota_limit(&name, b"bytes=-1").is_err());
+
+        assert_eq!(super::parse_project_id(b"7\n").ok(), Some(7));
+        assert!(super::parse_project_id(b"project").is_err());
+    }
+
+    #[test]
+    fn test_quota_ids() {
+        let attr = FileAttr {
+            uid: 1000,
+            gid: 2000,
+            project_id: 3,
+            ..FileAttr::default()
+        };
+        assert_eq!(
+            super::quota_ids(&attr),
+            [
+                (QuotaKind::User, 1000),
+                (QuotaKind::Group, 2000),
+                (QuotaKind::Project, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_count_usage() {
+        let attrs = [
+            FileAttr {
+                ino: 2,
+                size: 100,
+                uid: 1000,
+                ..FileAttr::default()
+            },
+            FileAttr {
+                ino: 3,
+                size: 5000,
+                uid: 1000,
+                ..FileAttr::default()
+            },
+            FileAttr {
+                ino: 66,
+                uid: 1000,
+                ..FileAttr::default()
+            },
+            FileAttr {
+                ino: 4,
+                size: 100,
+                uid: 1001,
+                ..FileAttr::default()
+            },
+        ];
+        // The usage is counted in the shards of the i-nodes, in whole blocks
+        assert_eq!(
+            super::count_usage(&attrs, QuotaKind::User, 1000),
+            BTreeMap::from([
+                (
+                    2,
+                    QuotaUsage {
+                        bytes: 4096,
+                        inodes: 2,
+                    }
+                ),
+                (
+                    3,
+                    QuotaUsage {
+                        bytes: 8192,
+                        inodes: 1,
+                    }
+                ),
+            ])
+        );
+        assert!(super::count_usage(&attrs, QuotaKind::Group, 1000).is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+//! Copy-on-write reflinks of regular files.
+//!
+//! The data of a regular file is stored in its own S3 object, except the
+//! ranges shared with other files, whose data is stored in shared S3 objects.
+//! Copying a block aligned range by `copy_file_range` clones the range instead
+//! of copying the data: the own S3 object of the source file is frozen and
+//! shared by the extents of both files, and the source file uploads to a new
+//! own S3 object afterwards. The written, punched or truncated blocks are no
+//! longer shared, they belong to the own S3 object of the file again.
+//!
+//! The number of i-nodes referencing a shared S3 object is stored in the KV
+//! engine under `KeyType::SharedObject(object)`, and updated in the same
+//! transaction as the i-nodes. The shared S3 object is deleted when no i-node
+//! references it anymore.
+//!
+//! `FICLONE` and `FICLONERANGE` are handled by the kernel, which fails them
+//! with `EOPNOTSUPP` for FUSE, so the reflinks are only made by
+//! `copy_file_range`.
+
+use std::collections::BTreeSet;
+
+use clippy_utilities::OverflowArithmetic;
+use serde::{Deserialize, Serialize};
+
+use crate::async_fuse::fuse::protocol::INum;
+
+/// A range `[start, end)` of a file whose data is stored in a shared S3 object
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SharedExtent {
+    /// The start offset in the file
+    start: u64,
+    /// The end offset in the file
+    end: u64,
+    /// The shared S3 object
+    object: INum,
+    /// The offset of `start` in the shared S3 object
+    object_offset: u64,
+}
+
+/// The extent map of a regular file, the shared extents are sorted and not
+/// overlapped
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct ExtentMap {
+    /// The own S3 object of the file, `None` means the i-number of the file
+    object: Option<INum>,
+    /// The shared extents of the file
+    shared: Vec<SharedExtent>,
+    /// The snapshot generation the own S3 object is uploaded at
+    #[serde(default)]
+    object_generation: u64,
+}
+
+impl ExtentMap {
+    /// The own S3 object of the file of `ino`
+    #[must_use]
+    pub fn object(&self, ino: INum) -> INum {
+        self.object.unwrap_or(ino)
+    }
+
+    /// Switch the own S3 object of the file to `object`
+    pub fn set_object(&mut self, object: INum) {
+        self.object = Some(object);
+    }
+
+    /// The snapshot generation the own S3 object is uploaded at
+    #[must_use]
+    pub const fn object_generation(&self) -> u64 {
+        self.object_generation
+    }
+
+    /// Record the snapshot generation the own S3 object is uploaded at
+    pub fn set_object_generation(&mut self, generation: u64) {
+        self.object_generation = generation;
+    }
+
+    /// The shared S3 objects referenced by the file
+    #[must_use]
+    pub fn shared_objects(&self) -> BTreeSet<INum> {
+        self.shared.iter().map(|extent| extent.object).collect()
+    }
+
+    /// The ranges of the shared extents
+    #[must_use]
+    pub fn shared_ranges(&self) -> Vec<(u64, u64)> {
+        self.shared
+            .iter()
+            .map(|extent| (extent.start, extent.end))
+            .collect()
+    }
+
+    /// Split `[start, end)` into segments of `(start, end, shared)`, where
+    /// `shared` is the shared S3 object and the offset of `start` in it, the
+    /// segments out of the shared extents are stored in the own S3 object
+    #[must_use]
+    pub fn segments(&self, start: u64, end: u64) -> Vec<(u64, u64, Option<(INum, u64)>)> {
+        let mut segments = Vec::new();
+        if start >= end {
+            return segments;
+        }
+        let mut cur = start;
+        for extent in &self.shared {
+            if extent.end <= cur {
+                continue;
+            }
+            if extent.start >= end {
+                break;
+            }
+            if extent.start > cur {
+                segments.push((cur, extent.start, None));
+            }
+            let seg_start = extent.start.max(cur);
+            let seg_end = extent.end.min(end);
+            let object_offset = extent
+                .object_offset
+                .overflow_add(seg_start.overflow_sub(extent.start));
+            segments.push((seg_start, seg_end, Some((extent.object, object_offset))));
+            cur = seg_end;
+        }
+        if cur < end {
+            segments.push((cur, end, None));
+        }
+        segments
+    }
+
+    /// Stop sharing `[start, end)`, the range belongs to the own S3 object
+    pub fn unshare(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let mut result = Vec::with_capacity(self.shared.len().overflow_add(1));
+        for &extent in &self.shared {
+            if extent.end <= start || extent.start >= end {
+                result.push(extent);
+                continue;
+            }
+            if extent.start < start {
+                result.push(SharedExtent {
+                    end: start,
+                    ..extent
+                });
+            }
+            if extent.end > end {
+                result.push(SharedExtent {
+                    start: end,
+                    object_offset: extent
+                        .object_offset
+                        .overflow_add(end.overflow_sub(extent.start)),
+                    ..extent
+                });
+            }
+        }
+        self.shared = result;
+    }
+
+    /// Share `[start, end)` with `object`, whose data is at `object_offset` of
+    /// the shared S3 object
+    pub fn share(&mut self, start: u64, end: u64, object: INum, object_offset: u64) {
+        if start >= end {
+            return;
+        }
+        self.unshare(start, end);
+        let pos = self.shared.partition_point(|extent| extent.start < start);
+        self.shared.insert(
+            pos,
+            SharedExtent {
+                start,
+                end,
+                object,
+                object_offset,
+            },
+        );
+    }
+
+    /// Drop the shared extents beyond `size`
+    pub fn truncate(&mut self, size: u64) {
+        self.unshare(size, u64::MAX);
+    }
+}
+
+/// The changes of the reference counts of the shared S3 objects when the
+/// shared S3 objects referenced by an i-node change from `old` to `new`
+#[must_use]
+pub fn ref_deltas(old: &BTreeSet<INum>, new: &BTreeSet<INum>) -> Vec<(INum, i64)> {
+    old.difference(new)
+        .map(|&object| (object, -1))
+        .chain(new.difference(old).map(|&object| (object, 1)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::ExtentMap;
+
+    #[test]
+    fn test_extent_map_share_unshare() {
+        let mut extents = ExtentMap::default();
+        assert_eq!(extents.object(5), 5);
+        extents.set_object(9);
+        assert_eq!(extents.object(5), 9);
+        assert_eq!(extents.segments(0, 100), vec![(0, 100, None)]);
+
+        extents.share(40, 80, 7, 0);
+        extents.share(0, 20, 8, 100);
+        assert_eq!(extents.shared_ranges(), vec![(0, 20), (40, 80)]);
+        assert_eq!(extents.shared_objects(), BTreeSet::from([7, 8]));
+        assert_eq!(
+            extents.segments(10, 100),
+            vec![
+                (10, 20, Some((8, 110))),
+                (20, 40, None),
+                (40, 80, Some((7, 0))),
+                (80, 100, None),
+            ]
+        );
+
+        // Unsharing the middle of an extent splits it
+        extents.unshare(50, 60);
+        assert_eq!(
+            extents.segments(45, 70),
+            vec![
+                (45, 50, Some((7, 5))),
+                (50, 60, None),
+                (60, 70, Some((7, 20)))
+            ]
+        );
+        // Sharing a range again replaces the shared extents in it
+        extents.share(10, 55, 6, 0);
+        assert_eq!(extents.shared_ranges(), vec![(0, 10), (10, 55), (60, 80)]);
+        assert_eq!(extents.shared_objects(), BTreeSet::from([6, 7, 8]));
+
+        extents.truncate(65);
+        assert_eq!(
+            extents.segments(60, 80),
+            vec![(60, 65, Some((7, 20))), (65, 80, None)]
+        );
+        extents.truncate(5);
+        assert_eq!(extents.shared_objects(), BTreeSet::from([8]));
+        assert!(extents.segments(5, 5).is_empty());
+    }
+
+    #[test]
+    fn test_ref_deltas() {
+        let old = BTreeSet::from([1, 2, 3]);
+        let new = BTreeSet::from([2, 3, 4]);
+        assert_eq!(super::ref_deltas(&old, &new), vec![(1, -1), (4, 1)]);
+        assert!(super::ref_deltas(&old, &old).is_empty());
+    }
+}
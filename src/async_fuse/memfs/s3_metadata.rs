@@ -1,35 +1,50 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::io::RawFd;
-use std::sync::atomic::AtomicU32;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
 use clippy_utilities::{Cast, OverflowArithmetic};
 use datenlord::config::{StorageConfig, StorageParams};
+use futures::StreamExt;
 use nix::errno::Errno;
 use nix::sys::stat::SFlag;
-use parking_lot::RwLock as SyncRwLock; // conflict with tokio RwLock
+use parking_lot::{Mutex as SyncMutex, RwLock as SyncRwLock}; // conflict with tokio locks
 use tokio::sync::Mutex;
-use tracing::{debug, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 
+use super::acl::{PosixAcl, XATTR_NAME_POSIX_ACL_ACCESS, XATTR_NAME_POSIX_ACL_DEFAULT};
 use super::cache::{GlobalCache, IoMemBlock};
+use super::checkpoint::{MetaCheckpoint, CHECKPOINT_OBJECT_NAME};
 use super::dir::DirEntry;
 use super::dist::client as dist_client;
+use super::dist::id_alloc::IdType;
 use super::dist::server::CacheServer;
+use super::file_lock::{FileLock, FileLockKind, FileLockManager};
 use super::fs_util::{self, FileAttr, NEED_CHECK_PERM};
 use super::id_alloc_used::INumAllocator;
-use super::kv_engine::{KVEngine, KVEngineType, KeyType, MetaTxn, ValueType};
+use super::kv_engine::{
+    kv_utils, KVEngine, KVEngineType, KeyType, LockKeyType, MetaTxn, ValueType,
+};
 use super::metadata::{error, MetaData, ReqContext};
 use super::node::Node;
-use super::s3_node::S3Node;
+use super::quota::{self, Quota, QuotaKind, QuotaLimit, QuotaUsage, XATTR_NAME_PROJECT};
+use super::s3_node::{self, S3Node, FALLOC_FL_KEEP_SIZE};
 use super::s3_wrapper::S3BackEnd;
-use super::{check_type_supported, CreateParam, RenameParam, SetAttrParam};
+use super::serial::{self, SerialNode};
+use super::snapshot::{self, SnapshotInfo, SnapshotState};
+use super::volume_stat::{self, VolumeStat};
+use super::{
+    check_type_supported, reflink, CopyRangeParam, CreateParam, FileLockParam, HandleSnapshot,
+    RenameParam, SetAttrParam, WriteData,
+};
 #[cfg(feature = "abi-7-18")]
 use crate::async_fuse::fuse::fuse_reply::FuseDeleteNotification;
+#[cfg(feature = "abi-7-21")]
+use crate::async_fuse::fuse::fuse_reply::ReplyDirectoryPlus;
 use crate::async_fuse::fuse::fuse_reply::{ReplyDirectory, StatFsParam};
 use crate::async_fuse::fuse::protocol::{FuseAttr, INum, FUSE_ROOT_ID};
 use crate::async_fuse::memfs::check_name_length;
@@ -53,6 +68,31 @@ const MY_GENERATION: u64 = 1; // TODO: find a proper way to set generation
 #[allow(dead_code)]
 /// The limit of transaction commit retrying times.
 const TXN_RETRY_LIMIT: u32 = 5;
+/// The max length of an extended attribute name
+const XATTR_NAME_MAX: usize = 255;
+/// The max size of an extended attribute value
+const XATTR_SIZE_MAX: usize = 65536;
+/// Perform a pure create, which fails if the named attribute exists already
+const XATTR_CREATE: u32 = 1;
+/// Perform a pure replace operation, which fails if the named attribute does
+/// not already exist
+const XATTR_REPLACE: u32 = 2;
+/// The max number of i-nodes to forget in one transaction, to keep the
+/// transaction under the operation limit of the KV engine
+const FORGET_BATCH_SIZE: usize = 32;
+/// The interval to watch the quota limits again after the watch fails
+const QUOTA_WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the data of an i-node is flushed by `flush_node`
+#[derive(Debug, Clone, Copy)]
+enum FlushKind {
+    /// Flush the data written through a file handle
+    Flush(u64),
+    /// Close a file handle, and flush the data written through it if asked
+    Close(u64, bool),
+    /// Flush all the data of the file
+    All,
+}
 
 /// File system in-memory meta-data
 #[derive(Debug)]
@@ -62,18 +102,27 @@ pub struct S3MetaData<S: S3BackEnd + Send + Sync + 'static> {
     pub(crate) s3_backend: Arc<S>,
     /// Global data cache
     pub(crate) data_cache: Arc<GlobalCache>,
-    /// Current available fd, it'll increase after using
-    pub(crate) cur_fd: AtomicU32,
     /// Current service id
     pub(crate) node_id: Arc<str>,
     /// Storage config
     pub(crate) storage_config: Arc<StorageConfig>,
-    /// Fuse fd
-    fuse_fd: Mutex<RawFd>,
+    /// Fuse fd, shared with the cache server to notify the kernel
+    fuse_fd: Arc<Mutex<RawFd>>,
     /// KV engine
     pub(crate) kv_engine: Arc<KVEngineType>,
     /// Inum allocator
     inum_allocator: INumAllocator<KVEngineType>,
+    /// Cluster-wide POSIX lock manager
+    file_lock_manager: Arc<FileLockManager>,
+    /// The files opened on this node, i-number -> open count, their data is
+    /// flushed on shutdown
+    open_files: SyncMutex<BTreeMap<INum, usize>>,
+    /// The i-nodes looked up by the kernel of this node, i-number -> lookup
+    /// count, they are handed over to the new process on live upgrade
+    lookup_counts: SyncMutex<BTreeMap<INum, u64>>,
+    /// The quotas with a limit, only whose usage is counted, kept up to date
+    /// by watching the limits in kv engine
+    limited_quotas: SyncRwLock<BTreeSet<(QuotaKind, u32)>>,
 }
 
 #[async_trait]
@@ -86,19 +135,16 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
         ino: u64,
         fh: u64,
         _flags: u32,
-        _lock_owner: u64,
+        lock_owner: Option<u64>,
         flush: bool,
     ) -> DatenLordResult<()> {
-        retry_txn!(TXN_RETRY_LIMIT, {
-            let mut txn = self.kv_engine.new_meta_txn().await;
-            let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
-            inode.close(ino, fh, flush).await;
-            txn.set(
-                &KeyType::INum2Node(ino),
-                &ValueType::Node(inode.into_serial_node()),
-            );
-            (txn.commit().await, ())
-        })?;
+        if let Some(lock_owner) = lock_owner {
+            self.file_lock_manager
+                .release_owner_locks(ino, lock_owner, FileLockKind::Flock)
+                .await?;
+        }
+        self.flush_node(ino, FlushKind::Close(fh, flush)).await?;
+        self.remove_open_file(ino);
         Ok(())
     }
 
@@ -140,9 +186,8 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
             .get_node_from_kv_engine(ino)
             .await?
             .ok_or_else(|| build_inconsistent_fs!(ino))?;
-        inode
-            .get_attr()
-            .check_perm(context.user_id, context.group_id, 5)?;
+        self.check_perm_with_acl(&inode, context.user_id, context.group_id, 5)
+            .await?;
         if inode.need_load_dir_data() {
             inode.load_data(0_usize, 0_usize).await?;
         }
@@ -157,13 +202,282 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
         Ok(())
     }
 
+    #[cfg(feature = "abi-7-21")]
+    #[instrument(skip(self), err, ret)]
+    async fn readdirplus(
+        &self,
+        context: ReqContext,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        reply: &mut ReplyDirectoryPlus,
+    ) -> DatenLordResult<()> {
+        let inode = self
+            .get_node_from_kv_engine(ino)
+            .await?
+            .ok_or_else(|| build_inconsistent_fs!(ino))?;
+        self.check_perm_with_acl(&inode, context.user_id, context.group_id, 5)
+            .await?;
+        if inode.need_load_dir_data() {
+            inode.load_data(0_usize, 0_usize).await?;
+        }
+
+        // Collect the children first, their attributes are fetched from the KV
+        // engine afterwards
+        let mut child_entries = Vec::new();
+        let mut readdirplus_helper = |data: &BTreeMap<String, DirEntry>| -> usize {
+            for (i, (child_name, child_entry)) in data.iter().enumerate().skip(offset.cast()) {
+                child_entries.push((
+                    child_entry.ino(),
+                    i.cast::<i64>().overflow_add(1), // i + 1 means the index of the next entry
+                    child_name.clone(),
+                ));
+            }
+            child_entries.len()
+        };
+        inode.read_dir(&mut readdirplus_helper);
+
+        let ttl = Duration::new(MY_TTL_SEC, 0);
+        let mut num_child_entries = 0_usize;
+        for (child_ino, child_offset, child_name) in child_entries {
+            let child_node = self
+                .get_node_from_kv_engine(child_ino)
+                .await?
+                .ok_or_else(|| build_inconsistent_fs!(child_ino))?;
+            let attr = child_node.lookup_attr();
+            if reply.add(
+                child_offset,
+                ttl,
+                fs_util::convert_to_fuse_attr(attr),
+                MY_GENERATION,
+                &child_name,
+            ) {
+                // The reply buffer is full, the rest entries will be read by the next
+                // readdirplus call, so do not increase their lookup count
+                break;
+            }
+            // The kernel increases the lookup count of each entry replied by readdirplus
+            self.set_node_to_kv_engine(child_ino, child_node).await?;
+            self.add_lookup(child_ino);
+            num_child_entries = num_child_entries.overflow_add(1);
+            debug!(
+                "readdirplus() found one child of ino={}, name={:?}, offset={} \
+                    under the directory of ino={}",
+                child_ino, child_name, child_offset, ino,
+            );
+        }
+        debug!(
+            "readdirplus() successfully read {} entries \
+                under the directory of ino={} and name={:?}",
+            num_child_entries,
+            ino,
+            inode.get_name(),
+        );
+        Ok(())
+    }
+
+    #[instrument(skip(self, value), err, ret)]
+    async fn setxattr(
+        &self,
+        context: ReqContext,
+        ino: INum,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> DatenLordResult<()> {
+        if value.len() > XATTR_SIZE_MAX {
+            return build_error_result_from_errno(
+                Errno::E2BIG,
+                format!(
+                    "setxattr() found the value size={} of xattr name={name:?} exceeds {XATTR_SIZE_MAX}",
+                    value.len(),
+                ),
+            );
+        }
+        if Self::is_quota_xattr(name) {
+            return self.set_quota_xattr(context, ino, name, Some(value)).await;
+        }
+        if name.starts_with(snapshot::XATTR_PREFIX_SNAPSHOT) {
+            return self.set_snapshot_xattr(context, ino, name, true).await;
+        }
+        retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
+            Self::xattr_pre_check(&context, &inode, name, 2)?;
+            let mut xattrs = Self::get_xattrs_from_txn(txn.as_mut(), ino).await?;
+            let exists = xattrs.contains_key(name);
+            if flags & XATTR_CREATE != 0 && exists {
+                return build_error_result_from_errno(
+                    Errno::EEXIST,
+                    format!("setxattr() found xattr name={name:?} already exists in ino={ino}"),
+                );
+            }
+            if flags & XATTR_REPLACE != 0 && !exists {
+                return build_error_result_from_errno(
+                    Errno::ENODATA,
+                    format!(
+                        "setxattr() cannot replace the missing xattr name={name:?} in ino={ino}"
+                    ),
+                );
+            }
+            if name == XATTR_NAME_POSIX_ACL_ACCESS || name == XATTR_NAME_POSIX_ACL_DEFAULT {
+                Self::set_acl_helper(&mut inode, &mut xattrs, name, value)?;
+            } else {
+                xattrs.insert(name.to_owned(), value.to_vec());
+            }
+            let mut attr = inode.get_attr();
+            attr.ctime = SystemTime::now();
+            inode.set_attr(attr);
+            if xattrs.is_empty() {
+                txn.delete(&KeyType::INum2XAttr(ino));
+            } else {
+                txn.set(&KeyType::INum2XAttr(ino), &ValueType::XAttr(xattrs));
+            }
+            txn.set(
+                &KeyType::INum2Node(ino),
+                &ValueType::Node(inode.into_serial_node()),
+            );
+            (txn.commit().await, ())
+        })?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), err)]
+    async fn getxattr(
+        &self,
+        context: ReqContext,
+        ino: INum,
+        name: &str,
+    ) -> DatenLordResult<Vec<u8>> {
+        let inode = self
+            .get_node_from_kv_engine(ino)
+            .await?
+            .ok_or_else(|| build_inconsistent_fs!(ino))?;
+        Self::xattr_pre_check(&context, &inode, name, 4)?;
+        if Self::is_quota_xattr(name) {
+            return self.get_quota_xattr(&inode, name).await;
+        }
+        if name.starts_with(snapshot::XATTR_PREFIX_SNAPSHOT) {
+            return self.get_snapshot_xattr(&inode, name).await;
+        }
+        let mut xattrs = self.get_xattrs_from_kv_engine(ino).await?;
+        xattrs.remove(name).map_or_else(
+            || {
+                build_error_result_from_errno(
+                    Errno::ENODATA,
+                    format!("getxattr() failed to find xattr name={name:?} in ino={ino}"),
+                )
+            },
+            Ok,
+        )
+    }
+
+    #[instrument(skip(self), err)]
+    async fn listxattr(&self, context: ReqContext, ino: INum) -> DatenLordResult<Vec<u8>> {
+        let inode = self
+            .get_node_from_kv_engine(ino)
+            .await?
+            .ok_or_else(|| build_inconsistent_fs!(ino))?;
+        self.check_perm_with_acl(&inode, context.user_id, context.group_id, 4)
+            .await?;
+        let mut xattr_names: Vec<String> = self
+            .get_xattrs_from_kv_engine(ino)
+            .await?
+            .into_keys()
+            .collect();
+        // The virtual xattrs of the quotas
+        if inode.get_attr().project_id != 0 {
+            xattr_names.push(XATTR_NAME_PROJECT.to_owned());
+        }
+        if ino == FUSE_ROOT_ID {
+            for (kind, id, limit) in kv_utils::list_quota_limits(&self.kv_engine).await? {
+                if limit != QuotaLimit::default() {
+                    xattr_names.push(quota::quota_xattr_name(kind, id));
+                }
+            }
+            for (name, _) in kv_utils::list_snapshots(&self.kv_engine).await? {
+                xattr_names.push(snapshot::snapshot_xattr_name(&name));
+            }
+        }
+        let mut names = Vec::new();
+        for name in xattr_names {
+            // Only the superuser can see the trusted xattrs
+            if NEED_CHECK_PERM && context.user_id != 0 && name.starts_with("trusted.") {
+                continue;
+            }
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    #[instrument(skip(self), err, ret)]
+    async fn removexattr(&self, context: ReqContext, ino: INum, name: &str) -> DatenLordResult<()> {
+        if Self::is_quota_xattr(name) {
+            return self.set_quota_xattr(context, ino, name, None).await;
+        }
+        if name.starts_with(snapshot::XATTR_PREFIX_SNAPSHOT) {
+            return self.set_snapshot_xattr(context, ino, name, false).await;
+        }
+        retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
+            Self::xattr_pre_check(&context, &inode, name, 2)?;
+            let mut xattrs = Self::get_xattrs_from_txn(txn.as_mut(), ino).await?;
+            if xattrs.remove(name).is_none() {
+                return build_error_result_from_errno(
+                    Errno::ENODATA,
+                    format!("removexattr() failed to find xattr name={name:?} in ino={ino}"),
+                );
+            }
+            if xattrs.is_empty() {
+                txn.delete(&KeyType::INum2XAttr(ino));
+            } else {
+                txn.set(&KeyType::INum2XAttr(ino), &ValueType::XAttr(xattrs));
+            }
+            let mut attr = inode.get_attr();
+            attr.ctime = SystemTime::now();
+            inode.set_attr(attr);
+            txn.set(
+                &KeyType::INum2Node(ino),
+                &ValueType::Node(inode.into_serial_node()),
+            );
+            (txn.commit().await, ())
+        })?;
+        Ok(())
+    }
+
+    #[instrument(skip(self), err, ret)]
+    async fn getlk(
+        &self,
+        ino: INum,
+        lk_param: &FileLockParam,
+    ) -> DatenLordResult<Option<FileLock>> {
+        self.file_lock_manager.getlk(ino, lk_param).await
+    }
+
+    #[instrument(skip(self), err, ret)]
+    async fn setlk(&self, ino: INum, lk_param: &FileLockParam, sleep: bool) -> DatenLordResult<()> {
+        self.file_lock_manager
+            .setlk(ino, lk_param, FileLockKind::Posix, sleep)
+            .await
+    }
+
+    #[instrument(skip(self), err, ret)]
+    async fn flock(&self, ino: INum, lk_param: &FileLockParam, sleep: bool) -> DatenLordResult<()> {
+        self.file_lock_manager
+            .setlk(ino, lk_param, FileLockKind::Flock, sleep)
+            .await
+    }
+
     #[instrument(skip(self), err, ret)]
     async fn opendir(&self, context: ReqContext, ino: u64, flags: u32) -> DatenLordResult<RawFd> {
         let result = retry_txn!(TXN_RETRY_LIMIT, {
             let mut txn = self.kv_engine.new_meta_txn().await;
             let node = self.get_inode_from_txn(txn.as_mut(), ino).await?;
             let o_flags = fs_util::parse_oflag(flags);
-            node.open_pre_check(o_flags, context.user_id, context.group_id)?;
+            let acl = Self::get_access_acl_from_txn(txn.as_mut(), ino).await?;
+            node.open_pre_check(o_flags, context.user_id, context.group_id, acl.as_ref())?;
 
             let result = node.dup_fd(o_flags).await?;
             txn.set(
@@ -190,24 +504,26 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
             .get_node_from_kv_engine(ino)
             .await?
             .ok_or_else(|| build_inconsistent_fs!(ino))?;
-        node.get_attr()
-            .check_perm(context.user_id, context.group_id, 5)?;
-        node.statefs().await
+        self.check_perm_with_acl(&node, context.user_id, context.group_id, 5)
+            .await?;
+        let stat = kv_utils::get_volume_stat(&self.kv_engine)
+            .await
+            .add_context(format!(
+                "{}() failed to get the usage of the volume from kv engine",
+                function_name!()
+            ))?;
+        Ok(stat.to_statfs(
+            self.storage_config.volume_capacity,
+            self.storage_config.volume_inodes,
+        ))
     }
 
     #[instrument(skip(self))]
-    async fn flush(&self, ino: u64, fh: u64) -> DatenLordResult<()> {
-        retry_txn!(TXN_RETRY_LIMIT, {
-            let mut txn = self.kv_engine.new_meta_txn().await;
-            let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
-            inode.flush(ino, fh).await;
-            txn.set(
-                &KeyType::INum2Node(ino),
-                &ValueType::Node(inode.into_serial_node()),
-            );
-            (txn.commit().await, ())
-        })?;
-        Ok(())
+    async fn flush(&self, ino: u64, fh: u64, lock_owner: u64) -> DatenLordResult<()> {
+        self.file_lock_manager
+            .release_owner_locks(ino, lock_owner, FileLockKind::Posix)
+            .await?;
+        self.flush_node(ino, FlushKind::Flush(fh)).await
     }
 
     #[instrument(skip(self))]
@@ -219,6 +535,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
             let is_deleted = self.delete_check(&node).await?;
             if is_deleted {
                 txn.delete(&KeyType::INum2Node(ino));
+                txn.delete(&KeyType::INum2XAttr(ino));
             } else {
                 txn.set(
                     &KeyType::INum2Node(ino),
@@ -267,11 +584,12 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
         //         flags,
         //     ))
         // } else {
-        retry_txn!(TXN_RETRY_LIMIT, {
+        let fd = retry_txn!(TXN_RETRY_LIMIT, {
             let mut txn = self.kv_engine.new_meta_txn().await;
             let node = self.get_inode_from_txn(txn.as_mut(), ino).await?;
             let o_flags = fs_util::parse_oflag(flags);
-            node.open_pre_check(o_flags, context.user_id, context.group_id)?;
+            let acl = Self::get_access_acl_from_txn(txn.as_mut(), ino).await?;
+            node.open_pre_check(o_flags, context.user_id, context.group_id, acl.as_ref())?;
 
             let result = node.dup_fd(o_flags).await;
             txn.set(
@@ -279,7 +597,9 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
                 &ValueType::Node(node.into_serial_node()),
             );
             (txn.commit().await, result)
-        })?
+        })??;
+        self.add_open_file(ino);
+        Ok(fd)
     }
 
     #[instrument(skip(self), err, ret)]
@@ -295,23 +615,33 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
     }
 
     #[instrument(skip(self))]
-    async fn forget(&self, ino: u64, nlookup: u64) -> DatenLordResult<()> {
-        retry_txn!(TXN_RETRY_LIMIT, {
-            let mut txn = self.kv_engine.new_meta_txn().await;
-            let inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
-            inode.dec_lookup_count_by(nlookup);
-            let is_deleted = self.delete_check(&inode).await?;
-            if is_deleted {
-                txn.delete(&KeyType::INum2Node(ino));
-            } else {
-                txn.set(
-                    &KeyType::INum2Node(ino),
-                    &ValueType::Node(inode.into_serial_node()),
+    async fn forget(&self, nodes: &[(INum, u64)]) -> DatenLordResult<()> {
+        // The same i-node may be forgotten more than once in a batch, merge them
+        // since a key cannot be read twice in one transaction
+        let mut nlookups = BTreeMap::new();
+        for &(ino, nlookup) in nodes {
+            let total: &mut u64 = nlookups.entry(ino).or_default();
+            *total = total.overflow_add(nlookup);
+        }
+        let nlookups: Vec<(INum, u64)> = nlookups.into_iter().collect();
+        let mut result = Ok(());
+        for batch in nlookups.chunks(FORGET_BATCH_SIZE) {
+            if let Err(e) = self.forget_batch(batch).await {
+                // Never let a bad i-node block forgetting the others in the batch
+                warn!(
+                    "failed to forget {} i-nodes in a batch, forget them one by one, the error is: {}",
+                    batch.len(),
+                    e,
                 );
+                for &(ino, nlookup) in batch {
+                    if let Err(e) = self.forget_batch(&[(ino, nlookup)]).await {
+                        error!("failed to forget ino={}, the error is: {}", ino, e);
+                        result = Err(e);
+                    }
+                }
             }
-            (txn.commit().await, ())
-        })?;
-        Ok(())
+        }
+        result
     }
 
     #[instrument(skip(self), err, ret)]
@@ -322,25 +652,138 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
         param: &SetAttrParam,
     ) -> DatenLordResult<(Duration, FuseAttr)> {
         let ttl = Duration::new(MY_TTL_SEC, 0);
-        let file_attr = retry_txn!(TXN_RETRY_LIMIT, {
+        let (file_attr, released) = retry_txn!(TXN_RETRY_LIMIT, {
             let mut txn = self.kv_engine.new_meta_txn().await;
             let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
+            // The extended attributes hold the access ACL, which is checked and
+            // kept in sync with the new permission bits
+            let mut xattrs = if NEED_CHECK_PERM || param.mode.is_some() {
+                Self::get_xattrs_from_txn(txn.as_mut(), ino).await?
+            } else {
+                BTreeMap::new()
+            };
+            let acl = if NEED_CHECK_PERM {
+                Self::access_acl(&xattrs)?
+            } else {
+                None
+            };
             let (attr_changed, file_attr) = inode
-                .setattr_precheck(param, context.user_id, context.group_id)
+                .setattr_precheck(param, context.user_id, context.group_id, acl.as_ref())
                 .await?;
             debug!("setattr_helper() attr_changed={}", attr_changed);
+            let mut released = Vec::new();
             if attr_changed {
+                let old_attr = inode.get_attr();
+                let old_shared = inode.shared_objects();
                 inode.set_attr(file_attr);
+                // The truncated file may stop sharing some S3 objects
+                let ref_deltas = reflink::ref_deltas(&old_shared, &inode.shared_objects());
+                released = Self::update_object_refs_in_txn(txn.as_mut(), &ref_deltas).await?;
+                if Self::retain_objects_in_txn(txn.as_mut(), &released, None).await? {
+                    released.clear();
+                }
+                let (old_used_bytes, used_bytes) = (
+                    volume_stat::used_bytes(&old_attr),
+                    volume_stat::used_bytes(&file_attr),
+                );
+                if old_used_bytes != used_bytes
+                    || quota::quota_ids(&old_attr) != quota::quota_ids(&file_attr)
+                {
+                    // The file is resized or its owner is changed
+                    self.charge_usage_in_txn(
+                        txn.as_mut(),
+                        &[
+                            (&old_attr, volume_stat::size_delta(old_used_bytes, 0), -1),
+                            (&file_attr, volume_stat::size_delta(0, used_bytes), 1),
+                        ],
+                        true,
+                    )
+                    .await?;
+                }
+                if param.mode.is_some() {
+                    // Keep the access ACL in sync with the new permission bits
+                    if let Some(value) = xattrs.get_mut(XATTR_NAME_POSIX_ACL_ACCESS) {
+                        let mut acl = PosixAcl::from_xattr(value)?;
+                        acl.set_perm(file_attr.perm);
+                        *value = acl.to_xattr();
+                        txn.set(&KeyType::INum2XAttr(ino), &ValueType::XAttr(xattrs));
+                    }
+                }
             }
             txn.set(
                 &KeyType::INum2Node(ino),
                 &ValueType::Node(inode.into_serial_node()),
             );
-            (txn.commit().await, file_attr)
+            (txn.commit().await, (file_attr, released))
         })?;
+        self.delete_shared_objects(&released).await;
         Ok((ttl, fs_util::convert_to_fuse_attr(file_attr)))
     }
 
+    #[instrument(skip(self), err, ret)]
+    async fn link(
+        &self,
+        context: ReqContext,
+        ino: INum,
+        new_parent: INum,
+        new_name: &str,
+    ) -> DatenLordResult<(Duration, FuseAttr, u64)> {
+        check_name_length(new_name)?;
+        let attr = retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
+            if inode.get_type() == SFlag::S_IFDIR {
+                return build_error_result_from_errno(
+                    Errno::EPERM,
+                    format!("link() cannot create a hard link to the directory of ino={ino}"),
+                );
+            }
+            if inode.is_deferred_deletion() {
+                return build_error_result_from_errno(
+                    Errno::ENOENT,
+                    format!("link() cannot create a hard link to the deleted i-node of ino={ino}"),
+                );
+            }
+            let mut parent_node = self.get_inode_from_txn(txn.as_mut(), new_parent).await?;
+            if parent_node.get_type() != SFlag::S_IFDIR {
+                return build_error_result_from_errno(
+                    Errno::ENOTDIR,
+                    format!("link() found the new parent of ino={new_parent} is not a directory"),
+                );
+            }
+            let parent_acl = Self::get_access_acl_from_txn(txn.as_mut(), new_parent).await?;
+            parent_node.get_attr().check_perm_with_acl(
+                context.user_id,
+                context.group_id,
+                3,
+                parent_acl.as_ref(),
+            )?;
+            parent_node.check_name_availability(new_name)?;
+
+            inode.inc_nlink();
+            // The kernel increases the lookup count of the linked i-node
+            let attr = inode.lookup_attr();
+            let previous_entry = parent_node.insert_entry_for_link(new_name, attr);
+            debug_assert!(previous_entry.is_none());
+            txn.set(
+                &KeyType::INum2Node(ino),
+                &ValueType::Node(inode.into_serial_node()),
+            );
+            txn.set(
+                &KeyType::INum2Node(new_parent),
+                &ValueType::Node(parent_node.into_serial_node()),
+            );
+            (txn.commit().await, attr)
+        })?;
+        self.add_lookup(ino);
+        debug!(
+            "link() successfully linked ino={} to name={:?} under parent ino={}, nlink={}",
+            ino, new_name, new_parent, attr.nlink,
+        );
+        let ttl = Duration::new(MY_TTL_SEC, 0);
+        Ok((ttl, fs_util::convert_to_fuse_attr(attr), MY_GENERATION))
+    }
+
     #[instrument(skip(self), err, ret)]
     async fn unlink(&self, context: ReqContext, parent: INum, name: &str) -> DatenLordResult<()> {
         let entry_type = {
@@ -396,20 +839,33 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
             Arc::clone(&kv_engine),
             node_id,
         ));
+        let file_lock_manager =
+            FileLockManager::new(Arc::clone(&kv_engine), Arc::<str>::from(node_id.to_owned()));
 
         let meta = Arc::new(Self {
             s3_backend: Arc::clone(&s3_backend),
             data_cache: Arc::<GlobalCache>::clone(&data_cache),
-            cur_fd: AtomicU32::new(4),
             node_id: Arc::<str>::from(node_id.to_owned()),
             storage_config: Arc::<StorageConfig>::from(storage_config.clone()),
-            fuse_fd: Mutex::new(-1_i32),
+            fuse_fd: Arc::new(Mutex::new(-1_i32)),
             inum_allocator: INumAllocator::new(Arc::clone(&kv_engine)),
             kv_engine,
+            file_lock_manager,
+            open_files: SyncMutex::new(BTreeMap::new()),
+            lookup_counts: SyncMutex::new(BTreeMap::new()),
+            limited_quotas: SyncRwLock::new(BTreeSet::new()),
         });
 
-        let server = CacheServer::new(ip.to_owned(), port.to_owned(), data_cache);
+        let server = CacheServer::new(
+            ip.to_owned(),
+            port.to_owned(),
+            data_cache,
+            Arc::clone(&meta.fuse_fd),
+        );
 
+        if storage_config.metadata_recover {
+            meta.recover_from_checkpoint().await?;
+        }
         retry_txn!(TXN_RETRY_LIMIT, {
             let mut txn = meta.kv_engine.new_meta_txn().await;
             let prev = meta
@@ -438,9 +894,35 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
                     &KeyType::INum2Node(FUSE_ROOT_ID),
                     &ValueType::Node(root_inode.into_serial_node()),
                 );
+                // The root i-node is the first one of the volume
+                txn.set(
+                    &KeyType::VolumeStat(volume_stat::shard_of(FUSE_ROOT_ID)),
+                    &ValueType::VolumeStat(VolumeStat {
+                        used_bytes: 0,
+                        inodes: 1,
+                    }),
+                );
                 (txn.commit().await, ())
             }
         })?;
+        meta.load_limited_quotas().await?;
+        // The limits never change in a snapshot
+        if storage_config.metadata_snapshot.is_none() {
+            tokio::spawn(Self::watch_limited_quotas(
+                Arc::downgrade(&meta),
+                Arc::clone(&meta.kv_engine),
+            ));
+        }
+        // A snapshot never overwrites the checkpoint of the latest metadata
+        if storage_config.metadata_checkpoint_interval > 0
+            && storage_config.metadata_snapshot.is_none()
+        {
+            tokio::spawn(Self::checkpoint_periodically(
+                Arc::downgrade(&meta),
+                Arc::clone(&meta.kv_engine),
+                Duration::from_secs(storage_config.metadata_checkpoint_interval),
+            ));
+        }
         Ok((meta, Some(server)))
     }
 
@@ -450,6 +932,51 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
         *self.fuse_fd.lock().await = fuse_fd;
     }
 
+    #[instrument(skip(self), err, ret)]
+    async fn flush_all(&self) -> DatenLordResult<()> {
+        let inos: Vec<INum> = self.open_files.lock().keys().copied().collect();
+        let mut result = Ok(());
+        // Try to flush all the files even if some of them fail
+        for ino in inos {
+            if let Err(e) = self.flush_open_file(ino).await {
+                error!(
+                    "flush_all() failed to flush the data of ino={}, the error is: {:?}",
+                    ino, e,
+                );
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    fn handle_snapshot(&self) -> HandleSnapshot {
+        HandleSnapshot {
+            open_files: self.open_files.lock().clone(),
+            lookup_counts: self.lookup_counts.lock().clone(),
+            next_fh: s3_node::next_fd(),
+            file_locks: self.file_lock_manager.snapshot(),
+        }
+    }
+
+    fn restore_handles(&self, snapshot: HandleSnapshot) {
+        *self.open_files.lock() = snapshot.open_files;
+        *self.lookup_counts.lock() = snapshot.lookup_counts;
+        s3_node::restore_next_fd(snapshot.next_fh);
+        // The locks held by the kernel are kept with the lease of the previous
+        // process
+        self.file_lock_manager.restore(snapshot.file_locks);
+    }
+
+    async fn release_lookups(&self) -> DatenLordResult<()> {
+        let lookups: Vec<(INum, u64)> = self
+            .lookup_counts
+            .lock()
+            .iter()
+            .map(|(&ino, &nlookup)| (ino, nlookup))
+            .collect();
+        self.forget(&lookups).await
+    }
+
     #[instrument(skip(self, node), ret)]
     /// Try to delete node that is marked as deferred deletion
     async fn delete_check(&self, node: &S3Node<S>) -> DatenLordResult<bool> {
@@ -476,43 +1003,32 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
     // Create and open a file
     // If the file does not exist, first create it with
     // the specified mode, and then open it.
-    #[allow(clippy::too_many_lines)]
     async fn mknod(&self, param: CreateParam) -> DatenLordResult<(Duration, FuseAttr, u64)> {
-        check_name_length(&param.name)?;
-        check_type_supported(&param.node_type)?;
-        let parent_ino = param.parent;
-        // pre-check : check whether the child name is valid
-        let mut parent_node = self
-            .get_node_from_kv_engine(parent_ino)
-            .await?
-            .ok_or_else(|| {
-                error::build_inconsistent_fs_with_context(
-                    function_name!(),
-                    format!(
-                        "parent of ino={parent_ino} should be in cache before create its child"
-                    ),
-                )
-            })?;
-        parent_node.check_name_availability(&param.name)?;
-        // allocate a new i-node number
-        let new_inum = self.alloc_inum().await?;
+        let (new_inum, fuse_attr) = self.create_node_helper(&param, false).await?;
+        self.add_lookup(new_inum);
 
-        let new_node = parent_node
-            .create_child_node(
-                &param,
-                new_inum,
-                Arc::<GlobalCache>::clone(&self.data_cache),
-            )
-            .await?;
+        let ttl = Duration::new(MY_TTL_SEC, 0);
+        Ok((ttl, fuse_attr, MY_GENERATION))
+    }
 
-        let new_ino = new_node.get_ino();
-        let new_node_attr = new_node.get_attr();
-        let fuse_attr = fs_util::convert_to_fuse_attr(new_node_attr);
-        self.set_node_to_kv_engine(new_ino, new_node).await?;
-        self.set_node_to_kv_engine(parent_ino, parent_node).await?;
+    #[instrument(skip(self), err, ret)]
+    async fn create(
+        &self,
+        param: CreateParam,
+        _flags: u32,
+    ) -> DatenLordResult<(Duration, FuseAttr, u64, RawFd)> {
+        let parent_ino = param.parent;
+        let (new_inum, fuse_attr) = self.create_node_helper(&param, true).await?;
+        let fd = s3_node::new_fd().cast();
+        self.add_open_file(new_inum);
+        self.add_lookup(new_inum);
+        debug!(
+            "create() successfully created and opened ino={} under parent ino={}, fd={}",
+            new_inum, parent_ino, fd,
+        );
 
         let ttl = Duration::new(MY_TTL_SEC, 0);
-        Ok((ttl, fuse_attr, MY_GENERATION))
+        Ok((ttl, fuse_attr, MY_GENERATION, fd))
     }
 
     #[instrument(skip(self), err, ret)]
@@ -531,6 +1047,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
         );
         self.remove_node_local(context, parent, node_name, node_type, false)
             .await?;
+        self.invalidate_remote_entry(parent, node_name).await;
         Ok(())
     }
 
@@ -567,6 +1084,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
             attr
         );
         self.set_node_to_kv_engine(child_ino, child_node).await?;
+        self.add_lookup(child_ino);
         let fuse_attr = fs_util::convert_to_fuse_attr(attr);
         Ok((ttl, fuse_attr, MY_GENERATION))
     }
@@ -674,7 +1192,10 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
             }
 
             (txn.commit().await, ())
-        })
+        })?;
+        self.invalidate_remote_entry(old_parent, old_name).await;
+        self.invalidate_remote_entry(new_parent, new_name).await;
+        Ok(())
     }
 
     #[instrument(skip(self), err, ret)]
@@ -686,6 +1207,10 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
     ) -> DatenLordResult<()> {
         self.rename_may_replace_local(context, &param, false)
             .await?;
+        self.invalidate_remote_entry(param.old_parent, &param.old_name)
+            .await;
+        self.invalidate_remote_entry(param.new_parent, &param.new_name)
+            .await;
         Ok(())
     }
 
@@ -698,14 +1223,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
         _datasync: bool,
         // reply: ReplyEmpty,
     ) -> DatenLordResult<()> {
-        let mut inode = self
-            .get_node_from_kv_engine(ino)
-            .await?
-            .ok_or_else(|| build_inconsistent_fs!(ino))?;
-
-        inode.flush(ino, fh).await;
-
-        Ok(())
+        self.flush_node(ino, FlushKind::Flush(fh)).await
     }
 
     #[instrument(skip(self), err, ret)]
@@ -713,41 +1231,529 @@ impl<S: S3BackEnd + Sync + Send + 'static> MetaData for S3MetaData<S> {
     async fn write_helper(
         &self,
         ino: u64,
-        fh: u64,
+        _fh: u64,
         offset: i64,
-        data: Vec<u8>,
+        data: WriteData,
         flags: u32,
     ) -> DatenLordResult<usize> {
         let data_len = data.len();
-        let (result, _) = {
-            let mut inode = self
-                .get_node_from_kv_engine(ino)
-                .await?
-                .ok_or_else(|| build_inconsistent_fs!(ino))?;
-            let parent_ino = inode.get_parent_ino();
-
+        // Only the new size and blocks of the i-node are committed in the
+        // transaction, where the growth is charged exactly once. The data is
+        // written to the cache after the commit, so a failed write never leaves
+        // its data in the cache.
+        let (inode, released) = retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
             debug!(
                 "write_helper() about to write {} byte data to file of ino={} \
-                and name {:?} at offset={}",
-                data.len(),
+                and name {:?} at offset={} with flags={:?}",
+                data_len,
                 ino,
                 inode.get_name(),
-                offset
+                offset,
+                fs_util::parse_oflag(flags),
             );
-            let o_flags = fs_util::parse_oflag(flags);
-            let write_to_disk = true;
-            let res = inode
-                .write_file(fh, offset, data, o_flags, write_to_disk)
-                .await;
-            self.set_node_to_kv_engine(ino, inode).await?;
-            (res, parent_ino)
-        };
+            let old_shared = inode.shared_objects();
+            let end = offset.cast::<u64>().overflow_add(data_len.cast());
+            let old_used_bytes = self
+                .charge_growth_in_txn(txn.as_mut(), &inode.get_attr(), end)
+                .await?;
+            inode.prepare_write(offset.cast(), data_len).await?;
+            let released = self
+                .set_nodes_with_size_change_in_txn(
+                    txn.as_mut(),
+                    &[(ino, &inode, old_used_bytes, &old_shared)],
+                    None,
+                )
+                .await?;
+            (txn.commit().await, (inode, released))
+        })?;
+        inode.write_cache(offset.cast(), data).await?;
+        self.delete_shared_objects(&released).await;
         self.invalidate_remote(ino, offset, data_len).await?;
-        result
+        Ok(data_len)
+    }
+
+    #[instrument(skip(self), err, ret)]
+    async fn fallocate(&self, ino: u64, offset: u64, len: u64, mode: u32) -> DatenLordResult<()> {
+        let released = retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
+            let attr = inode.get_attr();
+            let old_shared = inode.shared_objects();
+            let old_used_bytes = if mode & FALLOC_FL_KEEP_SIZE == 0 {
+                self.charge_growth_in_txn(txn.as_mut(), &attr, offset.overflow_add(len))
+                    .await?
+            } else {
+                volume_stat::used_bytes(&attr)
+            };
+            inode.fallocate(offset, len, mode).await?;
+            let released = self
+                .set_nodes_with_size_change_in_txn(
+                    txn.as_mut(),
+                    &[(ino, &inode, old_used_bytes, &old_shared)],
+                    None,
+                )
+                .await?;
+            (txn.commit().await, released)
+        })?;
+        self.delete_shared_objects(&released).await;
+        // The zeroed and punched blocks may be cached by other nodes
+        self.invalidate_remote(ino, offset.cast(), len.cast()).await
+    }
+
+    #[instrument(skip(self), err, ret)]
+    async fn lseek(&self, ino: u64, offset: u64, whence: u32) -> DatenLordResult<u64> {
+        let inode = self
+            .get_node_from_kv_engine(ino)
+            .await?
+            .ok_or_else(|| build_inconsistent_fs!(ino))?;
+        inode.lseek(offset, whence)
+    }
+
+    #[instrument(skip(self), err, ret)]
+    #[allow(clippy::too_many_lines)]
+    async fn copy_file_range(&self, param: &CopyRangeParam) -> DatenLordResult<usize> {
+        let same_file = param.ino_in == param.ino_out;
+        if same_file
+            && param.off_in < param.off_out.overflow_add(param.len)
+            && param.off_out < param.off_in.overflow_add(param.len)
+        {
+            return build_error_result_from_errno(
+                Errno::EINVAL,
+                format!(
+                    "copy_file_range() found the source and destination ranges \
+                        of ino={} are overlapped",
+                    param.ino_in,
+                ),
+            );
+        }
+        // The S3 objects are allocated once for all the attempts
+        let (mut upload_reserved, mut new_reserved) = (None, None);
+        let (copied_size, released, upload_used) = retry_txn!(TXN_RETRY_LIMIT, {
+            // The source file is flushed and its S3 object is shared out of the
+            // transaction, which commits the i-nodes only if neither they nor
+            // the snapshot state are changed in the meantime
+            let state = self.get_snapshot_state_from_kv_engine().await?;
+            let (mut dst_inode, dst_serial_node) = self
+                .get_node_and_serial_from_kv_engine(param.ino_out)
+                .await?;
+            // The source of the same file is only read
+            let (mut src_inode, src_serial_node) = self
+                .get_node_and_serial_from_kv_engine(param.ino_in)
+                .await?;
+            let src_old_used_bytes = volume_stat::used_bytes(&src_inode.get_attr());
+            let dst_old_used_bytes = volume_stat::used_bytes(&dst_inode.get_attr());
+            let src_old_shared = src_inode.shared_objects();
+            let dst_old_shared = dst_inode.shared_objects();
+            let mut retained = None;
+            // Clone the block aligned ranges by sharing the S3 objects, copy the
+            // others in the cache
+            let copied_size =
+                if dst_inode.can_reflink(&src_inode, param.off_in, param.off_out, param.len) {
+                    let old_object = src_inode.own_object();
+                    let upload_object = if src_inode.object_captured(&state) {
+                        Some(self.reserve_inum(&mut upload_reserved).await?)
+                    } else {
+                        None
+                    };
+                    src_inode.prepare_upload(state.generation, upload_object);
+                    let new_object = self.reserve_inum(&mut new_reserved).await?;
+                    let copied_size = dst_inode
+                        .reflink_from(
+                            &mut src_inode,
+                            param.off_in,
+                            param.off_out,
+                            param.len,
+                            new_object,
+                        )
+                        .await?;
+                    if upload_object.is_some() {
+                        retained = Some(old_object);
+                    }
+                    copied_size
+                } else {
+                    dst_inode
+                        .copy_file_range(&mut src_inode, param.off_in, param.off_out, param.len)
+                        .await?
+                };
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let unchanged = Self::get_snapshot_state_from_txn(txn.as_mut()).await? == state
+                && Self::node_unchanged_in_txn(txn.as_mut(), param.ino_out, &dst_serial_node)
+                    .await?
+                && (same_file
+                    || Self::node_unchanged_in_txn(txn.as_mut(), param.ino_in, &src_serial_node)
+                        .await?);
+            if unchanged {
+                // Retain the S3 object captured by the snapshots after the
+                // source file switches away from it
+                if let Some(object) = retained {
+                    txn.set(
+                        &KeyType::RetainedObject(object),
+                        &ValueType::Generation(state.generation),
+                    );
+                }
+                // The source file may be flushed to S3 and share its S3 object
+                let mut changes = vec![(
+                    param.ino_out,
+                    &dst_inode,
+                    dst_old_used_bytes,
+                    &dst_old_shared,
+                )];
+                if !same_file {
+                    changes.push((
+                        param.ino_in,
+                        &src_inode,
+                        src_old_used_bytes,
+                        &src_old_shared,
+                    ));
+                }
+                let released = self
+                    .set_nodes_with_size_change_in_txn(txn.as_mut(), &changes, Some(state))
+                    .await?;
+                (
+                    txn.commit().await,
+                    (copied_size, released, retained.is_some()),
+                )
+            } else {
+                (Ok(false), (0, Vec::new(), false))
+            }
+        })?;
+        self.delete_shared_objects(&released).await;
+        // The S3 object uploaded by a previous attempt is not referenced
+        if let Some(object) = upload_reserved.filter(|_| !upload_used) {
+            self.delete_shared_objects(&[object]).await;
+        }
+        if copied_size > 0 {
+            self.invalidate_remote(param.ino_out, param.off_out.cast(), copied_size)
+                .await?;
+        }
+        Ok(copied_size)
     }
 }
 
 impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
+    /// Take a checkpoint of the metadata in kv engine to the storage backend,
+    /// the metadata is read at a single revision so the checkpoint is
+    /// consistent. Return the revision of the checkpoint.
+    async fn checkpoint(&self) -> DatenLordResult<i64> {
+        let revision = self.kv_engine.revision().await?;
+        let nodes = kv_utils::list_nodes_at(&self.kv_engine, revision).await?;
+        let xattrs = kv_utils::list_xattrs_at(&self.kv_engine, revision).await?;
+        let quota_limits = kv_utils::list_quota_limits_at(&self.kv_engine, revision).await?;
+        let checkpoint = MetaCheckpoint::new(nodes, xattrs, quota_limits);
+        self.s3_backend
+            .put_meta(CHECKPOINT_OBJECT_NAME, &checkpoint.encode()?)
+            .await
+            .map_err(|e| DatenLordError::from(anyhow!(e)))?;
+        debug!(
+            "checkpoint() saved {} i-nodes of the metadata at revision={revision}",
+            checkpoint.nodes.len()
+        );
+        Ok(revision)
+    }
+
+    /// Compact the history of kv engine before the checkpoint at `revision`,
+    /// which is no longer needed for recovery, but keep the history the
+    /// snapshots are read at. Nothing is compacted while a snapshot is being
+    /// created, as its revision is not recorded yet. Return the revision the
+    /// history is compacted to, which is `compacted` if no more is compacted.
+    async fn compact_history(&self, revision: i64, compacted: i64) -> DatenLordResult<i64> {
+        // The snapshots are listed after the checkpoint, so a snapshot missed
+        // here records a revision after the checkpoint
+        let mut target = revision;
+        for (name, info) in self.list_snapshots().await? {
+            let Some(snapshot_revision) = info.revision else {
+                debug!("compact_history() skipped as snapshot name={name:?} is being created");
+                return Ok(compacted);
+            };
+            target = target.min(snapshot_revision);
+        }
+        if target <= compacted {
+            return Ok(compacted);
+        }
+        self.kv_engine.compact(target).await?;
+        debug!("compact_history() compacted the metadata history to revision={target}");
+        Ok(target)
+    }
+
+    /// Take checkpoints of the metadata every `interval` until the metadata is
+    /// dropped. The nodes mounting the volume take turns to checkpoint: the
+    /// node acquiring the checkpoint lock takes the checkpoint, and the lock
+    /// is held until its lease expires after `interval`, so a single node
+    /// writes the checkpoint in each interval. The history of kv engine before
+    /// each checkpoint is compacted except for the snapshots.
+    async fn checkpoint_periodically(
+        meta: Weak<Self>,
+        kv_engine: Arc<KVEngineType>,
+        interval: Duration,
+    ) {
+        let mut compacted = 0;
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = kv_engine.lock(&LockKeyType::CheckpointLock, interval).await {
+                warn!(
+                    "failed to lock the metadata checkpoint, the error is: {}",
+                    e
+                );
+                continue;
+            }
+            let Some(meta) = meta.upgrade() else {
+                break;
+            };
+            match meta.checkpoint().await {
+                Ok(revision) => match meta.compact_history(revision, compacted).await {
+                    Ok(revision) => compacted = revision,
+                    Err(e) => warn!(
+                        "failed to compact the history of the metadata, the error is: {}",
+                        e
+                    ),
+                },
+                Err(e) => warn!("failed to checkpoint the metadata, the error is: {}", e),
+            }
+        }
+    }
+
+    /// Load the set of the quotas with a limit from kv engine
+    async fn load_limited_quotas(&self) -> DatenLordResult<()> {
+        let limited_quotas = kv_utils::list_quota_limits(&self.kv_engine)
+            .await
+            .add_context(format!(
+                "{}() failed to get the quota limits from kv engine",
+                function_name!()
+            ))?
+            .into_iter()
+            .filter(|&(_, _, limit)| limit != QuotaLimit::default())
+            .map(|(kind, id, _)| (kind, id))
+            .collect();
+        *self.limited_quotas.write() = limited_quotas;
+        Ok(())
+    }
+
+    /// Reload the set of the quotas with a limit once the limits are changed
+    /// by any node, until the metadata is dropped. The set is reloaded after
+    /// the watch is created, so no change is missed.
+    async fn watch_limited_quotas(meta: Weak<Self>, kv_engine: Arc<KVEngineType>) {
+        loop {
+            match kv_utils::watch_quota_limits(&kv_engine).await {
+                Ok(mut changes) => loop {
+                    let Some(meta) = meta.upgrade() else {
+                        return;
+                    };
+                    if let Err(e) = meta.load_limited_quotas().await {
+                        warn!("failed to reload the quota limits, the error is: {}", e);
+                        break;
+                    }
+                    drop(meta);
+                    match changes.next().await {
+                        Some(Ok(())) => {}
+                        Some(Err(e)) => {
+                            warn!("failed to watch the quota limits, the error is: {}", e);
+                            break;
+                        }
+                        None => break,
+                    }
+                },
+                Err(e) => warn!("failed to watch the quota limits, the error is: {}", e),
+            }
+            tokio::time::sleep(QUOTA_WATCH_RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Rebuild the metadata in kv engine from the checkpoint in the storage
+    /// backend, if kv engine has no root node
+    async fn recover_from_checkpoint(&self) -> DatenLordResult<()> {
+        if self.get_node_from_kv_engine(FUSE_ROOT_ID).await?.is_some() {
+            info!("[recover] root node already exists, skip recovery");
+            return Ok(());
+        }
+        let data = self
+            .s3_backend
+            .get_meta(CHECKPOINT_OBJECT_NAME)
+            .await
+            .map_err(|e| DatenLordError::from(anyhow!(e)))?;
+        let Some(data) = data else {
+            return build_error_result_from_errno(
+                Errno::ENOENT,
+                "recover_from_checkpoint() found no metadata checkpoint in the storage backend"
+                    .to_owned(),
+            );
+        };
+        let mut checkpoint = MetaCheckpoint::decode(&data)?;
+        info!(
+            "[recover] recover {} i-nodes from the checkpoint taken at {:?}",
+            checkpoint.nodes.len(),
+            checkpoint.time,
+        );
+        let root_node = checkpoint
+            .nodes
+            .remove(&FUSE_ROOT_ID)
+            .ok_or_else(|| build_inconsistent_fs!(FUSE_ROOT_ID))?;
+        let (mut stat, quota_usages) = checkpoint.usage();
+        let object_refs = checkpoint.object_refs();
+        let max_ino = checkpoint.max_ino();
+
+        // The new i-numbers are allocated after the recovered ones
+        let mut kvs = vec![(
+            KeyType::IdAllocatorValue(IdType::INum),
+            ValueType::NextIdAllocateRangeBegin(max_ino.max(FUSE_ROOT_ID).overflow_add(1)),
+        )];
+        // The root i-node is counted by the volume but not by the quotas, the
+        // recovered usage is all counted in the first shard
+        stat.update(0, 1);
+        kvs.push((KeyType::VolumeStat(0), ValueType::VolumeStat(stat)));
+        for (kind, id, limit) in checkpoint.quota_limits {
+            kvs.push((KeyType::Quota(kind, id), ValueType::QuotaLimit(limit)));
+        }
+        for ((kind, id), usage) in quota_usages {
+            kvs.push((
+                KeyType::QuotaUsage(kind, id, 0),
+                ValueType::QuotaUsage(usage),
+            ));
+        }
+        for (object, count) in object_refs {
+            kvs.push((KeyType::SharedObject(object), ValueType::RefCount(count)));
+        }
+        for (ino, xattrs) in checkpoint.xattrs {
+            kvs.push((KeyType::INum2XAttr(ino), ValueType::XAttr(xattrs)));
+        }
+        for (ino, node) in checkpoint.nodes {
+            kvs.push((KeyType::INum2Node(ino), ValueType::Node(node)));
+        }
+        // The root i-node is recovered at last, so an interrupted recovery is
+        // retried on the next start
+        kvs.push((KeyType::INum2Node(FUSE_ROOT_ID), ValueType::Node(root_node)));
+        for (key, value) in kvs {
+            self.kv_engine
+                .set(&key, &value, None)
+                .await
+                .add_context(format!("failed to recover {key} to kv engine"))?;
+        }
+        info!("[recover] metadata recovered from the checkpoint");
+        Ok(())
+    }
+
+    /// Record that a file is opened on this node
+    fn add_open_file(&self, ino: INum) {
+        let mut open_files = self.open_files.lock();
+        let count = open_files.entry(ino).or_insert(0);
+        *count = count.overflow_add(1);
+    }
+
+    /// Record that a file opened on this node is released
+    fn remove_open_file(&self, ino: INum) {
+        let mut open_files = self.open_files.lock();
+        if let Some(count) = open_files.get_mut(&ino) {
+            *count = count.overflow_sub(1);
+            if *count == 0 {
+                open_files.remove(&ino);
+            }
+        }
+    }
+
+    /// Record that the kernel of this node looks up an i-node once more
+    fn add_lookup(&self, ino: INum) {
+        let mut lookup_counts = self.lookup_counts.lock();
+        let count = lookup_counts.entry(ino).or_insert(0);
+        *count = count.overflow_add(1);
+    }
+
+    /// Record that the kernel of this node forgets `nlookup` lookups of an
+    /// i-node
+    fn remove_lookups(&self, ino: INum, nlookup: u64) {
+        let mut lookup_counts = self.lookup_counts.lock();
+        if let Some(count) = lookup_counts.get_mut(&ino) {
+            *count = count.saturating_sub(nlookup);
+            if *count == 0 {
+                lookup_counts.remove(&ino);
+            }
+        }
+    }
+
+    /// Forget the i-nodes of a batch in one transaction
+    async fn forget_batch(&self, batch: &[(INum, u64)]) -> DatenLordResult<()> {
+        retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            for &(ino, nlookup) in batch {
+                let inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
+                inode.dec_lookup_count_by(nlookup);
+                let is_deleted = self.delete_check(&inode).await?;
+                if is_deleted {
+                    txn.delete(&KeyType::INum2Node(ino));
+                    txn.delete(&KeyType::INum2XAttr(ino));
+                } else {
+                    txn.set(
+                        &KeyType::INum2Node(ino),
+                        &ValueType::Node(inode.into_serial_node()),
+                    );
+                }
+            }
+            (txn.commit().await, ())
+        })?;
+        for &(ino, nlookup) in batch {
+            self.remove_lookups(ino, nlookup);
+        }
+        Ok(())
+    }
+
+    /// Flush the data of an open file to the storage backend
+    async fn flush_open_file(&self, ino: INum) -> DatenLordResult<()> {
+        self.flush_node(ino, FlushKind::All).await
+    }
+
+    /// Flush the data of an i-node to the storage backend. The data is
+    /// uploaded out of the transaction, which saves the i-node only if neither
+    /// it nor the snapshot state is changed in the meantime.
+    async fn flush_node(&self, ino: INum, kind: FlushKind) -> DatenLordResult<()> {
+        // The new S3 object is allocated once for all the attempts
+        let mut reserved = None;
+        let (res, switched) = retry_txn!(TXN_RETRY_LIMIT, {
+            let state = self.get_snapshot_state_from_kv_engine().await?;
+            let (mut inode, serial_node) = self.get_node_and_serial_from_kv_engine(ino).await?;
+            let old_object = inode.own_object();
+            let new_object = if inode.object_captured(&state) {
+                Some(self.reserve_inum(&mut reserved).await?)
+            } else {
+                None
+            };
+            inode.prepare_upload(state.generation, new_object);
+            let res = match kind {
+                FlushKind::Flush(fh) => {
+                    inode.flush(ino, fh).await;
+                    Ok(())
+                }
+                FlushKind::Close(fh, flush) => {
+                    inode.close(ino, fh, flush).await;
+                    Ok(())
+                }
+                FlushKind::All => inode.flush_all_data().await,
+            };
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            if Self::get_snapshot_state_from_txn(txn.as_mut()).await? == state
+                && Self::node_unchanged_in_txn(txn.as_mut(), ino, &serial_node).await?
+            {
+                let switched = inode.own_object() != old_object;
+                Self::retain_flushed_object_in_txn(
+                    txn.as_mut(),
+                    &inode,
+                    (old_object, state.generation),
+                );
+                txn.set(
+                    &KeyType::INum2Node(ino),
+                    &ValueType::Node(inode.into_serial_node()),
+                );
+                (txn.commit().await, (res, switched))
+            } else {
+                (Ok(false), (Ok(()), false))
+            }
+        })?;
+        // The S3 object uploaded by a previous attempt is not referenced
+        if let Some(object) = reserved.filter(|_| !switched) {
+            self.delete_shared_objects(&[object]).await;
+        }
+        res
+    }
+
     #[allow(clippy::unwrap_used)]
     /// Get a node from kv engine by inum
     pub async fn get_node_from_kv_engine(&self, inum: INum) -> DatenLordResult<Option<S3Node<S>>> {
@@ -764,6 +1770,26 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
         })
     }
 
+    /// Get node from kv engine along with its serial form, which is compared
+    /// by `node_unchanged_in_txn` later to check the node is not changed
+    async fn get_node_and_serial_from_kv_engine(
+        &self,
+        inum: INum,
+    ) -> DatenLordResult<(S3Node<S>, SerialNode)> {
+        let serial_node = self
+            .kv_engine
+            .get(&KeyType::INum2Node(inum))
+            .await
+            .add_context(format!(
+                "{}() failed to get node of ino={inum} from kv engine",
+                function_name!()
+            ))?
+            .ok_or_else(|| build_inconsistent_fs!(inum))? // inode must exist
+            .into_serial_node();
+        let inode = S3Node::from_serial_node(serial_node.clone(), self).await?;
+        Ok((inode, serial_node))
+    }
+
     /// Set node to kv engine use inum
     pub async fn set_node_to_kv_engine(&self, inum: INum, node: S3Node<S>) -> DatenLordResult<()> {
         let inum_key = KeyType::INum2Node(inum);
@@ -793,95 +1819,139 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
     }
 
     /// Helper function to pre-check if node can be deferred deleted.
-    fn deferred_delete_pre_check(inode: &S3Node<S>) -> (bool, INum, String) {
+    fn deferred_delete_pre_check(inode: &S3Node<S>) -> bool {
         debug_assert!(inode.get_lookup_count() >= 0); // lookup count cannot be negative
         debug_assert!(inode.get_open_count() >= 0);
         // pre-check whether deferred delete or not
-        (
-            inode.get_lookup_count() > 0 || inode.get_open_count() > 0,
-            inode.get_parent_ino(),
-            inode.get_name().to_owned(),
-        )
+        inode.get_lookup_count() > 0 || inode.get_open_count() > 0
     }
 
     /// Helper function to delete or deferred delete node
+    ///
+    /// The entry `node_name` is removed from the parent directory and the link
+    /// count of the i-node is decreased. The i-node and its data are only
+    /// removed when the last link of the i-node is gone.
     async fn may_deferred_delete_node_helper(
         &self,
+        parent_ino: INum,
+        node_name: &str,
         ino: INum,
         from_remote: bool,
     ) -> DatenLordResult<()> {
-        // remove entry from parent i-node
-        let inode = self.get_node_from_kv_engine(ino).await?.ok_or_else(|| {
-            anyhow::anyhow!(
-                "{}() failed to \
-                         find the i-node of ino={ino} to remove",
-                function_name!()
-            )
-        })?;
-        let (deferred_deletion, parent_ino, node_name) = Self::deferred_delete_pre_check(&inode);
-        let mut parent_node = self
-            .get_node_from_kv_engine(parent_ino)
-            .await?
-            .ok_or_else(|| {
-                anyhow::anyhow!(
-                    "{}() failed to \
-                     find the parent of ino={parent_ino} for i-node of ino={ino}",
+        let (last_link, lookup_count, objects) = retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
+            let mut parent_node = self.get_inode_from_txn(txn.as_mut(), parent_ino).await?;
+            // remove entry from parent i-node
+            let deleted_entry = parent_node
+                .unlink_entry(node_name)
+                .await
+                .add_context(format!(
+                    "{}() failed to remove entry name={node_name:?} \
+                     and ino={ino} from parent directory ino={parent_ino}",
                     function_name!()
-                )
-            })?;
-        let deleted_entry = parent_node
-            .unlink_entry(&node_name)
-            .await
-            .add_context(format!(
-                "{}() failed to remove entry name={node_name:?} \
-                 and ino={ino} from parent directory ino={parent_ino}",
-                function_name!()
-            ))?;
-        debug!(
-            "may_deferred_delete_node_helper() successfully remove entry name={:?} \
-                 ino={} from parent directory ino={}",
-            node_name, ino, parent_ino
-        );
-        debug_assert_eq!(node_name, deleted_entry.entry_name());
-        debug_assert_eq!(deleted_entry.ino(), ino);
-
-        if deferred_deletion {
-            // Deferred deletion
-            let inode = self.get_node_from_kv_engine(ino).await?.unwrap_or_else(|| {
-                unreachable!(
-                    "impossible case, may_deferred_delete_node_helper() \
-                     i-node of ino={ino} is not in cache",
-                );
-            });
+                ))?;
             debug!(
-                "may_deferred_delete_node_helper() deferred removed \
-                    the i-node name={:?} of ino={} under parent ino={}, \
-                    open count={}, lookup count={}",
-                inode.get_name(),
-                ino,
-                parent_ino,
-                inode.get_open_count(),
-                inode.get_lookup_count(),
+                "may_deferred_delete_node_helper() successfully remove entry name={:?} \
+                     ino={} from parent directory ino={}",
+                node_name, ino, parent_ino
             );
-            inode.mark_deferred_deletion();
-            // Notify kernel to drop cache
-            if from_remote && inode.get_lookup_count() > 0 {
-                let fuse_fd = *self.fuse_fd.lock().await;
-                // fuse_fd must be set
-                assert!(fuse_fd > 0_i32);
-                #[cfg(feature = "abi-7-18")]
-                {
-                    let fuse_delete_notification = FuseDeleteNotification::new(fuse_fd);
-                    fuse_delete_notification
-                        .notify(parent_ino, ino, inode.get_name().to_owned())
-                        .await?;
+            debug_assert_eq!(node_name, deleted_entry.entry_name());
+            debug_assert_eq!(deleted_entry.ino(), ino);
+
+            // Directories cannot be hard linked, so they always lose their last link
+            let last_link = inode.dec_nlink() == 0 || inode.get_type() == SFlag::S_IFDIR;
+            // Special files have no data in S3
+            let has_data = !matches!(
+                inode.get_type(),
+                SFlag::S_IFIFO | SFlag::S_IFSOCK | SFlag::S_IFCHR | SFlag::S_IFBLK
+            );
+            let mut own_object = None;
+            let mut released = Vec::new();
+            if last_link {
+                // The i-node and its data are released from the volume
+                let attr = inode.get_attr();
+                let bytes_delta = volume_stat::size_delta(volume_stat::used_bytes(&attr), 0);
+                self.charge_usage_in_txn(txn.as_mut(), &[(&attr, bytes_delta, -1)], false)
+                    .await?;
+                let ref_deltas =
+                    reflink::ref_deltas(&inode.release_shared_extents(), &BTreeSet::new());
+                released = Self::update_object_refs_in_txn(txn.as_mut(), &ref_deltas).await?;
+                own_object = has_data.then(|| inode.own_object());
+                let objects: Vec<INum> = released.iter().copied().chain(own_object).collect();
+                if Self::retain_objects_in_txn(txn.as_mut(), &objects, None).await? {
+                    own_object = None;
+                    released.clear();
                 }
             }
-        } else {
-            // immediate deletion
-            self.remove_node_from_kv_engine(ino).await?;
+            if !last_link {
+                // Other links still refer to the i-node, keep it
+                debug!(
+                    "may_deferred_delete_node_helper() removed one link of \
+                        the i-node of ino={}, {} links left",
+                    ino,
+                    inode.get_attr().nlink,
+                );
+                txn.set(
+                    &KeyType::INum2Node(ino),
+                    &ValueType::Node(inode.to_serial_node()),
+                );
+            } else if Self::deferred_delete_pre_check(&inode) {
+                // Deferred deletion
+                debug!(
+                    "may_deferred_delete_node_helper() deferred removed \
+                        the i-node name={:?} of ino={} under parent ino={}, \
+                        open count={}, lookup count={}",
+                    node_name,
+                    ino,
+                    parent_ino,
+                    inode.get_open_count(),
+                    inode.get_lookup_count(),
+                );
+                inode.mark_deferred_deletion();
+                txn.set(
+                    &KeyType::INum2Node(ino),
+                    &ValueType::Node(inode.to_serial_node()),
+                );
+            } else {
+                // immediate deletion
+                txn.delete(&KeyType::INum2Node(ino));
+                txn.delete(&KeyType::INum2XAttr(ino));
+            }
+            txn.set(
+                &KeyType::INum2Node(parent_ino),
+                &ValueType::Node(parent_node.into_serial_node()),
+            );
+            (
+                txn.commit().await,
+                (last_link, inode.get_lookup_count(), (own_object, released)),
+            )
+        })?;
+
+        if last_link {
+            // delete from disk only when no link refers to the i-node, and no
+            // snapshot retains the data
+            let (own_object, released) = objects;
+            if let Some(own_object) = own_object {
+                if let Err(e) = self.s3_backend.delete_data(own_object).await {
+                    panic!("failed to delete data of {ino} from s3 backend, error is {e:?}");
+                }
+            }
+            self.delete_shared_objects(&released).await;
+        }
+        // Notify kernel to drop cache
+        if from_remote && lookup_count > 0 {
+            let fuse_fd = *self.fuse_fd.lock().await;
+            // fuse_fd must be set
+            assert!(fuse_fd > 0_i32);
+            #[cfg(feature = "abi-7-18")]
+            {
+                let fuse_delete_notification = FuseDeleteNotification::new(fuse_fd);
+                fuse_delete_notification
+                    .notify(parent_ino, ino, node_name.to_owned())
+                    .await?;
+            }
         }
-        self.set_node_to_kv_engine(parent_ino, parent_node).await?;
         Ok(())
     }
 
@@ -898,7 +1968,8 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
             .get_node_from_kv_engine(parent)
             .await?
             .ok_or_else(|| build_inconsistent_fs!(parent))?;
-        parent_node.get_attr().check_perm(user_id, group_id, 1)?;
+        self.check_perm_with_acl(&parent_node, user_id, group_id, 1)
+            .await?;
         if let Some(child_entry) = parent_node.get_entry(name) {
             let ino = child_entry.ino();
             let child_type = child_entry.entry_type();
@@ -1222,9 +2293,14 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
             }
         };
 
+        // If the old entry and the new entry are links to the same i-node, do nothing
+        if new_entry_ino == Some(old_entry_ino) {
+            return Ok(());
+        }
+
         // Just replace new entry, may deferred delete
         if let Some(new_ino) = new_entry_ino {
-            self.may_deferred_delete_node_helper(new_ino, from_remote)
+            self.may_deferred_delete_node_helper(new_parent, new_name, new_ino, from_remote)
                 .await
                 .add_context(format!(
                     "{}() failed to \
@@ -1262,17 +2338,92 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
         Ok(())
     }
 
-    /// Helper function to remove node locally
-    pub(crate) async fn remove_node_local(
+    /// Helper function to create a node in a single transaction, the name is
+    /// checked in the transaction, so `O_EXCL` is atomic across nodes. The new
+    /// node is counted as opened if `open` is true. Return the i-node number
+    /// and the attribute of the new node.
+    async fn create_node_helper(
         &self,
-        context: ReqContext,
-        parent: INum,
-        node_name: &str,
-        node_type: SFlag,
-        from_remote: bool,
-    ) -> DatenLordResult<()> {
-        let node_ino: INum;
-        {
+        param: &CreateParam,
+        open: bool,
+    ) -> DatenLordResult<(INum, FuseAttr)> {
+        check_name_length(&param.name)?;
+        check_type_supported(&param.node_type)?;
+        let parent_ino = param.parent;
+        // allocate a new i-node number, it's wasted if the creation fails
+        let new_inum = self.alloc_inum().await?;
+
+        let fuse_attr = retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let mut parent_node = self.get_inode_from_txn(txn.as_mut(), parent_ino).await?;
+            // pre-check : check whether the child name is valid
+            parent_node.check_name_availability(&param.name)?;
+            let parent_xattrs = Self::get_xattrs_from_txn(txn.as_mut(), parent_ino).await?;
+            // The mode may be changed by the default ACL, so retry with the
+            // original one
+            let mut child_param = param.clone();
+            let child_xattrs = Self::inherit_default_acl(parent_xattrs, &mut child_param)?;
+
+            let new_node = parent_node
+                .create_child_node(
+                    &child_param,
+                    new_inum,
+                    Arc::<GlobalCache>::clone(&self.data_cache),
+                )
+                .await?;
+            self.charge_usage_in_txn(txn.as_mut(), &[(&new_node.get_attr(), 0, 1)], true)
+                .await?;
+            if open {
+                new_node.inc_open_count();
+            }
+            let fuse_attr = fs_util::convert_to_fuse_attr(new_node.get_attr());
+
+            if !child_xattrs.is_empty() {
+                txn.set(
+                    &KeyType::INum2XAttr(new_inum),
+                    &ValueType::XAttr(child_xattrs),
+                );
+            }
+            txn.set(
+                &KeyType::INum2Node(new_inum),
+                &ValueType::Node(new_node.into_serial_node()),
+            );
+            txn.set(
+                &KeyType::INum2Node(parent_ino),
+                &ValueType::Node(parent_node.into_serial_node()),
+            );
+            (txn.commit().await, fuse_attr)
+        })?;
+
+        // Put the data to the S3 backend after the commit, so that a retried
+        // transaction doesn't upload it again
+        if let SFlag::S_IFREG | SFlag::S_IFLNK = param.node_type {
+            let data = param.link.as_ref().map_or("", |link| {
+                link.to_str()
+                    .unwrap_or_else(|| panic!("failed to convert {link:?} to utf8 string"))
+            });
+            if let Err(e) = self
+                .s3_backend
+                .put_data(new_inum, data.as_bytes(), 0, data.len())
+                .await
+            {
+                panic!("failed to put data of file {new_inum} to s3 backend, error is {e:?}");
+            }
+        }
+        Ok((new_inum, fuse_attr))
+    }
+
+    /// Helper function to remove node locally
+    pub(crate) async fn remove_node_local(
+        &self,
+        context: ReqContext,
+        parent: INum,
+        node_name: &str,
+        node_type: SFlag,
+        from_remote: bool,
+    ) -> DatenLordResult<()> {
+        let node_ino: INum;
+        {
             // pre-checks
             let parent_node = self.get_node_from_kv_engine(parent).await?.ok_or_else(|| {
                 error::build_inconsistent_fs_with_context(
@@ -1296,6 +2447,8 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
                     );
                 }
                 Some(child_entry) => {
+                    self.check_perm_with_acl(&parent_node, context.user_id, context.group_id, 3)
+                        .await?;
                     Self::check_sticky_bit(&context, &parent_node, child_entry)?;
                     node_ino = child_entry.ino();
                     if let SFlag::S_IFDIR = node_type {
@@ -1338,9 +2491,10 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
                             format!("i-node name={node_name:?} of ino={node_ino} found under the parent of ino={parent}, but no i-node found for this node"
                             )))?;
 
+                    // The name and parent of a hard linked i-node are the ones it was
+                    // created with, which may be unlinked already, so they are not
+                    // checked against the entry
                     debug_assert_eq!(node_ino, child_node.get_ino());
-                    debug_assert_eq!(node_name, child_node.get_name());
-                    debug_assert_eq!(parent, child_node.get_parent_ino());
                     debug_assert_eq!(node_type, child_node.get_type());
                     debug_assert_eq!(node_type, child_node.get_attr().kind);
                 }
@@ -1349,7 +2503,7 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
         {
             // all checks passed, ready to remove,
             // when deferred deletion, remove entry from directory first
-            self.may_deferred_delete_node_helper(node_ino, from_remote)
+            self.may_deferred_delete_node_helper(parent, node_name, node_ino, from_remote)
                 .await
                 .add_context(format!(
                     "{}() failed to maybe deferred delete child i-node of ino={node_ino}, \
@@ -1373,6 +2527,17 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
         result
     }
 
+    /// Allocate a new inum into `reserved` unless one is already reserved by
+    /// a previous attempt of the transaction, so the retries do not leak inums
+    async fn reserve_inum(&self, reserved: &mut Option<INum>) -> DatenLordResult<INum> {
+        if let Some(inum) = *reserved {
+            return Ok(inum);
+        }
+        let inum = self.alloc_inum().await?;
+        *reserved = Some(inum);
+        Ok(inum)
+    }
+
     /// Invalidate cache from other nodes
     async fn invalidate_remote(
         &self,
@@ -1401,6 +2566,32 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
         .add_context("failed to invlidate others' cache")
     }
 
+    /// Invalidate the directory entry cached by the kernel of other nodes, the
+    /// local change is already committed, so a failure is only logged
+    async fn invalidate_remote_entry(&self, parent: INum, name: &str) {
+        let volume_info = match serde_json::to_string(self.storage_config.as_ref()) {
+            Ok(volume_info) => volume_info,
+            Err(e) => {
+                warn!("failed to serialize the volume information, error is {e:?}");
+                return;
+            }
+        };
+        if let Err(e) = dist_client::invalidate_entry(
+            &self.kv_engine,
+            &self.node_id,
+            &volume_info,
+            parent,
+            name,
+        )
+        .await
+        {
+            warn!(
+                "failed to invalidate others' directory entry name={name:?} \
+                    under parent ino={parent}, error is {e:?}"
+            );
+        }
+    }
+
     /// If sticky bit is set, only the owner of the directory, the owner of the
     /// file, or the superuser can rename or delete files.
     fn check_sticky_bit(
@@ -1410,10 +2601,10 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
     ) -> DatenLordResult<()> {
         let parent_attr = parent_node.get_attr();
         if NEED_CHECK_PERM
-            && context.uid!= 0
+            && context.user_id != 0
             && (parent_attr.perm & 0o1000 != 0)
-            && context.uid!= parent_attr.uid
-            && context.uid!= child_entry.file_attr_arc_ref().read().uid
+            && context.user_id != parent_attr.uid
+            && context.user_id != child_entry.file_attr_arc_ref().read().uid
         {
             build_error_result_from_errno(Errno::EACCES, "Sticky bit set".to_owned())
         } else {
@@ -1421,6 +2612,978 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
         }
     }
 
+    /// Helper function to check the name of an extended attribute and whether
+    /// the user can access it, `access_mode` is 4 for read and 2 for write.
+    fn xattr_pre_check(
+        context: &ReqContext,
+        inode: &S3Node<S>,
+        name: &str,
+        access_mode: u8,
+    ) -> DatenLordResult<()> {
+        if name.is_empty() || name.len() > XATTR_NAME_MAX {
+            return build_error_result_from_errno(
+                Errno::ERANGE,
+                format!("the length of xattr name={name:?} is invalid"),
+            );
+        }
+        let attr = inode.get_attr();
+        if name.starts_with("user.") {
+            // User xattrs are only permitted on regular files and directories
+            if attr.kind != SFlag::S_IFREG && attr.kind != SFlag::S_IFDIR {
+                return build_error_result_from_errno(
+                    Errno::EPERM,
+                    format!(
+                        "user xattr name={name:?} is not permitted on ino={} of type={:?}",
+                        attr.ino, attr.kind,
+                    ),
+                );
+            }
+            attr.check_perm(context.user_id, context.group_id, access_mode)
+        } else if name.starts_with("trusted.") {
+            if NEED_CHECK_PERM && context.user_id != 0 {
+                build_error_result_from_errno(
+                    Errno::EPERM,
+                    format!("only the superuser can access trusted xattr name={name:?}"),
+                )
+            } else {
+                Ok(())
+            }
+        } else if name.starts_with("security.")
+            || name == XATTR_NAME_POSIX_ACL_ACCESS
+            || name == XATTR_NAME_POSIX_ACL_DEFAULT
+        {
+            // Only the owner or the superuser can modify security xattrs and ACLs
+            if NEED_CHECK_PERM
+                && access_mode == 2
+                && context.user_id != 0
+                && context.user_id != attr.uid
+            {
+                build_error_result_from_errno(
+                    Errno::EPERM,
+                    format!("only the owner can modify xattr name={name:?}"),
+                )
+            } else {
+                Ok(())
+            }
+        } else {
+            build_error_result_from_errno(
+                Errno::EOPNOTSUPP,
+                format!("the namespace of xattr name={name:?} is not supported"),
+            )
+        }
+    }
+
+    /// Helper function to set the access ACL or the default ACL of an i-node,
+    /// the permission bits of the i-node are kept in sync with its access ACL,
+    /// and the access ACL is not stored if it is equivalent to the permission
+    /// bits.
+    fn set_acl_helper(
+        inode: &mut S3Node<S>,
+        xattrs: &mut BTreeMap<String, Vec<u8>>,
+        name: &str,
+        value: &[u8],
+    ) -> DatenLordResult<()> {
+        let acl = PosixAcl::from_xattr(value)?;
+        if name == XATTR_NAME_POSIX_ACL_DEFAULT {
+            if inode.get_type() != SFlag::S_IFDIR {
+                return build_error_result_from_errno(
+                    Errno::EACCES,
+                    format!(
+                        "set_acl_helper() cannot set default ACL on the non-directory of ino={}",
+                        inode.get_ino(),
+                    ),
+                );
+            }
+            xattrs.insert(name.to_owned(), acl.to_xattr());
+        } else {
+            let mut attr = inode.get_attr();
+            attr.perm = (attr.perm & !0o777) | acl.get_perm();
+            inode.set_attr(attr);
+            if acl.is_equiv_mode() {
+                xattrs.remove(name);
+            } else {
+                xattrs.insert(name.to_owned(), acl.to_xattr());
+            }
+        }
+        Ok(())
+    }
+
+    /// Helper function to inherit the default ACL of the parent directory for a
+    /// new child, the permission bits in `param` are masked by the default ACL.
+    /// Return the ACL xattrs of the new child, the child directory also
+    /// inherits the default ACL itself.
+    fn inherit_default_acl(
+        mut parent_xattrs: BTreeMap<String, Vec<u8>>,
+        param: &mut CreateParam,
+    ) -> DatenLordResult<BTreeMap<String, Vec<u8>>> {
+        let mut child_xattrs = BTreeMap::new();
+        if param.node_type == SFlag::S_IFLNK {
+            // Symlinks have no ACL
+            return Ok(child_xattrs);
+        }
+        if let Some(value) = parent_xattrs.remove(XATTR_NAME_POSIX_ACL_DEFAULT) {
+            let default_acl = PosixAcl::from_xattr(&value)?;
+            let (access_acl, perm) = default_acl.inherit(fs_util::parse_mode_bits(param.mode));
+            param.mode = perm.into();
+            if !access_acl.is_equiv_mode() {
+                child_xattrs.insert(
+                    XATTR_NAME_POSIX_ACL_ACCESS.to_owned(),
+                    access_acl.to_xattr(),
+                );
+            }
+            if param.node_type == SFlag::S_IFDIR {
+                child_xattrs.insert(XATTR_NAME_POSIX_ACL_DEFAULT.to_owned(), value);
+            }
+        }
+        Ok(child_xattrs)
+    }
+
+    /// Helper function to get the access ACL of an i-node from `MetaTxn`, the
+    /// ACL is only loaded when we check permission by ourselves
+    async fn get_access_acl_from_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+        ino: INum,
+    ) -> DatenLordResult<Option<PosixAcl>> {
+        if !NEED_CHECK_PERM {
+            return Ok(None);
+        }
+        Self::access_acl(&Self::get_xattrs_from_txn(txn, ino).await?)
+    }
+
+    /// Helper function to get the access ACL of an i-node from kv engine, the
+    /// ACL is only loaded when we check permission by ourselves
+    async fn get_access_acl_from_kv_engine(&self, ino: INum) -> DatenLordResult<Option<PosixAcl>> {
+        if !NEED_CHECK_PERM {
+            return Ok(None);
+        }
+        Self::access_acl(&self.get_xattrs_from_kv_engine(ino).await?)
+    }
+
+    /// Helper function to parse the access ACL in the extended attributes of
+    /// an i-node
+    fn access_acl(xattrs: &BTreeMap<String, Vec<u8>>) -> DatenLordResult<Option<PosixAcl>> {
+        xattrs
+            .get(XATTR_NAME_POSIX_ACL_ACCESS)
+            .map(|value| PosixAcl::from_xattr(value))
+            .transpose()
+    }
+
+    /// Helper function to check the permission of an i-node like
+    /// `FileAttr::check_perm()`, evaluating its access ACL if it has one
+    async fn check_perm_with_acl(
+        &self,
+        inode: &S3Node<S>,
+        user_id: u32,
+        group_id: u32,
+        access_mode: u8,
+    ) -> DatenLordResult<()> {
+        let acl = self.get_access_acl_from_kv_engine(inode.get_ino()).await?;
+        inode
+            .get_attr()
+            .check_perm_with_acl(user_id, group_id, access_mode, acl.as_ref())
+    }
+
+    /// Helper function to get the extended attributes of an i-node from
+    /// `MetaTxn`
+    async fn get_xattrs_from_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+        ino: INum,
+    ) -> DatenLordResult<BTreeMap<String, Vec<u8>>> {
+        Ok(txn
+            .get(&KeyType::INum2XAttr(ino))
+            .await
+            .add_context(format!(
+                "{}() failed to get xattrs of ino={ino} from kv engine",
+                function_name!()
+            ))?
+            .map(ValueType::into_xattr)
+            .unwrap_or_default())
+    }
+
+    /// Helper function to get a shard of the usage of the volume from
+    /// `MetaTxn`
+    async fn get_volume_stat_from_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+        shard: u32,
+    ) -> DatenLordResult<VolumeStat> {
+        Ok(txn
+            .get(&KeyType::VolumeStat(shard))
+            .await
+            .add_context(format!(
+                "{}() failed to get the shard={shard} of the usage of the volume from kv engine",
+                function_name!()
+            ))?
+            .map(ValueType::into_volume_stat)
+            .unwrap_or_default())
+    }
+
+    /// Helper function to get the limit of a quota from `MetaTxn`
+    async fn get_quota_limit_from_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+        kind: QuotaKind,
+        id: u32,
+    ) -> DatenLordResult<QuotaLimit> {
+        Ok(txn
+            .get(&KeyType::Quota(kind, id))
+            .await
+            .add_context(format!(
+                "{}() failed to get the limit of {kind:?} quota of id={id} from kv engine",
+                function_name!()
+            ))?
+            .map(ValueType::into_quota_limit)
+            .unwrap_or_default())
+    }
+
+    /// Helper function to get a shard of the usage of a quota from `MetaTxn`
+    async fn get_quota_usage_from_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+        kind: QuotaKind,
+        id: u32,
+        shard: u32,
+    ) -> DatenLordResult<QuotaUsage> {
+        Ok(txn
+            .get(&KeyType::QuotaUsage(kind, id, shard))
+            .await
+            .add_context(format!(
+                "{}() failed to get the shard={shard} of the usage of {kind:?} quota of id={id} \
+                    from kv engine",
+                function_name!()
+            ))?
+            .map(ValueType::into_quota_usage)
+            .unwrap_or_default())
+    }
+
+    /// Helper function to get the limit and the usage of a quota from kv
+    /// engine, the usage is the sum of its shards
+    async fn get_quota_from_kv_engine(&self, kind: QuotaKind, id: u32) -> DatenLordResult<Quota> {
+        let limit = self
+            .kv_engine
+            .get(&KeyType::Quota(kind, id))
+            .await
+            .add_context(format!(
+                "{}() failed to get the limit of {kind:?} quota of id={id} from kv engine",
+                function_name!()
+            ))?
+            .map(ValueType::into_quota_limit)
+            .unwrap_or_default();
+        let mut usage = QuotaUsage::default();
+        for (_, shard_usage) in kv_utils::list_quota_usages(&self.kv_engine, kind, id)
+            .await
+            .add_context(format!(
+                "{}() failed to get the usage of {kind:?} quota of id={id} from kv engine",
+                function_name!()
+            ))?
+        {
+            usage.merge(&shard_usage);
+        }
+        Ok(Quota { limit, usage })
+    }
+
+    /// Helper function to check the quota of `kind` and `id` allows its usage
+    /// to grow by `delta` in `MetaTxn`. The limit and the shards of the usage
+    /// in `charged` are read in the transaction, the other shards are read out
+    /// of it.
+    async fn check_quota_in_txn<T: MetaTxn + ?Sized>(
+        &self,
+        txn: &mut T,
+        kind: QuotaKind,
+        id: u32,
+        charged: &BTreeMap<u32, (QuotaUsage, (i64, i64))>,
+        (bytes_delta, inodes_delta): (i64, i64),
+    ) -> DatenLordResult<()> {
+        let limit = Self::get_quota_limit_from_txn(txn, kind, id).await?;
+        if limit == QuotaLimit::default() {
+            return Ok(());
+        }
+        let mut usage = QuotaUsage::default();
+        for (shard, shard_usage) in kv_utils::list_quota_usages(&self.kv_engine, kind, id)
+            .await
+            .add_context(format!(
+                "{}() failed to get the usage of {kind:?} quota of id={id} from kv engine",
+                function_name!()
+            ))?
+        {
+            if !charged.contains_key(&shard) {
+                usage.merge(&shard_usage);
+            }
+        }
+        for &(ref shard_usage, _) in charged.values() {
+            usage.merge(shard_usage);
+        }
+        Quota { limit, usage }.check(kind, id, bytes_delta, inodes_delta)
+    }
+
+    /// Helper function to charge the changes of the used bytes and the number
+    /// of i-nodes to the usage of the volume and the quotas in `MetaTxn`. Each
+    /// change is `(attr, bytes_delta, inodes_delta)` of an i-node, which is
+    /// charged to the shards of the i-node in the usage of the volume and the
+    /// quotas of its owners and its project with a limit. If `enforce` is set,
+    /// it fails with `ENOSPC` if the i-nodes of the volume run out, or
+    /// `EDQUOT` if a quota is exceeded. The shards not charged are read out of
+    /// the transaction, so the concurrent changes in other shards may exceed
+    /// the limits slightly.
+    async fn charge_usage_in_txn<T: MetaTxn + ?Sized>(
+        &self,
+        txn: &mut T,
+        changes: &[(&FileAttr, i64, i64)],
+        enforce: bool,
+    ) -> DatenLordResult<()> {
+        // Merge the changes, as a key cannot be got twice in a transaction
+        let mut volume_deltas = BTreeMap::new();
+        let mut quota_deltas: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+        {
+            let limited_quotas = self.limited_quotas.read();
+            for &(attr, bytes_delta, inodes_delta) in changes {
+                let shard = volume_stat::shard_of(attr.ino);
+                let delta = volume_deltas.entry(shard).or_insert((0, 0));
+                *delta = quota::add_delta(*delta, (bytes_delta, inodes_delta));
+                // Only the usage of the quotas with a limit is counted
+                for key in quota::quota_ids(attr) {
+                    if !limited_quotas.contains(&key) {
+                        continue;
+                    }
+                    let delta = quota_deltas
+                        .entry(key)
+                        .or_default()
+                        .entry(shard)
+                        .or_insert((0, 0));
+                    *delta = quota::add_delta(*delta, (bytes_delta, inodes_delta));
+                }
+            }
+        }
+
+        if enforce
+            && volume_deltas
+                .values()
+                .any(|&(_, inodes_delta)| inodes_delta > 0)
+        {
+            kv_utils::get_volume_stat(&self.kv_engine)
+                .await
+                .add_context(format!(
+                    "{}() failed to get the usage of the volume from kv engine",
+                    function_name!()
+                ))?
+                .check_free_inode(self.storage_config.volume_inodes)?;
+        }
+        for (shard, (bytes_delta, inodes_delta)) in volume_deltas {
+            if (bytes_delta, inodes_delta) == (0, 0) {
+                continue;
+            }
+            let mut stat = Self::get_volume_stat_from_txn(txn, shard).await?;
+            stat.update(bytes_delta, inodes_delta);
+            txn.set(&KeyType::VolumeStat(shard), &ValueType::VolumeStat(stat));
+        }
+        for ((kind, id), shard_deltas) in quota_deltas {
+            let mut charged = BTreeMap::new();
+            let mut total_delta = (0, 0);
+            for (shard, delta) in shard_deltas {
+                if delta == (0, 0) {
+                    continue;
+                }
+                let usage = Self::get_quota_usage_from_txn(txn, kind, id, shard).await?;
+                charged.insert(shard, (usage, delta));
+                total_delta = quota::add_delta(total_delta, delta);
+            }
+            if enforce && (total_delta.0 > 0 || total_delta.1 > 0) {
+                self.check_quota_in_txn(txn, kind, id, &charged, total_delta)
+                    .await?;
+            }
+            for (shard, (mut usage, (bytes_delta, inodes_delta))) in charged {
+                usage.charge(bytes_delta, inodes_delta);
+                txn.set(
+                    &KeyType::QuotaUsage(kind, id, shard),
+                    &ValueType::QuotaUsage(usage),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Helper function to charge the growth of the i-node of `attr` to `end`
+    /// in `MetaTxn` before its data is changed, so the quotas are enforced.
+    /// The usage is counted in whole blocks, so it is only charged when the
+    /// growth crosses a block boundary. Return the used bytes of the i-node
+    /// including the growth, which should be passed to
+    /// `set_nodes_with_size_change_in_txn` as its old used bytes.
+    async fn charge_growth_in_txn<T: MetaTxn + ?Sized>(
+        &self,
+        txn: &mut T,
+        attr: &FileAttr,
+        end: u64,
+    ) -> DatenLordResult<u64> {
+        let old_used_bytes = volume_stat::used_bytes(attr);
+        let new_used_bytes = volume_stat::used_bytes(&FileAttr { size: end, ..*attr });
+        if new_used_bytes <= old_used_bytes {
+            return Ok(old_used_bytes);
+        }
+        let bytes_delta = volume_stat::size_delta(old_used_bytes, new_used_bytes);
+        self.charge_usage_in_txn(txn, &[(attr, bytes_delta, 0)], true)
+            .await?;
+        Ok(new_used_bytes)
+    }
+
+    /// Helper function to set the i-nodes in `MetaTxn`, the changes of their
+    /// sizes are charged to the usage of the volume and the quotas, and the
+    /// changes of their shared S3 objects are applied to the reference counts.
+    /// Each change is `(ino, inode, old_used_bytes, old_shared)`. Return the
+    /// shared S3 objects no longer referenced, which should be deleted after
+    /// the transaction is committed. The growth already charged by
+    /// `charge_growth_in_txn` should be included in `old_used_bytes`, as the
+    /// usage cannot be charged twice in a transaction, so is the snapshot
+    /// `state` already read in the transaction.
+    async fn set_nodes_with_size_change_in_txn<T: MetaTxn + ?Sized>(
+        &self,
+        txn: &mut T,
+        changes: &[(INum, &S3Node<S>, u64, &BTreeSet<INum>)],
+        state: Option<SnapshotState>,
+    ) -> DatenLordResult<Vec<INum>> {
+        let attrs: Vec<FileAttr> = changes
+            .iter()
+            .map(|&(_, inode, _, _)| inode.get_attr())
+            .collect();
+        let mut usage_changes = Vec::new();
+        // Merge the changes, as a key cannot be got twice in a transaction
+        let mut ref_deltas = BTreeMap::new();
+        for (&(_, inode, old_used_bytes, old_shared), attr) in changes.iter().zip(&attrs) {
+            let bytes_delta =
+                volume_stat::size_delta(old_used_bytes, volume_stat::used_bytes(attr));
+            if bytes_delta != 0 {
+                usage_changes.push((attr, bytes_delta, 0));
+            }
+            for (object, delta) in reflink::ref_deltas(old_shared, &inode.shared_objects()) {
+                let total = ref_deltas.entry(object).or_insert(0_i64);
+                *total = total.overflow_add(delta);
+            }
+        }
+        if !usage_changes.is_empty() {
+            // The data is already changed, so the quotas are not enforced, the
+            // growth should be enforced by `charge_growth_in_txn` beforehand
+            self.charge_usage_in_txn(txn, &usage_changes, false).await?;
+        }
+        let ref_deltas: Vec<(INum, i64)> = ref_deltas
+            .into_iter()
+            .filter(|&(_, delta)| delta != 0)
+            .collect();
+        let mut released = Self::update_object_refs_in_txn(txn, &ref_deltas).await?;
+        if Self::retain_objects_in_txn(txn, &released, state).await? {
+            released.clear();
+        }
+        for &(ino, inode, _, _) in changes {
+            txn.set(
+                &KeyType::INum2Node(ino),
+                &ValueType::Node(inode.to_serial_node()),
+            );
+        }
+        Ok(released)
+    }
+
+    /// Helper function to apply the changes of the reference counts of the
+    /// shared S3 objects in the transaction, return the objects no longer
+    /// referenced, which should be deleted after the transaction is committed
+    async fn update_object_refs_in_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+        ref_deltas: &[(INum, i64)],
+    ) -> DatenLordResult<Vec<INum>> {
+        let mut released = Vec::new();
+        for &(object, delta) in ref_deltas {
+            let key = KeyType::SharedObject(object);
+            let count = txn
+                .get(&key)
+                .await
+                .add_context(format!(
+                    "{}() failed to get the reference count of object={object} from kv engine",
+                    function_name!()
+                ))?
+                .map_or(0, ValueType::into_ref_count)
+                .saturating_add_signed(delta);
+            if count == 0 {
+                txn.delete(&key);
+                released.push(object);
+            } else {
+                txn.set(&key, &ValueType::RefCount(count));
+            }
+        }
+        Ok(released)
+    }
+
+    /// Helper function to delete the shared S3 objects no longer referenced
+    async fn delete_shared_objects(&self, objects: &[INum]) {
+        for &object in objects {
+            if let Err(e) = self.s3_backend.delete_data(object).await {
+                warn!(
+                    "failed to delete the shared object={object} from s3 backend, error is {e:?}"
+                );
+            }
+        }
+    }
+
+    /// Helper function to retain the S3 objects no longer referenced by the
+    /// volume for the snapshots in the transaction, return whether they are
+    /// retained, otherwise they should be deleted after the transaction is
+    /// committed. The snapshot `state` is read in the transaction unless it is
+    /// already read.
+    async fn retain_objects_in_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+        objects: &[INum],
+        state: Option<SnapshotState>,
+    ) -> DatenLordResult<bool> {
+        if objects.is_empty() {
+            return Ok(false);
+        }
+        let state = match state {
+            Some(state) => state,
+            None => Self::get_snapshot_state_from_txn(txn).await?,
+        };
+        if !state.retains() {
+            return Ok(false);
+        }
+        for &object in objects {
+            txn.set(
+                &KeyType::RetainedObject(object),
+                &ValueType::Generation(state.generation),
+            );
+        }
+        Ok(true)
+    }
+
+    /// Helper function to retain the own S3 object of an i-node before the
+    /// flush for the snapshots in the transaction, if the i-node switches to a
+    /// new S3 object
+    fn retain_flushed_object_in_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+        inode: &S3Node<S>,
+        (old_object, generation): (INum, u64),
+    ) {
+        if inode.own_object() != old_object {
+            txn.set(
+                &KeyType::RetainedObject(old_object),
+                &ValueType::Generation(generation),
+            );
+        }
+    }
+
+    /// Helper function to get the state of the snapshots from `MetaTxn`
+    async fn get_snapshot_state_from_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+    ) -> DatenLordResult<SnapshotState> {
+        Ok(txn
+            .get(&KeyType::SnapshotState)
+            .await
+            .add_context(format!(
+                "{}() failed to get the snapshot state from kv engine",
+                function_name!()
+            ))?
+            .map(ValueType::into_snapshot_state)
+            .unwrap_or_default())
+    }
+
+    /// Helper function to get the state of the snapshots from kv engine
+    async fn get_snapshot_state_from_kv_engine(&self) -> DatenLordResult<SnapshotState> {
+        Ok(self
+            .kv_engine
+            .get(&KeyType::SnapshotState)
+            .await
+            .add_context(format!(
+                "{}() failed to get the snapshot state from kv engine",
+                function_name!()
+            ))?
+            .map(ValueType::into_snapshot_state)
+            .unwrap_or_default())
+    }
+
+    /// Helper function to create or delete a snapshot through the root
+    /// directory
+    async fn set_snapshot_xattr(
+        &self,
+        context: ReqContext,
+        ino: INum,
+        name: &str,
+        create: bool,
+    ) -> DatenLordResult<()> {
+        let inode = self
+            .get_node_from_kv_engine(ino)
+            .await?
+            .ok_or_else(|| build_inconsistent_fs!(ino))?;
+        Self::xattr_pre_check(&context, &inode, name, 2)?;
+        if ino != FUSE_ROOT_ID {
+            return build_error_result_from_errno(
+                Errno::EPERM,
+                format!("snapshot xattr name={name:?} is only permitted on the root directory"),
+            );
+        }
+        if self.storage_config.metadata_snapshot.is_some() {
+            return build_error_result_from_errno(
+                Errno::EROFS,
+                format!("snapshot xattr name={name:?} cannot be changed in a snapshot"),
+            );
+        }
+        let snapshot = snapshot::parse_snapshot_xattr_name(name)?;
+        if create {
+            self.create_snapshot(snapshot).await
+        } else {
+            self.delete_snapshot(snapshot).await
+        }
+    }
+
+    /// Helper function to get a snapshot through the root directory
+    async fn get_snapshot_xattr(&self, inode: &S3Node<S>, name: &str) -> DatenLordResult<Vec<u8>> {
+        if inode.get_ino() != FUSE_ROOT_ID {
+            return build_error_result_from_errno(
+                Errno::ENODATA,
+                format!(
+                    "snapshot xattr name={name:?} is only in the root directory, not ino={}",
+                    inode.get_ino(),
+                ),
+            );
+        }
+        let snapshot = snapshot::parse_snapshot_xattr_name(name)?;
+        self.kv_engine
+            .get(&KeyType::Snapshot(snapshot.to_owned()))
+            .await?
+            .map_or_else(
+                || {
+                    build_error_result_from_errno(
+                        Errno::ENODATA,
+                        format!("get_snapshot_xattr() failed to find snapshot name={snapshot:?}"),
+                    )
+                },
+                |value| value.into_snapshot().to_xattr(snapshot),
+            )
+    }
+
+    /// Create the snapshot `name` of the metadata. The snapshot generation is
+    /// bumped first, so the S3 objects captured by the snapshot are never
+    /// overwritten afterwards, then the revision the generation is bumped at
+    /// is recorded in the snapshot. The snapshot left incomplete by an
+    /// interruption in between is deleted after `SNAPSHOT_CREATE_TIMEOUT`.
+    async fn create_snapshot(&self, name: &str) -> DatenLordResult<()> {
+        let key = KeyType::Snapshot(name.to_owned());
+        let (generation, revision) = retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            if txn.get(&key).await?.is_some() {
+                return build_error_result_from_errno(
+                    Errno::EEXIST,
+                    format!("create_snapshot() found snapshot name={name:?} already exists"),
+                );
+            }
+            let mut state = Self::get_snapshot_state_from_txn(txn.as_mut()).await?;
+            state.generation = state.generation.overflow_add(1);
+            state.count = state.count.overflow_add(1);
+            txn.set(&KeyType::SnapshotState, &ValueType::SnapshotState(state));
+            txn.set(
+                &key,
+                &ValueType::Snapshot(SnapshotInfo {
+                    generation: state.generation,
+                    revision: None,
+                    time: SystemTime::now(),
+                }),
+            );
+            // The files flushed after the commit record the new generation, so
+            // the snapshot captures the revision of the commit
+            let committed = txn.commit().await;
+            (committed, (state.generation, txn.revision()))
+        })?;
+        retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let info = txn.get(&key).await?.map(ValueType::into_snapshot);
+            // The snapshot may be deleted or recreated in the meantime
+            let Some(mut info) = info.filter(|info| info.generation == generation) else {
+                return build_error_result_from_errno(
+                    Errno::EIO,
+                    format!(
+                        "create_snapshot() found snapshot name={name:?} deleted before it is \
+                            complete"
+                    ),
+                );
+            };
+            info.revision = Some(revision);
+            txn.set(&key, &ValueType::Snapshot(info));
+            (txn.commit().await, ())
+        })?;
+        info!(
+            "create_snapshot() created snapshot name={:?} of generation={} at revision={}",
+            name, generation, revision,
+        );
+        Ok(())
+    }
+
+    /// Helper function to list the snapshots, the ones abandoned by an
+    /// interrupted creation are deleted instead
+    async fn list_snapshots(&self) -> DatenLordResult<Vec<(String, SnapshotInfo)>> {
+        let now = SystemTime::now();
+        let mut snapshots = Vec::new();
+        for (name, info) in kv_utils::list_snapshots(&self.kv_engine).await? {
+            if info.is_abandoned(now) {
+                warn!(
+                    "list_snapshots() found snapshot name={:?} of generation={} abandoned, \
+                        delete it",
+                    name, info.generation,
+                );
+                self.delete_abandoned_snapshot(&name, info.generation)
+                    .await?;
+                continue;
+            }
+            snapshots.push((name, info));
+        }
+        Ok(snapshots)
+    }
+
+    /// Delete the snapshot `name` of `generation` abandoned by an interrupted
+    /// creation, unless it is completed, deleted or recreated in the meantime
+    async fn delete_abandoned_snapshot(&self, name: &str, generation: u64) -> DatenLordResult<()> {
+        let key = KeyType::Snapshot(name.to_owned());
+        retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let info = txn.get(&key).await?.map(ValueType::into_snapshot);
+            let abandoned = info.map_or(false, |info| {
+                info.generation == generation && info.revision.is_none()
+            });
+            if !abandoned {
+                return Ok(());
+            }
+            let mut state = Self::get_snapshot_state_from_txn(txn.as_mut()).await?;
+            state.count = state.count.saturating_sub(1);
+            txn.set(&KeyType::SnapshotState, &ValueType::SnapshotState(state));
+            txn.delete(&key);
+            (txn.commit().await, ())
+        })
+    }
+
+    /// Delete the snapshot `name` of the metadata, and the retained S3 objects
+    /// no longer referenced by any snapshot
+    async fn delete_snapshot(&self, name: &str) -> DatenLordResult<()> {
+        let key = KeyType::Snapshot(name.to_owned());
+        retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            if txn.get(&key).await?.is_none() {
+                return build_error_result_from_errno(
+                    Errno::ENODATA,
+                    format!("delete_snapshot() failed to find snapshot name={name:?}"),
+                );
+            }
+            let mut state = Self::get_snapshot_state_from_txn(txn.as_mut()).await?;
+            state.count = state.count.saturating_sub(1);
+            txn.set(&KeyType::SnapshotState, &ValueType::SnapshotState(state));
+            txn.delete(&key);
+            (txn.commit().await, ())
+        })?;
+        info!("delete_snapshot() deleted snapshot name={:?}", name);
+        self.collect_retained_objects().await
+    }
+
+    /// Delete the retained S3 objects not referenced by any remaining snapshot
+    /// created before they are retained
+    async fn collect_retained_objects(&self) -> DatenLordResult<()> {
+        let retained = kv_utils::list_retained_objects(&self.kv_engine).await?;
+        let Some(max_retained_at) = retained.iter().map(|&(_, generation)| generation).max() else {
+            return Ok(());
+        };
+        let mut snapshots = Vec::new();
+        for (name, info) in self.list_snapshots().await? {
+            // The later snapshots reference no retained S3 object
+            if info.generation > max_retained_at {
+                continue;
+            }
+            let Some(revision) = info.revision else {
+                // The S3 objects referenced by an incomplete snapshot are unknown
+                warn!(
+                    "collect_retained_objects() found snapshot name={:?} incomplete, skip",
+                    name
+                );
+                return Ok(());
+            };
+            let nodes = kv_utils::list_nodes_at(&self.kv_engine, revision).await?;
+            snapshots.push((info.generation, snapshot::referenced_objects(&nodes)));
+        }
+        for object in snapshot::collectable_objects(&retained, &snapshots) {
+            if let Err(e) = self.s3_backend.delete_data(object).await {
+                warn!(
+                    "failed to delete the retained object={object} from s3 backend, error is {e:?}"
+                );
+                continue;
+            }
+            self.kv_engine
+                .delete(&KeyType::RetainedObject(object), None)
+                .await
+                .add_context(format!(
+                    "{}() failed to delete the retained object={object} from kv engine",
+                    function_name!()
+                ))?;
+        }
+        Ok(())
+    }
+
+    /// Helper function to set or remove the project id of an i-node, or the
+    /// limit of a quota through the root directory. The usage of the i-node is
+    /// moved to the new project. The usage of a quota is counted from all the
+    /// i-nodes when its limit is set, and removed with its limit.
+    async fn set_quota_xattr(
+        &self,
+        context: ReqContext,
+        ino: INum,
+        name: &str,
+        value: Option<&[u8]>,
+    ) -> DatenLordResult<()> {
+        let limited = retry_txn!(TXN_RETRY_LIMIT, {
+            let mut txn = self.kv_engine.new_meta_txn().await;
+            let mut inode = self.get_inode_from_txn(txn.as_mut(), ino).await?;
+            Self::xattr_pre_check(&context, &inode, name, 2)?;
+            if name == XATTR_NAME_PROJECT {
+                let old_attr = inode.get_attr();
+                if old_attr.kind != SFlag::S_IFREG && old_attr.kind != SFlag::S_IFDIR {
+                    return build_error_result_from_errno(
+                        Errno::EPERM,
+                        format!(
+                            "project id is not permitted on ino={ino} of type={:?}",
+                            old_attr.kind,
+                        ),
+                    );
+                }
+                let project_id = value.map_or(Ok(0), quota::parse_project_id)?;
+                let mut attr = old_attr;
+                attr.project_id = project_id;
+                attr.ctime = SystemTime::now();
+                inode.set_attr(attr);
+                let used_bytes = volume_stat::used_bytes(&attr);
+                self.charge_usage_in_txn(
+                    txn.as_mut(),
+                    &[
+                        (&old_attr, volume_stat::size_delta(used_bytes, 0), -1),
+                        (&attr, volume_stat::size_delta(0, used_bytes), 1),
+                    ],
+                    true,
+                )
+                .await?;
+                txn.set(
+                    &KeyType::INum2Node(ino),
+                    &ValueType::Node(inode.into_serial_node()),
+                );
+                (txn.commit().await, None)
+            } else {
+                if ino != FUSE_ROOT_ID {
+                    return build_error_result_from_errno(
+                        Errno::EPERM,
+                        format!(
+                            "quota xattr name={name:?} is only permitted on the root directory"
+                        ),
+                    );
+                }
+                let (kind, id) = quota::parse_quota_xattr_name(name)?;
+                let limit = value.map_or(Ok(QuotaLimit::default()), |v| {
+                    quota::parse_quota_limit(name, v)
+                })?;
+                let old_limit = Self::get_quota_limit_from_txn(txn.as_mut(), kind, id).await?;
+                let limited = limit != QuotaLimit::default();
+                // The usage is stored apart, so it is kept when the limit is
+                // changed, and counted again when the limit is set
+                if limited != (old_limit != QuotaLimit::default()) {
+                    let usages = if limited {
+                        self.count_quota_usage(kind, id).await?
+                    } else {
+                        BTreeMap::new()
+                    };
+                    for (shard, _) in kv_utils::list_quota_usages(&self.kv_engine, kind, id)
+                        .await
+                        .add_context(format!(
+                            "{}() failed to get the usage of {kind:?} quota of id={id} \
+                                from kv engine",
+                            function_name!()
+                        ))?
+                    {
+                        if !usages.contains_key(&shard) {
+                            txn.delete(&KeyType::QuotaUsage(kind, id, shard));
+                        }
+                    }
+                    for (shard, usage) in usages {
+                        txn.set(
+                            &KeyType::QuotaUsage(kind, id, shard),
+                            &ValueType::QuotaUsage(usage),
+                        );
+                    }
+                }
+                if limited {
+                    txn.set(&KeyType::Quota(kind, id), &ValueType::QuotaLimit(limit));
+                } else {
+                    txn.delete(&KeyType::Quota(kind, id));
+                }
+                (txn.commit().await, Some(((kind, id), limited)))
+            }
+        })?;
+        // Count the usage on this node at once, the other nodes are notified
+        // by watching the limits
+        if let Some((key, limited)) = limited {
+            if limited {
+                self.limited_quotas.write().insert(key);
+            } else {
+                self.limited_quotas.write().remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Helper function to count the usage of the quota of `kind` and `id` from
+    /// all the i-nodes in kv engine, the root i-node is not counted
+    async fn count_quota_usage(
+        &self,
+        kind: QuotaKind,
+        id: u32,
+    ) -> DatenLordResult<BTreeMap<u32, QuotaUsage>> {
+        let attrs: Vec<FileAttr> = kv_utils::list_nodes(&self.kv_engine)
+            .await
+            .add_context(format!(
+                "{}() failed to get the i-nodes from kv engine",
+                function_name!()
+            ))?
+            .iter()
+            .map(|node| serial::serial_to_file_attr(&node.attr))
+            .filter(|attr| attr.ino != FUSE_ROOT_ID)
+            .collect();
+        Ok(quota::count_usage(&attrs, kind, id))
+    }
+
+    /// Helper function to get the project id of an i-node, or the limit and
+    /// usage of a quota through the root directory
+    async fn get_quota_xattr(&self, inode: &S3Node<S>, name: &str) -> DatenLordResult<Vec<u8>> {
+        if name == XATTR_NAME_PROJECT {
+            return Ok(inode.get_attr().project_id.to_string().into_bytes());
+        }
+        if inode.get_ino() != FUSE_ROOT_ID {
+            return build_error_result_from_errno(
+                Errno::ENODATA,
+                format!(
+                    "quota xattr name={name:?} is only in the root directory, not ino={}",
+                    inode.get_ino(),
+                ),
+            );
+        }
+        let (kind, id) = quota::parse_quota_xattr_name(name)?;
+        Ok(self.get_quota_from_kv_engine(kind, id).await?.to_xattr())
+    }
+
+    /// Helper function to check whether the xattr name is the project id or a
+    /// quota
+    fn is_quota_xattr(name: &str) -> bool {
+        name == XATTR_NAME_PROJECT || name.starts_with(quota::XATTR_PREFIX_QUOTA)
+    }
+
+    /// Helper function to get the extended attributes of an i-node from kv
+    /// engine
+    async fn get_xattrs_from_kv_engine(
+        &self,
+        ino: INum,
+    ) -> DatenLordResult<BTreeMap<String, Vec<u8>>> {
+        Ok(self
+            .kv_engine
+            .get(&KeyType::INum2XAttr(ino))
+            .await
+            .add_context(format!(
+                "{}() failed to get xattrs of ino={ino} from kv engine",
+                function_name!()
+            ))?
+            .map(ValueType::into_xattr)
+            .unwrap_or_default())
+    }
+
     /// Helper function to get inode from `MetaTxn`
     async fn try_get_inode_from_txn<T: MetaTxn + ?Sized>(
         &self,
@@ -1440,6 +3603,24 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3MetaData<S> {
         }
     }
 
+    /// Helper function to check whether the inode in `MetaTxn` is still
+    /// `serial_node`
+    async fn node_unchanged_in_txn<T: MetaTxn + ?Sized>(
+        txn: &mut T,
+        ino: INum,
+        serial_node: &SerialNode,
+    ) -> DatenLordResult<bool> {
+        let inode = txn
+            .get(&KeyType::INum2Node(ino))
+            .await
+            .add_context(format!(
+                "{}() failed to get i-node of ino={ino} from kv engine",
+                function_name!()
+            ))?
+            .map(ValueType::into_serial_node);
+        Ok(inode.as_ref() == Some(serial_node))
+    }
+
     /// Helper function to get inode that must exist from `MetaTxn`
     async fn get_inode_from_txn<T: MetaTxn + ?Sized>(
         &self,
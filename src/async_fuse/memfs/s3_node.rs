@@ -1,6 +1,6 @@
 //! The implementation of filesystem node
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering};
@@ -19,27 +19,56 @@ use nix::unistd;
 use parking_lot::RwLock;
 use tracing::debug;
 
+use super::acl::PosixAcl;
 use super::cache::{GlobalCache, IoMemBlock};
 use super::dir::DirEntry;
 use super::dist::client as dist_client;
+use super::dist::request::Index;
 use super::fs_util::{self, FileAttr, NEED_CHECK_PERM};
+use super::hole::HoleMap;
 use super::kv_engine::KVEngineType;
 use super::node::Node;
+use super::reflink::ExtentMap;
 use super::s3_metadata::S3MetaData;
 use super::s3_wrapper::S3BackEnd;
 use super::serial::{
     dir_entry_to_serial, file_attr_to_serial, serial_to_file_attr, SerialNode, SerialNodeData,
 };
-use super::{CreateParam, SetAttrParam};
-use crate::async_fuse::fuse::fuse_reply::{AsIoVec, StatFsParam};
+use super::snapshot::SnapshotState;
+use super::{CreateParam, SetAttrParam, WriteData};
+use crate::async_fuse::fuse::fuse_reply::AsIoVec;
 use crate::async_fuse::fuse::protocol::{INum, FUSE_ROOT_ID};
 use crate::async_fuse::metrics;
 use crate::async_fuse::util::build_error_result_from_errno;
 use crate::common::error::{DatenLordError, DatenLordResult};
 
+/// Keep the file size unchanged, the same as `libc::FALLOC_FL_KEEP_SIZE`
+pub(crate) const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+/// Deallocate the range, the same as `libc::FALLOC_FL_PUNCH_HOLE`
+const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+/// Zero the range, the same as `libc::FALLOC_FL_ZERO_RANGE`
+const FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+
 /// S3's available fd count
 static GLOBAL_S3_FD_CNT: AtomicU32 = AtomicU32::new(4);
 
+/// Get new fd
+pub(crate) fn new_fd() -> u32 {
+    // Add global fd counter
+    GLOBAL_S3_FD_CNT.fetch_add(1, Ordering::SeqCst)
+}
+
+/// Get the fd to allocate next, which is handed over on live upgrade
+pub(crate) fn next_fd() -> u32 {
+    GLOBAL_S3_FD_CNT.load(Ordering::SeqCst)
+}
+
+/// Continue allocating fds from `next_fd` handed over by the previous process,
+/// so that the new fds never collide with the ones the kernel holds
+pub(crate) fn restore_next_fd(next_fd: u32) {
+    GLOBAL_S3_FD_CNT.fetch_max(next_fd, Ordering::SeqCst);
+}
+
 /// A file node data or a directory node data
 #[derive(Debug)]
 pub enum S3NodeData {
@@ -50,6 +79,9 @@ pub enum S3NodeData {
     /// Symlink target data
     // SymLink(Box<SymLinkData>),
     SymLink(PathBuf),
+    /// FIFO, socket or device node, which has no data, the device number is in
+    /// the attribute
+    Special,
 }
 
 impl S3NodeData {
@@ -65,6 +97,7 @@ impl S3NodeData {
             }
             Self::RegFile(_) => SerialNodeData::File,
             Self::SymLink(ref target) => SerialNodeData::SymLink(target.clone()),
+            Self::Special => SerialNodeData::Special,
         }
     }
 }
@@ -88,6 +121,13 @@ pub struct S3Node<S: S3BackEnd + Sync + Send + 'static> {
     lookup_count: AtomicI64,
     /// If S3Node has been marked as deferred deletion
     deferred_deletion: AtomicBool,
+    /// The holes of a regular file
+    holes: HoleMap,
+    /// The extents of a regular file shared with other files
+    extents: ExtentMap,
+    /// The snapshot generation of the next upload and the new own S3 object
+    /// it uploads to, prepared by `prepare_upload()`
+    pending_upload: Option<(u64, Option<INum>)>,
     /// KVEngine
     kv_engine: Arc<KVEngineType>,
     /// K8s node id
@@ -120,6 +160,9 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
             // lookup count set to 1 by creation
             lookup_count: AtomicI64::new(1),
             deferred_deletion: AtomicBool::new(false),
+            holes: HoleMap::default(),
+            extents: ExtentMap::default(),
+            pending_upload: None,
             kv_engine: Arc::clone(kv_engine),
             k8s_node_id: Arc::clone(k8s_node_id),
             storage_config: Arc::clone(storage_config),
@@ -177,6 +220,9 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
                 open_count: AtomicI64::new(serial_node.open_count),
                 lookup_count: AtomicI64::new(serial_node.lookup_count),
                 deferred_deletion: AtomicBool::new(serial_node.deferred_deletion),
+                holes: serial_node.holes,
+                extents: serial_node.extents,
+                pending_upload: None,
                 kv_engine: Arc::clone(&meta.kv_engine),
                 k8s_node_id: Arc::clone(&meta.node_id),
                 storage_config: Arc::clone(&meta.storage_config),
@@ -195,6 +241,8 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
             open_count: self.open_count.load(Ordering::SeqCst),
             lookup_count: self.lookup_count.load(Ordering::SeqCst),
             deferred_deletion: self.deferred_deletion.load(Ordering::SeqCst),
+            holes: self.holes,
+            extents: self.extents,
         }
     }
 
@@ -208,6 +256,8 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
             open_count: self.open_count.load(Ordering::SeqCst),
             lookup_count: self.lookup_count.load(Ordering::SeqCst),
             deferred_deletion: self.deferred_deletion.load(Ordering::SeqCst),
+            holes: self.holes.clone(),
+            extents: self.extents.clone(),
         }
     }
 
@@ -229,6 +279,9 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
                     panic!("type is S_IFLNK, but target_path is None");
                 }
             }
+            SFlag::S_IFIFO | SFlag::S_IFSOCK | SFlag::S_IFCHR | SFlag::S_IFBLK => {
+                S3NodeData::Special
+            }
             _ => panic!("unsupported type {:?}", child_attr.read().kind),
         };
         Self {
@@ -242,6 +295,9 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
             // open count set to 0 for sync
             lookup_count: AtomicI64::new(0),
             deferred_deletion: AtomicBool::new(false),
+            holes: HoleMap::default(),
+            extents: ExtentMap::default(),
+            pending_upload: None,
             kv_engine: Arc::clone(&parent.kv_engine),
             k8s_node_id: Arc::clone(&parent.k8s_node_id),
             storage_config: Arc::clone(&parent.storage_config),
@@ -255,19 +311,13 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
             S3NodeData::Directory(..) => debug_assert_eq!(new_attr.kind, SFlag::S_IFDIR),
             S3NodeData::RegFile(..) => debug_assert_eq!(new_attr.kind, SFlag::S_IFREG),
             S3NodeData::SymLink(..) => debug_assert_eq!(new_attr.kind, SFlag::S_IFLNK),
+            S3NodeData::Special => debug_assert_eq!(new_attr.kind, old_attr.kind),
         }
 
         self.attr.write().clone_from(&new_attr);
         old_attr
     }
 
-    #[allow(clippy::unused_self)]
-    /// Get new fd
-    fn new_fd(&self) -> u32 {
-        // Add global fd counter
-        GLOBAL_S3_FD_CNT.fetch_add(1, Ordering::SeqCst)
-    }
-
     /// Update mtime and ctime to now
     fn update_mtime_ctime_to_now(&mut self) {
         let mut attr = self.get_attr();
@@ -316,7 +366,7 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
     pub(crate) fn get_dir_data(&self) -> &BTreeMap<String, DirEntry> {
         match self.data {
             S3NodeData::Directory(ref dir_data) => dir_data,
-            S3NodeData::RegFile(..) | S3NodeData::SymLink(..) => {
+            S3NodeData::RegFile(..) | S3NodeData::SymLink(..) | S3NodeData::Special => {
                 panic!("forbidden to get DirData from non-directory node")
             }
         }
@@ -326,14 +376,14 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
     pub(crate) fn get_dir_data_mut(&mut self) -> &mut BTreeMap<String, DirEntry> {
         match self.data {
             S3NodeData::Directory(ref mut dir_data) => dir_data,
-            S3NodeData::RegFile(..) | S3NodeData::SymLink(..) => {
+            S3NodeData::RegFile(..) | S3NodeData::SymLink(..) | S3NodeData::Special => {
                 panic!("forbidden to get DirData from non-directory node")
             }
         }
     }
 
     /// Increase node open count
-    fn inc_open_count(&self) -> i64 {
+    pub(crate) fn inc_open_count(&self) -> i64 {
         // TODO: add the usage
         self.open_count.fetch_add(1, Ordering::AcqRel)
     }
@@ -376,41 +426,54 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
     }
 
     /// flush all data of a node
-    async fn flush_all_data(&mut self) -> DatenLordResult<()> {
-        if self.is_deferred_deletion() {
+    pub(crate) async fn flush_all_data(&mut self) -> DatenLordResult<()> {
+        // The S3 objects of a snapshot are never overwritten
+        if self.is_deferred_deletion() || self.storage_config.metadata_snapshot.is_some() {
             return Ok(());
         }
         let data_cache = match self.data {
             S3NodeData::RegFile(ref data_cache) => Arc::<GlobalCache>::clone(data_cache),
-            // Do nothing for Directory.
-            // TODO: Sync dir data to S3 storage
-            S3NodeData::Directory(..) => return Ok(()),
+            // Do nothing for Directory and special files, which are persisted
+            // by the metadata checkpoints
+            S3NodeData::Directory(..) | S3NodeData::Special => return Ok(()),
             S3NodeData::SymLink(..) => panic!("forbidden to flush data for link"),
         };
 
         let size = self.attr.read().size;
-        if self.need_load_file_data(0, size.cast()).await {
-            let load_res = self.load_data(0, size.cast()).await;
-            if let Err(e) = load_res {
-                debug!(
-                    "failed to load data for file {} while flushing data, the error is: {:?}",
-                    self.get_name(),
-                    e,
-                );
-                return Err(e);
+        // The holes and the shared extents are not uploaded, the own S3 object
+        // only stores the data out of them
+        let mut file_data = Vec::new();
+        for (start, end) in self.own_data_segments(0, size) {
+            let (start, len) = (start.cast(), end.overflow_sub(start).cast());
+            if self.need_load_file_data(start, len).await {
+                let load_res = self.load_data(start, len).await;
+                if let Err(e) = load_res {
+                    debug!(
+                        "failed to load data for file {} while flushing data, the error is: {:?}",
+                        self.get_name(),
+                        e,
+                    );
+                    return Err(e);
+                }
             }
+            file_data.append(&mut data_cache.get_file_cache(self.get_ino(), start, len));
         }
 
-        let put_result = self
-            .s3_backend
-            .put_data_vec(
-                self.get_ino(),
-                data_cache.get_file_cache(self.get_ino(), 0, size.cast()),
-            )
-            .await;
+        let (generation, new_object) = self.pending_upload.take().unwrap_or_default();
+        let object = new_object.unwrap_or_else(|| self.own_object());
+        let put_result = self.s3_backend.put_data_vec(object, file_data).await;
 
         match put_result {
-            Ok(()) => Ok(()),
+            Ok(()) => {
+                self.holes.uploaded(size, &self.extents.shared_ranges());
+                if new_object.is_some() {
+                    self.extents.set_object(object);
+                }
+                if generation > self.extents.object_generation() {
+                    self.extents.set_object_generation(generation);
+                }
+                Ok(())
+            }
             Err(e) => {
                 debug!(
                     "flush_all_data() failed to flush data for file {}, the error is: {}",
@@ -422,15 +485,512 @@ impl<S: S3BackEnd + Send + Sync + 'static> S3Node<S> {
         }
     }
 
+    /// Load the file data of `[offset, offset + len)` out of the holes into
+    /// the cache, from the cache of other nodes or from S3
+    async fn load_file_segment(
+        &self,
+        global_cache: &GlobalCache,
+        offset: usize,
+        len: usize,
+    ) -> DatenLordResult<usize> {
+        // The cache of other nodes holds the latest data, not the snapshot's
+        let file_data_vec = if self.storage_config.metadata_snapshot.is_some() {
+            self.load_object_data(offset, len).await?
+        } else {
+            self.load_remote_or_object_data(global_cache, offset, len)
+                .await?
+        };
+
+        let read_size = file_data_vec.len();
+        debug!(
+            "load_data() successfully load {} byte file content data",
+            read_size
+        );
+        global_cache
+            .write_or_update(self.get_ino(), offset, read_size, &file_data_vec, false)
+            .await;
+        Ok(read_size)
+    }
+
+    /// Load the file data of `[offset, offset + len)` from the cache of other
+    /// nodes, or from the S3 objects if no other node caches it
+    async fn load_remote_or_object_data(
+        &self,
+        global_cache: &GlobalCache,
+        offset: usize,
+        len: usize,
+    ) -> DatenLordResult<Vec<u8>> {
+        let volume_info = serde_json::to_string(self.storage_config.as_ref())?;
+
+        // dist_client::read_data() won't get lock at remote, OK to put here.
+        let file_data_vec = match dist_client::read_data(
+            &self.kv_engine,
+            &self.k8s_node_id,
+            &volume_info,
+            self.get_ino(),
+            offset.overflow_div(global_cache.get_align()).cast(),
+            offset
+                .overflow_add(len)
+                .overflow_sub(1)
+                .overflow_div(global_cache.get_align())
+                .cast(),
+        )
+        .await?
+        {
+            None => self.load_object_data(offset, len).await?,
+            Some(data) => data,
+        };
+        Ok(file_data_vec)
+    }
+
+    /// Get the data segments of `[start, end)` stored in the own S3 object,
+    /// skipping the holes and the shared extents
+    fn own_data_segments(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut segments = Vec::new();
+        for (data_start, data_end) in self.holes.data_segments(start, end) {
+            segments.extend(
+                self.extents
+                    .segments(data_start, data_end)
+                    .into_iter()
+                    .filter(|&(_, _, shared)| shared.is_none())
+                    .map(|(s, e, _)| (s, e)),
+            );
+        }
+        segments
+    }
+
+    /// Load the file data of `[offset, offset + len)` from the S3 objects, the
+    /// shared extents are loaded from the shared S3 objects and the others
+    /// from the own S3 object, the ranges not stored in the S3 objects are
+    /// read as zeros
+    async fn load_object_data(&self, offset: usize, len: usize) -> DatenLordResult<Vec<u8>> {
+        let own_object = self.own_object();
+        let mut segments = Vec::new();
+        for (start, end, shared) in self
+            .extents
+            .segments(offset.cast(), offset.overflow_add(len).cast())
+        {
+            if shared.is_some() {
+                segments.push((start, end, shared));
+                continue;
+            }
+            segments.extend(
+                self.holes
+                    .object_segments(start, end)
+                    .into_iter()
+                    .map(|(s, e, object_offset)| {
+                        (s, e, object_offset.map(|off| (own_object, off)))
+                    }),
+            );
+        }
+        let segment_cnt = segments.len();
+        let mut file_data = Vec::with_capacity(len);
+        for (idx, (start, end, location)) in segments.into_iter().enumerate() {
+            let segment_len: usize = end.overflow_sub(start).cast();
+            let Some((object, object_offset)) = location else {
+                file_data.resize(file_data.len().overflow_add(segment_len), 0);
+                continue;
+            };
+            let mut data = match self
+                .s3_backend
+                .get_partial_data(object, object_offset.cast(), segment_len)
+                .await
+            {
+                Ok(a) => a,
+                Err(e) => {
+                    let anyhow_err: anyhow::Error = e.into();
+                    return Err(DatenLordError::from(
+                        anyhow_err.context("load_data() failed to load file content data"),
+                    ));
+                }
+            };
+            data.truncate(segment_len);
+            // Keep the offsets of the following segments
+            if idx.overflow_add(1) < segment_cnt {
+                data.resize(segment_len, 0);
+            }
+            file_data.append(&mut data);
+        }
+        Ok(file_data)
+    }
+
+    /// Write zeros to `[start, end)` of the file, block by block
+    async fn write_zeros(&mut self, align: u64, start: u64, end: u64) -> DatenLordResult<()> {
+        let mut offset = start;
+        while offset < end {
+            let block_end = offset
+                .overflow_sub(offset.overflow_rem(align))
+                .overflow_add(align);
+            let zero_end = end.min(block_end);
+            self.write_file(
+                0,
+                offset.cast(),
+                &vec![0; zero_end.overflow_sub(offset).cast()],
+                OFlag::empty(),
+                true,
+            )
+            .await?;
+            offset = zero_end;
+        }
+        Ok(())
+    }
+
+    /// Punch a hole of `[start, end)`, the whole blocks in the range are
+    /// dropped from the cache and the partial blocks are zeroed
+    async fn punch_hole(
+        &mut self,
+        global_cache: &GlobalCache,
+        start: u64,
+        end: u64,
+    ) -> DatenLordResult<()> {
+        let size = self.attr.read().size;
+        let end = end.min(size);
+        if start >= end {
+            return Ok(());
+        }
+        let align: u64 = global_cache.get_align().cast();
+        let hole_start: u64 = global_cache.round_up(start.cast()).cast();
+        // The last partial block can be dropped as well if the hole reaches EOF
+        let hole_end: u64 = if end == size {
+            global_cache.round_up(size.cast()).cast()
+        } else {
+            global_cache.round_down(end.cast()).cast()
+        };
+        if hole_start >= hole_end {
+            return self.write_zeros(align, start, end).await;
+        }
+        self.write_zeros(align, start, hole_start).await?;
+        self.write_zeros(align, hole_end.min(end), end).await?;
+        self.punch_blocks(global_cache, hole_start, hole_end);
+        self.update_mtime_ctime_to_now();
+        Ok(())
+    }
+
+    /// Punch a hole of the block aligned `[start, end)`, the blocks are
+    /// dropped from the cache
+    fn punch_blocks(&mut self, global_cache: &GlobalCache, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let align: u64 = global_cache.get_align().cast();
+        global_cache.invalidate(
+            self.get_ino(),
+            vec![Index::Range(
+                start.overflow_div(align).cast(),
+                end.overflow_div(align).overflow_sub(1).cast(),
+            )],
+        );
+        self.holes.punch(start, end);
+        self.extents.unshare(start, end);
+    }
+
+    /// The range beyond the old EOF is never written when a file is extended,
+    /// so the whole blocks in it are holes
+    fn punch_extended_blocks(&mut self, old_size: u64, new_size: u64) {
+        let global_cache = match self.data {
+            S3NodeData::RegFile(ref global_cache) => Arc::clone(global_cache),
+            S3NodeData::Directory(..) | S3NodeData::SymLink(..) | S3NodeData::Special => return,
+        };
+        if new_size > old_size {
+            self.punch_blocks(
+                &global_cache,
+                global_cache.round_up(old_size.cast()).cast(),
+                global_cache.round_down(new_size.cast()).cast(),
+            );
+        }
+    }
+
+    /// The shared S3 objects referenced by the file
+    pub(crate) fn shared_objects(&self) -> BTreeSet<INum> {
+        self.extents.shared_objects()
+    }
+
+    /// The own S3 object of the file
+    pub(crate) fn own_object(&self) -> INum {
+        self.extents.object(self.get_ino())
+    }
+
+    /// Check if the own S3 object of the file may be captured by a snapshot
+    /// of `state`, so the file has to upload to a new own S3 object
+    pub(crate) fn object_captured(&self, state: &SnapshotState) -> bool {
+        matches!(self.data, S3NodeData::RegFile(..))
+            && !self.is_deferred_deletion()
+            && self.storage_config.metadata_snapshot.is_none()
+            && state.captures(self.extents.object_generation())
+    }
+
+    /// Prepare the next upload of the own S3 object at the snapshot
+    /// `generation`, the data is uploaded to `new_object` if any, and the file
+    /// switches to it once uploaded
+    pub(crate) fn prepare_upload(&mut self, generation: u64, new_object: Option<INum>) {
+        self.pending_upload = Some((generation, new_object));
+    }
+
+    /// Stop sharing any extent when the data of the file is released, return
+    /// the shared S3 objects referenced before
+    pub(crate) fn release_shared_extents(&mut self) -> BTreeSet<INum> {
+        let objects = self.extents.shared_objects();
+        self.extents.truncate(0);
+        objects
+    }
+
+    /// Freeze the own S3 object, which stores all the data out of the holes
+    /// and the shared extents after the file is flushed. The data is shared by
+    /// the file from the frozen S3 object, and the file switches to
+    /// `new_object`. Do nothing if the own S3 object stores no data.
+    fn freeze_object(&mut self, new_object: INum) {
+        let size = self.attr.read().size;
+        let object = self.own_object();
+        let mut frozen = false;
+        for (start, end) in self.own_data_segments(0, size) {
+            for (s, e, object_offset) in self.holes.object_segments(start, end) {
+                if let Some(object_offset) = object_offset {
+                    self.extents.share(s, e, object, object_offset);
+                    frozen = true;
+                }
+            }
+        }
+        if frozen {
+            self.extents.set_object(new_object);
+            // Nothing is uploaded to the new S3 object yet
+            self.holes.uploaded(size, &[(0, size)]);
+        }
+    }
+
+    /// Check if `[src_offset, src_offset + len)` of `src` can be cloned to
+    /// `dst_offset` of this file by a reflink, the ranges have to be block
+    /// aligned except the tail reaching EOF of both files
+    pub(crate) fn can_reflink(
+        &self,
+        src: &Self,
+        src_offset: u64,
+        dst_offset: u64,
+        len: u64,
+    ) -> bool {
+        let S3NodeData::RegFile(ref global_cache) = self.data else {
+            return false;
+        };
+        if !matches!(src.data, S3NodeData::RegFile(..))
+            || src.get_ino() == self.get_ino()
+            || src.is_deferred_deletion()
+        {
+            return false;
+        }
+        let src_size = src.attr.read().size;
+        if src_offset >= src_size || len == 0 {
+            return false;
+        }
+        let len = len.min(src_size.overflow_sub(src_offset));
+        let align: u64 = global_cache.get_align().cast();
+        let reach_eof = src_offset.overflow_add(len) == src_size
+            && dst_offset.overflow_add(len) >= self.attr.read().size;
+        src_offset.overflow_rem(align) == 0
+            && dst_offset.overflow_rem(align) == 0
+            && (len.overflow_rem(align) == 0 || reach_eof)
+    }
+
+    /// Clone `[src_offset, src_offset + len)` of `src` to `dst_offset` of this
+    /// file by sharing the S3 objects of `src`, which is checked by
+    /// `can_reflink()`. The own S3 object of `src` is frozen, and `src`
+    /// switches to `new_object`. Return the cloned size.
+    pub(crate) async fn reflink_from(
+        &mut self,
+        src: &mut Self,
+        src_offset: u64,
+        dst_offset: u64,
+        len: u64,
+        new_object: INum,
+    ) -> DatenLordResult<usize> {
+        let S3NodeData::RegFile(ref global_cache) = self.data else {
+            return build_error_result_from_errno(
+                Errno::EINVAL,
+                format!(
+                    "reflink_from() found {:?} is not a regular file",
+                    self.get_name()
+                ),
+            );
+        };
+        let global_cache = Arc::clone(global_cache);
+        let len = len.min(src.attr.read().size.saturating_sub(src_offset));
+        let src_end = src_offset.overflow_add(len);
+        // All the data of `src` has to be in the S3 objects before sharing them
+        src.flush_all_data().await?;
+        src.freeze_object(new_object);
+
+        let size = self.attr.read().size;
+        // The layout of the own S3 object has to be fixed before the holes change
+        self.holes.init_object_size(size);
+        let dst_end = dst_offset.overflow_add(len);
+        if dst_end > size {
+            self.punch_extended_blocks(size, dst_end);
+            self.attr.write().size = dst_end;
+        }
+        let align: u64 = global_cache.get_align().cast();
+        let block_end: u64 = global_cache.round_up(dst_end.cast()).cast();
+        global_cache.invalidate(
+            self.get_ino(),
+            vec![Index::Range(
+                dst_offset.overflow_div(align).cast(),
+                block_end.overflow_div(align).overflow_sub(1).cast(),
+            )],
+        );
+        self.extents.unshare(dst_offset, block_end);
+
+        for (start, end, is_hole) in src.holes.segments(src_offset, src_end) {
+            let dst_start = start.overflow_sub(src_offset).overflow_add(dst_offset);
+            let dst_seg_end = end.overflow_sub(src_offset).overflow_add(dst_offset);
+            if is_hole {
+                self.holes.punch(dst_start, dst_seg_end);
+                continue;
+            }
+            self.holes.fill(dst_start, dst_seg_end);
+            for (s, e, shared) in src.extents.segments(start, end) {
+                let Some((object, object_offset)) = shared else {
+                    return build_error_result_from_errno(
+                        Errno::EIO,
+                        format!(
+                            "reflink_from() found [{s}, {e}) of {:?} is not shared \
+                                after its S3 object is frozen",
+                            src.get_name(),
+                        ),
+                    );
+                };
+                self.extents.share(
+                    s.overflow_sub(src_offset).overflow_add(dst_offset),
+                    e.overflow_sub(src_offset).overflow_add(dst_offset),
+                    object,
+                    object_offset,
+                );
+            }
+            // The blocks cached by `src` are cached by this file as well, the
+            // others are loaded from the shared S3 objects on demand
+            let mut offset = start;
+            while offset < end {
+                let chunk_end = end.min(
+                    offset
+                        .overflow_sub(offset.overflow_rem(align))
+                        .overflow_add(align),
+                );
+                global_cache
+                    .copy(
+                        src.get_ino(),
+                        offset.cast(),
+                        self.get_ino(),
+                        offset
+                            .overflow_sub(src_offset)
+                            .overflow_add(dst_offset)
+                            .cast(),
+                        chunk_end.overflow_sub(offset).cast(),
+                    )
+                    .await;
+                offset = chunk_end;
+            }
+        }
+
+        self.update_mtime_ctime_to_now();
+        Ok(len.cast())
+    }
+
+    /// Copy `[src_offset, src_offset + len)` of `src` to `dst_offset` of this
+    /// file inside the cache, the holes of `src` are punched in this file
+    async fn copy_blocks_from(
+        &mut self,
+        global_cache: &GlobalCache,
+        src: &Self,
+        src_offset: u64,
+        dst_offset: u64,
+        len: u64,
+    ) -> DatenLordResult<()> {
+        let size = self.attr.read().size;
+        // The layout of the S3 object has to be fixed before the holes change,
+        // then the range beyond the old EOF is read as zeros instead of being
+        // loaded from S3
+        self.holes.init_object_size(size);
+        let dst_end = dst_offset.overflow_add(len);
+        if dst_end > size {
+            self.punch_extended_blocks(size, dst_end);
+            self.attr.write().size = dst_end;
+        }
+
+        let align: u64 = global_cache.get_align().cast();
+        for (start, end, is_hole) in src.holes.segments(src_offset, src_offset.overflow_add(len)) {
+            if is_hole {
+                self.punch_hole(
+                    global_cache,
+                    start.overflow_sub(src_offset).overflow_add(dst_offset),
+                    end.overflow_sub(src_offset).overflow_add(dst_offset),
+                )
+                .await?;
+                continue;
+            }
+            // Copy chunk by chunk, each chunk is in a single block of `src`
+            let mut offset = start;
+            while offset < end {
+                let chunk_end = end.min(
+                    offset
+                        .overflow_sub(offset.overflow_rem(align))
+                        .overflow_add(align),
+                );
+                let chunk_len: usize = chunk_end.overflow_sub(offset).cast();
+                let chunk_dst = offset.overflow_sub(src_offset).overflow_add(dst_offset);
+                if src.need_load_file_data(offset.cast(), chunk_len).await {
+                    src.load_data(offset.cast(), chunk_len).await?;
+                }
+                if self.need_load_file_data(chunk_dst.cast(), chunk_len).await {
+                    self.load_data(chunk_dst.cast(), chunk_len).await?;
+                }
+                let copied = global_cache
+                    .copy(
+                        src.get_ino(),
+                        offset.cast(),
+                        self.get_ino(),
+                        chunk_dst.cast(),
+                        chunk_len,
+                    )
+                    .await;
+                if !copied {
+                    return build_error_result_from_errno(
+                        Errno::EIO,
+                        format!(
+                            "copy_blocks_from() found the data at offset={offset} of {:?} \
+                                is evicted from the cache",
+                            src.get_name(),
+                        ),
+                    );
+                }
+                // The copied blocks are allocated in the cache, they are
+                // neither holes nor shared anymore
+                let (block_start, block_end): (u64, u64) = (
+                    global_cache.round_down(chunk_dst.cast()).cast(),
+                    global_cache
+                        .round_up(chunk_dst.cast::<usize>().overflow_add(chunk_len))
+                        .cast(),
+                );
+                self.holes.fill(block_start, block_end);
+                self.extents.unshare(block_start, block_end);
+                offset = chunk_end;
+            }
+        }
+
+        self.update_mtime_ctime_to_now();
+        Ok(())
+    }
+
     /// Check if given uid and gid can access this node
-    pub fn open_pre_check(&self, flags: OFlag, user_id: u32, group_id: u32) -> DatenLordResult<()> {
+    pub fn open_pre_check(
+        &self,
+        flags: OFlag,
+        user_id: u32,
+        group_id: u32,
+        acl: Option<&PosixAcl>,
+    ) -> DatenLordResult<()> {
         let attr = self.get_attr();
         let access_mode = match flags & (OFlag::O_RDONLY | OFlag::O_WRONLY | OFlag::O_RDWR) {
             OFlag::O_RDONLY => 4,
             OFlag::O_WRONLY => 2,
             _ => 6,
         };
-        attr.check_perm(user_id, group_id, access_mode)
+        attr.check_perm_with_acl(user_id, group_id, access_mode, acl)
     }
 
     /// Check if `name` is available for use
@@ -499,6 +1059,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
             S3NodeData::Directory(..) => SFlag::S_IFDIR,
             S3NodeData::RegFile(..) => SFlag::S_IFREG,
             S3NodeData::SymLink(..) => SFlag::S_IFLNK,
+            S3NodeData::Special => self.attr.read().kind,
         }
     }
 
@@ -510,6 +1071,10 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
 
     /// Set node attribute
     fn set_attr(&mut self, new_attr: FileAttr) -> FileAttr {
+        let old_size = self.attr.read().size;
+        self.punch_extended_blocks(old_size, new_attr.size);
+        self.holes.truncate(new_attr.size);
+        self.extents.truncate(new_attr.size);
         self._set_attr(new_attr, true)
     }
 
@@ -520,6 +1085,24 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
         attr
     }
 
+    /// Increase node hard link count, return the new count
+    fn inc_nlink(&mut self) -> u32 {
+        let mut attr = self.get_attr();
+        attr.nlink = attr.nlink.overflow_add(1);
+        attr.ctime = SystemTime::now();
+        self.set_attr(attr);
+        attr.nlink
+    }
+
+    /// Decrease node hard link count, return the new count
+    fn dec_nlink(&mut self) -> u32 {
+        let mut attr = self.get_attr();
+        attr.nlink = attr.nlink.saturating_sub(1);
+        attr.ctime = SystemTime::now();
+        self.set_attr(attr);
+        attr.nlink
+    }
+
     /// Get node open count
     fn get_open_count(&self) -> i64 {
         self.open_count.load(Ordering::Acquire)
@@ -571,14 +1154,15 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
     /// Duplicate fd
     async fn dup_fd(&self, _oflags: OFlag) -> DatenLordResult<RawFd> {
         self.inc_open_count();
-        Ok(self.new_fd().cast())
+        Ok(new_fd().cast())
     }
 
     /// Check whether a node is an empty file or an empty directory
     fn is_node_data_empty(&self) -> bool {
         match self.data {
             S3NodeData::Directory(ref dir_node) => dir_node.is_empty(),
-            S3NodeData::RegFile(..) => true, // always check the cache
+            // Always check the cache of regular files, special files have no data
+            S3NodeData::RegFile(..) | S3NodeData::Special => true,
             S3NodeData::SymLink(..) => panic!("forbidden to check symlink is empty or not"),
         }
     }
@@ -614,9 +1198,20 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
 
         match self.data {
             S3NodeData::RegFile(ref cache) => {
-                let file_cache = cache.get_file_cache(self.get_ino(), offset, len);
-                let cache_miss = file_cache.is_empty()
-                    || file_cache.iter().filter(|b| !(*b).can_convert()).count() != 0;
+                // The holes are never cached
+                let cache_miss = self
+                    .holes
+                    .data_segments(offset.cast(), offset.overflow_add(len).cast())
+                    .into_iter()
+                    .any(|(start, end)| {
+                        let file_cache = cache.get_file_cache(
+                            self.get_ino(),
+                            start.cast(),
+                            end.overflow_sub(start).cast(),
+                        );
+                        file_cache.is_empty()
+                            || file_cache.iter().filter(|b| !(*b).can_convert()).count() != 0
+                    });
                 if cache_miss {
                     metrics::CACHE_MISSES.inc();
                 } else {
@@ -624,7 +1219,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
                 }
                 cache_miss
             }
-            S3NodeData::Directory(..) | S3NodeData::SymLink(..) => {
+            S3NodeData::Directory(..) | S3NodeData::SymLink(..) | S3NodeData::Special => {
                 panic!("need_load_file_data should handle regular file")
             }
         }
@@ -647,17 +1242,6 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
             !dir_data.contains_key(child_symlink_name),
             "create_child_symlink() cannot create duplicated symlink name={child_symlink_name:?}",
         );
-        let target_str = target_path
-            .to_str()
-            .unwrap_or_else(|| panic!("failed to convert {target_path:?} to utf8 string"));
-        if let Err(e) = self
-            .s3_backend
-            .put_data(inum, target_str.as_bytes(), 0, target_str.len())
-            .await
-        {
-            panic!("failed to put data of file {inum} to s3 backend, error is {e:?}");
-        }
-
         // get symbol file attribute
         let child_attr = Arc::new(RwLock::new(FileAttr {
             ino: inum,
@@ -669,6 +1253,8 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
                 .cast(),
             blocks: 0,
             perm: 0o777,
+            nlink: 1,
+            project_id: self.attr.read().project_id,
             ..FileAttr::now()
         }));
 
@@ -749,6 +1335,8 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
             uid: user_id,
             gid: group_id,
             nlink: 1,
+            // The sub-directories are in the same project
+            project_id: self.attr.read().project_id,
             ..FileAttr::now()
         }));
 
@@ -811,9 +1399,6 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
             "open_child_file_helper() cannot create duplicated file name={child_file_name:?}"
         );
         debug_assert!(oflags.contains(OFlag::O_CREAT));
-        if let Err(e) = self.s3_backend.put_data(inum, b"", 0, 0).await {
-            panic!("failed to put data of file {inum} to s3 backend, error is {e:?}");
-        }
 
         // get new file attribute
         let child_attr = Arc::new(RwLock::new(FileAttr {
@@ -825,6 +1410,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
             uid: user_id,
             gid: group_id,
             nlink: 1,
+            project_id: self.attr.read().project_id,
             ..FileAttr::now()
         }));
         debug_assert_eq!(SFlag::S_IFREG, child_attr.read().kind);
@@ -850,6 +1436,54 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
         ))
     }
 
+    /// Create a FIFO, socket or device node in a directory, it has no data in
+    /// S3
+    async fn create_child_special(
+        &mut self,
+        inum: INum,
+        child_name: &str,
+        node_type: SFlag,
+        mode: Mode,
+        rdev: u32,
+        user_id: u32,
+        group_id: u32,
+    ) -> DatenLordResult<Self> {
+        let dir_data = self.get_dir_data();
+        debug_assert!(
+            !dir_data.contains_key(child_name),
+            "create_child_special() cannot create duplicated name={child_name:?}"
+        );
+
+        let child_attr = Arc::new(RwLock::new(FileAttr {
+            ino: inum,
+            kind: node_type,
+            perm: fs_util::parse_mode_bits(mode.bits()),
+            uid: user_id,
+            gid: group_id,
+            rdev,
+            nlink: 1,
+            project_id: self.attr.read().project_id,
+            ..FileAttr::now()
+        }));
+
+        let entry = DirEntry::new(child_name.to_owned(), Arc::clone(&child_attr));
+        let dir_data_mut = self.get_dir_data_mut();
+        let previous_value = dir_data_mut.insert(child_name.to_owned(), entry);
+        debug_assert!(previous_value.is_none()); // double check creation race
+
+        self.update_mtime_ctime_to_now();
+        Ok(Self::new(
+            self.get_ino(),
+            child_name,
+            child_attr,
+            S3NodeData::Special,
+            Arc::clone(&self.s3_backend),
+            &self.kv_engine,
+            &self.k8s_node_id,
+            &self.storage_config,
+        ))
+    }
+
     /// Load data from directory, file or symlink target.
     /// The `offset` and `len` is used for regular file
     async fn load_data(&self, offset: usize, len: usize) -> DatenLordResult<usize> {
@@ -875,63 +1509,29 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
                     offset, len, new_len, aligned_offset
                 );
 
-                let volume_info = serde_json::to_string(self.storage_config.as_ref())?;
-
-                // dist_client::read_data() won't get lock at remote, OK to put here.
-                let file_data_vec = match dist_client::read_data(
-                    &self.kv_engine,
-                    &self.k8s_node_id,
-                    &volume_info,
-                    self.get_ino(),
-                    aligned_offset
-                        .overflow_div(global_cache.get_align().cast())
-                        .cast(),
-                    aligned_offset
-                        .overflow_add(new_len.cast())
-                        .overflow_sub(1)
-                        .overflow_div(global_cache.get_align().cast())
-                        .cast(),
-                )
-                .await?
-                {
-                    None => {
-                        match self
-                            .s3_backend
-                            .get_partial_data(self.get_ino(), aligned_offset, new_len)
-                            .await
-                        {
-                            Ok(a) => a,
-                            Err(e) => {
-                                let anyhow_err: anyhow::Error = e.into();
-                                return Err(DatenLordError::from(
-                                    anyhow_err
-                                        .context("load_data() failed to load file content data"),
-                                ));
-                            }
-                        }
-                    }
-                    Some(data) => data,
-                };
-
-                let read_size = file_data_vec.len();
-                debug!(
-                    "load_data() successfully load {} byte file content data",
-                    read_size
-                );
-                global_cache
-                    .write_or_update(
-                        self.get_ino(),
-                        aligned_offset,
-                        read_size,
-                        &file_data_vec,
-                        false,
-                    )
-                    .await;
+                // The holes are never loaded
+                let mut read_size: usize = 0;
+                for (start, end) in self.holes.data_segments(
+                    aligned_offset.cast(),
+                    aligned_offset.overflow_add(new_len).cast(),
+                ) {
+                    let segment_size = self
+                        .load_file_segment(
+                            global_cache,
+                            start.cast(),
+                            end.overflow_sub(start).cast(),
+                        )
+                        .await?;
+                    read_size = read_size.overflow_add(segment_size);
+                }
                 Ok(read_size)
             }
             S3NodeData::SymLink(..) => {
                 panic!("forbidden to load symlink target data");
             }
+            S3NodeData::Special => {
+                panic!("forbidden to load data of special file");
+            }
         }
     }
 
@@ -960,7 +1560,27 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
         remove_res
     }
 
-    /// Unlink directory entry from both cache and disk
+    /// Insert directory entry pointing to an existing i-node for link()
+    fn insert_entry_for_link(
+        &mut self,
+        child_name: &str,
+        child_attr: FileAttr,
+    ) -> Option<DirEntry> {
+        let entry = DirEntry::new(child_name.to_owned(), Arc::new(RwLock::new(child_attr)));
+        let dir_data = self.get_dir_data_mut();
+        let previous_entry = dir_data.insert(child_name.to_owned(), entry);
+
+        self.update_mtime_ctime_to_now();
+        debug!(
+            "insert_entry_for_link() successfully linked entry name={:?} to ino={}",
+            child_name, child_attr.ino,
+        );
+
+        previous_entry
+    }
+
+    /// Unlink directory entry from cache, the data of the removed i-node is
+    /// kept until its last link is gone
     async fn unlink_entry(&mut self, child_name: &str) -> DatenLordResult<DirEntry> {
         let dir_data = self.get_dir_data_mut();
         let removed_entry = dir_data.remove(child_name).unwrap_or_else(|| {
@@ -973,14 +1593,8 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
             );
         });
 
-        // delete from disk and close the handler
         match removed_entry.entry_type() {
-            SFlag::S_IFDIR | SFlag::S_IFREG | SFlag::S_IFLNK => {
-                let ino = removed_entry.ino();
-                if let Err(e) = self.s3_backend.delete_data(ino).await {
-                    panic!("failed to delete data of {ino} from s3 backend, error is {e:?}");
-                }
-            }
+            SFlag::S_IFDIR | SFlag::S_IFREG | SFlag::S_IFLNK => {}
             _ => panic!(
                 "unlink_entry() found unsupported entry type={:?}",
                 removed_entry.entry_type()
@@ -999,35 +1613,39 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
     /// Get symlink target path
     fn get_symlink_target(&self) -> &Path {
         match self.data {
-            S3NodeData::Directory(..) | S3NodeData::RegFile(..) => {
+            S3NodeData::Directory(..) | S3NodeData::RegFile(..) | S3NodeData::Special => {
                 panic!("forbidden to read target path from non-symlink node")
             }
             S3NodeData::SymLink(ref target_path) => target_path,
         }
     }
 
-    /// Fake data for statefs
-    /// TODO: handle some important data from S3 storage
-    async fn statefs(&self) -> DatenLordResult<StatFsParam> {
-        Ok(StatFsParam {
-            blocks: 10_000_000_000,
-            bfree: 10_000_000_000,
-            bavail: 10_000_000_000,
-            files: 0, // TODO: file count is  temporarily zero
-            f_free: 1_000_000,
-            bsize: 4096, // TODO: consider use customized block size
-            namelen: 1024,
-            frsize: 4096,
-        })
-    }
-
     /// Get file data
     async fn get_file_data(&self, offset: usize, len: usize) -> Vec<IoMemBlock> {
         match self.data {
-            S3NodeData::Directory(..) | S3NodeData::SymLink(..) => {
+            S3NodeData::Directory(..) | S3NodeData::SymLink(..) | S3NodeData::Special => {
                 panic!("forbidden to load FileData from non-file node")
             }
-            S3NodeData::RegFile(ref cache) => cache.get_file_cache(self.get_ino(), offset, len),
+            S3NodeData::RegFile(ref cache) => {
+                let mut file_data = Vec::new();
+                for (start, end, is_hole) in self
+                    .holes
+                    .segments(offset.cast(), offset.overflow_add(len).cast())
+                {
+                    let segment_len = end.overflow_sub(start).cast();
+                    if is_hole {
+                        // The holes are not cached, read as zeros
+                        file_data.push(IoMemBlock::new_zeroed(segment_len));
+                    } else {
+                        file_data.append(&mut cache.get_file_cache(
+                            self.get_ino(),
+                            start.cast(),
+                            segment_len,
+                        ));
+                    }
+                }
+                file_data
+            }
         }
     }
 
@@ -1036,15 +1654,32 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
         &mut self,
         _fh: u64,
         offset: i64,
-        data: Vec<u8>,
+        data: &[u8],
         _oflags: OFlag,
         _write_to_disk: bool,
     ) -> DatenLordResult<usize> {
-        let this: &Self = self;
+        self.prepare_write(offset.cast(), data.len()).await?;
+        let cache = match self.data {
+            S3NodeData::Directory(..) | S3NodeData::SymLink(..) | S3NodeData::Special => {
+                panic!("forbidden to write FileData to non-file node")
+            }
+            S3NodeData::RegFile(ref file_data) => file_data,
+        };
+        cache
+            .write_or_update(self.get_ino(), offset.cast(), data.len(), data, true)
+            .await;
+        Ok(data.len())
+    }
+
+    async fn prepare_write(&mut self, offset: u64, len: usize) -> DatenLordResult<()> {
+        let old_size = self.attr.read().size;
+        self.punch_extended_blocks(old_size, offset);
 
-        let ino = this.get_ino();
-        if this.need_load_file_data(offset.cast(), data.len()).await {
-            let load_res = self.load_data(offset.cast(), data.len()).await;
+        let ino = self.get_ino();
+        // The partial blocks are loaded, so they are complete once the data is
+        // written to the cache
+        if self.need_load_file_data(offset.cast(), len).await {
+            let load_res = self.load_data(offset.cast(), len).await;
             if let Err(e) = load_res {
                 debug!(
                     "read() failed to load file data of ino={} and name={:?}, the error is: {:?}",
@@ -1057,38 +1692,182 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
         }
 
         let cache = match self.data {
-            S3NodeData::Directory(..) | S3NodeData::SymLink(..) => {
+            S3NodeData::Directory(..) | S3NodeData::SymLink(..) | S3NodeData::Special => {
                 panic!("forbidden to load FileData from non-file node")
             }
             S3NodeData::RegFile(ref file_data) => file_data,
         };
 
-        cache
-            .write_or_update(
-                self.get_ino(),
-                offset.cast(),
-                data.len(),
-                data.as_slice(),
-                true,
-            )
-            .await;
-
-        let written_size = data.len();
+        // The written blocks are allocated in the cache, they are neither
+        // holes nor shared anymore
+        let (block_start, block_end): (u64, u64) = (
+            cache.round_down(offset.cast()).cast(),
+            cache
+                .round_up(offset.cast::<usize>().overflow_add(len))
+                .cast(),
+        );
+        self.holes.fill(block_start, block_end);
+        self.extents.unshare(block_start, block_end);
 
         {
             let mut attr_write = self.attr.write();
             // update the attribute of the written file
-            attr_write.size = std::cmp::max(
-                attr_write.size,
-                offset.cast::<u64>().overflow_add(written_size.cast()),
-            );
+            attr_write.size = std::cmp::max(attr_write.size, offset.overflow_add(len.cast()));
         };
 
         debug!("file {:?} size = {:?}", self.name, self.attr.read().size);
         self.update_mtime_ctime_to_now();
         // FileAttr changed, remember to persist the directory after calling this fn
 
-        Ok(written_size)
+        Ok(())
+    }
+
+    async fn write_cache(&self, offset: u64, data: WriteData) -> DatenLordResult<()> {
+        let cache = match self.data {
+            S3NodeData::Directory(..) | S3NodeData::SymLink(..) | S3NodeData::Special => {
+                panic!("forbidden to write FileData to non-file node")
+            }
+            S3NodeData::RegFile(ref file_data) => file_data,
+        };
+        match data {
+            WriteData::Bytes(ref bytes) => {
+                cache
+                    .write_or_update(self.get_ino(), offset.cast(), bytes.len(), bytes, true)
+                    .await;
+            }
+            // The data left in the pipe is read into the cache directly
+            WriteData::Pipe(ref pipe, len) => {
+                if let Err(e) = cache
+                    .write_from_pipe(self.get_ino(), offset.cast(), len, pipe)
+                    .await
+                {
+                    return build_error_result_from_errno(
+                        Errno::EIO,
+                        format!(
+                            "failed to read {len} bytes of spliced write data of ino={}, \
+                                the error is: {e}",
+                            self.get_ino(),
+                        ),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn fallocate(&mut self, offset: u64, len: u64, mode: u32) -> DatenLordResult<()> {
+        let global_cache = match self.data {
+            S3NodeData::RegFile(ref global_cache) => Arc::clone(global_cache),
+            S3NodeData::Directory(..) | S3NodeData::SymLink(..) | S3NodeData::Special => {
+                return build_error_result_from_errno(
+                    Errno::ENODEV,
+                    format!(
+                        "fallocate() found {:?} is not a regular file",
+                        self.get_name()
+                    ),
+                );
+            }
+        };
+        let keep_size = mode & FALLOC_FL_KEEP_SIZE != 0;
+        let punch_hole = mode & FALLOC_FL_PUNCH_HOLE != 0;
+        let zero_range = mode & FALLOC_FL_ZERO_RANGE != 0;
+        if mode & !(FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0
+            || (punch_hole && (zero_range || !keep_size))
+        {
+            return build_error_result_from_errno(
+                Errno::EOPNOTSUPP,
+                format!("fallocate() found unsupported mode={mode:#x}"),
+            );
+        }
+
+        let size = self.attr.read().size;
+        let end = offset.overflow_add(len);
+        // The layout of the S3 object has to be fixed before the holes change
+        self.holes.init_object_size(size);
+        if punch_hole {
+            self.punch_hole(&global_cache, offset, end).await?;
+        } else if zero_range {
+            let end = if keep_size { end.min(size) } else { end };
+            self.write_zeros(global_cache.get_align().cast(), offset, end)
+                .await?;
+        } else {
+            // The allocated blocks are read as zeros before written, as they
+            // are not in the S3 object
+            self.holes.fill(
+                global_cache.round_down(offset.cast()).cast(),
+                global_cache.round_up(end.cast()).cast(),
+            );
+            if !keep_size && end > size {
+                self.attr.write().size = end;
+                self.update_mtime_ctime_to_now();
+            }
+        }
+        Ok(())
+    }
+
+    fn lseek(&self, offset: u64, whence: u32) -> DatenLordResult<u64> {
+        let size = self.attr.read().size;
+        let whence: i32 = whence.cast();
+        if whence != libc::SEEK_DATA && whence != libc::SEEK_HOLE {
+            return build_error_result_from_errno(
+                Errno::EINVAL,
+                format!("lseek() found unsupported whence={whence}"),
+            );
+        }
+        if offset >= size {
+            return build_error_result_from_errno(
+                Errno::ENXIO,
+                format!("lseek() found offset={offset} is beyond EOF={size}"),
+            );
+        }
+        if whence == libc::SEEK_DATA {
+            match self.holes.seek_data(offset, size) {
+                Some(data_offset) => Ok(data_offset),
+                None => build_error_result_from_errno(
+                    Errno::ENXIO,
+                    format!("lseek() found no data after offset={offset}"),
+                ),
+            }
+        } else {
+            Ok(self.holes.seek_hole(offset, size))
+        }
+    }
+
+    async fn copy_file_range(
+        &mut self,
+        src: &mut Self,
+        src_offset: u64,
+        dst_offset: u64,
+        len: u64,
+    ) -> DatenLordResult<usize> {
+        let S3NodeData::RegFile(ref global_cache) = self.data else {
+            return build_error_result_from_errno(
+                Errno::EINVAL,
+                format!(
+                    "copy_file_range() found {:?} is not a regular file",
+                    self.get_name()
+                ),
+            );
+        };
+        let global_cache = Arc::clone(global_cache);
+        if !matches!(src.data, S3NodeData::RegFile(..)) {
+            return build_error_result_from_errno(
+                Errno::EINVAL,
+                format!(
+                    "copy_file_range() found {:?} is not a regular file",
+                    src.get_name()
+                ),
+            );
+        }
+
+        let src_size = src.attr.read().size;
+        if src_offset >= src_size || len == 0 {
+            return Ok(0);
+        }
+        let len = len.min(src_size.overflow_sub(src_offset));
+        self.copy_blocks_from(&global_cache, src, src_offset, dst_offset, len)
+            .await?;
+        Ok(len.cast())
     }
 
     async fn close(&mut self, ino: INum, _fh: u64, _flush: bool) {
@@ -1108,6 +1887,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
         param: &SetAttrParam,
         user_id: u32,
         group_id: u32,
+        acl: Option<&PosixAcl>,
     ) -> DatenLordResult<(bool, FileAttr)> {
         let mut dirty_attr = self.get_attr();
         let cur_attr = self.get_attr();
@@ -1118,14 +1898,14 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
         let check_permission = || -> DatenLordResult<()> {
             if NEED_CHECK_PERM {
                 //  owner is root check the user_id
-                if cur_attr.uid == 0 && uid!= 0 {
+                if cur_attr.uid == 0 && user_id != 0 {
                     return build_error_result_from_errno(
                         Errno::EPERM,
                         "setattr() cannot change atime".to_owned(),
                     );
                 }
-                self.attr.read().check_perm(user_id, group_id, 2)?;
-                if uid!= cur_attr.uid {
+                cur_attr.check_perm_with_acl(user_id, group_id, 2, acl)?;
+                if user_id != cur_attr.uid {
                     return build_error_result_from_errno(
                         Errno::EACCES,
                         "setattr() cannot change atime".to_owned(),
@@ -1139,7 +1919,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
         };
 
         if let Some(gid) = param.g_id {
-            if uid!= 0 && cur_attr.uid != uid{
+            if user_id != 0 && cur_attr.uid != user_id {
                 return build_error_result_from_errno(
                     Errno::EPERM,
                     "setattr() cannot change gid".to_owned(),
@@ -1154,7 +1934,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
 
         if let Some(uid) = param.u_id {
             if cur_attr.uid != uid {
-                if uid!= 0 {
+                if user_id != 0 {
                     return build_error_result_from_errno(
                         Errno::EPERM,
                         "setattr() cannot change uid".to_owned(),
@@ -1168,7 +1948,7 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
         if let Some(mode) = param.mode {
             let mode: u16 = mode.cast();
             if mode != cur_attr.perm {
-                if uid!= 0 && uid!= cur_attr.uid {
+                if user_id != 0 && user_id != cur_attr.uid {
                     return build_error_result_from_errno(
                         Errno::EPERM,
                         "setattr() cannot change mode".to_owned(),
@@ -1259,6 +2039,20 @@ impl<S: S3BackEnd + Sync + Send + 'static> Node for S3Node<S> {
                     .await?;
                 Ok(child_node)
             }
+            SFlag::S_IFIFO | SFlag::S_IFSOCK | SFlag::S_IFCHR | SFlag::S_IFBLK => {
+                let child_node = self
+                    .create_child_special(
+                        new_inum,
+                        child_name,
+                        param.node_type,
+                        m_flags,
+                        param.rdev,
+                        param.uid,
+                        param.gid,
+                    )
+                    .await?;
+                Ok(child_node)
+            }
             _ => unreachable!("create_child_node() found unsupported node type"),
         }
     }
@@ -1,9 +1,12 @@
+use std::collections::BTreeMap;
 use std::fmt::Write;
 
 use async_trait::async_trait;
 use clippy_utilities::{Cast, OverflowArithmetic};
 #[cfg(test)]
 use mockall::{automock, predicate::str};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use s3::bucket::Bucket;
 use s3::bucket_ops::BucketConfiguration;
 use s3::command::{Command, Multipart};
@@ -52,8 +55,16 @@ pub trait S3BackEnd {
     async fn put_data_vec(&self, file: INum, data: Vec<IoMemBlock>) -> S3Result<()>;
     /// Delete a file from S3 backend
     async fn delete_data(&self, file: INum) -> S3Result<()>;
+    /// Put a metadata object to S3 backend
+    async fn put_meta(&self, name: &str, data: &[u8]) -> S3Result<()>;
+    /// Get a metadata object from S3 backend, `None` if it does not exist
+    async fn get_meta(&self, name: &str) -> S3Result<Option<Vec<u8>>>;
 }
 
+/// The prefix of the metadata objects, which never conflicts with the data
+/// objects named by i-node numbers
+const META_OBJECT_PREFIX: &str = "meta/";
+
 /// S3 backend implementation
 #[derive(Debug)]
 pub struct S3BackEndImpl {
@@ -145,6 +156,31 @@ impl S3BackEnd for S3BackEndImpl {
         resultify_anyhow!(self.bucket.delete_object(data.to_string()).await).map(|_| ())
     }
 
+    async fn put_meta(&self, name: &str, data: &[u8]) -> S3Result<()> {
+        resultify_anyhow!(
+            self.bucket
+                .put_object(format!("{META_OBJECT_PREFIX}{name}"), data)
+                .await
+        )
+        .map(|_| ())
+    }
+
+    async fn get_meta(&self, name: &str) -> S3Result<Option<Vec<u8>>> {
+        let (data, code) = resultify_anyhow!(
+            self.bucket
+                .get_object(format!("{META_OBJECT_PREFIX}{name}"))
+                .await
+        )?;
+        match code {
+            200 => Ok(Some(data)),
+            404 => Ok(None),
+            _ => Err(S3Error::S3InternalError(format!(
+                "S3 get metadata object {name} response code: {code}, response message: {}",
+                String::from_utf8_lossy(&data),
+            ))),
+        }
+    }
+
     async fn put_data_vec(&self, file: INum, vec: Vec<IoMemBlock>) -> S3Result<()> {
         if vec.is_empty() {
             return Ok(());
@@ -229,8 +265,15 @@ pub fn format_anyhow_error(error: &anyhow::Error) -> String {
     err_msg
 }
 
+/// The objects of the do nothing S3 backend, which are kept in the memory of
+/// the process
+static DO_NOTHING_OBJECTS: Lazy<Mutex<BTreeMap<String, Vec<u8>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
 #[derive(Debug)]
-/// Do nothing S3 backend
+/// Do nothing S3 backend, the objects are kept in memory instead of S3, so the
+/// data and the metadata checkpoints can be read by the later mounts in the
+/// process
 pub struct DoNothingImpl;
 
 #[async_trait]
@@ -240,23 +283,68 @@ impl S3BackEnd for DoNothingImpl {
         Ok(Self {})
     }
 
-    async fn get_data(&self, _: INum) -> S3Result<Vec<u8>> {
-        Ok(vec![])
+    async fn get_data(&self, file: INum) -> S3Result<Vec<u8>> {
+        Ok(DO_NOTHING_OBJECTS
+            .lock()
+            .get(&file.to_string())
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn get_partial_data(&self, file: INum, offset: usize, len: usize) -> S3Result<Vec<u8>> {
+        let objects = DO_NOTHING_OBJECTS.lock();
+        let data = objects
+            .get(&file.to_string())
+            .map_or(&[][..], Vec::as_slice);
+        let end = offset.overflow_add(len).min(data.len());
+        Ok(data.get(offset.min(end)..end).unwrap_or_default().to_vec())
     }
 
-    async fn get_partial_data(&self, _: INum, _: usize, _: usize) -> S3Result<Vec<u8>> {
-        Ok(vec![])
+    async fn put_data(&self, file: INum, data: &[u8], offset: usize, len: usize) -> S3Result<()> {
+        let data = data
+            .get(offset..(offset.overflow_add(len)))
+            .unwrap_or_else(|| {
+                panic!(
+                    "failed to get slice index {}..{}, slice size={}",
+                    offset,
+                    offset.overflow_add(len),
+                    data.len()
+                )
+            });
+        DO_NOTHING_OBJECTS
+            .lock()
+            .insert(file.to_string(), data.to_vec());
+        Ok(())
     }
 
-    async fn put_data(&self, _: INum, _: &[u8], _: usize, _: usize) -> S3Result<()> {
+    async fn delete_data(&self, file: INum) -> S3Result<()> {
+        DO_NOTHING_OBJECTS.lock().remove(&file.to_string());
         Ok(())
     }
 
-    async fn delete_data(&self, _: INum) -> S3Result<()> {
+    async fn put_data_vec(&self, file: INum, vec: Vec<IoMemBlock>) -> S3Result<()> {
+        if vec.is_empty() {
+            return Ok(());
+        }
+        let mut data = Vec::new();
+        for block in &vec {
+            data.extend_from_slice(unsafe { block.as_slice() });
+        }
+        DO_NOTHING_OBJECTS.lock().insert(file.to_string(), data);
         Ok(())
     }
 
-    async fn put_data_vec(&self, _: INum, _: Vec<IoMemBlock>) -> S3Result<()> {
+    async fn put_meta(&self, name: &str, data: &[u8]) -> S3Result<()> {
+        DO_NOTHING_OBJECTS
+            .lock()
+            .insert(format!("{META_OBJECT_PREFIX}{name}"), data.to_vec());
         Ok(())
     }
+
+    async fn get_meta(&self, name: &str) -> S3Result<Option<Vec<u8>>> {
+        Ok(DO_NOTHING_OBJECTS
+            .lock()
+            .get(&format!("{META_OBJECT_PREFIX}{name}"))
+            .cloned())
+    }
 }
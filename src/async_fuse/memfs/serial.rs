@@ -10,11 +10,13 @@ use serde::{Deserialize, Serialize};
 use super::cache::GlobalCache;
 use super::dir::DirEntry;
 use super::fs_util::FileAttr;
+use super::hole::HoleMap;
+use super::reflink::ExtentMap;
 use super::s3_node::S3NodeData;
 use crate::async_fuse::fuse::protocol::INum;
 
 /// Serializable `DirEntry`
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct SerialDirEntry {
     /// The entry name
     name: String,
@@ -57,6 +59,10 @@ pub struct SerialFileAttr {
     gid: u32,
     /// Rdev
     rdev: u32,
+    /// Project id for quota, the i-nodes stored before quotas are supported
+    /// are in no project
+    #[serde(default)]
+    project_id: u32,
 }
 
 impl SerialFileAttr {
@@ -76,12 +82,20 @@ pub enum SerialSFlag {
     Dir,
     /// Symbolic link
     Lnk,
+    /// Named pipe
+    Fifo,
+    /// Unix domain socket
+    Sock,
+    /// Character device
+    Chr,
+    /// Block device
+    Blk,
 }
 
 /// In order to derive Serialize and Deserialize,
 /// Replace the `BTreeMap`<String, `DirEntry`>' with `HashMap`<String,
 /// `SerialDirEntry`>'
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum SerialNodeData {
     /// Directory data
     Directory(BTreeMap<String, SerialDirEntry>),
@@ -89,6 +103,8 @@ pub enum SerialNodeData {
     File,
     /// Symbolic link data
     SymLink(PathBuf),
+    /// Special files have no data
+    Special,
 }
 
 impl SerialNodeData {
@@ -104,12 +120,13 @@ impl SerialNodeData {
             }
             SerialNodeData::File => S3NodeData::RegFile(data_cache),
             SerialNodeData::SymLink(path) => S3NodeData::SymLink(path),
+            SerialNodeData::Special => S3NodeData::Special,
         }
     }
 }
 /// TODO: We should discuss the design about persist
 /// Serializable 'Node'
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct SerialNode {
     /// Parent node i-number
     pub(crate) parent: u64,
@@ -125,6 +142,14 @@ pub struct SerialNode {
     pub(crate) lookup_count: i64,
     /// If S3Node has been marked as deferred deletion
     pub(crate) deferred_deletion: bool,
+    /// The holes of a regular file, the i-nodes stored before holes are
+    /// supported have none
+    #[serde(default)]
+    pub(crate) holes: HoleMap,
+    /// The extents of a regular file shared with other files, the i-nodes
+    /// stored before reflinks are supported have none
+    #[serde(default)]
+    pub(crate) extents: ExtentMap,
 }
 
 /// Convert `SFlag` to `SerialSFlag`
@@ -134,6 +159,10 @@ pub fn entry_type_to_serial(entry_type: SFlag) -> SerialSFlag {
         SFlag::S_IFDIR => SerialSFlag::Dir,
         SFlag::S_IFREG => SerialSFlag::Reg,
         SFlag::S_IFLNK => SerialSFlag::Lnk,
+        SFlag::S_IFIFO => SerialSFlag::Fifo,
+        SFlag::S_IFSOCK => SerialSFlag::Sock,
+        SFlag::S_IFCHR => SerialSFlag::Chr,
+        SFlag::S_IFBLK => SerialSFlag::Blk,
         _ => panic!("unsupported entry type {entry_type:?}"),
     }
 }
@@ -145,6 +174,10 @@ pub const fn serial_to_entry_type(entry_type: &SerialSFlag) -> SFlag {
         SerialSFlag::Dir => SFlag::S_IFDIR,
         SerialSFlag::Reg => SFlag::S_IFREG,
         SerialSFlag::Lnk => SFlag::S_IFLNK,
+        SerialSFlag::Fifo => SFlag::S_IFIFO,
+        SerialSFlag::Sock => SFlag::S_IFSOCK,
+        SerialSFlag::Chr => SFlag::S_IFCHR,
+        SerialSFlag::Blk => SFlag::S_IFBLK,
     }
 }
 
@@ -176,20 +209,13 @@ pub fn file_attr_to_serial(attr: &FileAttr) -> SerialFileAttr {
         atime: attr.atime,
         mtime: attr.mtime,
         ctime: attr.ctime,
-        kind: {
-            if attr.kind == SFlag::S_IFREG {
-                SerialSFlag::Reg
-            } else if attr.kind == SFlag::S_IFDIR {
-                SerialSFlag::Dir
-            } else {
-                SerialSFlag::Lnk
-            }
-        },
+        kind: entry_type_to_serial(attr.kind),
         perm: attr.perm,
         nlink: attr.nlink,
         uid: attr.uid,
         gid: attr.gid,
         rdev: attr.rdev,
+        project_id: attr.project_id,
     }
 }
 
@@ -203,18 +229,13 @@ pub const fn serial_to_file_attr(attr: &SerialFileAttr) -> FileAttr {
         atime: attr.atime,
         mtime: attr.mtime,
         ctime: attr.ctime,
-        kind: {
-            match attr.kind {
-                SerialSFlag::Lnk => SFlag::S_IFLNK,
-                SerialSFlag::Dir => SFlag::S_IFDIR,
-                SerialSFlag::Reg => SFlag::S_IFREG,
-            }
-        },
+        kind: serial_to_entry_type(&attr.kind),
         perm: attr.perm,
         nlink: attr.nlink,
         uid: attr.uid,
         gid: attr.gid,
         rdev: attr.rdev,
+        project_id: attr.project_id,
     }
 }
 
@@ -231,10 +252,18 @@ mod test {
         /// Test `entry_type_to_serial` and `serial_to_entry_type`
         /// `entry_type_to_serial` and `serial_to_entry_type` should be a pair
         /// of inverse functions We will test all the possible entry
-        /// types (currently just three types)
+        /// types
         use super::entry_type_to_serial;
         use super::serial_to_entry_type;
-        let entry_types = vec![SFlag::S_IFDIR, SFlag::S_IFREG, SFlag::S_IFLNK];
+        let entry_types = vec![
+            SFlag::S_IFDIR,
+            SFlag::S_IFREG,
+            SFlag::S_IFLNK,
+            SFlag::S_IFIFO,
+            SFlag::S_IFSOCK,
+            SFlag::S_IFCHR,
+            SFlag::S_IFBLK,
+        ];
         for entry_type_before in entry_types {
             let serial_entry_type = entry_type_to_serial(entry_type_before);
             let entry_type_after = serial_to_entry_type(&serial_entry_type);
@@ -263,6 +292,7 @@ mod test {
             uid: rng.gen(),
             gid: rng.gen(),
             rdev: rng.gen(),
+            project_id: rng.gen(),
         }
     }
 
@@ -288,6 +318,7 @@ mod test {
         assert_eq!(file_attr.uid, serial_file_attr.uid);
         assert_eq!(file_attr.gid, serial_file_attr.gid);
         assert_eq!(file_attr.rdev, serial_file_attr.rdev);
+        assert_eq!(file_attr.project_id, serial_file_attr.project_id);
     }
 
     // Return true for equal
@@ -304,6 +335,7 @@ mod test {
             && left.uid == right.uid
             && left.gid == right.gid
             && left.rdev == right.rdev
+            && left.project_id == right.project_id
     }
 
     // Test for serial_to_file_attr function
@@ -315,6 +347,38 @@ mod test {
         assert!(fileattr_equal(&file_attr, &converted_file_attr));
     }
 
+    #[test]
+    fn test_deserialize_node_without_holes() {
+        let node = SerialNode {
+            parent: 1,
+            name: "test_node".to_owned(),
+            attr: file_attr_to_serial(&create_file_attr()),
+            data: SerialNodeData::File,
+            open_count: 0,
+            lookup_count: 0,
+            deferred_deletion: false,
+            holes: HoleMap::default(),
+            extents: ExtentMap::default(),
+        };
+        let mut value = serde_json::to_value(&node).unwrap();
+        value.as_object_mut().unwrap().remove("holes");
+        let deserialized: SerialNode = serde_json::from_value(value).unwrap();
+        assert_eq!(deserialized, node);
+    }
+
+    #[test]
+    fn test_deserialize_attr_without_project_id() {
+        let attr = FileAttr {
+            project_id: 0,
+            ..create_file_attr()
+        };
+        let serial_attr = file_attr_to_serial(&attr);
+        let mut value = serde_json::to_value(&serial_attr).unwrap();
+        value.as_object_mut().unwrap().remove("project_id");
+        let deserialized: SerialFileAttr = serde_json::from_value(value).unwrap();
+        assert_eq!(deserialized, serial_attr);
+    }
+
     #[test]
     fn test_direntry_serialize() {
         let test_name = String::from("test_a_really_long_name");
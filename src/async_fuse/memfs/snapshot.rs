@@ -0,0 +1,278 @@
+//! Point-in-time snapshots of the metadata.
+//!
+//! The KV engine keeps the history of the keys by revisions, so a snapshot
+//! only records the revision of the KV engine when it is created, and no
+//! metadata is copied. The snapshots are stored in the KV engine under
+//! `KeyType::Snapshot(name)`.
+//!
+//! The S3 objects referenced by the snapshots are retained:
+//! - Creating a snapshot bumps the generation in `KeyType::SnapshotState`, and
+//!   a regular file records the generation when its own S3 object is uploaded.
+//!   If the own S3 object of a file is uploaded before the latest snapshot, the
+//!   next flush uploads the data to a new own S3 object instead of overwriting
+//!   the captured one.
+//! - While any snapshot exists, the S3 objects no longer referenced by the
+//!   volume are retained under `KeyType::RetainedObject(object)` with the
+//!   generation they are retained at, instead of being deleted.
+//! - Deleting a snapshot deletes the retained S3 objects not referenced by any
+//!   remaining snapshot created before they are retained.
+//!
+//! The snapshots are administrated by the superuser through the trusted
+//! extended attributes `trusted.datenlord.snapshot.<name>` of the root
+//! directory: setting one creates the snapshot, getting one returns its
+//! generation, revision and creation time, removing one deletes the snapshot,
+//! and listing the extended attributes of the root directory lists all the
+//! snapshots. A node started with `--storage-metadata-snapshot <name>` mounts
+//! the snapshot read-only.
+//!
+//! A snapshot captures the revision the generation is bumped at, so the files
+//! flushed afterwards never overwrite the S3 objects it references. The
+//! revision is recorded right after the generation is bumped, a snapshot
+//! left incomplete for `SNAPSHOT_CREATE_TIMEOUT` by an interrupted creation
+//! is deleted.
+//!
+//! The history of the KV engine is compacted along with the metadata
+//! checkpoints, but never beyond the revision of any snapshot, so the
+//! automatic compaction of etcd must be disabled. The data flushed while a
+//! snapshot is being created may be captured partially.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::errno::Errno;
+use serde::{Deserialize, Serialize};
+
+use super::serial::{SerialNode, SerialNodeData};
+use crate::async_fuse::fuse::protocol::INum;
+use crate::async_fuse::util::build_error_result_from_errno;
+use crate::common::error::DatenLordResult;
+
+/// The xattr name prefix of the snapshots
+pub const XATTR_PREFIX_SNAPSHOT: &str = "trusted.datenlord.snapshot.";
+/// The max length of a snapshot name
+const SNAPSHOT_NAME_MAX: usize = 128;
+/// The time after which an incomplete snapshot is deemed abandoned by an
+/// interrupted creation
+pub const SNAPSHOT_CREATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The state of the snapshots of the volume
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotState {
+    /// The generation of the latest snapshot, bumped by every snapshot
+    pub generation: u64,
+    /// The number of the existing snapshots
+    pub count: u64,
+}
+
+impl SnapshotState {
+    /// Whether an S3 object uploaded at `generation` may be captured by a
+    /// snapshot, so it must not be overwritten
+    #[must_use]
+    pub const fn captures(&self, generation: u64) -> bool {
+        self.count > 0 && generation < self.generation
+    }
+
+    /// Whether the S3 objects no longer referenced by the volume have to be
+    /// retained for the snapshots
+    #[must_use]
+    pub const fn retains(&self) -> bool {
+        self.count > 0
+    }
+}
+
+/// A snapshot of the metadata
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// The generation bumped by the snapshot
+    pub generation: u64,
+    /// The revision of the KV engine captured by the snapshot, `None` until
+    /// it is recorded after the snapshot is created
+    pub revision: Option<i64>,
+    /// The time the snapshot is created
+    pub time: SystemTime,
+}
+
+impl SnapshotInfo {
+    /// Whether the snapshot is left incomplete by an interrupted creation, as
+    /// its revision is not recorded for `SNAPSHOT_CREATE_TIMEOUT` till `now`
+    #[must_use]
+    pub fn is_abandoned(&self, now: SystemTime) -> bool {
+        self.revision.is_none()
+            && now
+                .duration_since(self.time)
+                .map_or(false, |elapsed| elapsed > SNAPSHOT_CREATE_TIMEOUT)
+    }
+
+    /// The xattr value of the snapshot, which fails with `EAGAIN` while the
+    /// snapshot is being created
+    pub fn to_xattr(&self, name: &str) -> DatenLordResult<Vec<u8>> {
+        let Some(revision) = self.revision else {
+            return build_error_result_from_errno(
+                Errno::EAGAIN,
+                format!("snapshot name={name:?} is being created"),
+            );
+        };
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Ok(format!(
+            "generation={},revision={revision},time={time}",
+            self.generation,
+        )
+        .into_bytes())
+    }
+}
+
+/// The xattr name of the snapshot of `name`
+#[must_use]
+pub fn snapshot_xattr_name(name: &str) -> String {
+    format!("{XATTR_PREFIX_SNAPSHOT}{name}")
+}
+
+/// Parse the xattr name of a snapshot, which starts with
+/// `XATTR_PREFIX_SNAPSHOT`, return the snapshot name
+pub fn parse_snapshot_xattr_name(name: &str) -> DatenLordResult<&str> {
+    let snapshot = name.strip_prefix(XATTR_PREFIX_SNAPSHOT).unwrap_or_default();
+    check_snapshot_name(snapshot)?;
+    Ok(snapshot)
+}
+
+/// Check the snapshot name is not empty and only contains ASCII letters,
+/// digits, `-`, `_` and `.`
+pub fn check_snapshot_name(name: &str) -> DatenLordResult<()> {
+    let valid = !name.is_empty()
+        && name.len() <= SNAPSHOT_NAME_MAX
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_' || c == b'.');
+    if !valid {
+        return build_error_result_from_errno(
+            Errno::EINVAL,
+            format!("invalid snapshot name={name:?}"),
+        );
+    }
+    Ok(())
+}
+
+/// The S3 objects referenced by the i-nodes, including the own S3 objects and
+/// the shared S3 objects of the regular files
+#[must_use]
+pub fn referenced_objects(nodes: &[SerialNode]) -> BTreeSet<INum> {
+    let mut objects = BTreeSet::new();
+    for node in nodes {
+        if matches!(node.data, SerialNodeData::File) {
+            objects.insert(node.extents.object(node.attr.get_ino()));
+            objects.extend(node.extents.shared_objects());
+        }
+    }
+    objects
+}
+
+/// The retained S3 objects to delete, `retained` are the retained S3 objects
+/// and the generations they are retained at, and `snapshots` are the
+/// generations of the remaining snapshots and the S3 objects they reference.
+/// A retained S3 object is kept if it is referenced by a snapshot created
+/// before it is retained.
+#[must_use]
+pub fn collectable_objects(
+    retained: &[(INum, u64)],
+    snapshots: &[(u64, BTreeSet<INum>)],
+) -> Vec<INum> {
+    retained
+        .iter()
+        .filter(|&&(object, retained_at)| {
+            !snapshots.iter().any(|&(generation, ref objects)| {
+                generation <= retained_at && objects.contains(&object)
+            })
+        })
+        .map(|&(object, _)| object)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{SnapshotInfo, SnapshotState, SNAPSHOT_CREATE_TIMEOUT};
+
+    #[test]
+    fn test_snapshot_state() {
+        let mut state = SnapshotState::default();
+        assert!(!state.captures(0));
+        assert!(!state.retains());
+
+        // Take a snapshot
+        state.generation = 1;
+        state.count = 1;
+        assert!(state.captures(0));
+        assert!(!state.captures(1));
+        assert!(state.retains());
+
+        // All the snapshots are deleted
+        state.count = 0;
+        assert!(!state.captures(0));
+        assert!(!state.retains());
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_result_states)]
+    fn test_snapshot_xattr() {
+        let name = super::snapshot_xattr_name("daily-2024.01_01");
+        assert_eq!(name, "trusted.datenlord.snapshot.daily-2024.01_01");
+        assert_eq!(
+            super::parse_snapshot_xattr_name(&name).ok(),
+            Some("daily-2024.01_01")
+        );
+        assert!(super::parse_snapshot_xattr_name("trusted.datenlord.snapshot.").is_err());
+        assert!(super::parse_snapshot_xattr_name("trusted.datenlord.snapshot.a/b").is_err());
+        assert!(super::check_snapshot_name(&"a".repeat(129)).is_err());
+
+        let mut info = SnapshotInfo {
+            generation: 3,
+            revision: Some(42),
+            time: UNIX_EPOCH + Duration::from_secs(1000),
+        };
+        assert_eq!(
+            info.to_xattr("daily").ok(),
+            Some(b"generation=3,revision=42,time=1000".to_vec())
+        );
+        // The revision of an incomplete snapshot is unknown
+        info.revision = None;
+        assert!(info.to_xattr("daily").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_abandoned() {
+        let mut info = SnapshotInfo {
+            generation: 3,
+            revision: None,
+            time: UNIX_EPOCH + Duration::from_secs(1000),
+        };
+        let created = info.time;
+        assert!(!info.is_abandoned(created));
+        assert!(!info.is_abandoned(created + SNAPSHOT_CREATE_TIMEOUT));
+        let expired = created + SNAPSHOT_CREATE_TIMEOUT + Duration::from_secs(1);
+        assert!(info.is_abandoned(expired));
+        // A clock behind the creator never abandons the snapshot
+        assert!(!info.is_abandoned(UNIX_EPOCH));
+        // A complete snapshot is never abandoned
+        info.revision = Some(42);
+        assert!(!info.is_abandoned(expired));
+    }
+
+    #[test]
+    fn test_collectable_objects() {
+        // Objects 5 and 6 are retained at generation 1, object 7 at generation 2
+        let retained = [(5, 1), (6, 1), (7, 2)];
+        // The snapshot of generation 1 references objects 5 and 7, and the
+        // snapshot of generation 3 references object 6, which is retained
+        // before it is created
+        let snapshots = [(1, BTreeSet::from([5, 7])), (3, BTreeSet::from([6]))];
+        assert_eq!(super::collectable_objects(&retained, &snapshots), vec![6]);
+        // No snapshot remains
+        assert_eq!(super::collectable_objects(&retained, &[]), vec![5, 6, 7]);
+    }
+}
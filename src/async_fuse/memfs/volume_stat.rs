@@ -0,0 +1,217 @@
+//! The usage of a volume reported by `statfs`.
+//!
+//! The used bytes and the number of i-nodes of the volume are stored in the KV
+//! engine under `KeyType::VolumeStat`, and updated in the same transaction as
+//! the i-nodes they count, so all the nodes mounting the volume report the same
+//! numbers. The usage is split into shards by i-number, so that the
+//! transactions changing different i-nodes seldom conflict on it, `statfs`
+//! reports the sum of the shards.
+
+use clippy_utilities::{Cast, OverflowArithmetic};
+use nix::errno::Errno;
+use nix::sys::stat::SFlag;
+use serde::{Deserialize, Serialize};
+
+use super::fs_util::FileAttr;
+use super::MAX_NAME_LEN;
+use crate::async_fuse::fuse::fuse_reply::StatFsParam;
+use crate::async_fuse::fuse::protocol::INum;
+use crate::async_fuse::util::build_error_result_from_errno;
+use crate::common::error::DatenLordResult;
+
+/// The block size reported by `statfs`
+pub const STATFS_BLOCK_SIZE: u32 = 4096;
+
+/// The number of shards the usage of the volume is split into
+pub const VOLUME_STAT_SHARDS: u32 = 64;
+
+/// The shard the usage of the i-node `ino` is counted in
+#[must_use]
+pub fn shard_of(ino: INum) -> u32 {
+    ino.overflow_rem(VOLUME_STAT_SHARDS.into()).cast()
+}
+
+/// The usage of a volume
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VolumeStat {
+    /// The bytes used by the regular files
+    pub used_bytes: u64,
+    /// The number of i-nodes
+    pub inodes: u64,
+}
+
+/// The bytes of the i-node of `attr` counted in the usage of the volume and
+/// the quotas. Only the data of regular files is counted, in whole blocks of
+/// `STATFS_BLOCK_SIZE`, so the writes within the last block of a file leave
+/// the usage unchanged and need not charge it.
+#[must_use]
+pub fn used_bytes(attr: &FileAttr) -> u64 {
+    if attr.kind != SFlag::S_IFREG {
+        return 0;
+    }
+    let bsize: u64 = STATFS_BLOCK_SIZE.into();
+    attr.size
+        .overflow_add(bsize.overflow_sub(1))
+        .overflow_div(bsize)
+        .overflow_mul(bsize)
+}
+
+/// The change of the used bytes when a file is resized from `old_size` to
+/// `new_size`
+#[must_use]
+pub fn size_delta(old_size: u64, new_size: u64) -> i64 {
+    new_size.cast::<i64>().overflow_sub(old_size.cast::<i64>())
+}
+
+impl VolumeStat {
+    /// Apply the changes of the used bytes and the number of i-nodes
+    pub fn update(&mut self, bytes_delta: i64, inodes_delta: i64) {
+        self.used_bytes = self.used_bytes.saturating_add_signed(bytes_delta);
+        self.inodes = self.inodes.saturating_add_signed(inodes_delta);
+    }
+
+    /// Add up the usage counted in another shard
+    pub fn merge(&mut self, other: &Self) {
+        self.used_bytes = self.used_bytes.overflow_add(other.used_bytes);
+        self.inodes = self.inodes.overflow_add(other.inodes);
+    }
+
+    /// Check there is a free i-node in the budget of `max_inodes` to create a
+    /// new one
+    pub fn check_free_inode(&self, max_inodes: u64) -> DatenLordResult<()> {
+        if self.inodes >= max_inodes {
+            return build_error_result_from_errno(
+                Errno::ENOSPC,
+                format!(
+                    "no free i-node in the volume, {} of {} i-nodes are used",
+                    self.inodes, max_inodes,
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    /// Build the `statfs` reply of the volume provisioned with `capacity` bytes
+    /// and `max_inodes` i-nodes
+    #[must_use]
+    pub fn to_statfs(&self, capacity: u64, max_inodes: u64) -> StatFsParam {
+        let bsize: u64 = STATFS_BLOCK_SIZE.into();
+        let blocks = capacity.overflow_div(bsize);
+        let used_blocks = self
+            .used_bytes
+            .overflow_add(bsize.overflow_sub(1))
+            .overflow_div(bsize);
+        let bfree = blocks.saturating_sub(used_blocks);
+        StatFsParam {
+            blocks,
+            bfree,
+            bavail: bfree,
+            files: max_inodes,
+            f_free: max_inodes.saturating_sub(self.inodes),
+            bsize: STATFS_BLOCK_SIZE,
+            namelen: MAX_NAME_LEN.cast(),
+            frsize: STATFS_BLOCK_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nix::sys::stat::SFlag;
+
+    use super::{shard_of, size_delta, used_bytes, VolumeStat, VOLUME_STAT_SHARDS};
+    use crate::async_fuse::memfs::fs_util::FileAttr;
+
+    #[test]
+    fn test_volume_stat_update() {
+        let mut stat = VolumeStat::default();
+        stat.update(size_delta(0, 10_000), 1);
+        stat.update(size_delta(0, 100), 1);
+        assert_eq!(stat.used_bytes, 10_100);
+        assert_eq!(stat.inodes, 2);
+
+        // Truncate and remove
+        stat.update(size_delta(10_000, 5000), 0);
+        stat.update(-100, -1);
+        assert_eq!(
+            stat,
+            VolumeStat {
+                used_bytes: 5000,
+                inodes: 1,
+            }
+        );
+
+        // Never goes below zero
+        stat.update(-10_000, -10);
+        assert_eq!(stat, VolumeStat::default());
+    }
+
+    #[test]
+    fn test_used_bytes() {
+        let mut attr = FileAttr {
+            kind: SFlag::S_IFREG,
+            ..FileAttr::default()
+        };
+        assert_eq!(used_bytes(&attr), 0);
+        attr.size = 1;
+        assert_eq!(used_bytes(&attr), 4096);
+        attr.size = 4096;
+        assert_eq!(used_bytes(&attr), 4096);
+        attr.size = 4097;
+        assert_eq!(used_bytes(&attr), 8192);
+
+        // The size of directories is not counted
+        attr.kind = SFlag::S_IFDIR;
+        assert_eq!(used_bytes(&attr), 0);
+    }
+
+    #[test]
+    fn test_volume_stat_shards() {
+        assert_eq!(shard_of(1), 1);
+        assert_eq!(shard_of(VOLUME_STAT_SHARDS.into()), 0);
+        assert_eq!(shard_of(u64::from(VOLUME_STAT_SHARDS) + 3), 3);
+
+        let mut total = VolumeStat::default();
+        for stat in [
+            VolumeStat {
+                used_bytes: 100,
+                inodes: 1,
+            },
+            VolumeStat {
+                used_bytes: 20,
+                inodes: 2,
+            },
+        ] {
+            total.merge(&stat);
+        }
+        assert_eq!(
+            total,
+            VolumeStat {
+                used_bytes: 120,
+                inodes: 3,
+            }
+        );
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_result_states)]
+    fn test_volume_stat_to_statfs() {
+        let stat = VolumeStat {
+            used_bytes: 4097,
+            inodes: 3,
+        };
+        let statfs = stat.to_statfs(40960, 10);
+        assert_eq!(statfs.blocks, 10);
+        assert_eq!(statfs.bfree, 8);
+        assert_eq!(statfs.bavail, 8);
+        assert_eq!(statfs.files, 10);
+        assert_eq!(statfs.f_free, 7);
+        assert!(stat.check_free_inode(10).is_ok());
+        assert!(stat.check_free_inode(3).is_err());
+
+        // The usage beyond the capacity is bounded
+        let statfs = stat.to_statfs(4096, 2);
+        assert_eq!(statfs.bfree, 0);
+        assert_eq!(statfs.f_free, 0);
+    }
+}
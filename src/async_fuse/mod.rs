@@ -1,12 +1,21 @@
 //! FUSE async implementation
 
+use std::future::{self, Future};
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::path::Path;
 use std::sync::Arc;
 
+use anyhow::Context;
 use datenlord::config::StorageParams;
 use memfs::s3_wrapper::{DoNothingImpl, S3BackEndImpl};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{self, SignalKind};
+use tracing::{info, warn};
 
 use self::memfs::kv_engine::KVEngineType;
+use self::memfs::MetaData;
 use crate::async_fuse::fuse::session;
+use crate::async_fuse::fuse::upgrade::{self, SessionSnapshot};
 use crate::AsyncFuseArgs;
 
 pub mod fuse;
@@ -25,6 +34,17 @@ pub async fn start_async_fuse(
 ) -> anyhow::Result<()> {
     metrics::start_metrics_server();
 
+    // A snapshot is mounted on the view of the kv engine at its revision, the
+    // writes of this node are kept in its memory
+    let kv_engine = match args.storage_config.metadata_snapshot {
+        Some(ref name) => Arc::new(
+            memfs::kv_engine::kv_utils::pin_at_snapshot(&kv_engine, name)
+                .await
+                .with_context(|| format!("failed to mount snapshot {name:?}"))?,
+        ),
+        None => kv_engine,
+    };
+
     memfs::kv_engine::kv_utils::register_node_id(
         &kv_engine,
         &args.node_id,
@@ -36,7 +56,14 @@ pub async fn start_async_fuse(
     let volume_info = serde_json::to_string(&args.storage_config)?;
     memfs::kv_engine::kv_utils::register_volume(&kv_engine, &args.node_id, &volume_info).await?;
 
-    let mount_point = std::path::Path::new(&args.mount_dir);
+    // Take over the FUSE session before creating the file system, which binds
+    // the same ports as the running process
+    let taken_over = match args.upgrade_socket {
+        Some(ref path) => upgrade::take_over(Path::new(path))
+            .await
+            .context("failed to take over FUSE session")?,
+        None => None,
+    };
 
     match args.storage_config.params {
         StorageParams::S3(_) => {
@@ -45,14 +72,13 @@ pub async fn start_async_fuse(
                 args.storage_config.cache_capacity,
                 &args.ip_address.to_string(),
                 args.server_port,
-                kv_engine,
+                Arc::clone(&kv_engine),
                 &args.node_id,
                 &args.storage_config,
             )
             .await?;
 
-            let ss = session::new_session_of_memfs(mount_point, fs).await?;
-            ss.run().await?;
+            run_session(&kv_engine, args, &volume_info, fs, taken_over).await?;
         }
         StorageParams::None(_) => {
             let fs: memfs::MemFs<memfs::S3MetaData<DoNothingImpl>> = memfs::MemFs::new(
@@ -60,20 +86,112 @@ pub async fn start_async_fuse(
                 args.storage_config.cache_capacity,
                 &args.ip_address.to_string(),
                 args.server_port,
-                kv_engine,
+                Arc::clone(&kv_engine),
                 &args.node_id,
                 &args.storage_config,
             )
             .await?;
 
-            let ss = session::new_session_of_memfs(mount_point, fs).await?;
-            ss.run().await?;
+            run_session(&kv_engine, args, &volume_info, fs, taken_over).await?;
         }
     }
 
     Ok(())
 }
 
+/// Run the FUSE session of `fs`, which is resumed on the fd `taken_over` from
+/// the previous process if any, until SIGTERM or SIGINT is received, then shut
+/// down gracefully: stop accepting new FUSE requests, drain the in-flight ones,
+/// flush all the open files, deregister this node and un-mount. If a new
+/// process connects to the upgrade socket instead, the session is handed over
+/// to it after being drained and flushed, without un-mounting, or shut down as
+/// above if it fails to hand over.
+#[allow(clippy::arithmetic_side_effects)] // for the auto generate code from tokio select!
+#[allow(clippy::pattern_type_mismatch)] // for tokio::select!
+async fn run_session<M: MetaData + Send + Sync + 'static>(
+    kv_engine: &Arc<KVEngineType>,
+    args: &AsyncFuseArgs,
+    volume_info: &str,
+    fs: memfs::MemFs<M>,
+    taken_over: Option<(RawFd, SessionSnapshot)>,
+) -> anyhow::Result<()> {
+    let shutdown = shutdown_signal()?;
+    let upgrade_listener = args
+        .upgrade_socket
+        .as_ref()
+        .map(|path| upgrade::listen(Path::new(path)))
+        .transpose()?;
+    let mount_point = Path::new(&args.mount_dir);
+    let fuse_config = args.storage_config.fuse_config;
+    let mut ss = match taken_over {
+        Some((fuse_fd, snapshot)) => {
+            session::resume_session_of_memfs(mount_point, fs, fuse_config, fuse_fd, snapshot).await
+        }
+        None => {
+            session::new_session_of_memfs(mount_point, fs, fuse_config, &args.mount_options).await?
+        }
+    };
+    let stop = async {
+        tokio::select! {
+            () = shutdown => None,
+            stream = upgrade_request(upgrade_listener.as_ref()) => Some(stream),
+        }
+    };
+    let run_res = match ss.run_until(stop).await {
+        Ok(Some(Some(stream))) => match ss.hand_over(stream).await {
+            Ok(stream) => {
+                // Keep the connection open until this process exits, so that
+                // the new process binds the ports after they are released
+                let _: RawFd = stream.into_raw_fd();
+                return Ok(());
+            }
+            Err(e) => Err(e),
+        },
+        run_res => run_res.map(|_| ()),
+    };
+
+    // Deregister this node even if it fails to flush the data, other nodes
+    // should not send requests to it any more
+    memfs::kv_engine::kv_utils::deregister_volume(kv_engine, &args.node_id, volume_info).await?;
+    memfs::kv_engine::kv_utils::deregister_node_id(kv_engine, &args.node_id).await?;
+    ss.umount().await?;
+    run_res
+}
+
+/// Wait for a new process to connect to `listener` to take over the FUSE
+/// session, never completes if live upgrade is disabled
+async fn upgrade_request(listener: Option<&UnixListener>) -> UnixStream {
+    let Some(listener) = listener else {
+        return future::pending().await;
+    };
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                info!("a new process is taking over, shutting down");
+                return stream;
+            }
+            Err(e) => warn!("failed to accept upgrade connection, the error is: {}", e),
+        }
+    }
+}
+
+/// Register the handlers of SIGTERM and SIGINT, the returned future completes
+/// when either of them is received
+#[allow(clippy::arithmetic_side_effects)] // for the auto generate code from tokio select!
+#[allow(clippy::pattern_type_mismatch)] // for tokio::select!
+fn shutdown_signal() -> anyhow::Result<impl Future<Output = ()>> {
+    let mut sigterm =
+        unix::signal(SignalKind::terminate()).context("failed to register SIGTERM handler")?;
+    let mut sigint =
+        unix::signal(SignalKind::interrupt()).context("failed to register SIGINT handler")?;
+    Ok(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("received SIGTERM, shutting down"),
+            _ = sigint.recv() => info!("received SIGINT, shutting down"),
+        }
+    })
+}
+
 #[cfg(test)]
 mod test {
     mod integration_tests;
@@ -2,10 +2,14 @@ use std::fs::File;
 use std::io::Write;
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use std::{fs, io, iter};
 
 use anyhow::Context;
-use clippy_utilities::OverflowArithmetic;
+use clippy_utilities::{Cast, OverflowArithmetic};
+use datenlord::config::FuseConfig;
 use nix::fcntl::{self, OFlag};
 use nix::sys::stat::Mode;
 use nix::unistd::{self, Whence};
@@ -20,6 +24,7 @@ pub const S3_DEFAULT_MOUNT_DIR: &str = "/tmp/datenlord_test_dir";
 pub const FILE_CONTENT: &str = "0123456789ABCDEF";
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_result_states)] // assert!(result.is_err()) is more readable for test
 fn test_create_file(mount_dir: &Path) -> anyhow::Result<()> {
     use smol::fs::unix::MetadataExt;
     info!("test create file");
@@ -32,15 +37,38 @@ fn test_create_file(mount_dir: &Path) -> anyhow::Result<()> {
     assert_eq!(file_metadata.mode() & 0o777, 0o644);
     // check the file owner
     let file_owner = file_metadata.uid();
-    let create_uid= unistd::getuid();
+    let create_user_id = unistd::getuid();
     assert_eq!(file_owner, create_user_id.as_raw());
     // check the file group
     let file_group = file_metadata.gid();
-    let create_gid= unistd::getgid();
+    let create_group_id = unistd::getgid();
     assert_eq!(file_group, create_group_id.as_raw());
     // check nlink == 1
     assert_eq!(file_metadata.nlink(), 1);
+    // check O_EXCL fails on the existing file
+    assert!(fcntl::open(&file_path, OFlag::O_CREAT | OFlag::O_EXCL, file_mode).is_err());
     fs::remove_file(&file_path)?; // immediate deletion
+
+    // The kernel sends a create request for a new name, the file is counted as
+    // opened by the daemon, so it's readable after being unlinked
+    let excl_file_path = Path::new(mount_dir).join("test_create_file_excl.txt");
+    let excl_fd = fcntl::open(
+        &excl_file_path,
+        OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR,
+        file_mode,
+    )?;
+    assert_eq!(
+        unistd::write(excl_fd, FILE_CONTENT.as_bytes())?,
+        FILE_CONTENT.len()
+    );
+    unistd::fsync(excl_fd)?;
+    fs::remove_file(&excl_file_path)?;
+    let mut buffer = vec![0_u8; FILE_CONTENT.len()];
+    unistd::lseek(excl_fd, 0, Whence::SeekSet)?;
+    assert_eq!(unistd::read(excl_fd, &mut buffer)?, FILE_CONTENT.len());
+    assert_eq!(buffer, FILE_CONTENT.as_bytes());
+    unistd::close(excl_fd)?;
+    assert!(!excl_file_path.exists());
     Ok(())
 }
 
@@ -133,6 +161,50 @@ fn test_directory_manipulation_rust_way(mount_dir: &Path) -> anyhow::Result<()>
     Ok(())
 }
 
+/// List a directory needing several READDIRPLUS requests, which the kernel
+/// issues for a directory listed from the beginning
+#[cfg(feature = "abi-7-21")]
+fn test_readdirplus(mount_dir: &Path) -> anyhow::Result<()> {
+    use std::collections::BTreeMap;
+    use std::os::unix::fs::{DirEntryExt, MetadataExt};
+    // The long names fill several reply buffers of a page
+    const FILE_NUM: usize = 200;
+    info!("test readdirplus");
+    let dir_path = Path::new(mount_dir).join("test_readdirplus_dir");
+    fs::create_dir(&dir_path)?;
+    let mut expected = BTreeMap::new();
+    for i in 0..FILE_NUM {
+        let name = format!("test_readdirplus_file_with_a_long_name_{i:04}");
+        let content = FILE_CONTENT.repeat(i.overflow_rem(4).overflow_add(1));
+        fs::write(dir_path.join(&name), &content)?;
+        expected.insert(name, content.len().cast::<u64>());
+    }
+
+    let mut listed = BTreeMap::new();
+    for entry in fs::read_dir(&dir_path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let ino = entry.ino();
+        assert!(
+            listed.insert(name.clone(), ino).is_none(),
+            "{name:?} is listed twice"
+        );
+    }
+    assert_eq!(listed.len(), FILE_NUM);
+    for (name, ino) in listed {
+        let metadata = fs::metadata(dir_path.join(&name))?;
+        assert_eq!(ino, metadata.ino(), "unexpected ino of {name:?}");
+        assert_eq!(
+            Some(&metadata.len()),
+            expected.get(&name),
+            "unexpected size of {name:?}"
+        );
+    }
+
+    fs::remove_dir_all(&dir_path)?;
+    Ok(())
+}
+
 #[cfg(test)]
 fn test_deferred_deletion(mount_dir: &Path) -> anyhow::Result<()> {
     info!("file deletion deferred");
@@ -693,9 +765,807 @@ fn test_write_read_only_file(mount_dir: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+#[cfg(test)]
+#[allow(clippy::assertions_on_result_states)] // assert!(result.is_err()) is more readable for test
+fn test_hard_link(mount_dir: &Path) -> anyhow::Result<()> {
+    use smol::fs::unix::MetadataExt;
+    info!("test hard link");
+    let file_path = Path::new(mount_dir).join("test_hard_link_src.txt");
+    let link_path = Path::new(mount_dir).join("test_hard_link_dst.txt");
+
+    fs::write(&file_path, FILE_CONTENT)?;
+    fs::hard_link(&file_path, &link_path)?;
+
+    let file_metadata = fs::metadata(&file_path)?;
+    let link_metadata = fs::metadata(&link_path)?;
+    assert_eq!(file_metadata.ino(), link_metadata.ino());
+    assert_eq!(link_metadata.nlink(), 2);
+
+    // Remove the source, the data is still reachable from the link
+    fs::remove_file(&file_path)?;
+    let content = fs::read_to_string(&link_path)?;
+    assert_eq!(content, FILE_CONTENT);
+    assert_eq!(fs::metadata(&link_path)?.nlink(), 1);
+
+    // Hard link to a directory is not permitted
+    let dir_path = Path::new(mount_dir).join("test_hard_link_dir");
+    let dir_link_path = Path::new(mount_dir).join("test_hard_link_dir_dst");
+    fs::create_dir(&dir_path)?;
+    assert!(fs::hard_link(&dir_path, dir_link_path).is_err());
+
+    fs::remove_dir(&dir_path)?;
+    fs::remove_file(&link_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_special_files(mount_dir: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    use std::os::unix::net::UnixListener;
+
+    use nix::sys::stat::{self, SFlag};
+    info!("test special files");
+    let fifo_path = Path::new(mount_dir).join("test_special_fifo");
+    let sock_path = Path::new(mount_dir).join("test_special_sock");
+    let chr_path = Path::new(mount_dir).join("test_special_chr");
+
+    unistd::mkfifo(&fifo_path, Mode::from_bits_truncate(0o644))?;
+    assert!(fs::metadata(&fifo_path)?.file_type().is_fifo());
+
+    let listener = UnixListener::bind(&sock_path)?;
+    assert!(fs::metadata(&sock_path)?.file_type().is_socket());
+    drop(listener);
+
+    let rdev = stat::makedev(1, 3);
+    stat::mknod(
+        &chr_path,
+        SFlag::S_IFCHR,
+        Mode::from_bits_truncate(0o644),
+        rdev,
+    )?;
+    let chr_metadata = fs::metadata(&chr_path)?;
+    assert!(chr_metadata.file_type().is_char_device());
+    assert_eq!(chr_metadata.rdev(), rdev);
+
+    // The special files are listed with their types
+    let mut types = fs::read_dir(mount_dir)?
+        .map(|entry| entry.and_then(|e| Ok((e.file_name(), e.file_type()?))))
+        .collect::<io::Result<Vec<_>>>()?;
+    types.retain(|&(ref name, _)| name.to_string_lossy().starts_with("test_special_"));
+    assert_eq!(types.len(), 3);
+
+    fs::remove_file(&fifo_path)?;
+    fs::remove_file(&sock_path)?;
+    fs::remove_file(&chr_path)?;
+    Ok(())
+}
+
+/// Set, get, list and remove the extended attributes of a file
+fn test_xattr(mount_dir: &Path) -> anyhow::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    info!("test xattr");
+    let file_path = Path::new(mount_dir).join("test_xattr.txt");
+    fs::write(&file_path, FILE_CONTENT)?;
+    let c_path = CString::new(file_path.as_os_str().as_bytes())?;
+    let errno = |res: isize| -> io::Result<usize> {
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res.cast())
+        }
+    };
+    let set_xattr = |name: &str, value: &[u8], flags: i32| -> io::Result<usize> {
+        let c_name = CString::new(name)?;
+        // SAFETY: the path and the name are valid C strings, and the value is
+        // valid for its length
+        let res = unsafe {
+            libc::setxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                flags,
+            )
+        };
+        errno(res.cast())
+    };
+    let get_xattr = |name: &str, buf: &mut [u8]| -> io::Result<usize> {
+        let c_name = CString::new(name)?;
+        // SAFETY: the path and the name are valid C strings, and the buffer is
+        // valid for its length
+        let res = unsafe {
+            libc::getxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        errno(res)
+    };
+    let list_xattr = |buf: &mut [u8]| -> io::Result<usize> {
+        // SAFETY: the path is a valid C string, and the buffer is valid for
+        // its length
+        let res = unsafe { libc::listxattr(c_path.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) };
+        errno(res)
+    };
+    let remove_xattr = |name: &str| -> io::Result<usize> {
+        let c_name = CString::new(name)?;
+        // SAFETY: the path and the name are valid C strings
+        let res = unsafe { libc::removexattr(c_path.as_ptr(), c_name.as_ptr()) };
+        errno(res.cast())
+    };
+    let raw_os_error = |res: io::Result<usize>| res.map_err(|e| e.raw_os_error());
+
+    let name = "user.test_xattr";
+    let value: Vec<u8> = (0..4096_usize)
+        .map(|i| i.overflow_rem(251).cast())
+        .collect();
+    set_xattr(name, &value, libc::XATTR_CREATE)?;
+    assert_eq!(
+        raw_os_error(set_xattr(name, &value, libc::XATTR_CREATE)),
+        Err(Some(libc::EEXIST))
+    );
+    assert_eq!(
+        raw_os_error(set_xattr(
+            "user.test_xattr_absent",
+            &value,
+            libc::XATTR_REPLACE
+        )),
+        Err(Some(libc::ENODATA))
+    );
+    let new_value: Vec<u8> = value.iter().take(1000).copied().collect();
+    set_xattr(name, &new_value, libc::XATTR_REPLACE)?;
+
+    // The size is probed with an empty buffer, and a smaller buffer is rejected
+    assert_eq!(get_xattr(name, &mut [])?, new_value.len());
+    assert_eq!(
+        raw_os_error(get_xattr(name, &mut [0_u8; 10])),
+        Err(Some(libc::ERANGE))
+    );
+    let mut buf = vec![0_u8; new_value.len()];
+    assert_eq!(get_xattr(name, &mut buf)?, new_value.len());
+    assert_eq!(buf, new_value);
+
+    let list_len = list_xattr(&mut [])?;
+    let mut list = vec![0_u8; list_len];
+    assert_eq!(list_xattr(&mut list)?, list_len);
+    assert!(
+        list.split(|&b| b == 0)
+            .any(|listed| listed == name.as_bytes()),
+        "{name} is not listed"
+    );
+
+    remove_xattr(name)?;
+    assert_eq!(
+        raw_os_error(get_xattr(name, &mut [])),
+        Err(Some(libc::ENODATA))
+    );
+    assert_eq!(raw_os_error(remove_xattr(name)), Err(Some(libc::ENODATA)));
+
+    fs::remove_file(&file_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_statfs(mount_dir: &Path) -> anyhow::Result<()> {
+    use nix::sys::statvfs;
+    info!("test statfs");
+    let file_path = Path::new(mount_dir).join("test_statfs.txt");
+    let before = statvfs::statvfs(mount_dir)?;
+    assert!(before.files() > 0);
+    assert!(before.blocks_free() <= before.blocks());
+
+    let mut file = File::create(&file_path)?;
+    file.write_all(&[1_u8; 8192])?;
+    file.sync_all()?;
+    let written = statvfs::statvfs(mount_dir)?;
+    assert_eq!(written.files_free().overflow_add(1), before.files_free());
+    assert_eq!(written.blocks_free().overflow_add(2), before.blocks_free());
+
+    // Truncate the file
+    file.set_len(4096)?;
+    let truncated = statvfs::statvfs(mount_dir)?;
+    assert_eq!(
+        truncated.blocks_free().overflow_add(1),
+        before.blocks_free()
+    );
+    drop(file);
+
+    fs::remove_file(&file_path)?;
+    let removed = statvfs::statvfs(mount_dir)?;
+    assert_eq!(removed.files_free(), before.files_free());
+    assert_eq!(removed.blocks_free(), before.blocks_free());
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_result_states)] // assert!(result.is_err()) is more readable for test
+fn test_quota(mount_dir: &Path) -> anyhow::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    info!("test quota");
+    let set_xattr = |path: &Path, name: &str, value: Option<&[u8]>| -> io::Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let c_name = CString::new(name)?;
+        // SAFETY: the path and the name are valid C strings, and the value is a
+        // valid buffer
+        let res = unsafe {
+            match value {
+                Some(value) => libc::setxattr(
+                    c_path.as_ptr(),
+                    c_name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                ),
+                None => libc::removexattr(c_path.as_ptr(), c_name.as_ptr()),
+            }
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+    let dir_path = Path::new(mount_dir).join("test_quota_dir");
+    let file_path = dir_path.join("test_quota.txt");
+    let quota_name = "trusted.datenlord.quota.project.42";
+    fs::create_dir(&dir_path)?;
+    set_xattr(&dir_path, "trusted.datenlord.project", Some(b"42"))?;
+    set_xattr(mount_dir, quota_name, Some(b"bytes=4096,inodes=2"))?;
+
+    // The file inherits the project of the directory
+    let mut file = File::create(&file_path)?;
+    file.write_all(&[1_u8; 4096])?;
+    file.sync_all()?;
+    let exceeded = file.write_all(&[1_u8; 4096]).and_then(|()| file.sync_all());
+    assert_eq!(
+        exceeded.map_err(|e| e.raw_os_error()),
+        Err(Some(libc::EDQUOT))
+    );
+    drop(file);
+    // The directory and the file use up the i-nodes
+    assert_eq!(
+        File::create(dir_path.join("test_quota_more.txt"))
+            .map(drop)
+            .map_err(|e| e.raw_os_error()),
+        Err(Some(libc::EDQUOT))
+    );
+
+    // Remove the limit
+    set_xattr(mount_dir, quota_name, None)?;
+    fs::write(dir_path.join("test_quota_more.txt"), [1_u8; 8192])?;
+    fs::remove_dir_all(&dir_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(feature = "abi-7-26")]
+fn test_posix_acl(mount_dir: &Path) -> anyhow::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    const NOBODY: u32 = 65534;
+    info!("test posix acl");
+    let set_acl = |path: &Path, user_perm: u16| -> io::Result<()> {
+        // Version 2 header, then (tag, perm, id) entries of USER_OBJ, USER,
+        // GROUP_OBJ, MASK and OTHER
+        let entries: [(u16, u16, u32); 5] = [
+            (0x01, 7, u32::MAX),
+            (0x02, user_perm, NOBODY),
+            (0x04, 0, u32::MAX),
+            (0x10, user_perm, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ];
+        let mut value = 2_u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let c_name = CString::new("system.posix_acl_access")?;
+        // SAFETY: the path and the name are valid C strings, and the value is a
+        // valid buffer
+        let res = unsafe {
+            libc::setxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    };
+    // Run `f` in a thread whose file system credentials are those of nobody,
+    // which drops the capabilities that bypass permission checks
+    let as_nobody = |f: &(dyn Fn() -> io::Result<()> + Sync)| -> Result<(), Option<i32>> {
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // SAFETY: setfsgid() and setfsuid() only change the credentials
+                // of the calling thread
+                unsafe {
+                    libc::setfsgid(NOBODY);
+                    libc::setfsuid(NOBODY);
+                }
+                f().map_err(|e| e.raw_os_error())
+            })
+            .join()
+            .unwrap_or_else(|_| panic!("the thread accessing as nobody panicked"))
+        })
+    };
+    let dir_path = Path::new(mount_dir).join("test_posix_acl_dir");
+    let file_path = dir_path.join("test_posix_acl.txt");
+    fs::create_dir(&dir_path)?;
+    fs::set_permissions(&dir_path, fs::Permissions::from_mode(0o700))?;
+    fs::write(&file_path, FILE_CONTENT)?;
+    fs::set_permissions(&file_path, fs::Permissions::from_mode(0o600))?;
+
+    let lookup = || fs::symlink_metadata(&file_path).map(|_| ());
+    let read = || -> io::Result<()> {
+        assert_eq!(fs::read_to_string(&file_path)?, FILE_CONTENT);
+        Ok(())
+    };
+    let unlink = || fs::remove_file(&file_path);
+    assert_eq!(as_nobody(&lookup), Err(Some(libc::EACCES)));
+
+    // The directory ACL grants nobody search and write access
+    set_acl(&dir_path, 7)?;
+    assert_eq!(as_nobody(&lookup), Ok(()));
+    assert_eq!(as_nobody(&read), Err(Some(libc::EACCES)));
+
+    // The file ACL grants nobody read access
+    set_acl(&file_path, 4)?;
+    assert_eq!(as_nobody(&read), Ok(()));
+
+    // Removing the search access of the directory hides the file again
+    set_acl(&dir_path, 2)?;
+    assert_eq!(as_nobody(&unlink), Err(Some(libc::EACCES)));
+    set_acl(&dir_path, 3)?;
+    assert_eq!(as_nobody(&unlink), Ok(()));
+
+    fs::remove_dir(&dir_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_posix_lock(mount_dir: &Path) -> anyhow::Result<()> {
+    use nix::fcntl::FcntlArg;
+    info!("test posix lock");
+    let file_path = Path::new(mount_dir).join("test_posix_lock.txt");
+    fs::write(&file_path, FILE_CONTENT)?;
+
+    let fd = fcntl::open(&file_path, OFlag::O_RDWR, Mode::empty())?;
+    let build_flock = |typ: i32, start: i64, len: i64| libc::flock {
+        l_type: typ.cast(),
+        l_whence: libc::SEEK_SET.cast(),
+        l_start: start,
+        l_len: len,
+        l_pid: 0,
+    };
+    // Lock the first half of the file, then test it from the same owner
+    fcntl::fcntl(fd, FcntlArg::F_SETLK(&build_flock(libc::F_WRLCK, 0, 8)))?;
+    let mut lock = build_flock(libc::F_WRLCK, 0, 16);
+    fcntl::fcntl(fd, FcntlArg::F_GETLK(&mut lock))?;
+    // The locks of the same owner never conflict
+    assert_eq!(lock.l_type, libc::F_UNLCK.cast::<i16>());
+
+    // Downgrade and release the lock
+    fcntl::fcntl(fd, FcntlArg::F_SETLKW(&build_flock(libc::F_RDLCK, 0, 8)))?;
+    fcntl::fcntl(fd, FcntlArg::F_SETLK(&build_flock(libc::F_UNLCK, 0, 0)))?;
+    unistd::close(fd)?;
+
+    fs::remove_file(&file_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(feature = "abi-7-17")]
+#[allow(clippy::assertions_on_result_states)] // assert!(result.is_err()) is more readable for test
+fn test_flock(mount_dir: &Path) -> anyhow::Result<()> {
+    use nix::fcntl::FlockArg;
+    info!("test flock");
+    let file_path = Path::new(mount_dir).join("test_flock.txt");
+    fs::write(&file_path, FILE_CONTENT)?;
+
+    // Two open file descriptions have different flock owners
+    let fd1 = fcntl::open(&file_path, OFlag::O_RDWR, Mode::empty())?;
+    let fd2 = fcntl::open(&file_path, OFlag::O_RDWR, Mode::empty())?;
+    fcntl::flock(fd1, FlockArg::LockShared)?;
+    fcntl::flock(fd2, FlockArg::LockSharedNonblock)?;
+    assert!(fcntl::flock(fd2, FlockArg::LockExclusiveNonblock).is_err());
+
+    // The lock is released when the file is closed
+    unistd::close(fd1)?;
+    fcntl::flock(fd2, FlockArg::LockExclusiveNonblock)?;
+    fcntl::flock(fd2, FlockArg::Unlock)?;
+    unistd::close(fd2)?;
+
+    fs::remove_file(&file_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(feature = "abi-7-19")]
+fn test_fallocate(mount_dir: &Path) -> anyhow::Result<()> {
+    use nix::fcntl::FallocateFlags;
+    info!("test fallocate");
+    let file_path = Path::new(mount_dir).join("test_fallocate.txt");
+    let file_size: usize = 2 * 1024 * 1024;
+    fs::write(&file_path, vec![b'a'; file_size])?;
+
+    let fd = fcntl::open(&file_path, OFlag::O_RDWR, Mode::empty())?;
+    // Preallocate beyond EOF without changing the file size
+    fcntl::fallocate(
+        fd,
+        FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        file_size.cast(),
+        4096,
+    )?;
+    assert_eq!(fs::metadata(&file_path)?.len(), file_size.cast::<u64>());
+    // Preallocate beyond EOF, the allocated range is read as zeros
+    fcntl::fallocate(fd, FallocateFlags::empty(), file_size.cast(), 4096)?;
+    let allocated_size = file_size.overflow_add(4096);
+    assert_eq!(
+        fs::metadata(&file_path)?.len(),
+        allocated_size.cast::<u64>()
+    );
+    // Punch a hole across blocks, the file size is unchanged
+    let (hole_start, hole_len): (usize, usize) = (1000, 1024 * 1024);
+    fcntl::fallocate(
+        fd,
+        FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        hole_start.cast(),
+        hole_len.cast(),
+    )?;
+    unistd::close(fd)?;
+    assert_eq!(
+        fs::metadata(&file_path)?.len(),
+        allocated_size.cast::<u64>()
+    );
+
+    let mut expected = vec![b'a'; file_size];
+    expected.resize(allocated_size, 0);
+    expected
+        .iter_mut()
+        .skip(hole_start)
+        .take(hole_len)
+        .for_each(|b| *b = 0);
+    assert!(
+        fs::read(&file_path)? == expected,
+        "content mismatch after fallocate"
+    );
+
+    fs::remove_file(&file_path)?;
+    Ok(())
+}
+
+fn test_lseek(mount_dir: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+    info!("test lseek");
+    let file_path = Path::new(mount_dir).join("test_lseek.txt");
+    let data_len: usize = 16;
+    let data_offset: u64 = 4 * 1024 * 1024;
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&file_path)?;
+    // Write at the beginning and far beyond EOF, leaving a hole in between
+    file.write_all_at(&vec![b'a'; data_len], 0)?;
+    file.write_all_at(&vec![b'b'; data_len], data_offset)?;
+    file.sync_all()?;
+
+    let fd = file.as_raw_fd();
+    assert_eq!(unistd::lseek(fd, 0, Whence::SeekData)?, 0);
+    let hole: u64 = unistd::lseek(fd, 0, Whence::SeekHole)?.cast();
+    assert!(
+        hole >= data_len.cast() && hole < data_offset,
+        "unexpected hole offset {hole}"
+    );
+    let data: u64 = unistd::lseek(fd, hole.cast(), Whence::SeekData)?.cast();
+    assert!(
+        data > hole && data <= data_offset,
+        "unexpected data offset {data}"
+    );
+    // There's an implicit hole at EOF
+    let file_size = data_offset.overflow_add(data_len.cast());
+    assert_eq!(
+        unistd::lseek(fd, data_offset.cast(), Whence::SeekHole)?,
+        file_size.cast::<i64>()
+    );
+    assert!(unistd::lseek(fd, file_size.cast(), Whence::SeekData).is_err());
+
+    // The hole is read as zeros
+    let mut buf = vec![0xff_u8; data_len];
+    file.read_exact_at(&mut buf, hole)?;
+    assert!(buf.iter().all(|&b| b == 0), "hole is not read as zeros");
+    drop(file);
+
+    fs::remove_file(&file_path)?;
+    Ok(())
+}
+
+/// Set the snapshot xattr `name` of `mount_dir`, which creates the snapshot if
+/// `create`, otherwise deletes it
+fn set_snapshot_xattr(mount_dir: &Path, name: &str, create: bool) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = CString::new(mount_dir.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+    // SAFETY: the path and the name are valid C strings
+    let res = unsafe {
+        if create {
+            libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null(), 0, 0)
+        } else {
+            libc::removexattr(c_path.as_ptr(), c_name.as_ptr())
+        }
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Get the snapshot xattr `name` of `mount_dir`
+fn get_snapshot_xattr(mount_dir: &Path, name: &str) -> io::Result<String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = CString::new(mount_dir.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+    let mut buf = vec![0_u8; 256];
+    // SAFETY: the path and the name are valid C strings, and the buffer is
+    // valid for its length
+    let res = unsafe {
+        libc::getxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(res.cast());
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn test_snapshot(mount_dir: &Path) -> anyhow::Result<()> {
+    info!("test snapshot");
+    let set_xattr = |name: &str, create: bool| set_snapshot_xattr(mount_dir, name, create);
+    let get_xattr = |name: &str| get_snapshot_xattr(mount_dir, name);
+    let file_path = Path::new(mount_dir).join("test_snapshot.txt");
+    let snapshot_name = "trusted.datenlord.snapshot.test";
+    fs::write(&file_path, [1_u8; 4096])?;
+    set_xattr(snapshot_name, true)?;
+    assert!(get_xattr(snapshot_name)?.starts_with("generation="));
+    assert_eq!(
+        set_xattr(snapshot_name, true).map_err(|e| e.raw_os_error()),
+        Err(Some(libc::EEXIST))
+    );
+
+    // The file captured by the snapshot can still be changed and deleted
+    fs::write(&file_path, [2_u8; 4096])?;
+    assert_eq!(fs::read(&file_path)?, [2_u8; 4096]);
+    fs::remove_file(&file_path)?;
+
+    set_xattr(snapshot_name, false)?;
+    assert_eq!(
+        get_xattr(snapshot_name).map_err(|e| e.raw_os_error()),
+        Err(Some(libc::ENODATA))
+    );
+    Ok(())
+}
+
+fn test_copy_file_range(mount_dir: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+    info!("test copy_file_range");
+    let src_path = Path::new(mount_dir).join("test_copy_file_range_src.txt");
+    let dst_path = Path::new(mount_dir).join("test_copy_file_range_dst.txt");
+    let file_size: usize = 2 * 1024 * 1024 + 1000;
+    let src_content: Vec<u8> = (0..file_size).map(|i| i.overflow_rem(251).cast()).collect();
+    fs::write(&src_path, &src_content)?;
+    fs::write(&dst_path, vec![b'a'; 1024])?;
+
+    let copy_range = |src_offset: usize, dst_offset: usize, len: usize| -> anyhow::Result<()> {
+        let src_file = File::open(&src_path)?;
+        let dst_file = fs::OpenOptions::new().write(true).open(&dst_path)?;
+        let mut off_in: i64 = src_offset.cast();
+        let mut off_out: i64 = dst_offset.cast();
+        let mut remaining = len;
+        while remaining > 0 {
+            let copied = fcntl::copy_file_range(
+                src_file.as_raw_fd(),
+                Some(&mut off_in),
+                dst_file.as_raw_fd(),
+                Some(&mut off_out),
+                remaining,
+            )?;
+            assert!(copied > 0, "copy_file_range() copied nothing");
+            remaining = remaining.overflow_sub(copied);
+        }
+        Ok(())
+    };
+
+    // Copy the whole file
+    copy_range(0, 0, file_size)?;
+    assert!(
+        fs::read(&dst_path)? == src_content,
+        "content mismatch after copying the whole file"
+    );
+    // The whole file is cloned by a reflink, writing the source file does not
+    // change the clone
+    let src_file = fs::OpenOptions::new().write(true).open(&src_path)?;
+    src_file.write_all_at(&[b'b'; 100], 0)?;
+    assert!(
+        fs::read(&dst_path)? == src_content,
+        "the clone changed after writing the source file"
+    );
+    src_file.write_all_at(
+        src_content
+            .get(..100)
+            .unwrap_or_else(|| panic!("failed to get the overwritten range")),
+        0,
+    )?;
+    drop(src_file);
+    // Copy a range across blocks to an unaligned offset beyond EOF
+    let (src_offset, dst_offset, len): (usize, usize, usize) =
+        (1000, file_size.overflow_add(10), 1024 * 1024);
+    copy_range(src_offset, dst_offset, len)?;
+    let mut expected = src_content.clone();
+    expected.resize(dst_offset, 0);
+    expected.extend_from_slice(
+        src_content
+            .get(src_offset..src_offset.overflow_add(len))
+            .unwrap_or_else(|| panic!("failed to get the copied range")),
+    );
+    assert!(
+        fs::read(&dst_path)? == expected,
+        "content mismatch after copying a range"
+    );
+
+    fs::remove_file(&src_path)?;
+    fs::remove_file(&dst_path)?;
+    Ok(())
+}
+
+/// Act as another node mounting the volume, which changes the metadata behind
+/// the kernel of the test node, and check the kernel drops its stale caches
+/// when notified
+#[cfg(feature = "abi-7-12")]
+async fn test_remote_invalidation(mount_dir: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    use crate::async_fuse::memfs::dist::client as dist_client;
+    use crate::async_fuse::memfs::kv_engine::{
+        kv_utils, KVEngine, KVEngineType, KeyType, ValueType,
+    };
+    use crate::async_fuse::memfs::serial::{self, SerialNode, SerialNodeData};
+    info!("test remote invalidation");
+    let kv_engine =
+        Arc::new(KVEngineType::new(vec![test_util::TEST_ETCD_ENDPOINT.to_owned()]).await?);
+    let volume_info = serde_json::to_string(&test_util::test_storage_config())?;
+    let remote_node_id = "test_remote_node";
+    kv_utils::register_node_id(
+        &kv_engine,
+        test_util::TEST_NODE_ID,
+        test_util::TEST_NODE_IP,
+        test_util::TEST_PORT,
+    )
+    .await?;
+    kv_utils::register_volume(&kv_engine, test_util::TEST_NODE_ID, &volume_info).await?;
+    let get_node = |ino: u64| {
+        let kv_engine = Arc::clone(&kv_engine);
+        async move {
+            Ok::<_, anyhow::Error>(
+                kv_engine
+                    .get(&KeyType::INum2Node(ino))
+                    .await?
+                    .unwrap_or_else(|| panic!("i-node of ino={ino} is not found"))
+                    .into_serial_node(),
+            )
+        }
+    };
+    let set_node = |ino: u64, node: SerialNode| {
+        let kv_engine = Arc::clone(&kv_engine);
+        async move {
+            kv_engine
+                .set(&KeyType::INum2Node(ino), &ValueType::Node(node), None)
+                .await?;
+            Ok::<_, anyhow::Error>(())
+        }
+    };
+
+    let dir_path = Path::new(mount_dir).join("test_remote_invalidation_dir");
+    let file_name = "test_remote_invalidation.txt";
+    let file_path = dir_path.join(file_name);
+    fs::create_dir(&dir_path)?;
+    fs::write(&file_path, FILE_CONTENT)?;
+    fs::set_permissions(&file_path, fs::Permissions::from_mode(0o644))?;
+    let dir_ino = fs::metadata(&dir_path)?.ino();
+    let file_ino = fs::metadata(&file_path)?.ino();
+
+    // The attributes are cached by the kernel till the i-node is invalidated
+    let mut file_node = get_node(file_ino).await?;
+    let mut attr = serial::serial_to_file_attr(&file_node.attr);
+    attr.perm = 0o600;
+    file_node.attr = serial::file_attr_to_serial(&attr);
+    set_node(file_ino, file_node).await?;
+    assert_eq!(fs::metadata(&file_path)?.mode() & 0o777, 0o644);
+    dist_client::invalidate(&kv_engine, remote_node_id, &volume_info, file_ino, 0, 0).await?;
+    assert_eq!(fs::metadata(&file_path)?.mode() & 0o777, 0o600);
+
+    // The entry is cached by the kernel till it's invalidated
+    let mut dir_node = get_node(dir_ino).await?;
+    let SerialNodeData::Directory(ref mut entries) = dir_node.data else {
+        panic!("ino={dir_ino} should be a directory");
+    };
+    let entry = entries
+        .remove(file_name)
+        .unwrap_or_else(|| panic!("{file_name} should be in ino={dir_ino}"));
+    set_node(dir_ino, dir_node).await?;
+    assert!(file_path.exists());
+    dist_client::invalidate_entry(&kv_engine, remote_node_id, &volume_info, dir_ino, file_name)
+        .await?;
+    assert!(!file_path.exists());
+
+    // Restore the entry to clean up
+    let mut dir_node = get_node(dir_ino).await?;
+    if let SerialNodeData::Directory(ref mut entries) = dir_node.data {
+        entries.insert(file_name.to_owned(), entry);
+    }
+    set_node(dir_ino, dir_node).await?;
+    fs::remove_file(&file_path)?;
+    fs::remove_dir(&dir_path)?;
+    kv_utils::deregister_volume(&kv_engine, test_util::TEST_NODE_ID, &volume_info).await?;
+    kv_utils::deregister_node_id(&kv_engine, test_util::TEST_NODE_ID).await?;
+    Ok(())
+}
+
+/// Shut down the session while the file system is being looked up, all the
+/// requests must be answered either by the session before it stops or by the
+/// kernel after the file system is un-mounted
+#[allow(clippy::assertions_on_result_states)] // assert!(result.is_err()) is more readable for test
+async fn test_shutdown(mount_dir: &Path, session: test_util::TestSession) -> anyhow::Result<()> {
+    info!("test shutdown");
+    // Look up a missing file, which holds no open file to keep the file system
+    // busy
+    let file_path = Path::new(mount_dir).join("test_shutdown.txt");
+    let done = Arc::new(AtomicBool::new(false));
+    let reader_done = Arc::clone(&done);
+    let reader = tokio::task::spawn_blocking(move || {
+        while !reader_done.load(Ordering::Acquire) {
+            assert!(
+                fs::symlink_metadata(&file_path).is_err(),
+                "{file_path:?} should not exist"
+            );
+        }
+    });
+    test_util::teardown(mount_dir, session).await?;
+    done.store(true, Ordering::Release);
+    tokio::time::timeout(Duration::from_secs(10), reader)
+        .await
+        .context("the requests issued during shutdown are not answered")??;
+    Ok(())
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test_all() -> anyhow::Result<()> {
-    run_test().await
+    run_test().await?;
+    run_fuse_channels_test().await?;
+    run_fuse_splice_test().await?;
+    run_checkpoint_test().await?;
+    run_snapshot_mount_test().await
 }
 
 async fn run_test() -> anyhow::Result<()> {
@@ -718,6 +1588,8 @@ async fn _run_test(mount_dir_str: &str, is_s3: bool) -> anyhow::Result<()> {
         .context("test_file_manipulation_rust_way() failed")?;
     test_directory_manipulation_rust_way(mount_dir)
         .context("test_directory_manipulation_rust_way() failed")?;
+    #[cfg(feature = "abi-7-21")]
+    test_readdirplus(mount_dir).context("test_readdirplus() failed")?;
     test_create_file(mount_dir).context("test_create_file() failed")?;
     test_name_too_long(mount_dir).context("test_name_too_long() failed")?;
     test_symlink_dir(mount_dir).context("test_symlink_dir() failed")?;
@@ -733,12 +1605,214 @@ async fn _run_test(mount_dir_str: &str, is_s3: bool) -> anyhow::Result<()> {
     test_create_file(mount_dir).context("test_create_file() failed")?;
     test_open_file_permission(mount_dir).context("test_open_file_permission() failed")?;
     test_write_read_only_file(mount_dir).context("test_write_read_only_file() failed")?;
+    test_hard_link(mount_dir).context("test_hard_link() failed")?;
+    test_special_files(mount_dir).context("test_special_files() failed")?;
+    test_xattr(mount_dir).context("test_xattr() failed")?;
+    test_statfs(mount_dir).context("test_statfs() failed")?;
+    test_quota(mount_dir).context("test_quota() failed")?;
+    test_snapshot(mount_dir).context("test_snapshot() failed")?;
+    #[cfg(feature = "abi-7-26")]
+    test_posix_acl(mount_dir).context("test_posix_acl() failed")?;
+    test_posix_lock(mount_dir).context("test_posix_lock() failed")?;
+    #[cfg(feature = "abi-7-17")]
+    test_flock(mount_dir).context("test_flock() failed")?;
+    #[cfg(feature = "abi-7-19")]
+    test_fallocate(mount_dir).context("test_fallocate() failed")?;
+    test_lseek(mount_dir).context("test_lseek() failed")?;
+    test_copy_file_range(mount_dir).context("test_copy_file_range() failed")?;
+    #[cfg(feature = "abi-7-12")]
+    test_remote_invalidation(mount_dir)
+        .await
+        .context("test_remote_invalidation() failed")?;
+
+    test_shutdown(mount_dir, th)
+        .await
+        .context("test_shutdown() failed")?;
 
-    test_util::teardown(mount_dir, th).await?;
+    Ok(())
+}
+
+/// Read and write the files from several threads at once, so that the
+/// requests are spread over the FUSE channels
+fn test_concurrent_io(mount_dir: &Path) -> anyhow::Result<()> {
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 32;
+    info!("test concurrent io");
+    std::thread::scope(|s| {
+        let handles: Vec<_> = (0..THREADS)
+            .map(|i| {
+                s.spawn(move || -> anyhow::Result<()> {
+                    let file_path =
+                        Path::new(mount_dir).join(format!("test_concurrent_io_{i}.txt"));
+                    for round in 0..ROUNDS {
+                        let content = format!("{FILE_CONTENT}-{i}-{round}");
+                        fs::write(&file_path, &content)?;
+                        assert_eq!(fs::read_to_string(&file_path)?, content);
+                    }
+                    fs::remove_file(&file_path)?;
+                    Ok(())
+                })
+            })
+            .collect();
+        handles.into_iter().try_for_each(|handle| {
+            handle
+                .join()
+                .unwrap_or_else(|_| panic!("the thread doing concurrent io panicked"))
+        })
+    })
+}
+
+/// Serve the requests through several FUSE channels, the session is shut down
+/// right after the requests are replied through the cloned channels
+async fn run_fuse_channels_test() -> anyhow::Result<()> {
+    info!("begin FUSE channels test");
+    let mount_dir = Path::new(DEFAULT_MOUNT_DIR);
+    let fuse_config = FuseConfig {
+        channels: 4,
+        ..FuseConfig::default()
+    };
+    let th = test_util::setup_with_fuse_config(mount_dir, true, fuse_config).await?;
+
+    test_concurrent_io(mount_dir).context("test_concurrent_io() failed")?;
+
+    test_util::teardown(mount_dir, th).await
+}
+
+/// Write the data of various sizes and read it back, the write requests with
+/// data are spliced into pipes and the read replies are spliced out of them
+fn test_splice_io(mount_dir: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileExt;
 
+    info!("test splice io");
+    let file_path = Path::new(mount_dir).join("test_splice_io.txt");
+    let mut expected = Vec::new();
+    {
+        let file = File::create(&file_path)?;
+        for (idx, size) in [1_usize, 4095, 4096, 64 * 1024, 128 * 1024 + 7]
+            .into_iter()
+            .enumerate()
+        {
+            let data: Vec<u8> = iter::repeat(b'a'.overflow_add(idx.cast()))
+                .take(size)
+                .collect();
+            file.write_all_at(&data, expected.len().cast())?;
+            file.sync_all()?;
+            expected.extend_from_slice(&data);
+        }
+        // Overwrite the middle of the file across the block boundary
+        let overwrite = FILE_CONTENT.repeat(512);
+        file.write_all_at(overwrite.as_bytes(), 4000)?;
+        file.sync_all()?;
+        expected
+            .get_mut(4000..4000_usize.overflow_add(overwrite.len()))
+            .unwrap_or_else(|| panic!("the overwritten range should be in the file"))
+            .copy_from_slice(overwrite.as_bytes());
+    }
+    assert_eq!(fs::read(&file_path)?, expected);
+    fs::remove_file(&file_path)?;
     Ok(())
 }
 
+/// Move the data of the write and read requests through the pipes by splice
+async fn run_fuse_splice_test() -> anyhow::Result<()> {
+    info!("begin FUSE splice test");
+    let mount_dir = Path::new(DEFAULT_MOUNT_DIR);
+    let fuse_config = FuseConfig {
+        splice: true,
+        ..FuseConfig::default()
+    };
+    let th = test_util::setup_with_fuse_config(mount_dir, true, fuse_config).await?;
+
+    test_splice_io(mount_dir).context("test_splice_io() failed")?;
+    test_file_manipulation_rust_way(mount_dir)
+        .context("test_file_manipulation_rust_way() failed")?;
+
+    test_util::teardown(mount_dir, th).await
+}
+
+/// Checkpoint the metadata to the storage backend, lose the metadata in the KV
+/// engine, and recover it from the checkpoint
+async fn run_checkpoint_test() -> anyhow::Result<()> {
+    use datenlord::config::StorageConfig;
+
+    use crate::common::etcd_delegate::EtcdDelegate;
+    info!("begin metadata checkpoint test");
+    let mount_dir = Path::new(DEFAULT_MOUNT_DIR);
+    let storage_config = StorageConfig {
+        metadata_checkpoint_interval: 1,
+        ..test_util::test_storage_config()
+    };
+    let th = test_util::setup_with_storage_config(mount_dir, true, storage_config).await?;
+    let dir_path = mount_dir.join("test_checkpoint_dir");
+    let file_path = dir_path.join("test_checkpoint.txt");
+    fs::create_dir(&dir_path)?;
+    fs::write(&file_path, FILE_CONTENT)?;
+    // Wait for a checkpoint taken after the changes
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    test_util::teardown(mount_dir, th).await?;
+
+    EtcdDelegate::new(vec![test_util::TEST_ETCD_ENDPOINT.to_owned()])
+        .await?
+        .delete_all()
+        .await?;
+    let storage_config = StorageConfig {
+        metadata_recover: true,
+        ..test_util::test_storage_config()
+    };
+    let th = test_util::setup_with_storage_config(mount_dir, true, storage_config).await?;
+    assert!(fs::metadata(&dir_path)?.is_dir());
+    assert_eq!(
+        fs::metadata(&file_path)?.len(),
+        FILE_CONTENT.len().cast::<u64>()
+    );
+    fs::remove_dir_all(&dir_path)?;
+
+    test_util::teardown(mount_dir, th).await
+}
+
+/// Create a snapshot, change the files after it, and mount the snapshot
+/// read-only, which shows the files as they were when it was created
+async fn run_snapshot_mount_test() -> anyhow::Result<()> {
+    use datenlord::config::StorageConfig;
+    info!("begin snapshot mount test");
+    let mount_dir = Path::new(DEFAULT_MOUNT_DIR);
+    let snapshot_name = "trusted.datenlord.snapshot.test_mount";
+    let dir_path = mount_dir.join("test_snapshot_mount_dir");
+    let changed_path = dir_path.join("test_snapshot_mount_changed.txt");
+    let deleted_path = dir_path.join("test_snapshot_mount_deleted.txt");
+    let created_path = dir_path.join("test_snapshot_mount_created.txt");
+    let th = test_util::setup(mount_dir, true).await?;
+    fs::create_dir(&dir_path)?;
+    fs::write(&changed_path, FILE_CONTENT)?;
+    fs::write(&deleted_path, FILE_CONTENT)?;
+    set_snapshot_xattr(mount_dir, snapshot_name, true)?;
+    fs::write(&changed_path, FILE_CONTENT.repeat(2))?;
+    fs::remove_file(&deleted_path)?;
+    fs::write(&created_path, FILE_CONTENT)?;
+    test_util::teardown(mount_dir, th).await?;
+
+    let storage_config = StorageConfig {
+        metadata_snapshot: Some("test_mount".to_owned()),
+        ..test_util::test_storage_config()
+    };
+    let th = test_util::setup_with_storage_config(mount_dir, true, storage_config).await?;
+    assert_eq!(fs::read_to_string(&changed_path)?, FILE_CONTENT);
+    assert_eq!(fs::read_to_string(&deleted_path)?, FILE_CONTENT);
+    assert!(!created_path.exists());
+    assert_eq!(
+        set_snapshot_xattr(mount_dir, snapshot_name, false).map_err(|e| e.raw_os_error()),
+        Err(Some(libc::EROFS))
+    );
+    test_util::teardown(mount_dir, th).await?;
+
+    let th = test_util::setup(mount_dir, true).await?;
+    assert_eq!(fs::read_to_string(&changed_path)?, FILE_CONTENT.repeat(2));
+    set_snapshot_xattr(mount_dir, snapshot_name, false)?;
+    fs::remove_dir_all(&dir_path)?;
+
+    test_util::teardown(mount_dir, th).await
+}
+
 // TODO: check the logic of this benchmark and make it could be run in CI
 #[allow(dead_code)]
 async fn run_bench() -> anyhow::Result<()> {
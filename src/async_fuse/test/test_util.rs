@@ -3,12 +3,14 @@ use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
-use datenlord::config::{StorageConfig, StorageParams, StorageS3Config};
+use datenlord::config::{FuseConfig, MountOptions, StorageConfig, StorageParams, StorageS3Config};
+use futures::FutureExt;
+use tokio::sync::oneshot;
 use tracing::{debug, info}; // warn, error
 
 use crate::async_fuse::fuse::{mount, session};
 use crate::async_fuse::memfs;
-use crate::async_fuse::memfs::kv_engine::{KVEngine, KVEngineType};
+use crate::async_fuse::memfs::kv_engine::{kv_utils, KVEngine, KVEngineType};
 use crate::async_fuse::memfs::s3_wrapper::DoNothingImpl;
 use crate::common::logger::{init_logger, LogRole};
 
@@ -19,8 +21,20 @@ pub const TEST_ETCD_ENDPOINT: &str = "127.0.0.1:2379";
 
 /// The default capacity in bytes for test, 1GB
 const CACHE_DEFAULT_CAPACITY: usize = 1024 * 1024 * 1024;
+/// The provisioned size of the test volume, 10GB
+const VOLUME_CAPACITY: u64 = 10 * 1024 * 1024 * 1024;
+/// The max number of inodes in the test volume
+const VOLUME_INODES: u64 = 1024 * 1024;
+
+/// The FUSE session running for test
+pub struct TestSession {
+    /// Shut down the session gracefully and un-mount the file system
+    shutdown: oneshot::Sender<()>,
+    /// The task running the session
+    handle: tokio::task::JoinHandle<()>,
+}
 
-fn test_storage_config() -> StorageConfig {
+pub fn test_storage_config() -> StorageConfig {
     let s3_config = StorageS3Config {
         endpoint_url: "http://127.0.0.1:9000".to_owned(),
         access_key_id: "test".to_owned(),
@@ -29,13 +43,41 @@ fn test_storage_config() -> StorageConfig {
     };
     StorageConfig {
         cache_capacity: CACHE_DEFAULT_CAPACITY,
+        volume_capacity: VOLUME_CAPACITY,
+        volume_inodes: VOLUME_INODES,
+        metadata_checkpoint_interval: 0,
+        metadata_recover: false,
+        metadata_snapshot: None,
         params: StorageParams::S3(s3_config),
+        fuse_config: FuseConfig::default(),
     }
 }
 
-#[allow(clippy::let_underscore_must_use)]
 // TODO : Remove `is_s3` arg due too we only support s3 now
-pub async fn setup(mount_dir: &Path, is_s3: bool) -> anyhow::Result<tokio::task::JoinHandle<()>> {
+pub async fn setup(mount_dir: &Path, is_s3: bool) -> anyhow::Result<TestSession> {
+    setup_with_fuse_config(mount_dir, is_s3, FuseConfig::default()).await
+}
+
+/// Mount the file system for test with the FUSE capabilities of `fuse_config`
+pub async fn setup_with_fuse_config(
+    mount_dir: &Path,
+    is_s3: bool,
+    fuse_config: FuseConfig,
+) -> anyhow::Result<TestSession> {
+    let storage_config = StorageConfig {
+        fuse_config,
+        ..test_storage_config()
+    };
+    setup_with_storage_config(mount_dir, is_s3, storage_config).await
+}
+
+/// Mount the file system for test with `storage_config`
+#[allow(clippy::let_underscore_must_use)]
+pub async fn setup_with_storage_config(
+    mount_dir: &Path,
+    is_s3: bool,
+    storage_config: StorageConfig,
+) -> anyhow::Result<TestSession> {
     init_logger(LogRole::Test);
     debug!("setup started with mount_dir: {:?}", mount_dir);
     if mount_dir.exists() {
@@ -61,10 +103,20 @@ pub async fn setup(mount_dir: &Path, is_s3: bool) -> anyhow::Result<tokio::task:
     fs::create_dir_all(mount_dir)?;
     let abs_root_path = fs::canonicalize(mount_dir)?;
 
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
     let fs_task = tokio::task::spawn(async move {
-        async fn run_fs(mount_point: &Path, is_s3: bool) -> anyhow::Result<()> {
-            let storage_config = test_storage_config();
+        async fn run_fs(
+            mount_point: &Path,
+            is_s3: bool,
+            storage_config: StorageConfig,
+            shutdown: oneshot::Receiver<()>,
+        ) -> anyhow::Result<()> {
             let kv_engine = Arc::new(KVEngineType::new(vec![TEST_ETCD_ENDPOINT.to_owned()]).await?);
+            // A snapshot is mounted on the view of the kv engine at its revision
+            let kv_engine = match storage_config.metadata_snapshot {
+                Some(ref name) => Arc::new(kv_utils::pin_at_snapshot(&kv_engine, name).await?),
+                None => kv_engine,
+            };
             if is_s3 {
                 let fs: memfs::MemFs<memfs::S3MetaData<DoNothingImpl>> = memfs::MemFs::new(
                     mount_point
@@ -79,8 +131,15 @@ pub async fn setup(mount_dir: &Path, is_s3: bool) -> anyhow::Result<tokio::task:
                     &storage_config,
                 )
                 .await?;
-                let ss = session::new_session_of_memfs(mount_point, fs).await?;
-                ss.run().await?;
+                let mut ss = session::new_session_of_memfs(
+                    mount_point,
+                    fs,
+                    storage_config.fuse_config,
+                    &MountOptions::default(),
+                )
+                .await?;
+                ss.run_until(shutdown.map(|_| ())).await?;
+                ss.umount().await?;
             } else {
                 let fs: memfs::MemFs<memfs::S3MetaData<DoNothingImpl>> = memfs::MemFs::new(
                     mount_point
@@ -95,13 +154,20 @@ pub async fn setup(mount_dir: &Path, is_s3: bool) -> anyhow::Result<tokio::task:
                     &storage_config,
                 )
                 .await?;
-                let ss = session::new_session_of_memfs(mount_point, fs).await?;
-                ss.run().await?;
+                let mut ss = session::new_session_of_memfs(
+                    mount_point,
+                    fs,
+                    storage_config.fuse_config,
+                    &MountOptions::default(),
+                )
+                .await?;
+                ss.run_until(shutdown.map(|_| ())).await?;
+                ss.umount().await?;
             };
 
             Ok(())
         }
-        if let Err(e) = run_fs(&abs_root_path, is_s3).await {
+        if let Err(e) = run_fs(&abs_root_path, is_s3, storage_config, shutdown_rx).await {
             panic!(
                 "failed to run filesystem, the error is: {}",
                 crate::common::util::format_anyhow_error(&e),
@@ -123,32 +189,45 @@ pub async fn setup(mount_dir: &Path, is_s3: bool) -> anyhow::Result<tokio::task:
     tokio::time::sleep(Duration::new(seconds, 0)).await;
 
     info!("setup finished");
-    Ok(th)
+    Ok(TestSession {
+        shutdown: shutdown_tx,
+        handle: th,
+    })
+}
+
+/// Check whether `path` is a mount point of current process
+fn is_mounted(path: &Path) -> anyhow::Result<bool> {
+    let mount_info = fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mount_info
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .any(|mount_point| Path::new(mount_point) == path))
 }
 
-pub async fn teardown(mount_dir: &Path, th: tokio::task::JoinHandle<()>) -> anyhow::Result<()> {
+pub async fn teardown(mount_dir: &Path, session: TestSession) -> anyhow::Result<()> {
     info!("begin teardown");
     let seconds = 1;
     debug!("sleep {} seconds for teardown", seconds);
     tokio::time::sleep(Duration::new(seconds, 0)).await;
 
-    mount::umount(mount_dir).await.unwrap_or_else(|err| {
-        panic!(
-            "failed to un-mount {:?}, the error is: {}",
-            mount_dir,
-            crate::common::util::format_anyhow_error(&err),
-        )
-    });
+    // Shut down the session gracefully, which un-mounts the file system
     let abs_mount_path = fs::canonicalize(mount_dir)?;
-    fs::remove_dir_all(&abs_mount_path)?;
-
+    session
+        .shutdown
+        .send(())
+        .unwrap_or_else(|()| panic!("the session of {mount_dir:?} quit before shutdown"));
     #[allow(box_pointers)] // thread join result involves box point
-    th.await.unwrap_or_else(|res| {
+    session.handle.await.unwrap_or_else(|res| {
         panic!(
             "failed to wait the test setup thread to finish, \
             the thread result is: {res:?}",
         );
     });
+    assert!(
+        !is_mounted(&abs_mount_path)?,
+        "{abs_mount_path:?} is still mounted after shutdown"
+    );
+    fs::remove_dir_all(&abs_mount_path)?;
 
     Ok(())
 }
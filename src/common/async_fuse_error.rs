@@ -7,4 +7,10 @@ pub enum KVEngineError {
     /// Error caused by std::io::Error
     #[error("Timeout arg in kv operation is <= 0")]
     WrongTimeoutArg,
+    /// The lease has expired or does not exist
+    #[error("Lease {0} has expired")]
+    LeaseExpired(i64),
+    /// The response has no header, which carries the revision
+    #[error("Response has no header")]
+    NoResponseHeader,
 }
@@ -35,6 +35,13 @@ pub struct Config {
     #[clap(flatten)]
     /// CSI related config
     pub csi_config: CSIConfig,
+    #[clap(flatten)]
+    /// FUSE mount options
+    pub mount_options: MountOptions,
+    #[clap(long = "upgrade-socket", value_name = "VALUE", default_value_t)]
+    /// The Unix domain socket for live upgrade, a new process started with the
+    /// same socket takes over the FUSE session of the running one
+    pub upgrade_socket: String,
 }
 
 #[derive(Debug, Parser)]
@@ -50,9 +57,46 @@ pub struct StorageConfig {
     )]
     /// Set memory cache capacity, default is 1GB
     pub cache_capacity: usize,
+    #[clap(
+        long = "storage-volume-capacity",
+        value_name = "VALUE",
+        default_value_t = 1099511627776
+    )]
+    /// Set the provisioned size of the volume in bytes, default is 1TB
+    pub volume_capacity: u64,
+    #[clap(
+        long = "storage-volume-inodes",
+        value_name = "VALUE",
+        default_value_t = 1048576
+    )]
+    /// Set the max number of inodes in the volume
+    pub volume_inodes: u64,
+    #[clap(
+        long = "storage-metadata-checkpoint-interval",
+        value_name = "VALUE",
+        default_value_t = 0
+    )]
+    /// Set the interval in seconds to checkpoint the metadata to the storage
+    /// backend and compact the history of the KV engine before the checkpoint,
+    /// 0 means never, which is the default
+    pub metadata_checkpoint_interval: u64,
+    #[clap(long = "storage-metadata-recover")]
+    /// Rebuild the metadata in the KV engine from the checkpoint in the storage
+    /// backend if the KV engine has none, for disaster recovery
+    pub metadata_recover: bool,
+    #[clap(
+        long = "storage-metadata-snapshot",
+        value_name = "VALUE",
+        default_value_t
+    )]
+    /// Mount the snapshot of the name read-only instead of the latest metadata
+    pub metadata_snapshot: String,
     #[clap(flatten)]
     /// S3 storage config
     pub s3_storage_config: S3StorageConfig,
+    #[clap(flatten)]
+    /// FUSE capabilities config
+    pub fuse_config: FuseConfig,
 }
 
 /// S3 storage config
@@ -84,6 +128,75 @@ pub struct S3StorageConfig {
     pub bucket_name: String,
 }
 
+/// FUSE capabilities negotiated with the kernel at INIT
+#[derive(Debug, Parser)]
+#[allow(clippy::struct_excessive_bools)] // Each bool is an independent capability
+pub struct FuseConfig {
+    #[clap(
+        long = "fuse-max-background",
+        value_name = "VALUE",
+        default_value_t = 10
+    )]
+    /// The max number of pending background requests from the kernel
+    pub max_background: u16,
+    #[clap(long = "fuse-channels", value_name = "VALUE", default_value_t = 1)]
+    /// The number of channels cloned from the FUSE device to read requests
+    pub channels: u16,
+    #[clap(long = "fuse-writeback-cache")]
+    /// Enable the kernel writeback cache for buffered writes
+    pub writeback_cache: bool,
+    #[clap(long = "fuse-async-dio")]
+    /// Enable asynchronous direct I/O submission
+    pub async_dio: bool,
+    #[clap(long = "fuse-parallel-dirops")]
+    /// Allow parallel lookups and readdir in a directory
+    pub parallel_dirops: bool,
+    #[clap(long = "fuse-splice")]
+    /// Enable splice to move data through the FUSE device
+    pub splice: bool,
+    #[clap(long = "fuse-auto-inval-data")]
+    /// Invalidate the cached pages when the file is changed remotely
+    pub auto_inval_data: bool,
+    #[clap(long = "fuse-cache-symlinks")]
+    /// Cache the symlink targets in the kernel
+    pub cache_symlinks: bool,
+}
+
+/// FUSE mount options
+#[derive(Debug, Parser)]
+pub struct MountOptions {
+    #[clap(
+        long = "mount-allow-other",
+        value_name = "VALUE",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    /// Allow the users other than the mounting user to access the file system
+    pub allow_other: bool,
+    #[clap(
+        long = "mount-default-permissions",
+        value_name = "VALUE",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    /// Let the kernel check the file permissions
+    pub default_permissions: bool,
+    #[clap(long = "mount-read-only")]
+    /// Mount the file system read-only
+    pub read_only: bool,
+    #[clap(
+        long = "mount-fsname",
+        value_name = "VALUE",
+        default_value = "datenlord"
+    )]
+    /// The file system name shown in the mount table, e.g. by `df`
+    pub fsname: String,
+    #[clap(long = "mount-subtype", value_name = "VALUE", default_value_t)]
+    /// The file system subtype, the file system type is shown as
+    /// `fuse.<subtype>` if it's set
+    pub subtype: String,
+}
+
 /// CSI related config
 #[derive(Debug, Clone, Parser)]
 pub struct CSIConfig {
@@ -105,7 +218,9 @@ mod tests {
     use std::str::FromStr;
 
     use super::*;
-    use crate::config::inner::{InnerConfig, Role, StorageParams as InnerStorageParams};
+    use crate::config::inner::{
+        InnerConfig, MountOptions as InnerMountOptions, Role, StorageParams as InnerStorageParams,
+    };
 
     #[test]
     #[allow(clippy::indexing_slicing)]
@@ -156,6 +271,29 @@ mod tests {
         // Cache capacity
         assert_eq!(config.storage.cache_capacity, 0x4000_0000);
 
+        // Volume capacity and inodes
+        assert_eq!(config.storage.volume_capacity, 0x100_0000_0000);
+        assert_eq!(config.storage.volume_inodes, 0x10_0000);
+
+        // Metadata checkpoint
+        assert_eq!(config.storage.metadata_checkpoint_interval, 0);
+        assert!(!config.storage.metadata_recover);
+        assert!(config.storage.metadata_snapshot.is_empty());
+
+        // FUSE capabilities
+        assert_eq!(config.storage.fuse_config.max_background, 10);
+        assert_eq!(config.storage.fuse_config.channels, 1);
+        assert!(!config.storage.fuse_config.writeback_cache);
+        assert!(!config.storage.fuse_config.splice);
+
+        // Mount options
+        assert!(config.mount_options.allow_other);
+        assert!(config.mount_options.default_permissions);
+        assert!(!config.mount_options.read_only);
+        assert_eq!(config.mount_options.fsname, "datenlord");
+        assert!(config.mount_options.subtype.is_empty());
+        assert!(config.upgrade_socket.is_empty());
+
         // Cast to InnerConfig
         let inner_config: InnerConfig = config.try_into().unwrap();
         assert_eq!(inner_config.role, Role::Node);
@@ -171,6 +309,11 @@ mod tests {
 
         let storage_config = inner_config.storage;
         assert_eq!(storage_config.cache_capacity, 0x4000_0000);
+        assert_eq!(storage_config.volume_capacity, 0x100_0000_0000);
+        assert_eq!(storage_config.volume_inodes, 0x10_0000);
+        assert_eq!(storage_config.metadata_checkpoint_interval, 0);
+        assert!(!storage_config.metadata_recover);
+        assert!(storage_config.metadata_snapshot.is_none());
         match storage_config.params {
             InnerStorageParams::None(_) => {}
             InnerStorageParams::S3(_) => panic!("storage params should be None"),
@@ -180,6 +323,9 @@ mod tests {
         assert_eq!(csi_config.endpoint, "unix:///tmp/node.sock ");
         assert_eq!(csi_config.driver_name, "io.datenlord.csi.plugin");
         assert_eq!(csi_config.worker_port, 9001);
+
+        assert_eq!(inner_config.mount_options, InnerMountOptions::default());
+        assert!(inner_config.upgrade_socket.is_none());
     }
 
     #[test]
@@ -203,6 +349,13 @@ mod tests {
             "S3",
             "--storage-cache-capacity",
             "1024",
+            "--storage-volume-capacity",
+            "1048576",
+            "--storage-volume-inodes",
+            "100",
+            "--storage-metadata-checkpoint-interval",
+            "60",
+            "--storage-metadata-recover",
             "--storage-s3-endpoint-url",
             "http://127.0.0.1:9000",
             "--storage-s3-access-key-id",
@@ -213,10 +366,47 @@ mod tests {
             "test_bucket",
             "--kv-server-list",
             "127.0.0.1:7890,127.0.0.1:7891",
+            "--fuse-max-background",
+            "64",
+            "--fuse-channels",
+            "4",
+            "--fuse-writeback-cache",
+            "--fuse-parallel-dirops",
+            "--mount-allow-other",
+            "false",
+            "--mount-read-only",
+            "--mount-fsname",
+            "datenlord-s3",
+            "--mount-subtype",
+            "datenlord",
+            "--upgrade-socket",
+            "/tmp/datenlord_upgrade.sock",
         ];
         let config: InnerConfig = Config::parse_from(args).try_into().unwrap();
+        assert_eq!(
+            config.upgrade_socket.as_deref(),
+            Some("/tmp/datenlord_upgrade.sock")
+        );
+        let mount_options = config.mount_options;
+        assert!(!mount_options.allow_other);
+        assert!(mount_options.default_permissions);
+        assert!(mount_options.read_only);
+        assert_eq!(mount_options.fsname, "datenlord-s3");
+        assert_eq!(mount_options.subtype.as_deref(), Some("datenlord"));
         let storage_config = config.storage;
         assert_eq!(storage_config.cache_capacity, 1024);
+        assert_eq!(storage_config.volume_capacity, 1_048_576);
+        assert_eq!(storage_config.volume_inodes, 100);
+        assert_eq!(storage_config.metadata_checkpoint_interval, 60);
+        assert!(storage_config.metadata_recover);
+        let fuse_config = storage_config.fuse_config;
+        assert_eq!(fuse_config.max_background, 64);
+        assert_eq!(fuse_config.channels, 4);
+        assert!(fuse_config.writeback_cache);
+        assert!(fuse_config.parallel_dirops);
+        assert!(!fuse_config.async_dio);
+        assert!(!fuse_config.auto_inval_data);
+        assert!(!fuse_config.cache_symlinks);
         match storage_config.params {
             InnerStorageParams::S3(s3_config) => {
                 assert_eq!(s3_config.endpoint_url, "http://127.0.0.1:9000");
@@ -280,5 +470,143 @@ mod tests {
         let wrong_args = vec!["datenlord", "--role"];
         let config = Config::try_parse_from(wrong_args);
         assert!(config.is_err());
+
+        // Test too small fuse max background
+        let wrong_args = vec![
+            "datenlord",
+            "--role",
+            "node",
+            "--node-name",
+            "node1",
+            "--node-ip",
+            "127.0.0.1",
+            "--mount-path",
+            "/tmp/datenlord_data_dir",
+            "--kv-server-list",
+            "127.0.0.1:7890",
+            "--fuse-max-background",
+            "1",
+        ];
+        let config: Result<InnerConfig, _> = Config::parse_from(wrong_args).try_into();
+        assert!(config.is_err());
+
+        // Test no fuse channel
+        let wrong_args = vec![
+            "datenlord",
+            "--role",
+            "node",
+            "--node-name",
+            "node1",
+            "--node-ip",
+            "127.0.0.1",
+            "--mount-path",
+            "/tmp/datenlord_data_dir",
+            "--kv-server-list",
+            "127.0.0.1:7890",
+            "--fuse-channels",
+            "0",
+        ];
+        let config: Result<InnerConfig, _> = Config::parse_from(wrong_args).try_into();
+        assert!(config.is_err());
+
+        // Test fsname mixed with other mount options
+        let wrong_args = vec![
+            "datenlord",
+            "--role",
+            "node",
+            "--node-name",
+            "node1",
+            "--node-ip",
+            "127.0.0.1",
+            "--mount-path",
+            "/tmp/datenlord_data_dir",
+            "--kv-server-list",
+            "127.0.0.1:7890",
+            "--mount-fsname",
+            "datenlord,suid",
+        ];
+        let config: Result<InnerConfig, _> = Config::parse_from(wrong_args).try_into();
+        assert!(config.is_err());
+
+        // Test recovering the metadata when mounting a snapshot
+        let wrong_args = vec![
+            "datenlord",
+            "--role",
+            "node",
+            "--node-name",
+            "node1",
+            "--node-ip",
+            "127.0.0.1",
+            "--mount-path",
+            "/tmp/datenlord_data_dir",
+            "--kv-server-list",
+            "127.0.0.1:7890",
+            "--storage-metadata-recover",
+            "--storage-metadata-snapshot",
+            "daily",
+        ];
+        let config: Result<InnerConfig, _> = Config::parse_from(wrong_args).try_into();
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_snapshot_config() {
+        let args = vec![
+            "datenlord",
+            "--role",
+            "node",
+            "--node-name",
+            "node1",
+            "--node-ip",
+            "127.0.0.1",
+            "--mount-path",
+            "/tmp/datenlord_snapshot_dir",
+            "--kv-server-list",
+            "127.0.0.1:7890",
+            "--storage-metadata-snapshot",
+            "daily",
+        ];
+        let config: InnerConfig = Config::parse_from(args).try_into().unwrap();
+        assert_eq!(config.storage.metadata_snapshot.as_deref(), Some("daily"));
+        // The snapshot is mounted read-only
+        assert!(config.mount_options.read_only);
+    }
+
+    #[test]
+    fn test_volume_info_skips_node_config() {
+        let args = vec![
+            "datenlord",
+            "--role",
+            "node",
+            "--node-name",
+            "node1",
+            "--node-ip",
+            "127.0.0.1",
+            "--mount-path",
+            "/tmp/datenlord_data_dir",
+            "--kv-server-list",
+            "127.0.0.1:7890",
+        ];
+        let node_args = [
+            "--storage-volume-capacity",
+            "4096",
+            "--storage-volume-inodes",
+            "16",
+            "--storage-metadata-checkpoint-interval",
+            "60",
+            "--storage-metadata-recover",
+            "--fuse-channels",
+            "4",
+            "--fuse-splice",
+        ];
+        let config: InnerConfig = Config::parse_from(args.clone()).try_into().unwrap();
+        let node_config: InnerConfig = Config::parse_from(args.into_iter().chain(node_args))
+            .try_into()
+            .unwrap();
+        // The nodes mounting the volume register the same volume information
+        assert_eq!(
+            serde_json::to_string(&config.storage).unwrap(),
+            serde_json::to_string(&node_config.storage).unwrap()
+        );
     }
 }
@@ -5,7 +5,8 @@ use serde::{Deserialize, Serialize};
 
 use crate::common::error::DatenLordError;
 use crate::config::config::{
-    CSIConfig as SupperCSIConfig, Config as SuperConfig, S3StorageConfig as SuperS3StorageConfig,
+    CSIConfig as SupperCSIConfig, Config as SuperConfig, FuseConfig as SuperFuseConfig,
+    MountOptions as SuperMountOptions, S3StorageConfig as SuperS3StorageConfig,
     StorageConfig as SuperStorageConfig,
 };
 
@@ -62,6 +63,10 @@ pub struct InnerConfig {
     pub storage: StorageConfig,
     /// CSI related config
     pub csi_config: CSIConfig,
+    /// FUSE mount options
+    pub mount_options: MountOptions,
+    /// The Unix domain socket for live upgrade, disabled if `None`
+    pub upgrade_socket: Option<String>,
 }
 
 impl TryFrom<SuperConfig> for InnerConfig {
@@ -79,7 +84,7 @@ impl TryFrom<SuperConfig> for InnerConfig {
             }
         })?;
         let mount_path = value.mount_path;
-        let storage = value.storage.try_into()?;
+        let storage: StorageConfig = value.storage.try_into()?;
         let kv_addrs: Vec<String> = value.kv_server_list;
         if kv_addrs.is_empty() {
             return Err(DatenLordError::ArgumentInvalid {
@@ -87,6 +92,12 @@ impl TryFrom<SuperConfig> for InnerConfig {
             });
         }
         let csi_config = value.csi_config.try_into()?;
+        let mut mount_options: MountOptions = value.mount_options.try_into()?;
+        // A snapshot is always mounted read-only
+        if storage.metadata_snapshot.is_some() {
+            mount_options.read_only = true;
+        }
+        let upgrade_socket = (!value.upgrade_socket.is_empty()).then_some(value.upgrade_socket);
         Ok(InnerConfig {
             role,
             node_name,
@@ -97,6 +108,8 @@ impl TryFrom<SuperConfig> for InnerConfig {
             scheduler_extender_port,
             storage,
             csi_config,
+            mount_options,
+            upgrade_socket,
         })
     }
 }
@@ -104,12 +117,35 @@ impl TryFrom<SuperConfig> for InnerConfig {
 /// Storage config
 /// Cache related config, currently only support cache capacity
 /// Storage backend related config, currently only support S3
+/// The config is serialized as the volume information registered by all the
+/// nodes mounting the volume, so the knobs of each node are skipped
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StorageConfig {
     /// Cache capacity
     pub cache_capacity: usize,
+    /// The provisioned size of the volume in bytes
+    #[serde(skip)]
+    pub volume_capacity: u64,
+    /// The max number of inodes in the volume
+    #[serde(skip)]
+    pub volume_inodes: u64,
+    /// The interval in seconds to checkpoint the metadata to the storage
+    /// backend, 0 means never
+    #[serde(skip)]
+    pub metadata_checkpoint_interval: u64,
+    /// Whether to rebuild the metadata from the checkpoint in the storage
+    /// backend if the KV engine has none
+    #[serde(skip)]
+    pub metadata_recover: bool,
+    /// The name of the snapshot to mount read-only, the latest metadata is
+    /// mounted if `None`
+    #[serde(skip)]
+    pub metadata_snapshot: Option<String>,
     /// Storage params
     pub params: StorageParams,
+    /// FUSE capabilities config
+    #[serde(skip)]
+    pub fuse_config: FuseConfig,
 }
 
 impl TryFrom<SuperStorageConfig> for StorageConfig {
@@ -118,6 +154,16 @@ impl TryFrom<SuperStorageConfig> for StorageConfig {
     #[inline]
     fn try_from(value: SuperStorageConfig) -> Result<Self, Self::Error> {
         let cache_capacity = value.cache_capacity;
+        if value.volume_capacity == 0 || value.volume_inodes == 0 {
+            return Err(DatenLordError::ArgumentInvalid {
+                context: vec!["volume capacity and inodes should be positive".to_owned()],
+            });
+        }
+        if value.metadata_recover && !value.metadata_snapshot.is_empty() {
+            return Err(DatenLordError::ArgumentInvalid {
+                context: vec!["cannot recover the metadata when mounting a snapshot".to_owned()],
+            });
+        }
         let params = match value.storage_type.as_str() {
             "S3" => StorageParams::S3(value.s3_storage_config.try_into()?),
             "none" => StorageParams::None(value.s3_storage_config.try_into()?),
@@ -130,9 +176,17 @@ impl TryFrom<SuperStorageConfig> for StorageConfig {
                 })
             }
         };
+        let fuse_config = value.fuse_config.try_into()?;
         Ok(StorageConfig {
             cache_capacity,
+            volume_capacity: value.volume_capacity,
+            volume_inodes: value.volume_inodes,
+            metadata_checkpoint_interval: value.metadata_checkpoint_interval,
+            metadata_recover: value.metadata_recover,
+            metadata_snapshot: (!value.metadata_snapshot.is_empty())
+                .then_some(value.metadata_snapshot),
             params,
+            fuse_config,
         })
     }
 }
@@ -176,6 +230,133 @@ impl TryFrom<SuperS3StorageConfig> for StorageS3Config {
     }
 }
 
+/// FUSE capabilities config
+/// The optional capabilities are enabled only if the kernel supports them
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[allow(clippy::struct_excessive_bools)] // Each bool is an independent capability
+pub struct FuseConfig {
+    /// The max number of pending background requests from the kernel
+    pub max_background: u16,
+    /// The number of channels cloned from the FUSE device to read requests,
+    /// each channel has its own reader and buffer pool
+    pub channels: u16,
+    /// Enable the kernel writeback cache for buffered writes
+    pub writeback_cache: bool,
+    /// Enable asynchronous direct I/O submission
+    pub async_dio: bool,
+    /// Allow parallel lookups and readdir in a directory
+    pub parallel_dirops: bool,
+    /// Enable splice to move data through the FUSE device
+    pub splice: bool,
+    /// Invalidate the cached pages when the file is changed remotely
+    pub auto_inval_data: bool,
+    /// Cache the symlink targets in the kernel
+    pub cache_symlinks: bool,
+}
+
+impl Default for FuseConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            max_background: 10,
+            channels: 1,
+            writeback_cache: false,
+            async_dio: false,
+            parallel_dirops: false,
+            splice: false,
+            auto_inval_data: false,
+            cache_symlinks: false,
+        }
+    }
+}
+
+impl TryFrom<SuperFuseConfig> for FuseConfig {
+    type Error = DatenLordError;
+
+    #[inline]
+    fn try_from(value: SuperFuseConfig) -> Result<Self, Self::Error> {
+        // Less background requests may deadlock the FUSE session
+        if value.max_background < 4 {
+            return Err(DatenLordError::ArgumentInvalid {
+                context: vec![format!(
+                    "fuse max background {} is less than 4",
+                    value.max_background
+                )],
+            });
+        }
+        if value.channels == 0 {
+            return Err(DatenLordError::ArgumentInvalid {
+                context: vec!["fuse channels should be at least 1".to_owned()],
+            });
+        }
+        Ok(FuseConfig {
+            max_background: value.max_background,
+            channels: value.channels,
+            writeback_cache: value.writeback_cache,
+            async_dio: value.async_dio,
+            parallel_dirops: value.parallel_dirops,
+            splice: value.splice,
+            auto_inval_data: value.auto_inval_data,
+            cache_symlinks: value.cache_symlinks,
+        })
+    }
+}
+
+/// FUSE mount options
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountOptions {
+    /// Allow the users other than the mounting user to access the file system
+    pub allow_other: bool,
+    /// Let the kernel check the file permissions
+    pub default_permissions: bool,
+    /// Mount the file system read-only
+    pub read_only: bool,
+    /// The file system name shown in the mount table
+    pub fsname: String,
+    /// The file system subtype, the file system type is `fuse.<subtype>`
+    pub subtype: Option<String>,
+}
+
+impl Default for MountOptions {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            allow_other: true,
+            default_permissions: true,
+            read_only: false,
+            fsname: "datenlord".to_owned(),
+            subtype: None,
+        }
+    }
+}
+
+impl TryFrom<SuperMountOptions> for MountOptions {
+    type Error = DatenLordError;
+
+    #[inline]
+    fn try_from(value: SuperMountOptions) -> Result<Self, Self::Error> {
+        // The mount options are separated by commas
+        let is_valid = |s: &str| !s.contains(|c: char| c == ',' || c == '=' || c.is_whitespace());
+        if value.fsname.is_empty() || !is_valid(&value.fsname) {
+            return Err(DatenLordError::ArgumentInvalid {
+                context: vec![format!("mount fsname {:?} is invalid", value.fsname)],
+            });
+        }
+        if !is_valid(&value.subtype) {
+            return Err(DatenLordError::ArgumentInvalid {
+                context: vec![format!("mount subtype {:?} is invalid", value.subtype)],
+            });
+        }
+        Ok(MountOptions {
+            allow_other: value.allow_other,
+            default_permissions: value.default_permissions,
+            read_only: value.read_only,
+            fsname: value.fsname,
+            subtype: (!value.subtype.is_empty()).then_some(value.subtype),
+        })
+    }
+}
+
 /// CSI config struct
 #[derive(Clone, Debug)]
 pub struct CSIConfig {
@@ -83,7 +83,7 @@ use clap::Parser;
 use csi::meta_data::MetaData;
 use csi::scheduler_extender::SchedulerExtender;
 use datenlord::config;
-use datenlord::config::{InnerConfig, NodeRole, StorageConfig};
+use datenlord::config::{InnerConfig, MountOptions, NodeRole, StorageConfig};
 
 use crate::common::error::DatenLordResult;
 use crate::common::etcd_delegate::EtcdDelegate;
@@ -102,6 +102,10 @@ pub struct AsyncFuseArgs {
     pub mount_dir: String,
     /// Storage config
     pub storage_config: StorageConfig,
+    /// FUSE mount options
+    pub mount_options: MountOptions,
+    /// The Unix domain socket for live upgrade
+    pub upgrade_socket: Option<String>,
 }
 /// Parse config from command line arguments, and return the created `MetaData`
 async fn parse_metadata(config: &InnerConfig) -> DatenLordResult<MetaData> {
@@ -123,6 +127,7 @@ async fn parse_metadata(config: &InnerConfig) -> DatenLordResult<MetaData> {
 }
 
 #[allow(clippy::too_many_lines)]
+#[allow(clippy::pattern_type_mismatch)] // for tokio::select!
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let config = InnerConfig::try_from(config::Config::parse())?;
@@ -144,7 +149,7 @@ async fn main() -> anyhow::Result<()> {
 
             let worker_server = csi::build_grpc_worker_server(Arc::<MetaData>::clone(&md))?;
             let node_server = csi::build_grpc_node_server(&csi_endpoint, &driver_name, md)?;
-            let csi_thread = tokio::task::spawn(async move {
+            let mut csi_thread = tokio::task::spawn(async move {
                 csi::run_grpc_servers(&mut [node_server, worker_server]).await;
             });
 
@@ -154,6 +159,8 @@ async fn main() -> anyhow::Result<()> {
                 server_port: config.server_port,
                 mount_dir: mount_dir.clone(),
                 storage_config: config.storage,
+                mount_options: config.mount_options,
+                upgrade_socket: config.upgrade_socket,
             };
             let async_fuse_thread = tokio::task::spawn(async move {
                 if let Err(e) = async_fuse::start_async_fuse(kv_engine, &async_args).await {
@@ -161,12 +168,16 @@ async fn main() -> anyhow::Result<()> {
                 }
             });
 
-            csi_thread
-                .await
-                .unwrap_or_else(|e| panic!("csi thread error: {e:?}"));
-            async_fuse_thread
-                .await
-                .unwrap_or_else(|e| panic!("csi thread error: {e:?}"));
+            // The CSI servers are stopped after async fuse is shut down
+            tokio::select! {
+                res = &mut csi_thread => {
+                    res.unwrap_or_else(|e| panic!("csi thread error: {e:?}"));
+                }
+                res = async_fuse_thread => {
+                    res.unwrap_or_else(|e| panic!("async fuse thread error: {e:?}"));
+                    csi_thread.abort();
+                }
+            }
         }
         NodeRole::Controller => {
             let metadata = parse_metadata(&config).await?;
@@ -204,6 +215,8 @@ async fn main() -> anyhow::Result<()> {
                 server_port: config.server_port,
                 mount_dir: mount_dir.clone(),
                 storage_config: config.storage,
+                mount_options: config.mount_options,
+                upgrade_socket: config.upgrade_socket,
             };
 
             if let Err(e) = async_fuse::start_async_fuse(kv_engine, &async_args).await {